@@ -0,0 +1,46 @@
+//! Measures the per-invocation overhead of `run()` and `run_stream()` in a
+//! tight script loop. Run with `cargo bench --bench run_overhead`.
+
+use rhai::packages::Package;
+use rhai::{Engine, EvalAltResult};
+use rhai_process::{Config, ProcessPackage};
+use std::time::Instant;
+
+const RUNS: i64 = 500;
+
+fn main() -> Result<(), Box<EvalAltResult>> {
+    let mut engine = Engine::new();
+    let package = ProcessPackage::new(Config::default());
+    package.register_into_engine(&mut engine);
+
+    for (name, pipeline, call) in [
+        ("run", r#"cmd(["true"])"#, "run()"),
+        (
+            "run_stream",
+            r#"cmd(["true"])"#,
+            "run_stream(|chunk| (), |chunk| ())",
+        ),
+        ("stage timeout", r#"cmd(["true"]).timeout(1000)"#, "run()"),
+        (
+            "two stages",
+            r#"cmd(["true"]).pipe(cmd(["true"]))"#,
+            "run()",
+        ),
+    ] {
+        let script = format!(
+            r#"
+            let exec = {pipeline}.build();
+            for i in 0..{RUNS} {{
+                exec.{call};
+            }}
+            "#
+        );
+        // Warm up, so lazily created state is not part of the measurement.
+        engine.run(&script)?;
+        let started = Instant::now();
+        engine.run(&script)?;
+        let per_run = started.elapsed() / RUNS as u32;
+        println!("{name:<24} {per_run:>10.1?} per invocation");
+    }
+    Ok(())
+}
@@ -0,0 +1,383 @@
+//! The tokio backend of `PipelineExecutor::run_async()`. Stages are spawned
+//! through `tokio::process`, and their output and exits are awaited on the
+//! runtime, so a running pipeline holds no thread of its own.
+
+use crate::command_spec::PipeSource;
+use crate::pipeline_executor::{
+    append_capped, cancelled_error, command_from_spec, PipelineExecutor, RunOutcome, StageSetup,
+    StreamKind,
+};
+use crate::pipeline_handle::{PipelineStatus, StartError};
+use crate::platform;
+use crate::process_result::ProcessResult;
+#[cfg(feature = "tracing")]
+use crate::telemetry;
+use crate::util::{map_io_err, runtime_error};
+use crate::RhaiResult;
+use std::future::{poll_fn, Future};
+use std::io;
+use std::pin::{pin, Pin};
+use std::process::{ExitStatus, Stdio};
+use std::task::Poll;
+use std::time::{Duration, Instant, SystemTime};
+use tokio::io::{AsyncRead, ReadBuf};
+use tokio::process::{Child, Command};
+
+/// Runs `executor` to completion. Dropping the future kills every stage
+/// along with the process groups they lead.
+pub(crate) async fn run(executor: PipelineExecutor) -> RhaiResult<ProcessResult> {
+    if executor.on_spawn.is_some() || executor.on_exit.is_some() {
+        return Err(runtime_error(
+            "run_async() cannot call on_spawn() or on_exit() callbacks",
+        ));
+    }
+    if executor.pty {
+        return Err(runtime_error("run_async() does not support pty()"));
+    }
+    let setup = StageSetup::new(&executor, false)?;
+    // Only waiting for a slot or for the spawn rate blocks, so only that
+    // runs on the blocking pool.
+    let admitting = executor.clone();
+    let slot = tokio::task::spawn_blocking(move || admitting.admit())
+        .await
+        .map_err(|err| map_io_err(io::Error::other(err)))??;
+
+    #[cfg(feature = "tracing")]
+    let span = telemetry::pipeline_span(&executor.commands);
+    let start = Instant::now();
+    let started_at = SystemTime::now();
+    let pipeline = Pipeline::spawn(&executor, &setup).map_err(|err| executor.start_error(err));
+    #[cfg(feature = "tracing")]
+    if let Err(err) = &pipeline {
+        telemetry::failed(&span, err);
+    }
+    let mut pipeline = pipeline?;
+    #[cfg(feature = "tracing")]
+    telemetry::spawned(&span, &executor.commands, &pipeline.pids);
+    let charge = executor.budget_charge(start);
+    let outcome = pipeline.drive(&executor, start, started_at).await;
+    if let Some(charge) = &charge {
+        charge.settle();
+    }
+    drop(slot);
+    #[cfg(feature = "tracing")]
+    telemetry::finished(&span, &outcome);
+    outcome
+}
+
+/// The started stages of a pipeline and the outputs still being read.
+struct Pipeline {
+    children: Vec<Child>,
+    pids: Vec<u32>,
+    outputs: Vec<Output>,
+    /// Holds everything each stage starts; see `PipelineHandle`.
+    #[cfg(windows)]
+    jobs: Vec<platform::Job>,
+}
+
+/// A pipe the pipeline's stdout or stderr is read from, `None` once it
+/// reached EOF.
+type Output = (StreamKind, Option<Pin<Box<dyn AsyncRead + Send>>>);
+
+/// What `Pipeline::next_event` saw.
+enum Event {
+    Data(StreamKind, Vec<u8>),
+    Exited(usize, io::Result<ExitStatus>),
+    Tick,
+}
+
+impl Pipeline {
+    /// Spawns every stage, connecting them through pipes like
+    /// `PipelineHandle::start`. Stages already started are killed when a later
+    /// one fails to start, as the pipeline is dropped.
+    fn spawn(executor: &PipelineExecutor, setup: &StageSetup) -> Result<Self, StartError> {
+        let mut pipeline = Self {
+            children: Vec::new(),
+            pids: Vec::new(),
+            outputs: Vec::new(),
+            #[cfg(windows)]
+            jobs: Vec::new(),
+        };
+        let last = executor.commands.len() - 1;
+        let mut next_stdin = None;
+        for (index, spec) in executor.commands.iter().enumerate() {
+            let failed = |error| StartError {
+                stage: index,
+                error,
+            };
+            let mut command = command_from_spec(spec, &executor.config, executor.cwd.as_ref());
+            setup.apply(&mut command).map_err(failed)?;
+            if let Some(reader) = next_stdin.take() {
+                command.stdin(reader);
+            }
+            // Output that does not go down the pipe is read as the
+            // pipeline's stderr, like every stage's stderr.
+            let mut stdout_kind = StreamKind::Stderr;
+            if index == last {
+                command.stdout(Stdio::piped()).stderr(Stdio::piped());
+                stdout_kind = StreamKind::Stdout;
+            } else {
+                let (reader, writer) = os_pipe::pipe().map_err(failed)?;
+                match spec.pipe_source {
+                    PipeSource::Stdout => command.stdout(writer).stderr(Stdio::piped()),
+                    PipeSource::Stderr => command.stdout(Stdio::piped()).stderr(writer),
+                    PipeSource::Both => {
+                        let stderr = writer.try_clone().map_err(failed)?;
+                        command.stdout(writer).stderr(stderr)
+                    }
+                };
+                next_stdin = Some(reader);
+            }
+            let mut command = Command::from(command);
+            command.kill_on_drop(true);
+            // Dropping `command` afterwards closes our copies of the pipe ends.
+            let mut child = command.spawn().map_err(failed)?;
+            let pid = child.id().unwrap_or_default();
+            #[cfg(windows)]
+            match platform::Job::for_suspended(&[pid], executor.resource_limits()) {
+                Ok(job) => pipeline.jobs.push(job),
+                Err(err) => {
+                    // Still suspended, so it has not run anything yet.
+                    let _ = child.start_kill();
+                    return Err(failed(err));
+                }
+            }
+            if let Some(stdout) = child.stdout.take() {
+                pipeline.outputs.push((stdout_kind, Some(Box::pin(stdout))));
+            }
+            if let Some(stderr) = child.stderr.take() {
+                pipeline
+                    .outputs
+                    .push((StreamKind::Stderr, Some(Box::pin(stderr))));
+            }
+            pipeline.children.push(child);
+            pipeline.pids.push(pid);
+        }
+        Ok(pipeline)
+    }
+
+    /// Reads the output and awaits the exits, enforcing the timeouts and
+    /// host cancellation like `RunningPipeline::drive`.
+    async fn drive(
+        &mut self,
+        executor: &PipelineExecutor,
+        start: Instant,
+        started_at: SystemTime,
+    ) -> RhaiResult<ProcessResult> {
+        let config = &executor.config;
+        let generation = config.cancellation.generation();
+        let timeout = executor.effective_timeout_ms().map(Duration::from_millis);
+        let idle_timeout = executor.idle_timeout_ms.map(Duration::from_millis);
+        let tick = Duration::from_millis(config.stream_poll_interval_ms);
+        let mut stage_deadlines: Vec<_> = executor
+            .commands
+            .iter()
+            .map(|command| {
+                command
+                    .timeout_ms
+                    .map(|ms| start + Duration::from_millis(ms))
+            })
+            .collect();
+        let mut statuses: Vec<Option<ExitStatus>> = vec![None; self.children.len()];
+        let mut stage_durations = vec![None; self.children.len()];
+        let mut buffer = vec![0; config.stream_buffer_size.max(1)];
+        let (mut stdout, mut stderr, mut combined) = (Vec::new(), Vec::new(), Vec::new());
+        let mut last_activity = start;
+        let mut timed_out = false;
+
+        loop {
+            config.check_progress()?;
+            if config.cancellation.cancelled_since(generation) {
+                self.terminate(None).await;
+                self.reap(&mut statuses).await;
+                return Err(cancelled_error(executor));
+            }
+            let expired = |since: Instant, limit: Option<Duration>| {
+                limit.is_some_and(|limit| since.elapsed() >= limit)
+            };
+            let message = if expired(start, timeout) {
+                Some("process execution timed out")
+            } else if expired(last_activity, idle_timeout) {
+                Some("process timed out waiting for output")
+            } else {
+                None
+            };
+            if let Some(message) = message {
+                self.terminate(executor.kill_grace()).await;
+                if !executor.timeout_returns_result {
+                    return Err(executor.io_error(io::Error::new(io::ErrorKind::TimedOut, message)));
+                }
+                timed_out = true;
+                break;
+            }
+            let now = Instant::now();
+            for (index, deadline) in stage_deadlines.iter_mut().enumerate() {
+                if deadline.is_some_and(|deadline| now >= deadline) {
+                    *deadline = None;
+                    self.terminate_stage(index, executor.kill_grace()).await;
+                }
+            }
+            let outputs_open = self.outputs.iter().any(|(_, output)| output.is_some());
+            if !outputs_open && statuses.iter().all(Option::is_some) {
+                break;
+            }
+
+            match self.next_event(&statuses, &mut buffer, tick).await {
+                Event::Data(kind, chunk) => {
+                    last_activity = Instant::now();
+                    let limit = config.max_output_bytes;
+                    match kind {
+                        StreamKind::Stdout => append_capped(&mut stdout, &chunk, limit),
+                        StreamKind::Stderr => append_capped(&mut stderr, &chunk, limit),
+                    }
+                    if executor.capture_combined {
+                        append_capped(&mut combined, &chunk, limit);
+                    }
+                }
+                Event::Exited(index, status) => {
+                    statuses[index] = Some(status.map_err(|err| executor.io_error(err))?);
+                    stage_durations[index] = Some(start.elapsed());
+                }
+                Event::Tick => {}
+            }
+        }
+        self.reap(&mut statuses).await;
+
+        let status = PipelineStatus {
+            usage: vec![None; statuses.len()],
+            stages: statuses.into_iter().flatten().collect(),
+        };
+        Ok(executor.result(RunOutcome {
+            status: &status,
+            pid: self.pids.first().map_or(-1, |pid| i64::from(*pid)),
+            started_at,
+            duration: start.elapsed(),
+            stage_durations,
+            stdout: &stdout,
+            stderr: &stderr,
+            combined: &combined,
+            cancelled: false,
+            timed_out,
+        }))
+    }
+
+    /// Waits for the next chunk of output or stage exit, or for `tick` to
+    /// pass, whichever comes first. An output that reaches EOF or fails is
+    /// closed.
+    async fn next_event(
+        &mut self,
+        statuses: &[Option<ExitStatus>],
+        buffer: &mut [u8],
+        tick: Duration,
+    ) -> Event {
+        let mut sleep = pin!(tokio::time::sleep(tick));
+        poll_fn(|cx| {
+            for (kind, output) in &mut self.outputs {
+                let Some(reader) = output else {
+                    continue;
+                };
+                let mut read = ReadBuf::new(buffer);
+                match reader.as_mut().poll_read(cx, &mut read) {
+                    Poll::Ready(Ok(())) if !read.filled().is_empty() => {
+                        return Poll::Ready(Event::Data(*kind, read.filled().to_vec()));
+                    }
+                    Poll::Ready(_) => *output = None,
+                    Poll::Pending => {}
+                }
+            }
+            for (index, child) in self.children.iter_mut().enumerate() {
+                if statuses[index].is_some() {
+                    continue;
+                }
+                // `wait()` may be cancelled and called again without losing
+                // the exit.
+                if let Poll::Ready(status) = pin!(child.wait()).poll(cx) {
+                    return Poll::Ready(Event::Exited(index, status));
+                }
+            }
+            sleep.as_mut().poll(cx).map(|()| Event::Tick)
+        })
+        .await
+    }
+
+    /// Reaps every stage that has not been reaped yet.
+    async fn reap(&mut self, statuses: &mut [Option<ExitStatus>]) {
+        for (child, status) in self.children.iter_mut().zip(statuses.iter_mut()) {
+            if status.is_none() {
+                // A failed wait leaves the stage to tokio's reaper.
+                *status = Some(child.wait().await.unwrap_or_default());
+            }
+        }
+    }
+
+    /// Kills every stage with the process group it leads, after asking them
+    /// to exit and waiting up to `grace` for it.
+    async fn terminate(&mut self, grace: Option<Duration>) {
+        for index in 0..self.children.len() {
+            self.terminate_stage(index, grace).await;
+        }
+    }
+
+    /// Kills the stage at `index` with the process group it leads, after
+    /// SIGTERM and up to `grace` for it to exit. The stage is not reaped in
+    /// between, so the group id cannot be reused by then.
+    #[cfg(unix)]
+    async fn terminate_stage(&mut self, index: usize, grace: Option<Duration>) {
+        let child = &self.children[index];
+        if let Some(grace) = grace {
+            signal_group(child, libc::SIGTERM);
+            let deadline = Instant::now() + grace;
+            while Instant::now() < deadline && !has_exited(child) {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        }
+        signal_group(child, libc::SIGKILL);
+    }
+
+    /// Windows has no group to signal; the stage's job holds its descendants.
+    #[cfg(not(unix))]
+    async fn terminate_stage(&mut self, index: usize, _grace: Option<Duration>) {
+        #[cfg(windows)]
+        let _ = self.jobs[index].terminate();
+        let _ = self.children[index].start_kill();
+    }
+}
+
+impl Drop for Pipeline {
+    /// Runs when the future is dropped, and after every run, when all stages
+    /// have been reaped and there is nothing left to kill. tokio kills and
+    /// reaps the stages themselves (`kill_on_drop`).
+    fn drop(&mut self) {
+        #[cfg(unix)]
+        for child in &self.children {
+            signal_group(child, libc::SIGKILL);
+        }
+        #[cfg(windows)]
+        for job in &self.jobs {
+            let _ = job.terminate();
+        }
+    }
+}
+
+/// Sends `signal` to the process group `child` leads, unless it has been
+/// reaped.
+#[cfg(unix)]
+fn signal_group(child: &Child, signal: libc::c_int) {
+    // `id()` is `None` once tokio has reaped the child.
+    if let Some(pid) = child.id() {
+        // SAFETY: kill takes no pointers. The child is borrowed, so it cannot
+        // be reaped meanwhile, and neither its pid nor the id of the group it
+        // leads can have been handed to another process.
+        unsafe {
+            libc::kill(-(pid as libc::pid_t), signal);
+        }
+    }
+}
+
+/// Whether `child` has exited, without reaping it.
+#[cfg(unix)]
+fn has_exited(child: &Child) -> bool {
+    child
+        .id()
+        .is_none_or(|pid| !matches!(platform::exited_usage(pid, false), Ok(None)))
+}
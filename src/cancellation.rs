@@ -0,0 +1,65 @@
+use crate::pipeline_handle::PipelineHandle;
+use crate::platform;
+use std::sync::{Arc, Mutex, Weak};
+
+/// Lets the host abort every pipeline started from one `Config` and its
+/// clones, e.g. on application shutdown or when a user cancels a task.
+/// Obtained from [`Config::cancellation_handle`](crate::Config::cancellation_handle)
+/// or [`ProcessPackage::cancellation_handle`](crate::ProcessPackage::cancellation_handle).
+#[derive(Clone, Debug, Default)]
+pub struct CancellationHandle {
+    inner: Arc<Mutex<InFlight>>,
+}
+
+#[derive(Debug, Default)]
+struct InFlight {
+    /// Bumped by every `cancel()`, so a pipeline can tell whether it was
+    /// cancelled after it started.
+    generation: u64,
+    pipelines: Vec<Weak<PipelineHandle>>,
+}
+
+impl CancellationHandle {
+    /// Kills every pipeline that is running, including those started with
+    /// `start()` that a script still holds. The scripts waiting on them get a
+    /// `"cancelled"` error. Pipelines started afterwards run normally, and
+    /// `run_detached()` pipelines are left alone.
+    pub fn cancel(&self) {
+        let pipelines = {
+            let mut in_flight = self.inner.lock().unwrap_or_else(|err| err.into_inner());
+            in_flight.generation += 1;
+            std::mem::take(&mut in_flight.pipelines)
+        };
+        for handle in pipelines.iter().filter_map(Weak::upgrade) {
+            if matches!(handle.try_wait(), Ok(None)) {
+                platform::kill_tree(&handle).ok();
+            }
+        }
+    }
+
+    /// Records a started pipeline and returns the generation it started in.
+    pub(crate) fn track(&self, handle: &Arc<PipelineHandle>) -> u64 {
+        let mut in_flight = self.inner.lock().unwrap_or_else(|err| err.into_inner());
+        in_flight
+            .pipelines
+            .retain(|pipeline| pipeline.strong_count() > 0);
+        in_flight.pipelines.push(Arc::downgrade(handle));
+        in_flight.generation
+    }
+
+    /// The generation a pipeline that is not tracked starts in, for runs
+    /// that check `cancelled_since` themselves.
+    #[cfg(feature = "async")]
+    pub(crate) fn generation(&self) -> u64 {
+        self.inner
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .generation
+    }
+
+    /// Whether `cancel()` was called since `generation`.
+    pub(crate) fn cancelled_since(&self, generation: u64) -> bool {
+        let in_flight = self.inner.lock().unwrap_or_else(|err| err.into_inner());
+        in_flight.generation != generation
+    }
+}
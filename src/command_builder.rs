@@ -1,10 +1,14 @@
-use crate::command_spec::CommandSpec;
+use crate::command_spec::{CommandSpec, PipeSource};
 use crate::config::Config;
+use crate::dotenv;
 use crate::pipe_builder::PipeBuilder;
 use crate::pipeline_executor::PipelineExecutor;
-use crate::util::{dynamic_to_string, runtime_error};
+use crate::util::{argument_to_string, denied_error, dynamic_to_string, map_io_err, runtime_error};
 use crate::{RhaiArray, RhaiResult};
-use rhai::Map as RhaiMap;
+use rhai::{Dynamic, ImmutableString, Map as RhaiMap, INT};
+use std::env;
+use std::fs;
+use std::path::PathBuf;
 use std::sync::Arc;
 
 #[derive(Clone, Debug)]
@@ -27,7 +31,9 @@ impl CommandBuilder {
         config.ensure_command_allowed(&program)?;
         let mut arg_list = Vec::new();
         for arg in items {
-            arg_list.push(dynamic_to_string(arg, "command argument")?);
+            let arg = argument_to_string(arg, config.strict_argument_types)?;
+            config.ensure_argument_allowed(&program, &arg)?;
+            arg_list.push(arg);
         }
 
         Ok(Self {
@@ -36,30 +42,235 @@ impl CommandBuilder {
         })
     }
 
+    /// Builds a command from a whitespace-separated template such as
+    /// `"grep {} {}"`. Every `{}` is replaced by the next value without any
+    /// shell interpretation, so a value always stays inside its own argument.
+    /// `{{` and `}}` produce literal braces.
+    pub(crate) fn from_template(
+        config: Arc<Config>,
+        template: ImmutableString,
+        values: RhaiArray,
+    ) -> RhaiResult<Self> {
+        let mut values = values.into_iter();
+        let mut argv = RhaiArray::new();
+        for word in template.split_whitespace() {
+            let mut arg = String::new();
+            let mut chars = word.chars().peekable();
+            while let Some(c) = chars.next() {
+                match (c, chars.peek()) {
+                    ('{', Some('{')) | ('}', Some('}')) => {
+                        chars.next();
+                        arg.push(c);
+                    }
+                    ('{', Some('}')) => {
+                        chars.next();
+                        let value = values.next().ok_or_else(|| {
+                            runtime_error("cmd_fmt has more placeholders than values")
+                        })?;
+                        arg.push_str(&argument_to_string(value, config.strict_argument_types)?);
+                    }
+                    _ => arg.push(c),
+                }
+            }
+            argv.push(Dynamic::from(arg));
+        }
+        if values.next().is_some() {
+            return Err(runtime_error("cmd_fmt has more values than placeholders"));
+        }
+        if argv.is_empty() {
+            return Err(runtime_error("cmd_fmt requires a non-empty template"));
+        }
+        Self::new(config, argv)
+    }
+
+    /// Runs `script` through the platform shell, if the embedder allowed it.
+    /// The shell goes through the same checks as any other command, so the
+    /// command lists, argument policies and search path apply to it.
+    pub(crate) fn shell(config: Arc<Config>, script: String) -> RhaiResult<Self> {
+        if !config.allow_shell {
+            return Err(denied_error(
+                None,
+                "shell execution is disabled; enable it with Config::allow_shell(true)",
+            ));
+        }
+        let (program, flag) = if cfg!(windows) {
+            ("cmd", "/C")
+        } else {
+            ("sh", "-c")
+        };
+        let argv = [program, flag]
+            .into_iter()
+            .map(Dynamic::from)
+            .chain([Dynamic::from(script)])
+            .collect();
+        Self::new(config, argv)
+    }
+
     pub(crate) fn with_env_map(mut self, map: RhaiMap) -> RhaiResult<Self> {
         for (key, value) in map.into_iter() {
             let string_key: String = key.into();
             let string_value = dynamic_to_string(value, "environment value")?;
-            self.config.ensure_env_allowed(&string_key)?;
-            self.command.env.insert(string_key, string_value);
+            self.config.ensure_env_allowed(&string_key, &string_value)?;
+            self.command.set_env(string_key, string_value);
         }
         Ok(self)
     }
 
     pub(crate) fn with_env_var(mut self, key: String, value: String) -> RhaiResult<Self> {
-        self.config.ensure_env_allowed(&key)?;
-        self.command.env.insert(key, value);
+        self.config.ensure_env_allowed(&key, &value)?;
+        self.command.set_env(key, value);
+        Ok(self)
+    }
+
+    /// Loads variables from a dotenv-format file, checking every key and value
+    /// against the env policy before any of them is applied.
+    pub(crate) fn env_file(mut self, path: String) -> RhaiResult<Self> {
+        let source = fs::read_to_string(&path).map_err(map_io_err)?;
+        let vars = dotenv::parse(&source)
+            .map_err(|err| runtime_error(format!("invalid env file '{path}': {err}")))?;
+        for (key, value) in &vars {
+            self.config.ensure_env_allowed(key, value)?;
+        }
+        for (key, value) in vars {
+            self.command.set_env(key, value);
+        }
+        Ok(self)
+    }
+
+    /// Copies the named variables from the host, e.g. after `env_clear()`.
+    /// Variables the host does not have are skipped.
+    pub(crate) fn passthrough_env(mut self, keys: RhaiArray) -> RhaiResult<Self> {
+        for key in keys {
+            let key = dynamic_to_string(key, "environment variable name")?;
+            if let Ok(value) = env::var(&key) {
+                self.config.ensure_env_allowed(&key, &value)?;
+                self.command.set_env(key, value);
+            }
+        }
+        Ok(self)
+    }
+
+    pub(crate) fn env_remove(mut self, key: String) -> Self {
+        self.command.remove_env(key);
+        self
+    }
+
+    pub(crate) fn env_clear(mut self) -> Self {
+        self.command.clear_env = true;
+        self
+    }
+
+    pub(crate) fn with_arg(mut self, arg: Dynamic) -> RhaiResult<Self> {
+        let arg = argument_to_string(arg, self.config.strict_argument_types)?;
+        self.config
+            .ensure_argument_allowed(&self.command.program, &arg)?;
+        self.command.args.push(arg);
+        Ok(self)
+    }
+
+    pub(crate) fn with_args(mut self, args: RhaiArray) -> RhaiResult<Self> {
+        for arg in args {
+            let arg = argument_to_string(arg, self.config.strict_argument_types)?;
+            self.config
+                .ensure_argument_allowed(&self.command.program, &arg)?;
+            self.command.args.push(arg);
+        }
+        Ok(self)
+    }
+
+    /// Appends an argument that Windows passes on exactly as written instead
+    /// of quoting it. Unix always passes arguments verbatim.
+    pub(crate) fn with_raw_arg(mut self, arg: String) -> RhaiResult<Self> {
+        self.config
+            .ensure_argument_allowed(&self.command.program, &arg)?;
+        self.command.raw_args.insert(self.command.args.len());
+        self.command.args.push(arg);
+        Ok(self)
+    }
+
+    pub(crate) fn with_cwd(mut self, path: String) -> RhaiResult<Self> {
+        if path.is_empty() {
+            self.command.cwd = None;
+        } else {
+            let path = PathBuf::from(path);
+            self.config.ensure_cwd_allowed(&path)?;
+            self.command.cwd = Some(path);
+        }
+        Ok(self)
+    }
+
+    pub(crate) fn with_timeout(mut self, timeout: INT) -> RhaiResult<Self> {
+        if timeout <= 0 {
+            return Err(runtime_error("timeout must be a positive integer"));
+        }
+        self.command.timeout_ms = Some(timeout as u64);
         Ok(self)
     }
 
     pub(crate) fn pipe(self, next: CommandBuilder) -> RhaiResult<PipeBuilder> {
-        crate::util::ensure_same_config(&self.config, &next.config)?;
-        let mut builder = PipeBuilder::from_single(Arc::clone(&self.config), self.command);
-        builder.push_command(next.command);
-        Ok(builder)
+        self.pipe_from(next, PipeSource::Stdout)
+    }
+
+    pub(crate) fn pipe_stderr(self, next: CommandBuilder) -> RhaiResult<PipeBuilder> {
+        self.pipe_from(next, PipeSource::Stderr)
+    }
+
+    pub(crate) fn pipe_both(self, next: CommandBuilder) -> RhaiResult<PipeBuilder> {
+        self.pipe_from(next, PipeSource::Both)
+    }
+
+    fn pipe_from(self, next: CommandBuilder, source: PipeSource) -> RhaiResult<PipeBuilder> {
+        PipeBuilder::from_single(Arc::clone(&self.config), self.command).pipe_from(next, source)
     }
 
     pub(crate) fn build(self) -> PipelineExecutor {
         PipeBuilder::from_single(self.config, self.command).into_executor()
     }
+
+    /// Rebuilds a command serialized from a `CommandBuilder`, checking it
+    /// against `config` as `cmd()` and the builder methods would.
+    #[cfg(feature = "serde")]
+    pub fn deserialize_with<'de, D>(config: Config, deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let command = <CommandSpec as serde::Deserialize>::deserialize(deserializer)?;
+        let config = Arc::new(config);
+        Self::check_definition(&config, &command).map_err(definition_error::<D::Error>)?;
+        Ok(Self { config, command })
+    }
+
+    /// Applies the checks a script's builder calls would have made to a
+    /// deserialized command.
+    #[cfg(feature = "serde")]
+    pub(crate) fn check_definition(config: &Config, command: &CommandSpec) -> RhaiResult<()> {
+        config.ensure_command_allowed(&command.program)?;
+        for arg in &command.args {
+            config.ensure_argument_allowed(&command.program, arg)?;
+        }
+        for (key, value) in &command.env {
+            config.ensure_env_allowed(key, value)?;
+        }
+        if let Some(path) = &command.cwd {
+            config.ensure_cwd_allowed(path)?;
+        }
+        if command.timeout_ms == Some(0) {
+            return Err(runtime_error("timeout must be a positive integer"));
+        }
+        Ok(())
+    }
+}
+
+/// The command only; env values are written as set, including secrets.
+#[cfg(feature = "serde")]
+impl serde::Serialize for CommandBuilder {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.command.serialize(serializer)
+    }
+}
+
+/// Turns a policy rejection into a deserialization error.
+#[cfg(feature = "serde")]
+pub(crate) fn definition_error<E: serde::de::Error>(err: Box<rhai::EvalAltResult>) -> E {
+    E::custom(crate::util::error_parts(&err).1)
 }
@@ -0,0 +1,96 @@
+use crate::command_builder::CommandBuilder;
+use crate::pipe_builder::PipeBuilder;
+use crate::pipeline_executor::PipelineExecutor;
+use crate::process_result::ProcessResult;
+use crate::util::{ensure_same_config, runtime_error};
+use crate::RhaiResult;
+use rhai::{Dynamic, NativeCallContext};
+
+/// Pipelines run one after another, each step gated on the outcome of
+/// what ran before it like a shell's `&&` and `||`.
+#[derive(Clone, Debug)]
+pub struct CommandSequence {
+    pub(crate) first: PipelineExecutor,
+    pub(crate) steps: Vec<(Chain, PipelineExecutor)>,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum Chain {
+    /// Run only if everything so far succeeded.
+    Then,
+    /// Run only if something so far failed.
+    OrElse,
+}
+
+impl CommandSequence {
+    pub(crate) fn new(first: PipelineExecutor) -> Self {
+        Self {
+            first,
+            steps: Vec::new(),
+        }
+    }
+
+    pub fn then(self, next: PipelineExecutor) -> RhaiResult<Self> {
+        self.push(Chain::Then, next)
+    }
+
+    pub fn or_else(self, next: PipelineExecutor) -> RhaiResult<Self> {
+        self.push(Chain::OrElse, next)
+    }
+
+    fn push(mut self, chain: Chain, next: PipelineExecutor) -> RhaiResult<Self> {
+        ensure_same_config(&self.first.config, &next.config)?;
+        self.steps.push((chain, next));
+        Ok(self)
+    }
+
+    pub fn run(self, context: &NativeCallContext) -> RhaiResult<ProcessResult> {
+        let mut ran = Vec::new();
+        // A step that could not run at all (e.g. a missing program) counts as
+        // a failure, so `or_else` can still fall back from it.
+        let mut outcome = self.first.execute(context);
+        if let Ok(result) = &outcome {
+            ran.push(result.clone());
+        }
+        for (chain, step) in self.steps {
+            let succeeded = matches!(&outcome, Ok(result) if result.success());
+            let should_run = match chain {
+                Chain::Then => succeeded,
+                Chain::OrElse => !succeeded,
+            };
+            if !should_run {
+                continue;
+            }
+            let next = step.execute(context);
+            if let Ok(result) = &next {
+                ran.push(result.clone());
+            }
+            outcome = match (chain, outcome, next) {
+                (Chain::Then, Ok(previous), Ok(next)) => Ok(next.after(&previous)),
+                (_, _, next) => next,
+            };
+        }
+
+        let mut result = outcome?;
+        result.steps = Some(ran);
+        Ok(result)
+    }
+}
+
+/// Accepts anything a sequence step can be built from: a command, a pipe or
+/// an already configured executor.
+pub(crate) fn executor_from(value: Dynamic) -> RhaiResult<PipelineExecutor> {
+    if value.is::<PipelineExecutor>() {
+        return Ok(value.cast::<PipelineExecutor>());
+    }
+    if value.is::<PipeBuilder>() {
+        return Ok(value.cast::<PipeBuilder>().build());
+    }
+    if value.is::<CommandBuilder>() {
+        return Ok(value.cast::<CommandBuilder>().build());
+    }
+    Err(runtime_error(format!(
+        "expected a command, pipe or executor, found {}",
+        value.type_name()
+    )))
+}
@@ -1,10 +1,50 @@
-use std::collections::BTreeMap;
+use crate::config::Config;
+use crate::shell_words::quote;
+use crate::RhaiArray;
+use rhai::{Dynamic, Map as RhaiMap};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::path::PathBuf;
 
+/// One stage of a pipeline. With the `serde` feature it serializes as the
+/// definition a builder was made from; only `program` is required.
 #[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+#[cfg_attr(feature = "serde", serde(deny_unknown_fields))]
 pub(crate) struct CommandSpec {
     pub(crate) program: String,
+    #[cfg_attr(feature = "serde", serde(default))]
     pub(crate) args: Vec<String>,
+    /// Indices of `args` passed to the program verbatim on Windows.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub(crate) raw_args: BTreeSet<usize>,
+    #[cfg_attr(feature = "serde", serde(default))]
     pub(crate) env: BTreeMap<String, String>,
+    /// Inherited variables to unset.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub(crate) env_remove: BTreeSet<String>,
+    /// Start without inheriting the host's environment.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub(crate) clear_env: bool,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub(crate) cwd: Option<PathBuf>,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub(crate) timeout_ms: Option<u64>,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub(crate) pipe_source: PipeSource,
+}
+
+/// Which output of a stage feeds the stdin of the next one.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub(crate) enum PipeSource {
+    #[default]
+    Stdout,
+    /// stderr goes down the pipe and stdout takes stderr's place.
+    Stderr,
+    /// stdout and stderr are merged into the pipe (`2>&1 |`).
+    Both,
 }
 
 impl CommandSpec {
@@ -12,7 +52,70 @@ impl CommandSpec {
         Self {
             program,
             args,
+            raw_args: BTreeSet::new(),
             env: BTreeMap::new(),
+            env_remove: BTreeSet::new(),
+            clear_env: false,
+            cwd: None,
+            timeout_ms: None,
+            pipe_source: PipeSource::Stdout,
+        }
+    }
+
+    /// Sets `key`, undoing an earlier `remove_env` of it.
+    pub(crate) fn set_env(&mut self, key: String, value: String) {
+        self.env_remove.remove(&key);
+        self.env.insert(key, value);
+    }
+
+    /// Unsets `key`, dropping any value set for it so far.
+    pub(crate) fn remove_env(&mut self, key: String) {
+        self.env.remove(&key);
+        self.env_remove.insert(key);
+    }
+
+    pub(crate) fn args_array(&self) -> RhaiArray {
+        self.args.iter().cloned().map(Dynamic::from).collect()
+    }
+
+    /// The script-provided variables, with secret values shown as `***`.
+    pub(crate) fn env_map(&self, config: &Config) -> RhaiMap {
+        self.env
+            .iter()
+            .map(|(key, value)| {
+                let value = if config.is_secret_key(key) {
+                    "***".to_string()
+                } else {
+                    value.clone()
+                };
+                (key.into(), Dynamic::from(value))
+            })
+            .collect()
+    }
+}
+
+/// Renders a pipeline as a shell command line, e.g. `ls -la | grep toml`.
+pub(crate) fn describe_pipeline(commands: &[CommandSpec]) -> String {
+    let mut line = String::new();
+    for (index, command) in commands.iter().enumerate() {
+        if index > 0 {
+            line.push_str(match commands[index - 1].pipe_source {
+                PipeSource::Stdout => " | ",
+                PipeSource::Stderr => " 3>&1 1>&2 2>&3 | ",
+                PipeSource::Both => " 2>&1 | ",
+            });
+        }
+        line.push_str(&command.to_string());
+    }
+    line
+}
+
+impl fmt::Display for CommandSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", quote(&self.program))?;
+        for arg in &self.args {
+            write!(f, " {}", quote(arg))?;
         }
+        Ok(())
     }
 }
@@ -1,25 +1,134 @@
-use crate::util::runtime_error;
+use crate::cancellation::CancellationHandle;
+use crate::job_registry::JobRegistry;
+use crate::platform;
+use crate::spawn_limits::{ProcessSlots, RuntimeBudget, SpawnRate};
+use crate::util::{denied_error, process_error, ErrorKind};
+use crate::worker_pool::WorkerPool;
 use crate::RhaiResult;
-use std::collections::HashSet;
+use regex::{Regex, RegexSet};
+use rhai::{Dynamic, Engine, EvalAltResult, Position};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::env;
+use std::ffi::OsString;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
 
 #[derive(Clone, Debug)]
 pub struct Config {
     pub(crate) command_policy: ListPolicy,
+    command_match: CommandMatch,
     pub(crate) env_policy: ListPolicy,
+    env_inheritance: EnvInheritance,
+    pub(crate) default_env: BTreeMap<String, String>,
+    secret_keys: Option<Matcher>,
+    pub(crate) redact_output: bool,
+    argument_policies: HashMap<String, Matcher>,
     pub(crate) default_timeout_ms: Option<u64>,
+    pub(crate) default_memory_limit_mb: Option<u64>,
+    pub(crate) stream_buffer_size: usize,
+    pub(crate) max_output_bytes: Option<usize>,
+    pub(crate) stream_poll_interval_ms: u64,
+    pub(crate) jobs: Arc<JobRegistry>,
+    pub(crate) kill_grace_ms: Option<u64>,
+    pub(crate) kill_on_drop: bool,
+    pub(crate) max_pipeline_stages: Option<usize>,
+    pub(crate) max_concurrent: Option<usize>,
+    pub(crate) slots: Arc<ProcessSlots>,
+    pub(crate) total_runtime_budget_ms: Option<u64>,
+    pub(crate) budget: Arc<RuntimeBudget>,
+    pub(crate) max_spawns_per_second: Option<usize>,
+    pub(crate) spawn_rate: Arc<SpawnRate>,
+    pub(crate) workers: Arc<WorkerPool>,
+    pub(crate) cancellation: CancellationHandle,
+    progress: Option<HostCallback<ProgressFn>>,
+    operations: Arc<AtomicU64>,
+    pub(crate) strict_argument_types: bool,
+    pub(crate) allow_shell: bool,
+    pub(crate) allow_windows_builtins: bool,
+    pub(crate) allow_privilege_change: bool,
+    pub(crate) allow_chroot: bool,
+    command_filter: Option<HostCallback<CommandFilterFn>>,
+    env_value_filter: Option<HostCallback<EnvValueFilterFn>>,
+    cwd_roots: Option<Vec<PathBuf>>,
+    search_path: Option<Vec<PathBuf>>,
 }
 
 impl Default for Config {
     fn default() -> Self {
         Self {
-            command_policy: ListPolicy::Unrestricted,
-            env_policy: ListPolicy::Unrestricted,
+            command_policy: ListPolicy::default(),
+            command_match: CommandMatch::default(),
+            env_policy: ListPolicy::default(),
+            env_inheritance: EnvInheritance::All,
+            default_env: BTreeMap::new(),
+            secret_keys: None,
+            redact_output: false,
+            argument_policies: HashMap::new(),
             default_timeout_ms: None,
+            default_memory_limit_mb: None,
+            stream_buffer_size: 8 * 1024,
+            max_output_bytes: None,
+            stream_poll_interval_ms: 50,
+            jobs: Arc::new(JobRegistry::default()),
+            kill_grace_ms: None,
+            kill_on_drop: false,
+            max_pipeline_stages: None,
+            max_concurrent: None,
+            slots: Arc::new(ProcessSlots::default()),
+            total_runtime_budget_ms: None,
+            budget: Arc::new(RuntimeBudget::default()),
+            max_spawns_per_second: None,
+            spawn_rate: Arc::new(SpawnRate::default()),
+            workers: Arc::new(WorkerPool::default()),
+            cancellation: CancellationHandle::default(),
+            progress: None,
+            operations: Arc::new(AtomicU64::new(0)),
+            strict_argument_types: false,
+            allow_shell: false,
+            allow_windows_builtins: false,
+            allow_privilege_change: false,
+            allow_chroot: false,
+            command_filter: None,
+            env_value_filter: None,
+            cwd_roots: None,
+            search_path: None,
         }
     }
 }
 
 impl Config {
+    /// A restrictive baseline for untrusted scripts: no commands until the
+    /// host allows some, an empty environment, no `cwd()` changes,
+    /// basename matching, a 30 second timeout, 1 MiB of captured output per
+    /// stream and children killed when their handle is dropped.
+    pub fn locked_down() -> Self {
+        Self::default()
+            .allow_commands(Vec::<String>::new())
+            .match_command_by(CommandMatch::Basename)
+            .allow_env_vars(Vec::<String>::new())
+            .inherit_env(false)
+            .allow_cwd_under(Vec::<PathBuf>::new())
+            .default_timeout_ms(30_000)
+            .max_output_bytes(1024 * 1024)
+            .max_pipeline_stages(8)
+            .kill_on_drop(true)
+    }
+
+    /// Defaults for build and CI scripts: every command is allowed, but runs
+    /// are bounded by a 10 minute timeout and 16 MiB of captured output per
+    /// stream, timed-out children get 5 seconds to exit after `SIGTERM`, and
+    /// children are killed when their handle is dropped.
+    pub fn ci_defaults() -> Self {
+        Self::default()
+            .default_timeout_ms(10 * 60 * 1000)
+            .kill_grace_ms(5_000)
+            .max_output_bytes(16 * 1024 * 1024)
+            .kill_on_drop(true)
+    }
+
     pub fn allow_commands<I, S>(mut self, commands: I) -> Self
     where
         I: IntoIterator<Item = S>,
@@ -40,6 +149,106 @@ impl Config {
         self
     }
 
+    /// Restricts `command` to arguments matching one of `args`. Every
+    /// argument is checked, and entries accept the same glob and `re:`
+    /// patterns as the command lists. Calling it again for the same command
+    /// extends its argument list.
+    ///
+    /// Which commands may run is still up to `allow_commands` and
+    /// `deny_commands`: with an allow list, `command` has to be on it too.
+    pub fn allow_command_with_args<C, I, S>(mut self, command: C, args: I) -> Self
+    where
+        C: Into<String>,
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.argument_policies
+            .entry(command.into())
+            .or_default()
+            .extend(args.into_iter().map(Into::into));
+        self
+    }
+
+    /// Whether children inherit the host's environment (the default).
+    pub fn inherit_env(mut self, enabled: bool) -> Self {
+        self.env_inheritance = if enabled {
+            EnvInheritance::All
+        } else {
+            EnvInheritance::Only(HashSet::new())
+        };
+        self
+    }
+
+    /// Lets children inherit only the listed host variables. Calling it again
+    /// extends the list.
+    pub fn inherit_only<I, S>(mut self, keys: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        let keys = keys.into_iter().map(Into::into);
+        match &mut self.env_inheritance {
+            EnvInheritance::Only(existing) => existing.extend(keys),
+            EnvInheritance::All => self.env_inheritance = EnvInheritance::Only(keys.collect()),
+        }
+        self
+    }
+
+    /// Sets variables for every command. Scripts can still override or
+    /// remove them with `env()` and `env_remove()`.
+    pub fn default_env<I, K, V>(mut self, vars: I) -> Self
+    where
+        I: IntoIterator<Item = (K, V)>,
+        K: Into<String>,
+        V: Into<String>,
+    {
+        self.default_env.extend(
+            vars.into_iter()
+                .map(|(key, value)| (key.into(), value.into())),
+        );
+        self
+    }
+
+    /// Installs a check for environment values set by scripts, called with
+    /// the key and value. Returning `false` rejects the value, e.g. one with
+    /// a newline or a path outside a sandbox.
+    pub fn env_value_filter<F>(mut self, filter: F) -> Self
+    where
+        F: Fn(&str, &str) -> bool + Send + Sync + 'static,
+    {
+        self.env_value_filter = Some(HostCallback(Arc::new(filter)));
+        self
+    }
+
+    /// Treats the values of environment variables whose keys match
+    /// `patterns` (same syntax as the env lists, e.g. `"*TOKEN*"`) as
+    /// secrets: they are shown as `***` in builder getters, `dry_run()` and
+    /// host filter errors, and in captured output with `redact_output(true)`.
+    pub fn redact_env_values<I, S>(mut self, patterns: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.secret_keys
+            .get_or_insert_with(Matcher::default)
+            .extend(patterns.into_iter().map(Into::into));
+        self
+    }
+
+    /// Also replaces secret values in captured `stdout`, `stderr` and
+    /// `combined` output. Has no effect without `redact_env_values`.
+    pub fn redact_output(mut self, enabled: bool) -> Self {
+        self.redact_output = enabled;
+        self
+    }
+
+    /// Chooses how program names are compared with the command lists and
+    /// `allow_command_with_args`.
+    pub fn match_command_by(mut self, mode: CommandMatch) -> Self {
+        self.command_match = mode;
+        self
+    }
+
     pub fn allow_env_vars<I, S>(mut self, keys: I) -> Self
     where
         I: IntoIterator<Item = S>,
@@ -60,6 +269,70 @@ impl Config {
         self
     }
 
+    /// Restricts `cwd()` to directories inside one of `roots`. Paths are
+    /// resolved (symlinks and `..`) before they are compared.
+    pub fn allow_cwd_under<I, P>(mut self, roots: I) -> Self
+    where
+        I: IntoIterator<Item = P>,
+        P: Into<PathBuf>,
+    {
+        self.cwd_roots
+            .get_or_insert_with(Vec::new)
+            .extend(roots.into_iter().map(Into::into));
+        self
+    }
+
+    /// Resolves programs only within `dirs`, in order, and runs children with
+    /// `PATH` set to exactly these directories. Programs found anywhere else,
+    /// including explicit paths outside them, are rejected.
+    pub fn search_path<I, P>(mut self, dirs: I) -> Self
+    where
+        I: IntoIterator<Item = P>,
+        P: Into<PathBuf>,
+    {
+        self.search_path
+            .get_or_insert_with(Vec::new)
+            .extend(dirs.into_iter().map(Into::into));
+        self
+    }
+
+    pub fn allow_shell(mut self, enabled: bool) -> Self {
+        self.allow_shell = enabled;
+        self
+    }
+
+    /// Lets scripts choose the user and group children run as, e.g. so a
+    /// host running as root can drop privileges for them. Unix only.
+    pub fn allow_privilege_change(mut self, enabled: bool) -> Self {
+        self.allow_privilege_change = enabled;
+        self
+    }
+
+    /// Lets scripts confine children to a directory tree with `chroot()`.
+    /// The host must run as root for it to work. Unix only.
+    pub fn allow_chroot(mut self, enabled: bool) -> Self {
+        self.allow_chroot = enabled;
+        self
+    }
+
+    /// On Windows, runs `cmd.exe` builtins such as `dir`, `copy` and `type`
+    /// through `cmd /C`, since they have no executable of their own.
+    pub fn allow_windows_builtins(mut self, enabled: bool) -> Self {
+        self.allow_windows_builtins = enabled;
+        self
+    }
+
+    /// Installs a host callback that sees every command right before it is
+    /// started and can veto it by returning `Err(message)`; the message is
+    /// raised to the script as is. It runs after the static policies.
+    pub fn command_filter<F>(mut self, filter: F) -> Self
+    where
+        F: Fn(&CommandRequest<'_>) -> Result<(), String> + Send + Sync + 'static,
+    {
+        self.command_filter = Some(HostCallback(Arc::new(filter)));
+        self
+    }
+
     pub fn default_timeout_ms(mut self, timeout: u64) -> Self {
         if timeout == 0 {
             panic!("default_timeout_ms must be greater than zero");
@@ -68,30 +341,530 @@ impl Config {
         self
     }
 
+    /// Caps the address space of every child, unless the executor sets its
+    /// own `limit_memory_mb()`.
+    pub fn default_memory_limit_mb(mut self, megabytes: u64) -> Self {
+        if megabytes == 0 {
+            panic!("default_memory_limit_mb must be greater than zero");
+        }
+        self.default_memory_limit_mb = Some(megabytes);
+        self
+    }
+
+    pub fn kill_grace_ms(mut self, grace: u64) -> Self {
+        if grace == 0 {
+            panic!("kill_grace_ms must be greater than zero");
+        }
+        self.kill_grace_ms = Some(grace);
+        self
+    }
+
+    pub fn kill_on_drop(mut self, enabled: bool) -> Self {
+        self.kill_on_drop = enabled;
+        self
+    }
+
+    /// Sets the engine's progress callback, which [`register`](crate::register)
+    /// and `ProcessPackage::register_into_engine` install with
+    /// `Engine::on_progress`; use this instead of setting it on the engine.
+    /// It is also called, with the last operation count, while a pipeline
+    /// blocks the script. Returning `Some(token)` terminates the script as
+    /// `Engine::on_progress` does and kills every pipeline running under this
+    /// config, including those started with `start()`.
+    pub fn on_progress<F>(mut self, callback: F) -> Self
+    where
+        F: Fn(u64) -> Option<Dynamic> + Send + Sync + 'static,
+    {
+        self.progress = Some(HostCallback(Arc::new(callback)));
+        self
+    }
+
+    /// Installs the `on_progress` callback, if any, on `engine`.
+    pub(crate) fn install_progress(&self, engine: &mut Engine) {
+        let Some(HostCallback(callback)) = self.progress.clone() else {
+            return;
+        };
+        let operations = Arc::clone(&self.operations);
+        let cancellation = self.cancellation.clone();
+        engine.on_progress(move |count| {
+            operations.store(count, Ordering::Relaxed);
+            let token = callback(count);
+            if token.is_some() {
+                cancellation.cancel();
+            }
+            token
+        });
+    }
+
+    /// Asks the `on_progress` callback whether a script blocked on a
+    /// pipeline should be terminated, killing the pipelines if so.
+    pub(crate) fn check_progress(&self) -> RhaiResult<()> {
+        let Some(HostCallback(callback)) = &self.progress else {
+            return Ok(());
+        };
+        match callback(self.operations.load(Ordering::Relaxed)) {
+            Some(token) => {
+                self.cancellation.cancel();
+                Err(EvalAltResult::ErrorTerminated(token, Position::NONE).into())
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// A handle the host can use to kill every pipeline running under this
+    /// config and its clones, see [`CancellationHandle::cancel`].
+    pub fn cancellation_handle(&self) -> CancellationHandle {
+        self.cancellation.clone()
+    }
+
+    pub fn max_pipeline_stages(mut self, stages: usize) -> Self {
+        if stages == 0 {
+            panic!("max_pipeline_stages must be greater than zero");
+        }
+        self.max_pipeline_stages = Some(stages);
+        self
+    }
+
+    /// Lets at most `pipelines` run at the same time across every engine
+    /// sharing this config; further starts wait for one of them to exit.
+    pub fn max_concurrent(mut self, pipelines: usize) -> Self {
+        if pipelines == 0 {
+            panic!("max_concurrent must be greater than zero");
+        }
+        self.max_concurrent = Some(pipelines);
+        self
+    }
+
+    /// Refuses to start pipelines once the pipelines run under this config
+    /// have taken `budget` milliseconds of wall-clock time in total.
+    pub fn total_runtime_budget_ms(mut self, budget: u64) -> Self {
+        if budget == 0 {
+            panic!("total_runtime_budget_ms must be greater than zero");
+        }
+        self.total_runtime_budget_ms = Some(budget);
+        self
+    }
+
+    /// Starts at most `spawns` pipelines in any one second; further starts
+    /// wait until they fit.
+    pub fn max_spawns_per_second(mut self, spawns: usize) -> Self {
+        if spawns == 0 {
+            panic!("max_spawns_per_second must be greater than zero");
+        }
+        self.max_spawns_per_second = Some(spawns);
+        self
+    }
+
+    pub fn strict_argument_types(mut self, enabled: bool) -> Self {
+        self.strict_argument_types = enabled;
+        self
+    }
+
+    pub fn stream_buffer_size(mut self, bytes: usize) -> Self {
+        if bytes == 0 {
+            panic!("stream_buffer_size must be greater than zero");
+        }
+        self.stream_buffer_size = bytes;
+        self
+    }
+
+    /// Caps how much of each stream `run()` keeps; output beyond the cap is
+    /// still drained from the child but dropped.
+    pub fn max_output_bytes(mut self, bytes: usize) -> Self {
+        if bytes == 0 {
+            panic!("max_output_bytes must be greater than zero");
+        }
+        self.max_output_bytes = Some(bytes);
+        self
+    }
+
+    pub fn stream_poll_interval_ms(mut self, interval: u64) -> Self {
+        if interval == 0 {
+            panic!("stream_poll_interval_ms must be greater than zero");
+        }
+        self.stream_poll_interval_ms = interval;
+        self
+    }
+
     pub(crate) fn ensure_command_allowed(&self, name: &str) -> RhaiResult<()> {
-        if self.command_policy.is_allowed(name) {
+        // A policy with a broken entry permits nothing rather than guessing.
+        if let Some(message) = self.invalid_pattern() {
+            return Err(denied_error(Some(name), message.to_string()));
+        }
+        if self.search_path.is_some() && self.resolve_program(name).is_none() {
+            return Err(process_error(
+                ErrorKind::NotFound,
+                Some(name),
+                None,
+                format!("command '{name}' was not found in the search path"),
+            ));
+        }
+        match self.command_key(name) {
+            Some(key) if self.command_policy.is_allowed(&key) => Ok(()),
+            _ => Err(denied_error(
+                Some(name),
+                format!("command '{name}' is not permitted"),
+            )),
+        }
+    }
+
+    pub(crate) fn ensure_argument_allowed(&self, program: &str, arg: &str) -> RhaiResult<()> {
+        if self.argument_policies.is_empty() {
+            return Ok(());
+        }
+        let allowed = self
+            .command_key(program)
+            .and_then(|key| self.argument_policies.get(key.as_ref()));
+        match allowed {
+            Some(allowed) if !allowed.matches(arg) => Err(denied_error(
+                Some(program),
+                format!("argument '{arg}' is not permitted for command '{program}'"),
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// The first entry of any policy that failed to compile.
+    fn invalid_pattern(&self) -> Option<&str> {
+        self.command_policy
+            .matchers()
+            .chain(self.env_policy.matchers())
+            .chain(&self.secret_keys)
+            .chain(self.argument_policies.values())
+            .find_map(|matcher| matcher.invalid.as_deref())
+    }
+
+    /// The name `program` is looked up under in the policies, or `None` if
+    /// it has to be resolved and cannot be.
+    fn command_key<'a>(&self, program: &'a str) -> Option<Cow<'a, str>> {
+        match self.command_match {
+            CommandMatch::FullPath => Some(Cow::Borrowed(program)),
+            CommandMatch::Basename => Some(
+                Path::new(program)
+                    .file_name()
+                    .map(|name| Cow::Owned(name.to_string_lossy().into_owned()))
+                    .unwrap_or(Cow::Borrowed(program)),
+            ),
+            CommandMatch::ResolvedPath => {
+                let resolved = self.resolve_program(program)?.canonicalize().ok()?;
+                Some(Cow::Owned(resolved.to_string_lossy().into_owned()))
+            }
+        }
+    }
+
+    /// The executable `program` refers to, looked up in the configured search
+    /// path or else the host's `PATH`.
+    pub(crate) fn resolve_program(&self, program: &str) -> Option<PathBuf> {
+        let Some(dirs) = &self.search_path else {
+            let dirs = env::var_os("PATH")
+                .map(|path| env::split_paths(&path).collect::<Vec<_>>())
+                .unwrap_or_default();
+            return platform::find_program(program, dirs);
+        };
+        let found = platform::find_program(program, dirs.iter().cloned())?;
+        // An explicit path must still point into one of the directories.
+        let parent = found.parent()?.canonicalize().ok()?;
+        dirs.iter()
+            .any(|dir| dir.canonicalize().is_ok_and(|dir| dir == parent))
+            .then_some(found)
+    }
+
+    /// The host variables children start with, or `None` to inherit all of
+    /// them.
+    pub(crate) fn inherited_env(&self) -> Option<HashMap<OsString, OsString>> {
+        match &self.env_inheritance {
+            EnvInheritance::All => None,
+            EnvInheritance::Only(keys) => Some(
+                env::vars_os()
+                    .filter(|(key, _)| key.to_str().is_some_and(|key| keys.contains(key)))
+                    .collect(),
+            ),
+        }
+    }
+
+    /// The `PATH` children run with when a search path is configured.
+    pub(crate) fn child_path(&self) -> Option<OsString> {
+        let dirs = self.search_path.as_ref()?;
+        env::join_paths(dirs).ok()
+    }
+
+    /// The program to hand to the OS. When commands are matched by resolved
+    /// path or a search path is set, the child runs exactly the binary the
+    /// policy approved, even if the script overrides `PATH`.
+    pub(crate) fn program_to_spawn<'a>(&self, program: &'a str) -> Cow<'a, str> {
+        if self.command_match != CommandMatch::ResolvedPath && self.search_path.is_none() {
+            return Cow::Borrowed(program);
+        }
+        match self.resolve_program(program) {
+            Some(path) => Cow::Owned(path.to_string_lossy().into_owned()),
+            None => Cow::Borrowed(program),
+        }
+    }
+
+    pub(crate) fn ensure_cwd_allowed(&self, path: &Path) -> RhaiResult<()> {
+        let Some(roots) = &self.cwd_roots else {
+            return Ok(());
+        };
+        let not_permitted = || {
+            denied_error(
+                None,
+                format!("working directory '{}' is not permitted", path.display()),
+            )
+        };
+        let resolved = path.canonicalize().map_err(|_| not_permitted())?;
+        let inside = roots.iter().any(|root| {
+            let root = root.canonicalize().unwrap_or_else(|_| root.clone());
+            resolved.starts_with(root)
+        });
+        if inside {
             Ok(())
         } else {
-            Err(runtime_error(format!("command '{name}' is not permitted")))
+            Err(not_permitted())
+        }
+    }
+
+    pub(crate) fn ensure_request_allowed(&self, request: &CommandRequest<'_>) -> RhaiResult<()> {
+        match &self.command_filter {
+            Some(HostCallback(filter)) => filter(request).map_err(|message| {
+                let secrets = self.secret_values(&[request.env]);
+                denied_error(Some(request.program), redact(&message, &secrets))
+            }),
+            None => Ok(()),
+        }
+    }
+
+    pub(crate) fn ensure_privilege_change_allowed(&self) -> RhaiResult<()> {
+        if !self.allow_privilege_change {
+            return Err(denied_error(
+                None,
+                "changing the user of child processes is disabled; \
+                 enable it with Config::allow_privilege_change(true)",
+            ));
+        }
+        Ok(())
+    }
+
+    pub(crate) fn ensure_chroot_allowed(&self) -> RhaiResult<()> {
+        if !self.allow_chroot {
+            return Err(denied_error(
+                None,
+                "chroot is disabled; enable it with Config::allow_chroot(true)",
+            ));
         }
+        Ok(())
     }
 
-    pub(crate) fn ensure_env_allowed(&self, key: &str) -> RhaiResult<()> {
+    pub(crate) fn ensure_budget_left(&self) -> RhaiResult<()> {
+        let Some(budget) = self.total_runtime_budget_ms else {
+            return Ok(());
+        };
+        let spent = self.budget.spent_ms();
+        if spent >= budget {
+            return Err(process_error(
+                ErrorKind::Budget,
+                None,
+                None,
+                format!("runtime budget of {budget} ms is used up ({spent} ms spent)"),
+            ));
+        }
+        Ok(())
+    }
+
+    pub(crate) fn is_secret_key(&self, key: &str) -> bool {
+        self.secret_keys
+            .as_ref()
+            .is_some_and(|keys| keys.matches(key))
+    }
+
+    /// Values of secret variables a child could see: those set by the script
+    /// in `envs`, the config defaults and the host's own environment. Longest
+    /// first, so a secret containing another is replaced whole.
+    pub(crate) fn secret_values(&self, envs: &[&BTreeMap<String, String>]) -> Vec<String> {
+        if self.secret_keys.is_none() {
+            return Vec::new();
+        }
+        let host = env::vars().collect::<Vec<_>>();
+        let mut values: Vec<String> = envs
+            .iter()
+            .flat_map(|env| env.iter())
+            .chain(&self.default_env)
+            .map(|(key, value)| (key.as_str(), value.as_str()))
+            .chain(
+                host.iter()
+                    .map(|(key, value)| (key.as_str(), value.as_str())),
+            )
+            .filter(|(key, value)| !value.is_empty() && self.is_secret_key(key))
+            .map(|(_, value)| value.to_string())
+            .collect();
+        values.sort_by(|a, b| b.len().cmp(&a.len()).then_with(|| a.cmp(b)));
+        values.dedup();
+        values
+    }
+
+    pub(crate) fn ensure_pipeline_length(&self, stages: usize) -> RhaiResult<()> {
+        match self.max_pipeline_stages {
+            Some(max) if stages > max => Err(denied_error(
+                None,
+                format!("pipeline exceeds the maximum of {max} stages"),
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    pub(crate) fn ensure_env_key_allowed(&self, key: &str) -> RhaiResult<()> {
         if self.env_policy.is_allowed(key) {
             Ok(())
         } else {
-            Err(runtime_error(format!(
-                "environment variable '{key}' is not permitted"
-            )))
+            Err(denied_error(
+                None,
+                format!("environment variable '{key}' is not permitted"),
+            ))
+        }
+    }
+
+    pub(crate) fn ensure_env_allowed(&self, key: &str, value: &str) -> RhaiResult<()> {
+        self.ensure_env_key_allowed(key)?;
+        match &self.env_value_filter {
+            Some(HostCallback(filter)) if !filter(key, value) => Err(denied_error(
+                None,
+                format!("value of environment variable '{key}' is not permitted"),
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+impl Config {
+    /// The policy in file form, with every setting spelled out so it reads
+    /// back the same on top of the default preset.
+    pub(crate) fn to_file(&self) -> crate::config_file::ConfigFile {
+        let entries = |matcher: &Matcher| matcher.entries.clone();
+        let mut file = crate::config_file::ConfigFile {
+            allow_commands: self.command_policy.allow.as_ref().map(entries),
+            deny_commands: entries(&self.command_policy.deny),
+            allow_command_with_args: self
+                .argument_policies
+                .iter()
+                .map(|(command, args)| (command.clone(), entries(args)))
+                .collect(),
+            match_command_by: Some(self.command_match),
+            allow_env_vars: self.env_policy.allow.as_ref().map(entries),
+            deny_env_vars: entries(&self.env_policy.deny),
+            default_env: self.default_env.clone(),
+            redact_env_values: self.secret_keys.as_ref().map(entries).unwrap_or_default(),
+            redact_output: Some(self.redact_output),
+            allow_cwd_under: self.cwd_roots.clone(),
+            search_path: self.search_path.clone(),
+            allow_shell: Some(self.allow_shell),
+            allow_windows_builtins: Some(self.allow_windows_builtins),
+            allow_privilege_change: Some(self.allow_privilege_change),
+            allow_chroot: Some(self.allow_chroot),
+            default_timeout_ms: self.default_timeout_ms,
+            default_memory_limit_mb: self.default_memory_limit_mb,
+            kill_grace_ms: self.kill_grace_ms,
+            kill_on_drop: Some(self.kill_on_drop),
+            max_pipeline_stages: self.max_pipeline_stages,
+            max_concurrent: self.max_concurrent,
+            total_runtime_budget_ms: self.total_runtime_budget_ms,
+            max_spawns_per_second: self.max_spawns_per_second,
+            max_output_bytes: self.max_output_bytes,
+            strict_argument_types: Some(self.strict_argument_types),
+            stream_buffer_size: Some(self.stream_buffer_size),
+            stream_poll_interval_ms: Some(self.stream_poll_interval_ms),
+            ..Default::default()
+        };
+        if let EnvInheritance::Only(keys) = &self.env_inheritance {
+            let mut keys: Vec<String> = keys.iter().cloned().collect();
+            keys.sort();
+            file.inherit_only = Some(keys);
         }
+        file
     }
 }
 
+/// Replaces every occurrence of `secrets` in `text` with `***`.
+pub(crate) fn redact(text: &str, secrets: &[String]) -> String {
+    secrets.iter().fold(text.to_string(), |text, secret| {
+        text.replace(secret.as_str(), "***")
+    })
+}
+
 #[derive(Clone, Debug)]
-pub(crate) enum ListPolicy {
-    Unrestricted,
-    Allow(HashSet<String>),
-    Deny(HashSet<String>),
+enum EnvInheritance {
+    All,
+    Only(HashSet<String>),
+}
+
+/// How program names are compared with the command policy.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum CommandMatch {
+    /// Only the file name: `/bin/rm` and `rm` both match `"rm"`.
+    Basename,
+    /// The program exactly as the script wrote it.
+    #[default]
+    FullPath,
+    /// The canonical path of the binary found via `PATH`, e.g.
+    /// `"/usr/bin/rm"`. Programs that cannot be found are rejected.
+    ResolvedPath,
+}
+
+/// A command about to be started, as passed to [`Config::command_filter`].
+#[derive(Clone, Copy, Debug)]
+pub struct CommandRequest<'a> {
+    pub(crate) program: &'a str,
+    pub(crate) args: &'a [String],
+    pub(crate) env: &'a BTreeMap<String, String>,
+    pub(crate) cwd: Option<&'a Path>,
+}
+
+impl<'a> CommandRequest<'a> {
+    pub fn program(&self) -> &'a str {
+        self.program
+    }
+
+    pub fn args(&self) -> &'a [String] {
+        self.args
+    }
+
+    /// Environment variables set by the script; everything else is inherited.
+    pub fn env(&self) -> &'a BTreeMap<String, String> {
+        self.env
+    }
+
+    /// Working directory, or `None` to inherit the host's.
+    pub fn cwd(&self) -> Option<&'a Path> {
+        self.cwd
+    }
+}
+
+type CommandFilterFn = dyn Fn(&CommandRequest<'_>) -> Result<(), String> + Send + Sync;
+type EnvValueFilterFn = dyn Fn(&str, &str) -> bool + Send + Sync;
+type ProgressFn = dyn Fn(u64) -> Option<Dynamic> + Send + Sync;
+
+/// A closure installed by the host; only its presence shows up in `Debug`.
+struct HostCallback<F: ?Sized>(Arc<F>);
+
+impl<F: ?Sized> Clone for HostCallback<F> {
+    fn clone(&self) -> Self {
+        Self(Arc::clone(&self.0))
+    }
+}
+
+impl<F: ?Sized> fmt::Debug for HostCallback<F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("HostCallback")
+    }
+}
+
+/// An optional allow list plus a deny list. Deny entries take precedence, so
+/// both can be combined to allow a set of names except a few.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct ListPolicy {
+    allow: Option<Matcher>,
+    deny: Matcher,
 }
 
 impl ListPolicy {
@@ -99,41 +872,142 @@ impl ListPolicy {
     where
         I: IntoIterator<Item = String>,
     {
-        match self {
-            ListPolicy::Unrestricted => {
-                let mut set = HashSet::new();
-                set.extend(values);
-                *self = ListPolicy::Allow(set);
-            }
-            ListPolicy::Allow(existing) => existing.extend(values),
-            ListPolicy::Deny(_) => {
-                panic!("deny list already specified; allow list cannot be combined")
-            }
-        }
+        self.allow
+            .get_or_insert_with(Matcher::default)
+            .extend(values);
     }
 
     fn insert_deny<I>(&mut self, values: I)
     where
         I: IntoIterator<Item = String>,
     {
-        match self {
-            ListPolicy::Unrestricted => {
-                let mut set = HashSet::new();
-                set.extend(values);
-                *self = ListPolicy::Deny(set);
+        self.deny.extend(values);
+    }
+
+    fn matchers(&self) -> impl Iterator<Item = &Matcher> {
+        self.allow.iter().chain([&self.deny])
+    }
+
+    fn is_allowed(&self, value: &str) -> bool {
+        if self.deny.matches(value) {
+            return false;
+        }
+        self.allow.as_ref().is_none_or(|allow| allow.matches(value))
+    }
+}
+
+/// Policy entries: exact names, glob patterns (any entry containing `*`, `?`
+/// or `[`) and regexes (entries prefixed with `re:`). Patterns are compiled
+/// into a single `RegexSet` when the list changes, not on every check.
+#[derive(Clone, Debug)]
+struct Matcher {
+    /// Every entry as given, for serializing the policy.
+    entries: Vec<String>,
+    exact: HashSet<String>,
+    patterns: Vec<String>,
+    compiled: RegexSet,
+    /// The first entry that failed to compile. The builders cannot fail, so
+    /// it is raised when the config checks its first command.
+    invalid: Option<String>,
+}
+
+impl Default for Matcher {
+    fn default() -> Self {
+        Self {
+            entries: Vec::new(),
+            exact: HashSet::new(),
+            patterns: Vec::new(),
+            compiled: RegexSet::empty(),
+            invalid: None,
+        }
+    }
+}
+
+impl Matcher {
+    fn extend<I>(&mut self, values: I)
+    where
+        I: IntoIterator<Item = String>,
+    {
+        let before = self.patterns.len();
+        for value in values {
+            if self.entries.contains(&value) {
+                continue;
             }
-            ListPolicy::Deny(existing) => existing.extend(values),
-            ListPolicy::Allow(_) => {
-                panic!("allow list already specified; deny list cannot be combined")
+            self.entries.push(value.clone());
+            if let Some(regex) = value.strip_prefix("re:") {
+                if let Err(err) = Regex::new(regex) {
+                    self.invalid
+                        .get_or_insert_with(|| format!("invalid pattern '{value}': {err}"));
+                    continue;
+                }
+                self.patterns.push(format!("^(?:{regex})$"));
+            } else if value.contains(['*', '?', '[']) {
+                self.patterns.push(glob_to_regex(&value));
+            } else {
+                self.exact.insert(value);
+            }
+        }
+        if self.patterns.len() != before {
+            match RegexSet::new(&self.patterns) {
+                Ok(compiled) => self.compiled = compiled,
+                Err(err) => {
+                    self.invalid
+                        .get_or_insert_with(|| format!("invalid policy patterns: {err}"));
+                }
             }
         }
     }
 
-    fn is_allowed(&self, value: &str) -> bool {
-        match self {
-            ListPolicy::Unrestricted => true,
-            ListPolicy::Allow(list) => list.contains(value),
-            ListPolicy::Deny(list) => !list.contains(value),
+    fn matches(&self, value: &str) -> bool {
+        self.exact.contains(value) || self.compiled.is_match(value)
+    }
+}
+
+/// Translates a shell-style glob into an anchored regex. `*` and `?` do not
+/// match `/`, so `/opt/tools/*` covers only the directory's direct entries.
+fn glob_to_regex(glob: &str) -> String {
+    let chars: Vec<char> = glob.chars().collect();
+    let mut regex = String::from("^");
+    let mut index = 0;
+    while index < chars.len() {
+        match chars[index] {
+            '*' => regex.push_str("[^/]*"),
+            '?' => regex.push_str("[^/]"),
+            '[' => {
+                // A `]` right after `[` or `[!` is part of the class.
+                let mut start = index + 1;
+                let negated = matches!(chars.get(start), Some('!' | '^'));
+                if negated {
+                    start += 1;
+                }
+                let end = chars
+                    .iter()
+                    .skip(start + 1)
+                    .position(|&c| c == ']')
+                    .map(|offset| start + 1 + offset);
+                match end {
+                    Some(end) => {
+                        regex.push('[');
+                        if negated {
+                            regex.push('^');
+                        }
+                        for &c in &chars[start..end] {
+                            if matches!(c, '\\' | '[' | ']' | '^' | '&' | '~') {
+                                regex.push('\\');
+                            }
+                            regex.push(c);
+                        }
+                        regex.push(']');
+                        index = end;
+                    }
+                    // An unterminated `[` is matched literally, as in the shell.
+                    None => regex.push_str("\\["),
+                }
+            }
+            c => regex.push_str(&regex::escape(c.encode_utf8(&mut [0; 4]))),
         }
+        index += 1;
     }
+    regex.push('$');
+    regex
 }
@@ -0,0 +1,240 @@
+use crate::config::{CommandMatch, Config};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::PathBuf;
+
+/// Error returned when a deployment config file cannot be loaded.
+#[derive(Clone, Debug)]
+pub struct ConfigError {
+    message: String,
+}
+
+impl ConfigError {
+    fn new(message: impl Into<String>) -> Self {
+        Self {
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// The file form of [`Config`]. Keys mirror the builder methods; anything
+/// left out keeps the value of the chosen preset.
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct ConfigFile {
+    #[serde(skip_serializing)]
+    pub(crate) preset: Preset,
+    pub(crate) allow_commands: Option<Vec<String>>,
+    pub(crate) deny_commands: Vec<String>,
+    pub(crate) allow_command_with_args: BTreeMap<String, Vec<String>>,
+    pub(crate) match_command_by: Option<CommandMatch>,
+    pub(crate) allow_env_vars: Option<Vec<String>>,
+    pub(crate) deny_env_vars: Vec<String>,
+    pub(crate) inherit_env: Option<bool>,
+    pub(crate) inherit_only: Option<Vec<String>>,
+    pub(crate) default_env: BTreeMap<String, String>,
+    pub(crate) redact_env_values: Vec<String>,
+    pub(crate) redact_output: Option<bool>,
+    pub(crate) allow_cwd_under: Option<Vec<PathBuf>>,
+    pub(crate) search_path: Option<Vec<PathBuf>>,
+    pub(crate) allow_shell: Option<bool>,
+    pub(crate) allow_windows_builtins: Option<bool>,
+    pub(crate) allow_privilege_change: Option<bool>,
+    pub(crate) allow_chroot: Option<bool>,
+    pub(crate) default_timeout_ms: Option<u64>,
+    pub(crate) default_memory_limit_mb: Option<u64>,
+    pub(crate) kill_grace_ms: Option<u64>,
+    pub(crate) kill_on_drop: Option<bool>,
+    pub(crate) max_pipeline_stages: Option<usize>,
+    pub(crate) max_concurrent: Option<usize>,
+    pub(crate) total_runtime_budget_ms: Option<u64>,
+    pub(crate) max_spawns_per_second: Option<usize>,
+    pub(crate) max_output_bytes: Option<usize>,
+    pub(crate) strict_argument_types: Option<bool>,
+    pub(crate) stream_buffer_size: Option<usize>,
+    pub(crate) stream_poll_interval_ms: Option<u64>,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Preset {
+    #[default]
+    Default,
+    LockedDown,
+    Ci,
+}
+
+impl Config {
+    /// Parses a config from TOML, e.g.
+    /// `allow_commands = ["git"]` and `default_timeout_ms = 5000`.
+    #[cfg(feature = "toml")]
+    pub fn from_toml_str(source: &str) -> Result<Self, ConfigError> {
+        let file: ConfigFile =
+            toml::from_str(source).map_err(|err| ConfigError::new(err.to_string()))?;
+        file.into_config()
+    }
+
+    /// Parses a config from JSON with the same keys as [`Config::from_toml_str`].
+    #[cfg(feature = "json")]
+    pub fn from_json_str(source: &str) -> Result<Self, ConfigError> {
+        let file: ConfigFile =
+            serde_json::from_str(source).map_err(|err| ConfigError::new(err.to_string()))?;
+        file.into_config()
+    }
+}
+
+/// Writes the policy in the same form [`Config::from_toml_str`] reads, with
+/// every setting spelled out. Host callbacks such as `command_filter` and
+/// the state shared between clones (jobs, concurrency slots, the runtime
+/// budget) are not part of it.
+impl Serialize for Config {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.to_file().serialize(serializer)
+    }
+}
+
+/// Reads the keys of [`Config::from_toml_str`], reporting the same errors.
+/// The result has no host callbacks and state of its own.
+impl<'de> Deserialize<'de> for Config {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        ConfigFile::deserialize(deserializer)?
+            .into_config()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+impl ConfigFile {
+    fn into_config(self) -> Result<Config, ConfigError> {
+        // The builders panic on zero; a file should get an error instead.
+        ensure_positive("default_timeout_ms", self.default_timeout_ms)?;
+        ensure_positive("kill_grace_ms", self.kill_grace_ms)?;
+        ensure_positive("default_memory_limit_mb", self.default_memory_limit_mb)?;
+        ensure_positive("stream_poll_interval_ms", self.stream_poll_interval_ms)?;
+        ensure_positive("max_pipeline_stages", self.max_pipeline_stages)?;
+        ensure_positive("max_concurrent", self.max_concurrent)?;
+        ensure_positive("total_runtime_budget_ms", self.total_runtime_budget_ms)?;
+        ensure_positive("max_spawns_per_second", self.max_spawns_per_second)?;
+        ensure_positive("max_output_bytes", self.max_output_bytes)?;
+        ensure_positive("stream_buffer_size", self.stream_buffer_size)?;
+        for pattern in self
+            .allow_commands
+            .iter()
+            .chain(self.allow_env_vars.iter())
+            .flatten()
+            .chain(&self.deny_commands)
+            .chain(&self.deny_env_vars)
+            .chain(self.allow_command_with_args.values().flatten())
+            .chain(&self.redact_env_values)
+        {
+            if let Some(regex) = pattern.strip_prefix("re:") {
+                regex::Regex::new(regex).map_err(|err| {
+                    ConfigError::new(format!("invalid pattern '{pattern}': {err}"))
+                })?;
+            }
+        }
+
+        let mut config = match self.preset {
+            Preset::Default => Config::default(),
+            Preset::LockedDown => Config::locked_down(),
+            Preset::Ci => Config::ci_defaults(),
+        };
+        if let Some(commands) = self.allow_commands {
+            config = config.allow_commands(commands);
+        }
+        config = config.deny_commands(self.deny_commands);
+        for (command, args) in self.allow_command_with_args {
+            config = config.allow_command_with_args(command, args);
+        }
+        if let Some(mode) = self.match_command_by {
+            config = config.match_command_by(mode);
+        }
+        if let Some(keys) = self.allow_env_vars {
+            config = config.allow_env_vars(keys);
+        }
+        config = config.deny_env_vars(self.deny_env_vars);
+        if let Some(enabled) = self.inherit_env {
+            config = config.inherit_env(enabled);
+        }
+        if let Some(keys) = self.inherit_only {
+            config = config.inherit_only(keys);
+        }
+        config = config.default_env(self.default_env);
+        if !self.redact_env_values.is_empty() {
+            config = config.redact_env_values(self.redact_env_values);
+        }
+        if let Some(enabled) = self.redact_output {
+            config = config.redact_output(enabled);
+        }
+        if let Some(roots) = self.allow_cwd_under {
+            config = config.allow_cwd_under(roots);
+        }
+        if let Some(dirs) = self.search_path {
+            config = config.search_path(dirs);
+        }
+        if let Some(enabled) = self.allow_shell {
+            config = config.allow_shell(enabled);
+        }
+        if let Some(enabled) = self.allow_windows_builtins {
+            config = config.allow_windows_builtins(enabled);
+        }
+        if let Some(enabled) = self.allow_privilege_change {
+            config = config.allow_privilege_change(enabled);
+        }
+        if let Some(enabled) = self.allow_chroot {
+            config = config.allow_chroot(enabled);
+        }
+        if let Some(timeout) = self.default_timeout_ms {
+            config = config.default_timeout_ms(timeout);
+        }
+        if let Some(megabytes) = self.default_memory_limit_mb {
+            config = config.default_memory_limit_mb(megabytes);
+        }
+        if let Some(grace) = self.kill_grace_ms {
+            config = config.kill_grace_ms(grace);
+        }
+        if let Some(enabled) = self.kill_on_drop {
+            config = config.kill_on_drop(enabled);
+        }
+        if let Some(stages) = self.max_pipeline_stages {
+            config = config.max_pipeline_stages(stages);
+        }
+        if let Some(pipelines) = self.max_concurrent {
+            config = config.max_concurrent(pipelines);
+        }
+        if let Some(budget) = self.total_runtime_budget_ms {
+            config = config.total_runtime_budget_ms(budget);
+        }
+        if let Some(spawns) = self.max_spawns_per_second {
+            config = config.max_spawns_per_second(spawns);
+        }
+        if let Some(bytes) = self.max_output_bytes {
+            config = config.max_output_bytes(bytes);
+        }
+        if let Some(enabled) = self.strict_argument_types {
+            config = config.strict_argument_types(enabled);
+        }
+        if let Some(bytes) = self.stream_buffer_size {
+            config = config.stream_buffer_size(bytes);
+        }
+        if let Some(interval) = self.stream_poll_interval_ms {
+            config = config.stream_poll_interval_ms(interval);
+        }
+        Ok(config)
+    }
+}
+
+fn ensure_positive<T: Default + PartialEq>(key: &str, value: Option<T>) -> Result<(), ConfigError> {
+    if value == Some(T::default()) {
+        return Err(ConfigError::new(format!("{key} must be greater than zero")));
+    }
+    Ok(())
+}
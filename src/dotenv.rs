@@ -0,0 +1,80 @@
+/// Parses dotenv-format `source` into key/value pairs, in file order. Lines
+/// are `KEY=value` with an optional leading `export`; blank lines and lines
+/// starting with `#` are skipped. Single-quoted values are literal,
+/// double-quoted values may span lines and understand `\n`, `\t`, `\"` and
+/// `\\`, and unquoted values end at a ` #` comment and are trimmed.
+pub(crate) fn parse(source: &str) -> Result<Vec<(String, String)>, String> {
+    let mut pairs = Vec::new();
+    let mut lines = source.lines().enumerate();
+    while let Some((index, line)) = lines.next() {
+        let line_number = index + 1;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let line = line
+            .strip_prefix("export")
+            .filter(|rest| rest.starts_with([' ', '\t']))
+            .map_or(line, str::trim_start);
+        let (key, value) = line
+            .split_once('=')
+            .ok_or_else(|| format!("line {line_number}: expected KEY=value"))?;
+        let key = key.trim_end();
+        let valid_key = !key.is_empty()
+            && !key.starts_with(|c: char| c.is_ascii_digit())
+            && key
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.');
+        if !valid_key {
+            return Err(format!("line {line_number}: invalid key '{key}'"));
+        }
+
+        let value = value.trim_start();
+        let value = if let Some(rest) = value.strip_prefix('\'') {
+            let end = rest
+                .find('\'')
+                .ok_or_else(|| format!("line {line_number}: missing closing single quote"))?;
+            rest[..end].to_string()
+        } else if let Some(rest) = value.strip_prefix('"') {
+            let mut text = rest.to_string();
+            loop {
+                if let Some(value) = unescape_double_quoted(&text) {
+                    break value;
+                }
+                let (_, next) = lines
+                    .next()
+                    .ok_or_else(|| format!("line {line_number}: missing closing double quote"))?;
+                text.push('\n');
+                text.push_str(next);
+            }
+        } else {
+            let end = value.find(" #").unwrap_or(value.len());
+            value[..end].trim_end().to_string()
+        };
+        pairs.push((key.to_string(), value));
+    }
+    Ok(pairs)
+}
+
+/// Returns the value up to the closing `"`, or `None` if `text` has none yet.
+fn unescape_double_quoted(text: &str) -> Option<String> {
+    let mut value = String::new();
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => return Some(value),
+            '\\' => match chars.next()? {
+                'n' => value.push('\n'),
+                't' => value.push('\t'),
+                'r' => value.push('\r'),
+                c @ ('"' | '\\' | '$') => value.push(c),
+                c => {
+                    value.push('\\');
+                    value.push(c);
+                }
+            },
+            c => value.push(c),
+        }
+    }
+    None
+}
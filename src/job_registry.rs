@@ -0,0 +1,113 @@
+use crate::command_spec::{describe_pipeline, CommandSpec};
+use crate::pipeline_handle::PipelineHandle;
+use crate::platform;
+use crate::process_handle::kill_if_running;
+use crate::RhaiArray;
+use rhai::{Dynamic, Map as RhaiMap, INT};
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+/// Tracks the pipelines started through `start()` so hosts and scripts can
+/// see and stop what is still running.
+#[derive(Debug, Default)]
+pub(crate) struct JobRegistry {
+    inner: Mutex<JobTable>,
+}
+
+#[derive(Debug, Default)]
+struct JobTable {
+    next_id: INT,
+    jobs: BTreeMap<INT, JobEntry>,
+}
+
+#[derive(Debug)]
+struct JobEntry {
+    pid: INT,
+    command: String,
+    started: Instant,
+    handle: Arc<PipelineHandle>,
+    kill_on_drop: Arc<AtomicBool>,
+}
+
+impl JobRegistry {
+    pub(crate) fn register(
+        &self,
+        commands: &[CommandSpec],
+        handle: &Arc<PipelineHandle>,
+        kill_on_drop: &Arc<AtomicBool>,
+    ) -> INT {
+        let mut table = self.inner.lock().unwrap_or_else(|err| err.into_inner());
+        // Pruning here as well keeps the table, and the handles it holds, to
+        // the jobs still running when scripts start jobs but never list them.
+        table.prune();
+        table.next_id += 1;
+        let id = table.next_id;
+        let command = describe_pipeline(commands);
+        let pid = handle.pids().first().map(|pid| *pid as INT).unwrap_or(-1);
+        table.jobs.insert(
+            id,
+            JobEntry {
+                pid,
+                command,
+                started: Instant::now(),
+                handle: Arc::clone(handle),
+                kill_on_drop: Arc::clone(kill_on_drop),
+            },
+        );
+        id
+    }
+
+    pub(crate) fn list(&self) -> RhaiArray {
+        let mut table = self.inner.lock().unwrap_or_else(|err| err.into_inner());
+        table.prune();
+        table
+            .jobs
+            .iter()
+            .map(|(id, entry)| {
+                let mut map = RhaiMap::new();
+                map.insert("id".into(), Dynamic::from_int(*id));
+                map.insert("pid".into(), Dynamic::from_int(entry.pid));
+                map.insert("command".into(), Dynamic::from(entry.command.clone()));
+                let elapsed: INT = entry
+                    .started
+                    .elapsed()
+                    .as_millis()
+                    .try_into()
+                    .unwrap_or(INT::MAX);
+                map.insert("elapsed_ms".into(), Dynamic::from_int(elapsed));
+                Dynamic::from_map(map)
+            })
+            .collect()
+    }
+
+    pub(crate) fn kill(&self, id: INT) -> bool {
+        let mut table = self.inner.lock().unwrap_or_else(|err| err.into_inner());
+        table.prune();
+        match table.jobs.remove(&id) {
+            Some(entry) => platform::kill_tree(&entry.handle).is_ok(),
+            None => false,
+        }
+    }
+}
+
+impl Drop for JobRegistry {
+    fn drop(&mut self) {
+        // The registry lives as long as the engine's modules, so this is where
+        // jobs still running at engine shutdown get cleaned up.
+        let table = self.inner.get_mut().unwrap_or_else(|err| err.into_inner());
+        for entry in table.jobs.values() {
+            if entry.kill_on_drop.load(Ordering::Relaxed) {
+                kill_if_running(&entry.handle);
+            }
+        }
+    }
+}
+
+impl JobTable {
+    fn prune(&mut self) {
+        self.jobs
+            .retain(|_, entry| matches!(entry.handle.try_wait(), Ok(None)));
+    }
+}
@@ -1,18 +1,48 @@
 #![doc = include_str!("../README.md")]
 
+#[cfg(feature = "async")]
+mod async_pipeline;
+mod cancellation;
 mod command_builder;
+mod command_sequence;
 mod command_spec;
 mod config;
+#[cfg(feature = "serde")]
+mod config_file;
+mod dotenv;
+mod job_registry;
+mod parallel;
+mod periodic;
 mod pipe_builder;
 mod pipeline_executor;
+mod pipeline_handle;
+mod platform;
+mod process_handle;
+mod process_result;
 mod registration;
+mod shell_words;
+mod spawn_limits;
+mod supervisor;
+#[cfg(feature = "tracing")]
+mod telemetry;
 mod util;
+mod worker_pool;
 
+pub use cancellation::CancellationHandle;
 pub use command_builder::CommandBuilder;
-pub use config::Config;
+pub use command_sequence::CommandSequence;
+pub use config::{CommandMatch, CommandRequest, Config};
+#[cfg(feature = "serde")]
+pub use config_file::ConfigError;
+pub use periodic::Periodic;
 pub use pipe_builder::PipeBuilder;
 pub use pipeline_executor::PipelineExecutor;
+pub use process_handle::ProcessHandle;
+pub use process_result::{ProcessResult, StageResult};
+#[cfg(feature = "definitions")]
+pub use registration::definitions;
 pub use registration::{builder_module, module, register, ProcessPackage};
+pub use supervisor::Supervisor;
 
 #[cfg(feature = "no_index")]
 use rhai::Dynamic;
@@ -0,0 +1,190 @@
+use crate::command_sequence::executor_from;
+use crate::pipeline_executor::{PipelineExecutor, RunningPipeline, StreamMode};
+use crate::process_handle::{kill_if_running, ProcessHandle};
+use crate::process_result::ProcessResult;
+use crate::util::runtime_error;
+use crate::{RhaiArray, RhaiResult};
+use rhai::{Dynamic, Map as RhaiMap, NativeCallContext, INT};
+use std::thread;
+use std::time::Duration;
+
+/// A pipeline started as part of a group, remembering its place in it.
+struct Member {
+    index: usize,
+    executor: PipelineExecutor,
+    running: RunningPipeline,
+}
+
+impl Member {
+    fn start(
+        context: &NativeCallContext,
+        index: usize,
+        executor: PipelineExecutor,
+    ) -> RhaiResult<Self> {
+        let running = RunningPipeline::start(&executor, Some(context), false)?;
+        Ok(Self {
+            index,
+            executor,
+            running,
+        })
+    }
+
+    fn finish(mut self, context: &NativeCallContext) -> RhaiResult<ProcessResult> {
+        self.running.drive(
+            &self.executor,
+            Some(context),
+            StreamMode::Capture,
+            None,
+            None,
+        )
+    }
+}
+
+/// Runs `executors` (commands, pipes or executors) at the same time, at most
+/// `limit` at once, and returns their results in the order given. The first
+/// error kills everything still running and is raised.
+pub(crate) fn run_parallel(
+    context: &NativeCallContext,
+    executors: RhaiArray,
+    limit: Option<usize>,
+) -> RhaiResult<RhaiArray> {
+    let executors = executors
+        .into_iter()
+        .map(executor_from)
+        .collect::<RhaiResult<Vec<_>>>()?;
+    let limit = limit.unwrap_or(executors.len());
+    let mut results: Vec<Option<ProcessResult>> = vec![None; executors.len()];
+    let mut pending = executors.into_iter().enumerate();
+    let mut running = Vec::new();
+
+    let outcome: RhaiResult<()> = (|| loop {
+        while running.len() < limit {
+            let Some((index, executor)) = pending.next() else {
+                break;
+            };
+            running.push(Member::start(context, index, executor)?);
+        }
+        let Some(position) = wait_for_due(&mut running)? else {
+            return Ok(());
+        };
+        let member = running.swap_remove(position);
+        let index = member.index;
+        results[index] = Some(member.finish(context)?);
+    })();
+    if let Err(err) = outcome {
+        for member in &running {
+            kill_if_running(&member.running.handle);
+        }
+        return Err(err);
+    }
+    Ok(results.into_iter().flatten().map(Dynamic::from).collect())
+}
+
+/// Starts all `executors` at once and returns the result of whichever
+/// finishes first, successful or not, killing the others.
+pub(crate) fn race(context: &NativeCallContext, executors: RhaiArray) -> RhaiResult<ProcessResult> {
+    let executors = executors
+        .into_iter()
+        .map(executor_from)
+        .collect::<RhaiResult<Vec<_>>>()?;
+    let mut running = Vec::with_capacity(executors.len());
+    let outcome = (|| {
+        for (index, executor) in executors.into_iter().enumerate() {
+            running.push(Member::start(context, index, executor)?);
+        }
+        let position = wait_for_due(&mut running)?
+            .ok_or_else(|| runtime_error("race needs at least one pipeline"))?;
+        running.swap_remove(position).finish(context)
+    })();
+    for member in &running {
+        kill_if_running(&member.running.handle);
+    }
+    outcome
+}
+
+/// Waits for every handle and returns their results in the order given.
+pub(crate) fn wait_all(context: &NativeCallContext, handles: RhaiArray) -> RhaiResult<RhaiArray> {
+    let handles = handles_from(handles)?;
+    let mut results: Vec<Option<ProcessResult>> = vec![None; handles.len()];
+    let mut waiting: Vec<usize> = (0..handles.len()).collect();
+    while !waiting.is_empty() {
+        let pending: Vec<&ProcessHandle> = waiting.iter().map(|index| &handles[*index]).collect();
+        let Some(position) = wait_for_due_handle(&pending)? else {
+            break;
+        };
+        let index = waiting.swap_remove(position);
+        results[index] = Some(handles[index].wait(context)?);
+    }
+    Ok(results.into_iter().flatten().map(Dynamic::from).collect())
+}
+
+/// Waits for whichever handle finishes first and returns
+/// `#{ index, result }`, leaving the others running.
+pub(crate) fn wait_any(context: &NativeCallContext, handles: RhaiArray) -> RhaiResult<RhaiMap> {
+    let handles = handles_from(handles)?;
+    let pending: Vec<&ProcessHandle> = handles.iter().collect();
+    let index = wait_for_due_handle(&pending)?
+        .ok_or_else(|| runtime_error("wait_any needs at least one handle"))?;
+    let result = handles[index].wait(context)?;
+    let mut map = RhaiMap::new();
+    map.insert("index".into(), Dynamic::from_int(index as INT));
+    map.insert("result".into(), Dynamic::from(result));
+    Ok(map)
+}
+
+/// Nothing can be written to the handles while blocked on them, so like
+/// `wait()` this closes their stdin.
+fn handles_from(handles: RhaiArray) -> RhaiResult<Vec<ProcessHandle>> {
+    let handles = handles
+        .into_iter()
+        .map(|value| {
+            let type_name = value.type_name();
+            value.try_cast::<ProcessHandle>().ok_or_else(|| {
+                runtime_error(format!("expected a ProcessHandle, found {type_name}"))
+            })
+        })
+        .collect::<RhaiResult<Vec<_>>>()?;
+    for handle in &handles {
+        handle.close_stdin();
+    }
+    Ok(handles)
+}
+
+/// Like [`wait_for_due`], for started handles.
+fn wait_for_due_handle(handles: &[&ProcessHandle]) -> RhaiResult<Option<usize>> {
+    let Some(poll_interval) = handles
+        .iter()
+        .map(|handle| handle.executor.config.stream_poll_interval_ms)
+        .min()
+    else {
+        return Ok(None);
+    };
+    loop {
+        for (position, handle) in handles.iter().enumerate() {
+            if handle.is_due()? {
+                return Ok(Some(position));
+            }
+        }
+        thread::sleep(Duration::from_millis(poll_interval));
+    }
+}
+
+/// Blocks until one of `members` has exited or run out of time, returning
+/// its position, or `None` if there are no members.
+fn wait_for_due(members: &mut [Member]) -> RhaiResult<Option<usize>> {
+    let Some(poll_interval) = members
+        .iter()
+        .map(|member| member.executor.config.stream_poll_interval_ms)
+        .min()
+    else {
+        return Ok(None);
+    };
+    loop {
+        for (position, member) in members.iter_mut().enumerate() {
+            if member.running.is_due(&member.executor)? {
+                return Ok(Some(position));
+            }
+        }
+        thread::sleep(Duration::from_millis(poll_interval));
+    }
+}
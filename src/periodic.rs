@@ -0,0 +1,230 @@
+use crate::command_sequence::executor_from;
+use crate::command_spec::describe_pipeline;
+use crate::config::Config;
+use crate::pipeline_executor::{PipelineExecutor, RunningPipeline, StreamMode};
+use crate::pipeline_handle::PipelineHandle;
+use crate::platform;
+use crate::process_result::ProcessResult;
+use crate::util::{error_parts, process_error, runtime_error, ErrorKind};
+use crate::RhaiResult;
+use rhai::{Dynamic, FnPtr, NativeCallContext, INT};
+use std::collections::VecDeque;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+/// A pipeline run over and over by `process::every()` on a background
+/// thread. Its results wait in a queue until the script calls `wait()`,
+/// `stop()`, `runs()` or `is_running()`, which pass them to the callback;
+/// the callback never runs otherwise. The queue keeps the latest
+/// [`MAX_QUEUED`] results. The callback returning `false` stops the
+/// schedule, and dropping the last clone of the handle stops it too.
+#[derive(Clone, Debug)]
+pub struct Periodic {
+    config: Arc<Config>,
+    command: String,
+    callback: FnPtr,
+    guard: Arc<StopGuard>,
+}
+
+/// How many results wait for the script at most. Older ones are dropped, so
+/// a script that polls rarely cannot pile up the output of every run.
+const MAX_QUEUED: usize = 16;
+
+#[derive(Debug, Default)]
+struct Schedule {
+    state: Mutex<ScheduleState>,
+    changed: Condvar,
+}
+
+#[derive(Debug, Default)]
+struct ScheduleState {
+    /// The run in progress, if any.
+    current: Option<Arc<PipelineHandle>>,
+    /// Results not yet passed to the callback, at most [`MAX_QUEUED`].
+    results: VecDeque<ProcessResult>,
+    /// Results passed to the callback so far.
+    delivered: usize,
+    stopping: bool,
+    /// Set once the schedule has ended, with the error that ended it if any.
+    /// An error is kept as its parts because it cannot cross threads.
+    outcome: Option<Result<(), (ErrorKind, String)>>,
+}
+
+#[derive(Debug)]
+struct StopGuard {
+    schedule: Arc<Schedule>,
+}
+
+impl Drop for StopGuard {
+    fn drop(&mut self) {
+        self.schedule.stop();
+    }
+}
+
+/// Runs `executor` (a command, pipe or executor) now and then every
+/// `interval_ms` milliseconds, passing each result to `callback`.
+pub(crate) fn every(
+    context: &NativeCallContext,
+    interval_ms: INT,
+    executor: Dynamic,
+    callback: FnPtr,
+) -> RhaiResult<Periodic> {
+    let interval = u64::try_from(interval_ms)
+        .ok()
+        .filter(|ms| *ms > 0)
+        .map(Duration::from_millis)
+        .ok_or_else(|| runtime_error("every interval must be greater than zero"))?;
+    let executor = executor_from(executor)?.into_background("every")?;
+    // Start the first run here so an executor that cannot start fails the call.
+    let running = RunningPipeline::start(&executor.0, Some(context), false)?;
+    let schedule = Arc::new(Schedule::default());
+    schedule.lock().current = Some(Arc::clone(&running.handle));
+
+    let config = Arc::clone(&executor.0.config);
+    let command = describe_pipeline(&executor.0.commands);
+    let scheduled = Arc::clone(&schedule);
+    config.workers.execute(move || {
+        let executor = executor;
+        scheduled.run(&executor.0, running, interval);
+    });
+    Ok(Periodic {
+        config,
+        command,
+        callback,
+        guard: Arc::new(StopGuard { schedule }),
+    })
+}
+
+impl Periodic {
+    fn schedule(&self) -> &Schedule {
+        &self.guard.schedule
+    }
+
+    /// Passes results to the callback as they arrive until the schedule
+    /// stops, raising the error that stopped it if any.
+    pub fn wait(&self, context: &NativeCallContext) -> RhaiResult<()> {
+        let poll_interval = Duration::from_millis(self.config.stream_poll_interval_ms);
+        loop {
+            // Read before delivering, so every result before the end is passed on.
+            let outcome = self.schedule().lock().outcome.clone();
+            self.deliver(context)?;
+            if let Some(outcome) = outcome {
+                return outcome.map_err(|(kind, message)| {
+                    process_error(kind, Some(&self.command), None, message)
+                });
+            }
+            self.config.check_progress()?;
+            let state = self.schedule().lock();
+            if state.outcome.is_none() && state.results.is_empty() {
+                let _ = self.schedule().changed.wait_timeout(state, poll_interval);
+            }
+        }
+    }
+
+    /// Stops the schedule, killing a run in progress, and passes the results
+    /// still queued to the callback.
+    pub fn stop(&self, context: &NativeCallContext) -> RhaiResult<()> {
+        self.schedule().stop();
+        self.wait(context)
+    }
+
+    /// How many results have been passed to the callback.
+    pub fn runs(&self, context: &NativeCallContext) -> RhaiResult<INT> {
+        self.deliver(context)?;
+        Ok(self.schedule().lock().delivered as INT)
+    }
+
+    /// Whether the schedule is still running.
+    pub fn is_running(&self, context: &NativeCallContext) -> RhaiResult<bool> {
+        self.deliver(context)?;
+        Ok(self.schedule().lock().outcome.is_none())
+    }
+
+    /// Calls the callback with each queued result, stopping the schedule
+    /// and dropping the rest if it returns `false`.
+    fn deliver(&self, context: &NativeCallContext) -> RhaiResult<()> {
+        loop {
+            let result = {
+                let mut state = self.schedule().lock();
+                let Some(result) = state.results.pop_front() else {
+                    return Ok(());
+                };
+                state.delivered += 1;
+                result
+            };
+            let keep_going = self
+                .callback
+                .call_within_context::<Dynamic>(context, (result,))?;
+            if keep_going.as_bool() == Ok(false) {
+                self.schedule().stop();
+                self.schedule().lock().results.clear();
+                return Ok(());
+            }
+        }
+    }
+}
+
+impl Schedule {
+    fn lock(&self) -> MutexGuard<'_, ScheduleState> {
+        self.state.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    fn stop(&self) {
+        let mut state = self.lock();
+        state.stopping = true;
+        if let Some(handle) = state.current.as_ref() {
+            if matches!(handle.try_wait(), Ok(None)) {
+                platform::kill_tree(handle).ok();
+            }
+        }
+        self.changed.notify_all();
+    }
+
+    /// Drives `running` to completion and starts the next run `interval`
+    /// after the previous one started, or right away if it ran longer,
+    /// until stopped or a run raises an error.
+    fn run(&self, executor: &PipelineExecutor, mut running: RunningPipeline, interval: Duration) {
+        let mut started = Instant::now();
+        let outcome = loop {
+            let result = running.drive(executor, None, StreamMode::Capture, None, None);
+            let mut state = self.lock();
+            state.current = None;
+            if state.stopping {
+                // A run killed by stop() has no result worth reporting.
+                break Ok(());
+            }
+            match result {
+                Ok(result) => {
+                    if state.results.len() == MAX_QUEUED {
+                        state.results.pop_front();
+                    }
+                    state.results.push_back(result);
+                }
+                Err(err) => break Err(error_parts(&err)),
+            }
+            self.changed.notify_all();
+            let delay = (started + interval).saturating_duration_since(Instant::now());
+            let (state, _) = self
+                .changed
+                .wait_timeout_while(state, delay, |state| !state.stopping)
+                .unwrap_or_else(|err| err.into_inner());
+            if state.stopping {
+                break Ok(());
+            }
+            drop(state);
+            started = Instant::now();
+            running = match RunningPipeline::start(executor, None, false) {
+                Ok(running) => running,
+                Err(err) => break Err(error_parts(&err)),
+            };
+            let mut state = self.lock();
+            state.current = Some(Arc::clone(&running.handle));
+            if state.stopping {
+                platform::kill_tree(&running.handle).ok();
+            }
+        };
+        let mut state = self.lock();
+        state.outcome = Some(outcome);
+        self.changed.notify_all();
+    }
+}
@@ -1,9 +1,11 @@
+#[cfg(feature = "serde")]
+use crate::command_builder::definition_error;
 use crate::command_builder::CommandBuilder;
-use crate::command_spec::CommandSpec;
+use crate::command_spec::{CommandSpec, PipeSource};
 use crate::config::Config;
 use crate::pipeline_executor::PipelineExecutor;
-use crate::util::ensure_same_config;
-use crate::RhaiResult;
+use crate::util::{ensure_same_config, runtime_error};
+use crate::{RhaiArray, RhaiResult};
 use std::sync::Arc;
 
 #[derive(Clone, Debug)]
@@ -20,21 +22,93 @@ impl PipeBuilder {
         }
     }
 
-    pub(crate) fn push_command(&mut self, spec: CommandSpec) {
+    pub(crate) fn from_arrays(config: Arc<Config>, stages: RhaiArray) -> RhaiResult<Self> {
+        let mut builder: Option<Self> = None;
+        for stage in stages {
+            let args = stage.try_cast::<RhaiArray>().ok_or_else(|| {
+                runtime_error("process::pipeline expects an array of command arrays")
+            })?;
+            let command = CommandBuilder::new(Arc::clone(&config), args)?.command;
+            match builder.as_mut() {
+                Some(builder) => builder.push_command(command)?,
+                None => builder = Some(Self::from_single(Arc::clone(&config), command)),
+            }
+        }
+        builder.ok_or_else(|| runtime_error("process::pipeline requires at least one command"))
+    }
+
+    pub(crate) fn push_command(&mut self, spec: CommandSpec) -> RhaiResult<()> {
+        self.config
+            .ensure_pipeline_length(self.commands.len() + 1)?;
         self.commands.push(spec);
+        Ok(())
     }
 
     pub(crate) fn into_executor(self) -> PipelineExecutor {
         PipelineExecutor::new(self.config, self.commands)
     }
 
-    pub fn pipe(mut self, next: CommandBuilder) -> RhaiResult<Self> {
+    pub fn pipe(self, next: CommandBuilder) -> RhaiResult<Self> {
+        self.pipe_from(next, PipeSource::Stdout)
+    }
+
+    pub fn pipe_stderr(self, next: CommandBuilder) -> RhaiResult<Self> {
+        self.pipe_from(next, PipeSource::Stderr)
+    }
+
+    pub fn pipe_both(self, next: CommandBuilder) -> RhaiResult<Self> {
+        self.pipe_from(next, PipeSource::Both)
+    }
+
+    pub(crate) fn pipe_from(
+        mut self,
+        next: CommandBuilder,
+        source: PipeSource,
+    ) -> RhaiResult<Self> {
         ensure_same_config(&self.config, &next.config)?;
-        self.push_command(next.command);
+        if let Some(last) = self.commands.last_mut() {
+            last.pipe_source = source;
+        }
+        self.push_command(next.command)?;
         Ok(self)
     }
 
     pub fn build(self) -> PipelineExecutor {
         self.into_executor()
     }
+
+    /// Rebuilds a pipeline serialized from a `PipeBuilder`, checking every
+    /// stage against `config` as `cmd()` and `pipe()` would. Scripts can run
+    /// it, but not pipe it into commands built by a module with another config.
+    #[cfg(feature = "serde")]
+    pub fn deserialize_with<'de, D>(config: Config, deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let commands = <Vec<CommandSpec> as serde::Deserialize>::deserialize(deserializer)?;
+        let config = Arc::new(config);
+        let mut stages = commands.into_iter();
+        let first = stages
+            .next()
+            .ok_or_else(|| serde::de::Error::custom("a pipeline needs at least one command"))?;
+        let mut builder = Self::from_single(Arc::clone(&config), first);
+        for command in stages {
+            builder
+                .push_command(command)
+                .map_err(definition_error::<D::Error>)?;
+        }
+        for command in &builder.commands {
+            CommandBuilder::check_definition(&config, command)
+                .map_err(definition_error::<D::Error>)?;
+        }
+        Ok(builder)
+    }
+}
+
+/// The stages, in order; env values are written as set, including secrets.
+#[cfg(feature = "serde")]
+impl serde::Serialize for PipeBuilder {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.commands.serialize(serializer)
+    }
 }
@@ -1,17 +1,33 @@
-use crate::command_spec::CommandSpec;
-use crate::config::Config;
-use crate::util::{map_io_err, normalize_exit_codes, runtime_error};
+use crate::command_spec::{describe_pipeline, CommandSpec, PipeSource};
+use crate::config::{redact, CommandRequest, Config};
+use crate::pipeline_handle::{PipelineHandle, PipelineStatus, StartError};
+use crate::platform::{self, CpuSet, IoPriority, ResourceLimits};
+use crate::process_handle::ProcessHandle;
+use crate::process_result::{ProcessResult, StageResult};
+use crate::spawn_limits::{BudgetCharge, SlotGuard};
+use crate::supervisor::Supervisor;
+#[cfg(feature = "tracing")]
+use crate::telemetry;
+use crate::util::{
+    argument_to_string, dynamic_to_string, io_error, map_io_err, normalize_exit_codes,
+    process_error, runtime_error, ErrorKind,
+};
+use crate::worker_pool::WorkerPool;
 use crate::{RhaiArray, RhaiResult};
 use duct::{self, Expression};
-use os_pipe::PipeReader;
-use rhai::{Dynamic, FnPtr, ImmutableString, Map as RhaiMap, NativeCallContext, INT};
-use std::collections::HashSet;
-use std::io::{self, ErrorKind, Read, Write};
+use os_pipe::{PipeReader, PipeWriter};
+use rhai::{
+    Dynamic, EvalAltResult, FnPtr, ImmutableString, Map as RhaiMap, NativeCallContext, INT,
+};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::ffi::OsString;
+use std::io::{self, Read, Write};
 use std::path::PathBuf;
-use std::sync::mpsc::{self, RecvTimeoutError, Sender};
-use std::sync::Arc;
-use std::thread;
-use std::time::{Duration, Instant};
+use std::process::{Command, ExitStatus};
+use std::sync::atomic::AtomicBool;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant, SystemTime};
 
 #[derive(Clone, Debug)]
 pub struct PipelineExecutor {
@@ -20,6 +36,20 @@ pub struct PipelineExecutor {
     pub(crate) timeout_override_ms: Option<u64>,
     pub(crate) allowed_exit_codes: Option<HashSet<i64>>,
     pub(crate) cwd: Option<PathBuf>,
+    pub(crate) capture_while_streaming: bool,
+    pub(crate) on_spawn: Option<FnPtr>,
+    pub(crate) idle_timeout_ms: Option<u64>,
+    pub(crate) on_exit: Option<FnPtr>,
+    pub(crate) capture_combined: bool,
+    pub(crate) grace_period_ms: Option<u64>,
+    pub(crate) pipefail: bool,
+    pub(crate) timeout_returns_result: bool,
+    pub(crate) limits: ResourceLimits,
+    pub(crate) creation_flags: u32,
+    pub(crate) process_group: bool,
+    pub(crate) new_session: bool,
+    pub(crate) chroot: Option<PathBuf>,
+    pub(crate) pty: bool,
 }
 
 impl PipelineExecutor {
@@ -30,6 +60,20 @@ impl PipelineExecutor {
             timeout_override_ms: None,
             allowed_exit_codes: None,
             cwd: None,
+            capture_while_streaming: false,
+            on_spawn: None,
+            idle_timeout_ms: None,
+            on_exit: None,
+            capture_combined: false,
+            grace_period_ms: None,
+            pipefail: true,
+            timeout_returns_result: false,
+            limits: ResourceLimits::default(),
+            creation_flags: 0,
+            process_group: false,
+            new_session: false,
+            chroot: None,
+            pty: false,
         }
     }
 
@@ -37,7 +81,81 @@ impl PipelineExecutor {
         if path.is_empty() {
             self.cwd = None;
         } else {
-            self.cwd = Some(PathBuf::from(path));
+            let path = PathBuf::from(path);
+            self.config.ensure_cwd_allowed(&path)?;
+            self.cwd = Some(path);
+        }
+        Ok(self)
+    }
+
+    pub fn capture_while_streaming(mut self, enabled: bool) -> RhaiResult<Self> {
+        self.capture_while_streaming = enabled;
+        Ok(self)
+    }
+
+    pub fn capture_combined(mut self) -> RhaiResult<Self> {
+        self.capture_combined = true;
+        Ok(self)
+    }
+
+    pub fn pipefail(mut self, enabled: bool) -> RhaiResult<Self> {
+        self.pipefail = enabled;
+        Ok(self)
+    }
+
+    /// Judges the pipeline by its last stage only, as a shell does without
+    /// `set -o pipefail`; same as `pipefail(false)`.
+    pub fn last_stage_status(self) -> RhaiResult<Self> {
+        self.pipefail(false)
+    }
+
+    /// Makes an expired timeout or idle timeout end the run with a result
+    /// marked `timed_out` instead of an error.
+    pub fn on_timeout_return_result(mut self, enabled: bool) -> RhaiResult<Self> {
+        self.timeout_returns_result = enabled;
+        Ok(self)
+    }
+
+    pub fn on_spawn(mut self, callback: FnPtr) -> RhaiResult<Self> {
+        self.on_spawn = Some(callback);
+        Ok(self)
+    }
+
+    pub fn on_exit(mut self, callback: FnPtr) -> RhaiResult<Self> {
+        self.on_exit = Some(callback);
+        Ok(self)
+    }
+
+    /// Appends arguments to the first stage, so an executor can serve as a
+    /// template for several runs.
+    pub fn with_args(mut self, args: RhaiArray) -> RhaiResult<Self> {
+        let strict = self.config.strict_argument_types;
+        if let Some(first) = self.commands.first_mut() {
+            for arg in args {
+                let arg = argument_to_string(arg, strict)?;
+                self.config.ensure_argument_allowed(&first.program, &arg)?;
+                first.args.push(arg);
+            }
+        }
+        Ok(self)
+    }
+
+    /// Adds environment variables to every stage.
+    pub fn with_env(mut self, env: RhaiMap) -> RhaiResult<Self> {
+        for (key, value) in env {
+            let value = dynamic_to_string(value, "environment value")?;
+            self.config.ensure_env_allowed(&key, &value)?;
+            for command in &mut self.commands {
+                command.set_env(key.to_string(), value.clone());
+            }
+        }
+        Ok(self)
+    }
+
+    /// Starts every stage with only the variables set by the script.
+    pub fn env_clear(mut self) -> RhaiResult<Self> {
+        for command in &mut self.commands {
+            command.clear_env = true;
         }
         Ok(self)
     }
@@ -50,6 +168,231 @@ impl PipelineExecutor {
         Ok(self)
     }
 
+    /// Runs every stage as the user `name`, with that user's primary group.
+    pub fn run_as_user(mut self, name: &str) -> RhaiResult<Self> {
+        self.config.ensure_privilege_change_allowed()?;
+        let (uid, gid) = platform::lookup_user(name)
+            .map_err(map_io_err)?
+            .ok_or_else(|| runtime_error(format!("unknown user '{name}'")))?;
+        self.limits.uid = Some(uid);
+        self.limits.gid = Some(gid);
+        Ok(self)
+    }
+
+    pub fn uid(mut self, uid: INT) -> RhaiResult<Self> {
+        self.config.ensure_privilege_change_allowed()?;
+        let uid = u32::try_from(uid)
+            .map_err(|_| runtime_error("uid must be a non-negative 32-bit integer"))?;
+        self.limits.uid = Some(uid);
+        Ok(self)
+    }
+
+    pub fn gid(mut self, gid: INT) -> RhaiResult<Self> {
+        self.config.ensure_privilege_change_allowed()?;
+        let gid = u32::try_from(gid)
+            .map_err(|_| runtime_error("gid must be a non-negative 32-bit integer"))?;
+        self.limits.gid = Some(gid);
+        Ok(self)
+    }
+
+    /// Confines every stage to the directory tree under `path`; an empty
+    /// path lifts the confinement.
+    pub fn chroot(mut self, path: String) -> RhaiResult<Self> {
+        self.config.ensure_chroot_allowed()?;
+        if path.is_empty() {
+            self.chroot = None;
+            return Ok(self);
+        }
+        let path = PathBuf::from(path);
+        if !path.is_dir() {
+            return Err(process_error(
+                ErrorKind::NotFound,
+                None,
+                None,
+                format!("chroot directory '{}' does not exist", path.display()),
+            ));
+        }
+        self.chroot = Some(path);
+        Ok(self)
+    }
+
+    /// Sets the file mode creation mask of every stage, e.g. `0o027`, so
+    /// files they create get the same permissions whatever the host's umask.
+    pub fn umask(mut self, mask: INT) -> RhaiResult<Self> {
+        if !(0..=0o777).contains(&mask) {
+            return Err(runtime_error("umask must be between 0o000 and 0o777"));
+        }
+        self.limits.umask = Some(mask as u32);
+        Ok(self)
+    }
+
+    pub fn limit_memory_mb(mut self, megabytes: INT) -> RhaiResult<Self> {
+        if megabytes <= 0 {
+            return Err(runtime_error("limit_memory_mb must be a positive integer"));
+        }
+        self.limits.memory_mb = Some(megabytes as u64);
+        Ok(self)
+    }
+
+    pub fn limit_cpu_seconds(mut self, seconds: INT) -> RhaiResult<Self> {
+        if seconds <= 0 {
+            return Err(runtime_error(
+                "limit_cpu_seconds must be a positive integer",
+            ));
+        }
+        self.limits.cpu_seconds = Some(seconds as u64);
+        Ok(self)
+    }
+
+    pub fn limit_open_files(mut self, files: INT) -> RhaiResult<Self> {
+        if files <= 0 {
+            return Err(runtime_error("limit_open_files must be a positive integer"));
+        }
+        self.limits.open_files = Some(files as u64);
+        Ok(self)
+    }
+
+    pub fn limit_processes(mut self, processes: INT) -> RhaiResult<Self> {
+        if processes <= 0 {
+            return Err(runtime_error("limit_processes must be a positive integer"));
+        }
+        self.limits.processes = Some(processes as u64);
+        Ok(self)
+    }
+
+    /// Runs every stage at niceness `nice`, from -20 (highest priority) to 19.
+    pub fn nice(mut self, nice: INT) -> RhaiResult<Self> {
+        if !(-20..=19).contains(&nice) {
+            return Err(runtime_error("nice must be between -20 and 19"));
+        }
+        self.limits.nice = Some(nice as i32);
+        Ok(self)
+    }
+
+    /// Named niceness levels: `"low"` (10), `"normal"` (0) and `"high"` (-10).
+    pub fn priority(self, level: &str) -> RhaiResult<Self> {
+        let nice = match level {
+            "low" => 10,
+            "normal" => 0,
+            "high" => -10,
+            _ => {
+                return Err(runtime_error(format!(
+                    "unknown priority '{level}'; expected \"low\", \"normal\" or \"high\""
+                )))
+            }
+        };
+        self.nice(nice)
+    }
+
+    /// Starts every stage in a process group of its own, so signals and
+    /// timeout kills reach everything it started and a Ctrl-C at the host's
+    /// terminal does not reach it.
+    pub fn process_group(mut self, enabled: bool) -> RhaiResult<Self> {
+        self.process_group = enabled;
+        Ok(self)
+    }
+
+    /// Like `process_group(true)`, but every stage also gets a session of
+    /// its own (`setsid`), detaching it from the host's terminal. Unix only.
+    pub fn new_session(mut self, enabled: bool) -> RhaiResult<Self> {
+        self.new_session = enabled;
+        Ok(self)
+    }
+
+    fn isolated(&self) -> bool {
+        self.process_group || self.new_session
+    }
+
+    /// Runs the pipeline on a pseudo-terminal instead of pipes, for programs
+    /// that only prompt, colour or draw progress bars when attached to a TTY.
+    /// Stdin, stdout and stderr of every stage share the terminal, so all
+    /// output arrives on stdout with `\r\n` line endings, and input written
+    /// to it is echoed back. Every stage gets a session of its own. Unix only.
+    pub fn pty(mut self, enabled: bool) -> RhaiResult<Self> {
+        self.pty = enabled;
+        Ok(self)
+    }
+
+    /// Starts console programs without a console window on Windows, so GUI
+    /// hosts do not flash one for every command.
+    pub fn hide_window(mut self, hide: bool) -> RhaiResult<Self> {
+        if hide {
+            self.creation_flags |= platform::CREATE_NO_WINDOW;
+        } else {
+            self.creation_flags &= !platform::CREATE_NO_WINDOW;
+        }
+        Ok(self)
+    }
+
+    /// Adds raw Windows process creation flags, e.g. `CREATE_NEW_PROCESS_GROUP`
+    /// (`0x200`), to every stage.
+    pub fn creation_flags(mut self, flags: INT) -> RhaiResult<Self> {
+        let flags = u32::try_from(flags)
+            .map_err(|_| runtime_error("creation_flags must fit in an unsigned 32-bit integer"))?;
+        self.creation_flags |= flags;
+        Ok(self)
+    }
+
+    /// Sets the I/O scheduling class of every stage: `"idle"`, or
+    /// `"best_effort"` at `level` 0 (first) to 7 (last), 4 if not given.
+    pub fn io_priority(mut self, class: &str, level: Option<INT>) -> RhaiResult<Self> {
+        let priority = match (class, level) {
+            ("idle", None) => IoPriority::Idle,
+            ("idle", Some(_)) => {
+                return Err(runtime_error("io_priority \"idle\" does not take a level"))
+            }
+            ("best_effort", level) => match level.unwrap_or(4) {
+                level @ 0..=7 => IoPriority::BestEffort(level as u8),
+                _ => return Err(runtime_error("io_priority level must be between 0 and 7")),
+            },
+            _ => {
+                return Err(runtime_error(format!(
+                    "unknown io_priority '{class}'; expected \"idle\" or \"best_effort\""
+                )))
+            }
+        };
+        self.limits.io_priority = Some(priority);
+        Ok(self)
+    }
+
+    /// Confines every stage to the CPUs numbered in `cpus`.
+    pub fn cpu_affinity(mut self, cpus: RhaiArray) -> RhaiResult<Self> {
+        let mut indices = Vec::with_capacity(cpus.len());
+        for cpu in cpus {
+            let cpu = cpu
+                .try_cast::<INT>()
+                .ok_or_else(|| runtime_error("cpu_affinity expects integers"))?;
+            indices.push(usize::try_from(cpu).unwrap_or(usize::MAX));
+        }
+        if indices.is_empty() {
+            return Err(runtime_error("cpu_affinity needs at least one CPU"));
+        }
+        let set = CpuSet::new(indices).ok_or_else(|| {
+            runtime_error(format!(
+                "cpu_affinity expects CPU numbers from 0 to {}",
+                CpuSet::CAPACITY - 1
+            ))
+        })?;
+        self.limits.cpus = Some(set);
+        Ok(self)
+    }
+
+    pub fn grace_period(mut self, grace: INT) -> RhaiResult<Self> {
+        if grace <= 0 {
+            return Err(runtime_error("grace_period must be a positive integer"));
+        }
+        self.grace_period_ms = Some(grace as u64);
+        Ok(self)
+    }
+
+    pub fn idle_timeout(mut self, timeout: INT) -> RhaiResult<Self> {
+        if timeout <= 0 {
+            return Err(runtime_error("idle_timeout must be a positive integer"));
+        }
+        self.idle_timeout_ms = Some(timeout as u64);
+        Ok(self)
+    }
+
     pub fn allow_exit_codes(mut self, codes: RhaiArray) -> RhaiResult<Self> {
         let mut set = HashSet::new();
         for code in codes {
@@ -63,15 +406,157 @@ impl PipelineExecutor {
         Ok(self)
     }
 
-    pub fn run(self) -> RhaiResult<RhaiMap> {
-        let timeout = self.timeout_override_ms.or(self.config.default_timeout_ms);
-        let result = run_pipeline(
-            &self.commands,
-            timeout,
-            self.allowed_exit_codes.clone(),
-            self.cwd,
-        )?;
-        Ok(result.into_map())
+    pub fn run(self, context: &NativeCallContext) -> RhaiResult<ProcessResult> {
+        self.execute(context)
+    }
+
+    /// Runs the pipeline and raises a `"failed"` error unless it succeeded.
+    pub fn check(self, context: &NativeCallContext) -> RhaiResult<ProcessResult> {
+        self.execute(context)?.check(None)
+    }
+
+    /// Like `check()`, prefixing the error message with `message`.
+    pub fn expect(self, context: &NativeCallContext, message: &str) -> RhaiResult<ProcessResult> {
+        self.execute(context)?.check(Some(message))
+    }
+
+    /// Describes what `run()` would execute without starting anything.
+    pub fn dry_run(&self) -> RhaiMap {
+        let optional_ms = |ms: Option<u64>| {
+            ms.map(|ms| Dynamic::from_int(ms.try_into().unwrap_or(INT::MAX)))
+                .unwrap_or(Dynamic::UNIT)
+        };
+        let stages: RhaiArray = self
+            .commands
+            .iter()
+            .map(|command| {
+                let cwd = command
+                    .cwd
+                    .as_ref()
+                    .or(self.cwd.as_ref())
+                    .map(|dir| Dynamic::from(dir.to_string_lossy().into_owned()))
+                    .unwrap_or(Dynamic::UNIT);
+                let mut stage = RhaiMap::new();
+                stage.insert("program".into(), Dynamic::from(command.program.clone()));
+                stage.insert("args".into(), Dynamic::from(command.args_array()));
+                stage.insert(
+                    "env".into(),
+                    Dynamic::from_map(command.env_map(&self.config)),
+                );
+                let removed: RhaiArray = command
+                    .env_remove
+                    .iter()
+                    .cloned()
+                    .map(Dynamic::from)
+                    .collect();
+                stage.insert("env_remove".into(), Dynamic::from(removed));
+                stage.insert("env_clear".into(), Dynamic::from_bool(command.clear_env));
+                stage.insert("cwd".into(), cwd);
+                stage.insert("timeout_ms".into(), optional_ms(command.timeout_ms));
+                Dynamic::from_map(stage)
+            })
+            .collect();
+
+        let mut map = RhaiMap::new();
+        map.insert(
+            "command".into(),
+            Dynamic::from(describe_pipeline(&self.commands)),
+        );
+        map.insert("stages".into(), Dynamic::from(stages));
+        map.insert(
+            "timeout_ms".into(),
+            optional_ms(self.effective_timeout_ms()),
+        );
+        map.insert("idle_timeout_ms".into(), optional_ms(self.idle_timeout_ms));
+        map
+    }
+
+    /// Prepares the executor to be run from a background thread by `api`.
+    pub(crate) fn into_background(self, api: &str) -> RhaiResult<BackgroundExecutor> {
+        if self.on_spawn.is_some() || self.on_exit.is_some() {
+            return Err(runtime_error(format!(
+                "{api}() cannot call on_spawn() or on_exit() callbacks"
+            )));
+        }
+        Ok(BackgroundExecutor(self))
+    }
+
+    pub(crate) fn execute(&self, context: &NativeCallContext) -> RhaiResult<ProcessResult> {
+        run_pipeline_stream(self, Some(context), StreamMode::Capture, None, None)
+    }
+
+    /// Runs the pipeline like `run()` for async hosts, through
+    /// `tokio::process`: output and exits are awaited on the runtime, and
+    /// dropping the future kills the pipeline. Scripts cannot await, so this
+    /// is for hosts that take an executor built by a script and run it
+    /// themselves; `on_spawn()` and `on_exit()` callbacks need the script and
+    /// `pty()` needs the blocking backend, so both are rejected.
+    #[cfg(feature = "async")]
+    pub async fn run_async(self) -> RhaiResult<ProcessResult> {
+        crate::async_pipeline::run(self).await
+    }
+
+    pub fn spawn(self, context: &NativeCallContext) -> RhaiResult<ProcessHandle> {
+        let running = RunningPipeline::start(&self, Some(context), true)?;
+        let kill_on_drop = Arc::new(AtomicBool::new(self.config.kill_on_drop));
+        let id = self
+            .config
+            .jobs
+            .register(&self.commands, &running.handle, &kill_on_drop);
+        Ok(ProcessHandle::new(self, running, id, kill_on_drop))
+    }
+
+    /// `spawn()` on a pseudo-terminal; see `pty()`.
+    pub fn spawn_pty(self, context: &NativeCallContext) -> RhaiResult<ProcessHandle> {
+        self.pty(true)?.spawn(context)
+    }
+
+    /// Starts the pipeline in the background and restarts it whenever it
+    /// exits unsuccessfully; see [`Supervisor`].
+    pub fn supervise(
+        self,
+        context: &NativeCallContext,
+        options: RhaiMap,
+    ) -> RhaiResult<Supervisor> {
+        Supervisor::start(self, context, options)
+    }
+
+    pub fn run_detached(self, context: &NativeCallContext) -> RhaiResult<INT> {
+        let mut stages = build_stages(&self, true)?;
+        for stage in stages.iter_mut() {
+            *stage = stage.stderr_null();
+        }
+        wire_ends(
+            &mut stages,
+            |first| first.stdin_null(),
+            |last| last.stdout_null(),
+        );
+        let slot = self.admit()?;
+        // Detaching gives every stage a session, and so a group, of its own.
+        #[cfg(feature = "tracing")]
+        let span = telemetry::pipeline_span(&self.commands);
+        let handle = PipelineHandle::start(&stages, true, self.resource_limits())
+            .map_err(|err| self.start_error(err));
+        #[cfg(feature = "tracing")]
+        if let Err(err) = &handle {
+            telemetry::failed(&span, err);
+        }
+        let handle = handle?;
+        #[cfg(feature = "tracing")]
+        telemetry::spawned(&span, &self.commands, &handle.pids());
+        drop(stages);
+        let charge = self.budget_charge(Instant::now());
+        notify_spawn(&self, Some(context), &handle)?;
+        let pid = handle.pids().first().map(|pid| *pid as INT).unwrap_or(-1);
+        // Only reap the children so they do not linger as zombies.
+        self.config.workers.execute(move || {
+            let _ = handle.wait();
+            if let Some(charge) = charge {
+                charge.settle();
+            }
+            drop(slot);
+        });
+        Ok(pid)
     }
 
     pub fn run_stream(
@@ -79,244 +564,1233 @@ impl PipelineExecutor {
         context: &NativeCallContext,
         stdout_cb: Option<FnPtr>,
         stderr_cb: Option<FnPtr>,
-    ) -> RhaiResult<RhaiMap> {
-        let timeout = self.timeout_override_ms.or(self.config.default_timeout_ms);
-        let result = run_pipeline_stream(
-            &self.commands,
-            timeout,
-            self.allowed_exit_codes.clone(),
-            self.cwd,
-            context,
-            stdout_cb,
-            stderr_cb,
-        )?;
-        Ok(result.into_map())
+    ) -> RhaiResult<ProcessResult> {
+        self.run_stream_with_mode(context, StreamMode::Chunks, stdout_cb, stderr_cb)
     }
-}
 
-#[derive(Debug)]
-struct ProcessResult {
-    success: bool,
-    status: i64,
-    stdout: String,
-    stderr: String,
-    duration_ms: u64,
-}
+    pub fn run_stream_lines(
+        self,
+        context: &NativeCallContext,
+        stdout_cb: Option<FnPtr>,
+        stderr_cb: Option<FnPtr>,
+    ) -> RhaiResult<ProcessResult> {
+        self.run_stream_with_mode(context, StreamMode::Lines, stdout_cb, stderr_cb)
+    }
 
-impl ProcessResult {
-    fn into_map(self) -> RhaiMap {
-        let mut map = RhaiMap::new();
-        map.insert("success".into(), Dynamic::from_bool(self.success));
-        map.insert("status".into(), Dynamic::from_int(self.status as INT));
-        map.insert("stdout".into(), Dynamic::from(self.stdout));
-        map.insert("stderr".into(), Dynamic::from(self.stderr));
-        let duration_int: INT = self.duration_ms.try_into().unwrap_or(i64::MAX);
-        map.insert("duration_ms".into(), Dynamic::from_int(duration_int));
-        map
+    #[cfg(not(feature = "no_index"))]
+    pub fn run_stream_bytes(
+        self,
+        context: &NativeCallContext,
+        stdout_cb: Option<FnPtr>,
+        stderr_cb: Option<FnPtr>,
+    ) -> RhaiResult<ProcessResult> {
+        self.run_stream_with_mode(context, StreamMode::Bytes, stdout_cb, stderr_cb)
     }
-}
 
-fn run_pipeline(
-    commands: &[CommandSpec],
-    timeout_ms: Option<u64>,
-    allowed_exit_codes: Option<HashSet<i64>>,
-    cwd: Option<PathBuf>,
-) -> RhaiResult<ProcessResult> {
-    if commands.is_empty() {
-        return Err(runtime_error("no command specified"));
-    }
-    let mut expression = build_expression(commands, cwd.as_ref())?;
-    expression = expression.stdout_capture().stderr_capture().unchecked();
-    let start = Instant::now();
-    let output = match timeout_ms {
-        Some(ms) => run_with_timeout(expression, Duration::from_millis(ms)).map_err(map_io_err)?,
-        None => expression.run().map_err(map_io_err)?,
-    };
-    let duration = start.elapsed();
-    let exit_code = output.status.code().map(|c| c as i64).unwrap_or(-1);
-    let mut success = output.status.success();
-    if !success {
-        if let Some(allowed) = allowed_exit_codes.as_ref() {
-            if allowed.contains(&exit_code) {
-                success = true;
+    fn run_stream_with_mode(
+        self,
+        context: &NativeCallContext,
+        mode: StreamMode,
+        stdout_cb: Option<FnPtr>,
+        stderr_cb: Option<FnPtr>,
+    ) -> RhaiResult<ProcessResult> {
+        run_pipeline_stream(&self, Some(context), mode, stdout_cb, stderr_cb)
+    }
+
+    /// Waits for a free slot if the config caps concurrent pipelines and for
+    /// its spawn rate to allow another start, then checks that the config's
+    /// runtime budget is not used up.
+    pub(crate) fn admit(&self) -> RhaiResult<Option<SlotGuard>> {
+        let slot = self
+            .config
+            .max_concurrent
+            .map(|limit| self.config.slots.acquire(limit));
+        if let Some(limit) = self.config.max_spawns_per_second {
+            self.config.spawn_rate.throttle(limit);
+        }
+        self.config.ensure_budget_left()?;
+        Ok(slot)
+    }
+
+    /// Tracks the run time of a pipeline started at `start`, if the config
+    /// has a runtime budget to charge it to.
+    pub(crate) fn budget_charge(&self, start: Instant) -> Option<Arc<BudgetCharge>> {
+        self.config
+            .total_runtime_budget_ms
+            .map(|_| Arc::new(BudgetCharge::new(&self.config.budget, start)))
+    }
+
+    /// An I/O error naming this pipeline as the failed command.
+    pub(crate) fn io_error(&self, err: io::Error) -> Box<EvalAltResult> {
+        io_error(err, Some(&describe_pipeline(&self.commands)))
+    }
+
+    /// Names the stage that could not be started, telling a missing program
+    /// or working directory apart from other failures.
+    pub(crate) fn start_error(&self, err: StartError) -> Box<EvalAltResult> {
+        let Some(command) = self.commands.get(err.stage) else {
+            return self.io_error(err.error);
+        };
+        let program = command.program.as_str();
+        let stage = if self.commands.len() > 1 {
+            format!(" (stage {} of {})", err.stage + 1, self.commands.len())
+        } else {
+            String::new()
+        };
+        let missing_cwd = command
+            .cwd
+            .as_ref()
+            .or(self.cwd.as_ref())
+            .filter(|dir| !dir.is_dir());
+        let (kind, message) = match (err.error.kind(), missing_cwd) {
+            (io::ErrorKind::NotFound, Some(dir)) => (
+                ErrorKind::NotFound,
+                format!(
+                    "working directory '{}' does not exist{stage}",
+                    dir.display()
+                ),
+            ),
+            (io::ErrorKind::NotFound, None) => (
+                ErrorKind::NotFound,
+                format!("command '{program}' was not found{stage}"),
+            ),
+            (io::ErrorKind::PermissionDenied, _) => (
+                ErrorKind::PermissionDenied,
+                format!("permission denied running '{program}'{stage}"),
+            ),
+            (_, _) => (
+                ErrorKind::Io,
+                format!(
+                    "process I/O error starting '{program}'{stage}: {}",
+                    err.error
+                ),
+            ),
+        };
+        process_error(kind, Some(program), None, message)
+    }
+
+    /// The executor's own limits, filled in from the config's defaults.
+    pub(crate) fn resource_limits(&self) -> ResourceLimits {
+        let mut limits = self.limits;
+        limits.memory_mb = limits.memory_mb.or(self.config.default_memory_limit_mb);
+        limits
+    }
+
+    pub(crate) fn effective_timeout_ms(&self) -> Option<u64> {
+        self.timeout_override_ms.or(self.config.default_timeout_ms)
+    }
+
+    pub(crate) fn kill_grace(&self) -> Option<Duration> {
+        self.grace_period_ms
+            .or(self.config.kill_grace_ms)
+            .map(Duration::from_millis)
+    }
+
+    /// The pipeline's outcome is decided by the rightmost stage that did not
+    /// succeed, or with `pipefail` off by its last stage, as in a shell.
+    fn exit_outcome(&self, status: &PipelineStatus) -> (bool, i64) {
+        match self.decisive_status(status) {
+            Some(status) => (self.accepts(status), status_code(status)),
+            None => (false, -1),
+        }
+    }
+
+    fn decisive_status<'a>(&self, status: &'a PipelineStatus) -> Option<&'a ExitStatus> {
+        if self.pipefail {
+            if let Some(failed) = status.stages.iter().rev().find(|s| !self.accepts(s)) {
+                return Some(failed);
             }
         }
+        status.stages.last()
     }
 
-    Ok(ProcessResult {
-        success,
-        status: exit_code,
-        stdout: String::from_utf8_lossy(&output.stdout).into_owned(),
-        stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
-        duration_ms: duration.as_millis().try_into().unwrap_or(u64::MAX),
-    })
+    fn accepts(&self, status: &ExitStatus) -> bool {
+        let allowed = self
+            .allowed_exit_codes
+            .as_ref()
+            .is_some_and(|codes| codes.contains(&status_code(status)));
+        status.success() || allowed
+    }
 }
 
 fn run_pipeline_stream(
-    commands: &[CommandSpec],
-    timeout_ms: Option<u64>,
-    allowed_exit_codes: Option<HashSet<i64>>,
-    cwd: Option<PathBuf>,
-    context: &NativeCallContext,
+    executor: &PipelineExecutor,
+    context: Option<&NativeCallContext>,
+    mode: StreamMode,
     stdout_cb: Option<FnPtr>,
     stderr_cb: Option<FnPtr>,
 ) -> RhaiResult<ProcessResult> {
-    if commands.is_empty() {
-        return Err(runtime_error("no command specified"));
+    let mut running = RunningPipeline::start(executor, context, false)?;
+    running.drive(
+        executor,
+        context,
+        mode,
+        stdout_cb.as_ref(),
+        stderr_cb.as_ref(),
+    )
+}
+
+/// An executor that can be moved to a background thread to run without the
+/// script. Only its `on_spawn()` and `on_exit()` callbacks tie an executor to
+/// the script's thread, and [`PipelineExecutor::into_background`] rejects
+/// those.
+pub(crate) struct BackgroundExecutor(pub(crate) PipelineExecutor);
+
+// SAFETY: every other field of `PipelineExecutor` is `Send`, and the
+// callbacks are `None`.
+#[cfg(not(feature = "sync"))]
+unsafe impl Send for BackgroundExecutor {}
+
+/// A started pipeline whose output is read by background threads and drained
+/// through a channel by `drive`.
+#[derive(Debug)]
+pub(crate) struct RunningPipeline {
+    pub(crate) handle: Arc<PipelineHandle>,
+    pub(crate) stdin: Option<PipeWriter>,
+    receiver: Receiver<StreamMessage>,
+    start: Instant,
+    started_at: SystemTime,
+    stage_exits: Vec<Arc<OnceLock<Duration>>>,
+    charge: Option<Arc<BudgetCharge>>,
+    generation: u64,
+    last_activity: Instant,
+    stdout_open: bool,
+    stderr_open: bool,
+    process_finished: bool,
+    cancelled: bool,
+    timed_out: bool,
+    stdout_pending: Vec<u8>,
+    stderr_pending: Vec<u8>,
+    stdout_captured: Vec<u8>,
+    stderr_captured: Vec<u8>,
+    combined_captured: Vec<u8>,
+    stdout_read: usize,
+    stderr_read: usize,
+    #[cfg(feature = "tracing")]
+    span: tracing::Span,
+}
+
+impl RunningPipeline {
+    pub(crate) fn start(
+        executor: &PipelineExecutor,
+        context: Option<&NativeCallContext>,
+        pipe_stdin: bool,
+    ) -> RhaiResult<Self> {
+        if executor.commands.is_empty() {
+            return Err(runtime_error("no command specified"));
+        }
+
+        let mut stages = build_stages(executor, false)?;
+        let (stdout_reader, stderr_reader, stdin) = if executor.pty {
+            wire_terminal(&mut stages, pipe_stdin)?
+        } else {
+            wire_pipes(&mut stages, pipe_stdin)?
+        };
+
+        let slot = executor.admit()?;
+        #[cfg(feature = "tracing")]
+        let span = telemetry::pipeline_span(&executor.commands);
+        // Taken before spawning, so stage durations cover the whole run.
+        let start = Instant::now();
+        let started_at = SystemTime::now();
+        let handle = PipelineHandle::start(
+            &stages,
+            executor.isolated() || executor.pty,
+            executor.resource_limits(),
+        )
+        .map_err(|err| executor.start_error(err));
+        #[cfg(feature = "tracing")]
+        if let Err(err) = &handle {
+            telemetry::failed(&span, err);
+        }
+        let handle = Arc::new(handle?);
+        #[cfg(feature = "tracing")]
+        telemetry::spawned(&span, &executor.commands, &handle.pids());
+        // The stages hold the write ends of our pipes; readers only see EOF once
+        // these copies are gone.
+        drop(stages);
+        let charge = executor.budget_charge(start);
+        if slot.is_some() || charge.is_some() {
+            // The slot is freed and the run time charged when the pipeline
+            // exits, not when the script collects it, so handles held by a
+            // script can neither deadlock it nor run for free.
+            let exited = Arc::clone(&handle);
+            let exit_charge = charge.clone();
+            executor.config.workers.execute(move || {
+                let _ = exited.wait();
+                if let Some(charge) = exit_charge {
+                    charge.settle();
+                }
+                drop(slot);
+            });
+        }
+        let generation = executor.config.cancellation.track(&handle);
+        notify_spawn(executor, context, &handle)?;
+        watch_stage_timeouts(executor, &handle);
+        let workers = &executor.config.workers;
+        let stage_exits = watch_stage_exits(workers, &handle, start);
+        let (tx, rx) = mpsc::channel();
+        let buffer_size = executor.config.stream_buffer_size;
+        spawn_stream_reader(
+            workers,
+            stdout_reader,
+            tx.clone(),
+            StreamKind::Stdout,
+            buffer_size,
+        );
+        let stderr_open = stderr_reader.is_some();
+        if let Some(stderr_reader) = stderr_reader {
+            spawn_stream_reader(workers, stderr_reader, tx, StreamKind::Stderr, buffer_size);
+        }
+
+        Ok(Self {
+            handle,
+            stdin,
+            receiver: rx,
+            start,
+            started_at,
+            stage_exits,
+            charge,
+            generation,
+            last_activity: start,
+            stdout_open: true,
+            stderr_open,
+            process_finished: false,
+            cancelled: false,
+            timed_out: false,
+            stdout_pending: Vec::new(),
+            stderr_pending: Vec::new(),
+            stdout_captured: Vec::new(),
+            stderr_captured: Vec::new(),
+            combined_captured: Vec::new(),
+            stdout_read: 0,
+            stderr_read: 0,
+            #[cfg(feature = "tracing")]
+            span,
+        })
     }
 
-    let mut expression = build_expression(commands, cwd.as_ref())?;
-    let (stdout_reader, stdout_writer) = os_pipe::pipe().map_err(map_io_err)?;
-    let (stderr_reader, stderr_writer) = os_pipe::pipe().map_err(map_io_err)?;
-    expression = expression
-        .stdout_file(stdout_writer)
-        .stderr_file(stderr_writer)
-        .unchecked();
-
-    let handle = expression.start().map_err(map_io_err)?;
-    drop(expression);
-    let start = Instant::now();
-    let (tx, rx) = mpsc::channel();
-    spawn_stream_reader(stdout_reader, tx.clone(), StreamKind::Stdout);
-    spawn_stream_reader(stderr_reader, tx, StreamKind::Stderr);
-
-    let mut stdout_open = true;
-    let mut stderr_open = true;
-    let mut process_finished = false;
-
-    while stdout_open || stderr_open {
-        if let Some(limit) = timeout_ms {
-            if start.elapsed() >= Duration::from_millis(limit) {
-                handle.kill().ok();
-                return Err(map_io_err(io::Error::new(
-                    ErrorKind::TimedOut,
-                    "process execution timed out",
-                )));
+    fn record_chunk(
+        &mut self,
+        executor: &PipelineExecutor,
+        kind: StreamKind,
+        chunk: &[u8],
+        capture: bool,
+    ) {
+        self.last_activity = Instant::now();
+        let limit = executor.config.max_output_bytes;
+        if capture {
+            match kind {
+                StreamKind::Stdout => append_capped(&mut self.stdout_captured, chunk, limit),
+                StreamKind::Stderr => append_capped(&mut self.stderr_captured, chunk, limit),
             }
         }
+        if executor.capture_combined {
+            append_capped(&mut self.combined_captured, chunk, limit);
+        }
+    }
+
+    /// Moves whatever the reader threads have produced so far into the capture
+    /// buffers without blocking.
+    pub(crate) fn pump(&mut self, executor: &PipelineExecutor) -> RhaiResult<()> {
+        while let Ok(message) = self.receiver.try_recv() {
+            self.receive(executor, message)?;
+        }
+        Ok(())
+    }
+
+    fn was_cancelled(&self, executor: &PipelineExecutor) -> bool {
+        executor
+            .config
+            .cancellation
+            .cancelled_since(self.generation)
+    }
+
+    /// Whether `drive` would return without waiting on the pipeline: it has
+    /// exited, been cancelled, or its timeout or idle timeout has expired.
+    pub(crate) fn is_due(&mut self, executor: &PipelineExecutor) -> RhaiResult<bool> {
+        executor.config.check_progress()?;
+        self.pump(executor)?;
+        if self.was_cancelled(executor)
+            || self
+                .handle
+                .try_wait()
+                .map_err(|err| executor.io_error(err))?
+                .is_some()
+        {
+            return Ok(true);
+        }
+        let expired = |since: Instant, limit: Option<u64>| {
+            limit.is_some_and(|ms| since.elapsed() >= Duration::from_millis(ms))
+        };
+        Ok(expired(self.start, executor.effective_timeout_ms())
+            || expired(self.last_activity, executor.idle_timeout_ms))
+    }
+
+    fn receive(&mut self, executor: &PipelineExecutor, message: StreamMessage) -> RhaiResult<()> {
+        match message {
+            StreamMessage::Data(kind, chunk) => self.record_chunk(executor, kind, &chunk, true),
+            StreamMessage::Eof(StreamKind::Stdout) => self.stdout_open = false,
+            StreamMessage::Eof(StreamKind::Stderr) => self.stderr_open = false,
+            StreamMessage::Error(err) => return Err(map_io_err(err)),
+        }
+        Ok(())
+    }
 
-        match rx.recv_timeout(Duration::from_millis(50)) {
-            Ok(StreamMessage::Data(kind, chunk)) => {
-                dispatch_stream_chunk(
-                    kind,
-                    &chunk,
-                    context,
-                    stdout_cb.as_ref(),
-                    stderr_cb.as_ref(),
-                )?;
+    /// Blocks until unread stdout matches `pattern`, then consumes and returns
+    /// it up to the end of the match. Raises a `"timeout"` error once
+    /// `deadline` passes and a `"failed"` error if stdout closes first.
+    pub(crate) fn expect(
+        &mut self,
+        executor: &PipelineExecutor,
+        pattern: &regex::bytes::Regex,
+        label: &str,
+        deadline: Instant,
+    ) -> RhaiResult<String> {
+        let command = describe_pipeline(&executor.commands);
+        loop {
+            executor.config.check_progress()?;
+            self.pump(executor)?;
+            let unread = &self.stdout_captured[self.stdout_read..];
+            if let Some(found) = pattern.find(unread) {
+                let text = String::from_utf8_lossy(&unread[..found.end()]).into_owned();
+                self.stdout_read += found.end();
+                return Ok(text);
             }
-            Ok(StreamMessage::Eof(kind)) => match kind {
-                StreamKind::Stdout => stdout_open = false,
-                StreamKind::Stderr => stderr_open = false,
-            },
-            Ok(StreamMessage::Error(err)) => {
-                handle.kill().ok();
-                return Err(map_io_err(err));
+            if self.was_cancelled(executor) {
+                return Err(cancelled_error(executor));
+            }
+            if !self.stdout_open {
+                return Err(process_error(
+                    ErrorKind::Failed,
+                    Some(&command),
+                    None,
+                    format!("output ended before '{label}' appeared"),
+                ));
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(process_error(
+                    ErrorKind::Timeout,
+                    Some(&command),
+                    None,
+                    format!("timed out waiting for '{label}'"),
+                ));
+            }
+            match self.receiver.recv_timeout(deadline - now) {
+                Ok(message) => self.receive(executor, message)?,
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => self.stdout_open = false,
+            }
+        }
+    }
+
+    /// Returns captured output that has not been read yet, holding back an
+    /// incomplete trailing UTF-8 sequence while the stream is still open.
+    pub(crate) fn read_available(&mut self, kind: StreamKind) -> String {
+        let (buffer, cursor, open) = self.read_state(kind);
+        let unread = &buffer[*cursor..];
+        let end = match std::str::from_utf8(unread) {
+            Err(err) if open && err.error_len().is_none() => err.valid_up_to(),
+            _ => unread.len(),
+        };
+        *cursor += end;
+        String::from_utf8_lossy(&unread[..end]).into_owned()
+    }
+
+    /// Returns the next complete unread line (without its line terminator),
+    /// or the unterminated remainder once the stream has closed.
+    pub(crate) fn read_line(&mut self, kind: StreamKind) -> Option<String> {
+        let (buffer, cursor, open) = self.read_state(kind);
+        let unread = &buffer[*cursor..];
+        let line = match unread.iter().position(|b| *b == b'\n') {
+            Some(pos) => {
+                *cursor += pos + 1;
+                let line = &unread[..pos];
+                line.strip_suffix(b"\r").unwrap_or(line)
+            }
+            None if !open && !unread.is_empty() => {
+                *cursor += unread.len();
+                unread
+            }
+            None => return None,
+        };
+        Some(String::from_utf8_lossy(line).into_owned())
+    }
+
+    fn read_state(&mut self, kind: StreamKind) -> (&[u8], &mut usize, bool) {
+        match kind {
+            StreamKind::Stdout => (
+                &self.stdout_captured,
+                &mut self.stdout_read,
+                self.stdout_open,
+            ),
+            StreamKind::Stderr => (
+                &self.stderr_captured,
+                &mut self.stderr_read,
+                self.stderr_open,
+            ),
+        }
+    }
+
+    pub(crate) fn drive(
+        &mut self,
+        executor: &PipelineExecutor,
+        context: Option<&NativeCallContext>,
+        mode: StreamMode,
+        stdout_cb: Option<&FnPtr>,
+        stderr_cb: Option<&FnPtr>,
+    ) -> RhaiResult<ProcessResult> {
+        let outcome = self.drive_to_exit(executor, context, mode, stdout_cb, stderr_cb);
+        #[cfg(feature = "tracing")]
+        telemetry::finished(&self.span, &outcome);
+        outcome
+    }
+
+    fn drive_to_exit(
+        &mut self,
+        executor: &PipelineExecutor,
+        context: Option<&NativeCallContext>,
+        mode: StreamMode,
+        stdout_cb: Option<&FnPtr>,
+        stderr_cb: Option<&FnPtr>,
+    ) -> RhaiResult<ProcessResult> {
+        let timeout_ms = executor.effective_timeout_ms();
+        let poll_interval = Duration::from_millis(executor.config.stream_poll_interval_ms);
+        let capture = executor.capture_while_streaming || matches!(mode, StreamMode::Capture);
+
+        while self.stdout_open || self.stderr_open {
+            executor.config.check_progress()?;
+            if self.was_cancelled(executor) {
+                platform::kill_tree(&self.handle).ok();
+                break;
+            }
+            if executor.on_exit.is_some() && !self.process_finished {
+                if let Some(status) = self
+                    .handle
+                    .try_wait()
+                    .map_err(|err| executor.io_error(err))?
+                {
+                    self.process_finished = true;
+                    let (success, exit_code) = executor.exit_outcome(&status);
+                    notify_exit(executor, context, success, exit_code, self.start.elapsed())?;
+                }
+            }
+
+            if let Some(limit) = timeout_ms {
+                if self.start.elapsed() >= Duration::from_millis(limit) {
+                    platform::terminate_tree(&self.handle, executor.kill_grace()).ok();
+                    if executor.timeout_returns_result {
+                        self.timed_out = true;
+                        break;
+                    }
+                    return Err(executor.io_error(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        "process execution timed out",
+                    )));
+                }
+            }
+            if let Some(limit) = executor.idle_timeout_ms {
+                if self.last_activity.elapsed() >= Duration::from_millis(limit) {
+                    platform::terminate_tree(&self.handle, executor.kill_grace()).ok();
+                    if executor.timeout_returns_result {
+                        self.timed_out = true;
+                        break;
+                    }
+                    return Err(executor.io_error(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        "process timed out waiting for output",
+                    )));
+                }
+            }
+
+            match self.receiver.recv_timeout(poll_interval) {
+                Ok(StreamMessage::Data(kind, chunk)) => {
+                    self.record_chunk(executor, kind, &chunk, capture);
+                    let keep_going = match mode {
+                        StreamMode::Capture => true,
+                        StreamMode::Chunks => dispatch_stream_chunk(
+                            kind,
+                            &chunk,
+                            script_context(context)?,
+                            stdout_cb,
+                            stderr_cb,
+                        )?,
+                        #[cfg(not(feature = "no_index"))]
+                        StreamMode::Bytes => dispatch_stream_bytes(
+                            kind,
+                            &chunk,
+                            script_context(context)?,
+                            stdout_cb,
+                            stderr_cb,
+                        )?,
+                        StreamMode::Lines => {
+                            let pending = match kind {
+                                StreamKind::Stdout => &mut self.stdout_pending,
+                                StreamKind::Stderr => &mut self.stderr_pending,
+                            };
+                            pending.extend_from_slice(&chunk);
+                            let mut keep_going = true;
+                            while let Some(pos) = pending.iter().position(|b| *b == b'\n') {
+                                let line: Vec<u8> = pending.drain(..=pos).collect();
+                                keep_going = dispatch_stream_line(
+                                    kind,
+                                    &line,
+                                    script_context(context)?,
+                                    stdout_cb,
+                                    stderr_cb,
+                                )?;
+                                if !keep_going {
+                                    break;
+                                }
+                            }
+                            keep_going
+                        }
+                    };
+                    if !keep_going {
+                        platform::kill_tree(&self.handle).ok();
+                        self.cancelled = true;
+                        break;
+                    }
+                }
+                Ok(StreamMessage::Eof(kind)) => {
+                    let pending = match kind {
+                        StreamKind::Stdout => {
+                            self.stdout_open = false;
+                            &mut self.stdout_pending
+                        }
+                        StreamKind::Stderr => {
+                            self.stderr_open = false;
+                            &mut self.stderr_pending
+                        }
+                    };
+                    if !pending.is_empty() {
+                        let line = std::mem::take(pending);
+                        let keep_going = dispatch_stream_line(
+                            kind,
+                            &line,
+                            script_context(context)?,
+                            stdout_cb,
+                            stderr_cb,
+                        )?;
+                        if !keep_going {
+                            platform::kill_tree(&self.handle).ok();
+                            self.cancelled = true;
+                            break;
+                        }
+                    }
+                }
+                Ok(StreamMessage::Error(err)) => {
+                    platform::kill_tree(&self.handle).ok();
+                    return Err(executor.io_error(err));
+                }
+                Err(RecvTimeoutError::Timeout) => continue,
+                Err(RecvTimeoutError::Disconnected) => break,
             }
-            Err(RecvTimeoutError::Timeout) => {
-                if !process_finished && handle.try_wait().map_err(map_io_err)?.is_some() {
-                    process_finished = true;
+        }
+
+        let status = match timeout_ms {
+            Some(limit) if !self.cancelled && !self.timed_out => {
+                let remaining = Duration::from_millis(limit).saturating_sub(self.start.elapsed());
+                match wait_with_timeout(
+                    &executor.config.workers,
+                    &self.handle,
+                    remaining,
+                    executor.kill_grace(),
+                ) {
+                    Err(err)
+                        if err.kind() == io::ErrorKind::TimedOut
+                            && executor.timeout_returns_result =>
+                    {
+                        self.timed_out = true;
+                        self.handle.wait()
+                    }
+                    status => status,
                 }
-                continue;
             }
-            Err(RecvTimeoutError::Disconnected) => break,
+            _ => self.handle.wait(),
+        }
+        .map_err(|err| executor.io_error(err))?;
+        let duration = self.start.elapsed();
+        if let Some(charge) = &self.charge {
+            charge.settle();
+        }
+        if self.was_cancelled(executor) {
+            return Err(cancelled_error(executor));
+        }
+        let (success, exit_code) = executor.exit_outcome(&status);
+        let success = success && !self.cancelled && !self.timed_out;
+        if !self.process_finished {
+            self.process_finished = true;
+            notify_exit(executor, context, success, exit_code, duration)?;
+        }
+
+        Ok(executor.result(RunOutcome {
+            status: &status,
+            pid: self.handle.pids().first().map_or(-1, |pid| i64::from(*pid)),
+            started_at: self.started_at,
+            duration,
+            stage_durations: self
+                .stage_exits
+                .iter()
+                .map(|exited| exited.get().copied())
+                .collect(),
+            stdout: &self.stdout_captured,
+            stderr: &self.stderr_captured,
+            combined: &self.combined_captured,
+            cancelled: self.cancelled,
+            timed_out: self.timed_out,
+        }))
+    }
+}
+
+/// What a finished run produced; see [`PipelineExecutor::result`].
+pub(crate) struct RunOutcome<'a> {
+    pub(crate) status: &'a PipelineStatus,
+    pub(crate) pid: i64,
+    pub(crate) started_at: SystemTime,
+    pub(crate) duration: Duration,
+    /// How long each stage ran, if it is known to have exited before the
+    /// whole pipeline did.
+    pub(crate) stage_durations: Vec<Option<Duration>>,
+    pub(crate) stdout: &'a [u8],
+    pub(crate) stderr: &'a [u8],
+    pub(crate) combined: &'a [u8],
+    pub(crate) cancelled: bool,
+    pub(crate) timed_out: bool,
+}
+
+impl PipelineExecutor {
+    /// The `ProcessResult` of a finished run, with secrets redacted from its
+    /// output.
+    pub(crate) fn result(&self, outcome: RunOutcome) -> ProcessResult {
+        let RunOutcome {
+            status, duration, ..
+        } = outcome;
+        let (success, exit_code) = self.exit_outcome(status);
+        let success = success && !outcome.cancelled && !outcome.timed_out;
+        let secrets = if self.config.redact_output {
+            let envs: Vec<_> = self.commands.iter().map(|command| &command.env).collect();
+            self.config.secret_values(&envs)
+        } else {
+            Vec::new()
+        };
+        let text = |bytes: &[u8]| redact(&String::from_utf8_lossy(bytes), &secrets);
+        // Only reported when it could be read for every stage.
+        let usage: Option<Vec<_>> = status.usage.iter().copied().collect();
+
+        ProcessResult {
+            command: describe_pipeline(&self.commands),
+            success,
+            status: exit_code,
+            pid: outcome.pid,
+            started_at: outcome.started_at,
+            finished_at: outcome.started_at + duration,
+            stdout: text(outcome.stdout),
+            stderr: text(outcome.stderr),
+            duration_ms: duration.as_millis().try_into().unwrap_or(u64::MAX),
+            cancelled: outcome.cancelled,
+            timed_out: outcome.timed_out,
+            combined: self.capture_combined.then(|| text(outcome.combined)),
+            signal: self
+                .decisive_status(status)
+                .and_then(platform::termination_signal),
+            max_rss_kb: usage
+                .as_ref()
+                .and_then(|usage| usage.iter().map(|stage| stage.max_rss_kb).max()),
+            cpu_time_ms: usage
+                .as_ref()
+                .map(|usage| usage.iter().map(|stage| stage.cpu_time_ms).sum()),
+            stage_statuses: status.stages.iter().map(status_code).collect(),
+            stages: self
+                .commands
+                .iter()
+                .zip(&status.stages)
+                .enumerate()
+                .map(|(index, (command, status))| {
+                    let ran_for = outcome
+                        .stage_durations
+                        .get(index)
+                        .copied()
+                        .flatten()
+                        .unwrap_or(duration);
+                    StageResult {
+                        program: command.program.clone(),
+                        args: command.args.clone(),
+                        status: status_code(status),
+                        signal: platform::termination_signal(status),
+                        duration_ms: ran_for.as_millis().try_into().unwrap_or(u64::MAX),
+                    }
+                })
+                .collect(),
+            steps: None,
         }
     }
+}
+
+pub(crate) fn cancelled_error(executor: &PipelineExecutor) -> Box<EvalAltResult> {
+    process_error(
+        ErrorKind::Cancelled,
+        Some(&describe_pipeline(&executor.commands)),
+        None,
+        "pipeline was cancelled by the host",
+    )
+}
+
+pub(crate) fn append_capped(buffer: &mut Vec<u8>, chunk: &[u8], limit: Option<usize>) {
+    let room = limit.map_or(chunk.len(), |limit| limit.saturating_sub(buffer.len()));
+    buffer.extend_from_slice(&chunk[..room.min(chunk.len())]);
+}
+
+/// The stages of `executor`, ready to start. `detached` stages are detached
+/// from the host, which gives them a session of their own instead of a
+/// process group.
+fn build_stages(executor: &PipelineExecutor, detached: bool) -> RhaiResult<Vec<Expression>> {
+    let setup = StageSetup::new(executor, detached)?;
+    Ok(executor
+        .commands
+        .iter()
+        .map(|command| {
+            let setup = setup.clone();
+            expression_from_spec(command, &executor.config, executor.cwd.as_ref())
+                .before_spawn(move |command| setup.apply(command))
+        })
+        .collect())
+}
 
-    let duration = start.elapsed();
-    let output = handle.wait().map_err(map_io_err)?;
-    let exit_code = output.status.code().map(|c| c as i64).unwrap_or(-1);
-    let mut success = output.status.success();
-    if !success {
-        if let Some(allowed) = allowed_exit_codes.as_ref() {
-            if allowed.contains(&exit_code) {
-                success = true;
+/// What is done to every stage's command right before it spawns: limits,
+/// confinement, process groups and creation flags.
+#[derive(Clone, Debug)]
+pub(crate) struct StageSetup {
+    limits: ResourceLimits,
+    root: Option<Arc<PathBuf>>,
+    uid: Option<u32>,
+    gid: Option<u32>,
+    isolated: bool,
+    new_session: bool,
+    detached: bool,
+    creation_flags: u32,
+}
+
+impl StageSetup {
+    /// Checks every stage of `executor` against the host's rules before any
+    /// of them is started.
+    pub(crate) fn new(executor: &PipelineExecutor, detached: bool) -> RhaiResult<Self> {
+        if executor.commands.is_empty() {
+            return Err(runtime_error("no command specified"));
+        }
+        // Every stage must pass the host filter before any of them is started.
+        for command in &executor.commands {
+            executor.config.ensure_request_allowed(&CommandRequest {
+                program: &command.program,
+                args: &command.args,
+                env: &command.env,
+                cwd: command
+                    .cwd
+                    .as_ref()
+                    .or(executor.cwd.as_ref())
+                    .map(PathBuf::as_path),
+            })?;
+        }
+        let mut limits = executor.resource_limits();
+        let root = executor.chroot.clone().map(Arc::new);
+        if root.is_some() {
+            let has_cwd = executor.cwd.is_some()
+                || executor
+                    .commands
+                    .iter()
+                    .any(|command| command.cwd.is_some());
+            if has_cwd {
+                return Err(runtime_error("cwd() cannot be combined with chroot()"));
             }
         }
+        // The user is switched after entering the new root instead.
+        let (uid, gid) = if root.is_some() {
+            (limits.uid.take(), limits.gid.take())
+        } else {
+            (None, None)
+        };
+        let mut creation_flags = executor.creation_flags;
+        if (executor.process_group || detached) && cfg!(windows) {
+            creation_flags |= platform::CREATE_NEW_PROCESS_GROUP;
+        }
+        if detached && cfg!(windows) {
+            creation_flags |= platform::DETACHED_PROCESS;
+        }
+        if let Some(nice) = limits.nice.filter(|_| cfg!(windows)) {
+            creation_flags |= platform::priority_class(nice);
+        }
+        // The stage is resumed once it is in its job.
+        if cfg!(windows) {
+            creation_flags |= platform::CREATE_SUSPENDED;
+        }
+        Ok(Self {
+            limits,
+            root,
+            uid,
+            gid,
+            // On Unix every stage leads a process group of its own, so killing
+            // the group reaches everything it started. A terminal or detaching
+            // gives every stage a session, and so a group, of its own already.
+            isolated: (cfg!(unix) || executor.isolated()) && !executor.pty && !detached,
+            new_session: executor.new_session,
+            detached,
+            creation_flags,
+        })
+    }
+
+    pub(crate) fn apply(&self, command: &mut Command) -> io::Result<()> {
+        if !self.limits.is_empty() {
+            platform::apply_limits(command, self.limits)?;
+        }
+        if let Some(root) = &self.root {
+            platform::confine(command, root, self.uid, self.gid)?;
+        }
+        if self.isolated {
+            platform::isolate(command, self.new_session)?;
+        }
+        if self.detached {
+            platform::detach(command);
+        }
+        if self.creation_flags != 0 {
+            platform::set_creation_flags(command, self.creation_flags);
+        }
+        Ok(())
+    }
+}
+
+/// The read ends of the pipeline's stdout and stderr and, with `pipe_stdin`,
+/// the write end of its stdin. Stderr is `None` when it shares stdout.
+type StageIo = (PipeReader, Option<PipeReader>, Option<PipeWriter>);
+
+/// Connects the pipeline's stdout and stderr, and with `pipe_stdin` its
+/// stdin, to fresh pipes.
+fn wire_pipes(stages: &mut [Expression], pipe_stdin: bool) -> RhaiResult<StageIo> {
+    let (stdout_reader, stdout_writer) = os_pipe::pipe().map_err(map_io_err)?;
+    let (stderr_reader, stderr_writer) = os_pipe::pipe().map_err(map_io_err)?;
+    for stage in stages.iter_mut() {
+        *stage = stage.stderr_file(stderr_writer.try_clone().map_err(map_io_err)?);
     }
+    drop(stderr_writer);
+    let mut stdin_reader = None;
+    let mut stdin = None;
+    if pipe_stdin {
+        let (reader, writer) = os_pipe::pipe().map_err(map_io_err)?;
+        stdin_reader = Some(reader);
+        stdin = Some(writer);
+    }
+    wire_ends(
+        stages,
+        |first| match stdin_reader {
+            Some(reader) => first.stdin_file(reader),
+            None => first.clone(),
+        },
+        |last| last.stdout_file(stdout_writer),
+    );
+    Ok((stdout_reader, Some(stderr_reader), stdin))
+}
 
-    Ok(ProcessResult {
-        success,
-        status: exit_code,
-        stdout: String::new(),
-        stderr: String::new(),
-        duration_ms: duration.as_millis().try_into().unwrap_or(u64::MAX),
-    })
+/// Connects the pipeline to a new pseudo-terminal: stdin of the first stage,
+/// stdout of the last and stderr of every stage. Its output all arrives as
+/// stdout.
+fn wire_terminal(stages: &mut [Expression], pipe_stdin: bool) -> RhaiResult<StageIo> {
+    let pty = platform::open_pty().map_err(map_io_err)?;
+    for stage in stages.iter_mut() {
+        *stage = stage
+            .stderr_file(pty.terminal.try_clone().map_err(map_io_err)?)
+            .before_spawn(platform::attach_terminal);
+    }
+    let stdin_terminal = pty.terminal.try_clone().map_err(map_io_err)?;
+    wire_ends(
+        stages,
+        |first| first.stdin_file(stdin_terminal),
+        |last| last.stdout_file(pty.terminal),
+    );
+    let stdin = pipe_stdin.then_some(pty.writer);
+    Ok((pty.reader, None, stdin))
 }
 
-fn build_expression(commands: &[CommandSpec], cwd: Option<&PathBuf>) -> RhaiResult<Expression> {
-    let mut iter = commands.iter();
-    let first = iter
-        .next()
-        .ok_or_else(|| runtime_error("no command specified"))?;
-    let mut expression = expression_from_spec(first, cwd);
-    for command in iter {
-        let next_expr = expression_from_spec(command, cwd);
-        expression = expression.pipe(next_expr);
+/// Redirects the pipeline's stdin (first stage) and stdout (last stage).
+fn wire_ends(
+    stages: &mut [Expression],
+    first: impl FnOnce(&Expression) -> Expression,
+    last: impl FnOnce(&Expression) -> Expression,
+) {
+    if let Some(stage) = stages.first_mut() {
+        *stage = first(stage);
+    }
+    if let Some(stage) = stages.last_mut() {
+        *stage = last(stage);
     }
-    Ok(expression)
 }
 
-fn expression_from_spec(spec: &CommandSpec, cwd: Option<&PathBuf>) -> Expression {
-    let mut expr = duct::cmd(spec.program.clone(), spec.args.clone());
-    if let Some(dir) = cwd {
+fn status_code(status: &ExitStatus) -> i64 {
+    status.code().map(|c| c as i64).unwrap_or(-1)
+}
+
+fn expression_from_spec(spec: &CommandSpec, config: &Config, cwd: Option<&PathBuf>) -> Expression {
+    let (program, args, raw) = stage_argv(spec, config);
+    let mut expr = if raw.is_empty() {
+        duct::cmd(program, args)
+    } else {
+        // Raw arguments keep their place among the quoted ones, so all of
+        // them are added together.
+        duct::cmd(program, Vec::<String>::new()).before_spawn(move |command| {
+            platform::add_args(command, &args, &raw);
+            Ok(())
+        })
+    };
+    // These must be the innermost redirections so they act on the pipe that
+    // `PipelineHandle::start` connects to this stage's stdout.
+    match spec.pipe_source {
+        PipeSource::Stdout => {}
+        PipeSource::Stderr => expr = expr.stdout_stderr_swap(),
+        PipeSource::Both => expr = expr.stderr_to_stdout(),
+    }
+    if let Some(dir) = spec.cwd.as_ref().or(cwd) {
         expr = expr.dir(dir.clone());
     }
+    match stage_env(spec, config) {
+        StageEnv::Full(env) => expr.full_env(env),
+        StageEnv::Changes(changes) => {
+            changes
+                .into_iter()
+                .fold(expr, |expr, (key, value)| match value {
+                    Some(value) => expr.env(key, value),
+                    None => expr.env_remove(key),
+                })
+        }
+    }
+}
+
+/// The command of a stage for `run_async()`, which spawns it through tokio.
+/// Its stdio and `StageSetup` are left to the caller.
+#[cfg(feature = "async")]
+pub(crate) fn command_from_spec(
+    spec: &CommandSpec,
+    config: &Config,
+    cwd: Option<&PathBuf>,
+) -> Command {
+    let (program, args, raw) = stage_argv(spec, config);
+    let mut command = Command::new(program);
+    platform::add_args(&mut command, &args, &raw);
+    if let Some(dir) = spec.cwd.as_ref().or(cwd) {
+        command.current_dir(dir);
+    }
+    match stage_env(spec, config) {
+        StageEnv::Full(env) => {
+            command.env_clear().envs(env);
+        }
+        StageEnv::Changes(changes) => {
+            for (key, value) in changes {
+                match value {
+                    Some(value) => command.env(key, value),
+                    None => command.env_remove(key),
+                };
+            }
+        }
+    }
+    command
+}
+
+/// The program a stage runs and its arguments, with the indices of those
+/// passed on verbatim on Windows.
+fn stage_argv(spec: &CommandSpec, config: &Config) -> (String, Vec<String>, BTreeSet<usize>) {
+    let mut program = config.program_to_spawn(&spec.program).into_owned();
+    let mut args = spec.args.clone();
+    let mut raw = spec.raw_args.clone();
+    if config.allow_windows_builtins && platform::is_cmd_builtin(&spec.program) {
+        args.splice(0..0, ["/C".to_string(), spec.program.clone()]);
+        raw = raw.into_iter().map(|index| index + 2).collect();
+        program = "cmd".into();
+    }
+    (program, args, raw)
+}
+
+/// The environment a stage starts with.
+enum StageEnv {
+    /// Replaces the inherited environment entirely.
+    Full(HashMap<OsString, OsString>),
+    /// Sets (`Some`) or unsets (`None`) variables of the inherited one.
+    Changes(BTreeMap<OsString, Option<OsString>>),
+}
+
+fn stage_env(spec: &CommandSpec, config: &Config) -> StageEnv {
+    // Later layers win: config defaults, then the script's removals and
+    // values, then the configured search path.
+    let mut overrides: BTreeMap<OsString, Option<OsString>> = BTreeMap::new();
+    for (key, value) in &config.default_env {
+        overrides.insert(key.into(), Some(value.into()));
+    }
+    for key in &spec.env_remove {
+        overrides.insert(key.into(), None);
+    }
     for (key, value) in &spec.env {
-        expr = expr.env(key, value);
+        overrides.insert(key.into(), Some(value.into()));
+    }
+    if let Some(path) = config.child_path() {
+        overrides.insert("PATH".into(), Some(path));
+    }
+
+    let inherited = if spec.clear_env {
+        Some(HashMap::new())
+    } else {
+        config.inherited_env()
+    };
+    let Some(mut env) = inherited else {
+        return StageEnv::Changes(overrides);
+    };
+    for (key, value) in overrides {
+        match value {
+            Some(value) => env.insert(key, value),
+            None => env.remove(&key),
+        };
     }
-    expr
+    StageEnv::Full(env)
 }
 
-fn run_with_timeout(expr: Expression, limit: Duration) -> io::Result<std::process::Output> {
-    let handle = Arc::new(expr.start()?);
-    drop(expr);
+/// The context stream callbacks are invoked in. Only pipelines run from a
+/// script stream, so it is always there.
+fn script_context<'a, 'b>(
+    context: Option<&'a NativeCallContext<'b>>,
+) -> RhaiResult<&'a NativeCallContext<'b>> {
+    context.ok_or_else(|| runtime_error("stream callbacks need a running script"))
+}
 
-    let wait_handle = Arc::clone(&handle);
-    let (tx, rx) = mpsc::channel();
-    thread::spawn(move || {
-        let result = wait_handle
-            .wait()
-            .map(|output| std::process::Output {
-                status: output.status,
-                stdout: output.stdout.clone(),
-                stderr: output.stderr.clone(),
+fn notify_spawn(
+    executor: &PipelineExecutor,
+    context: Option<&NativeCallContext>,
+    handle: &PipelineHandle,
+) -> RhaiResult<()> {
+    let (Some(callback), Some(context)) = (executor.on_spawn.as_ref(), context) else {
+        return Ok(());
+    };
+    for pid in handle.pids() {
+        if let Err(err) = callback.call_within_context::<Dynamic>(context, (pid as INT,)) {
+            platform::kill_tree(handle).ok();
+            return Err(err);
+        }
+    }
+    Ok(())
+}
+
+fn notify_exit(
+    executor: &PipelineExecutor,
+    context: Option<&NativeCallContext>,
+    success: bool,
+    exit_code: i64,
+    elapsed: Duration,
+) -> RhaiResult<()> {
+    let (Some(callback), Some(context)) = (executor.on_exit.as_ref(), context) else {
+        return Ok(());
+    };
+    let mut status = RhaiMap::new();
+    status.insert("success".into(), Dynamic::from_bool(success));
+    status.insert("status".into(), Dynamic::from_int(exit_code as INT));
+    let duration_int: INT = elapsed.as_millis().try_into().unwrap_or(INT::MAX);
+    status.insert("duration_ms".into(), Dynamic::from_int(duration_int));
+    let _ = callback.call_within_context::<Dynamic>(context, (status,))?;
+    Ok(())
+}
+
+/// Kills individual stages that outlive their own `timeout()`, leaving the
+/// rest of the pipeline running.
+fn watch_stage_timeouts(executor: &PipelineExecutor, handle: &PipelineHandle) {
+    for (index, command) in executor.commands.iter().enumerate() {
+        let (Some(ms), Some(stage)) = (command.timeout_ms, handle.stage(index)) else {
+            continue;
+        };
+        let stage = Arc::new(stage);
+        let grace = executor.kill_grace();
+        let workers = Arc::clone(&executor.config.workers);
+        executor.config.workers.execute(move || {
+            let _ = wait_with_timeout(&workers, &stage, Duration::from_millis(ms), grace);
+        });
+    }
+}
+
+/// Notes when each stage of a multi-stage pipeline exits, so results can
+/// report how long every stage ran. A single stage runs as long as the whole.
+fn watch_stage_exits(
+    workers: &Arc<WorkerPool>,
+    handle: &PipelineHandle,
+    start: Instant,
+) -> Vec<Arc<OnceLock<Duration>>> {
+    if handle.stage(1).is_none() {
+        return Vec::new();
+    }
+    (0..)
+        .map_while(|index| handle.stage(index))
+        .map(|stage| {
+            let exited = Arc::new(OnceLock::new());
+            let slot = Arc::clone(&exited);
+            workers.execute(move || {
+                if stage.wait().is_ok() {
+                    let _ = slot.set(start.elapsed());
+                }
             });
-        let _ = tx.send(result);
+            exited
+        })
+        .collect()
+}
+
+fn wait_with_timeout(
+    workers: &Arc<WorkerPool>,
+    handle: &Arc<PipelineHandle>,
+    limit: Duration,
+    grace: Option<Duration>,
+) -> io::Result<PipelineStatus> {
+    let wait_handle = Arc::clone(handle);
+    let (tx, rx) = mpsc::channel();
+    workers.execute(move || {
+        let _ = tx.send(wait_handle.wait());
     });
 
     match rx.recv_timeout(limit) {
         Ok(result) => result,
         Err(RecvTimeoutError::Timeout) => {
-            handle.kill()?;
+            platform::terminate_tree(handle, grace)?;
             Err(io::Error::new(
                 io::ErrorKind::TimedOut,
                 "process execution timed out",
             ))
         }
-        Err(RecvTimeoutError::Disconnected) => Err(io::Error::new(
-            io::ErrorKind::Other,
-            "process execution failed",
-        )),
+        Err(RecvTimeoutError::Disconnected) => Err(io::Error::other("process execution failed")),
     }
 }
 
 #[derive(Copy, Clone)]
-enum StreamKind {
+pub(crate) enum StreamMode {
+    Capture,
+    Chunks,
+    Lines,
+    #[cfg(not(feature = "no_index"))]
+    Bytes,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub(crate) enum StreamKind {
     Stdout,
     Stderr,
 }
 
+#[derive(Debug)]
 enum StreamMessage {
     Data(StreamKind, Vec<u8>),
     Eof(StreamKind),
     Error(io::Error),
 }
 
-fn spawn_stream_reader(reader: PipeReader, sender: Sender<StreamMessage>, kind: StreamKind) {
-    thread::spawn(move || {
+fn spawn_stream_reader(
+    workers: &Arc<WorkerPool>,
+    reader: PipeReader,
+    sender: Sender<StreamMessage>,
+    kind: StreamKind,
+    buffer_size: usize,
+) {
+    workers.execute(move || {
         let mut reader = reader;
-        let mut buffer = [0u8; 8 * 1024];
+        let mut buffer = vec![0u8; buffer_size];
         loop {
             match reader.read(&mut buffer) {
                 Ok(0) => {
@@ -331,7 +1805,11 @@ fn spawn_stream_reader(reader: PipeReader, sender: Sender<StreamMessage>, kind:
                         break;
                     }
                 }
-                Err(ref err) if err.kind() == ErrorKind::Interrupted => continue,
+                Err(ref err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(ref err) if platform::is_terminal_closed(err) => {
+                    let _ = sender.send(StreamMessage::Eof(kind));
+                    break;
+                }
                 Err(err) => {
                     let _ = sender.send(StreamMessage::Error(err));
                     break;
@@ -347,7 +1825,7 @@ fn dispatch_stream_chunk(
     context: &NativeCallContext,
     stdout_cb: Option<&FnPtr>,
     stderr_cb: Option<&FnPtr>,
-) -> RhaiResult<()> {
+) -> RhaiResult<bool> {
     let text = String::from_utf8_lossy(chunk).to_string();
     let value: ImmutableString = text.clone().into();
 
@@ -357,7 +1835,10 @@ fn dispatch_stream_chunk(
     };
 
     if let Some(callback) = target {
-        let _ = callback.call_within_context::<Dynamic>(context, (value,))?;
+        let outcome = callback.call_within_context::<Dynamic>(context, (value,))?;
+        if outcome.as_bool() == Ok(false) {
+            return Ok(false);
+        }
     } else {
         match kind {
             StreamKind::Stdout => {
@@ -371,5 +1852,68 @@ fn dispatch_stream_chunk(
         }
     }
 
-    Ok(())
+    Ok(true)
+}
+
+#[cfg(not(feature = "no_index"))]
+fn dispatch_stream_bytes(
+    kind: StreamKind,
+    chunk: &[u8],
+    context: &NativeCallContext,
+    stdout_cb: Option<&FnPtr>,
+    stderr_cb: Option<&FnPtr>,
+) -> RhaiResult<bool> {
+    let target = match kind {
+        StreamKind::Stdout => stdout_cb,
+        StreamKind::Stderr => stderr_cb,
+    };
+
+    if let Some(callback) = target {
+        let value = Dynamic::from_blob(chunk.to_vec());
+        let outcome = callback.call_within_context::<Dynamic>(context, (value,))?;
+        if outcome.as_bool() == Ok(false) {
+            return Ok(false);
+        }
+    } else {
+        match kind {
+            StreamKind::Stdout => {
+                let mut out = io::stdout();
+                let _ = out.write_all(chunk);
+                let _ = out.flush();
+            }
+            StreamKind::Stderr => {
+                let mut err = io::stderr();
+                let _ = err.write_all(chunk);
+                let _ = err.flush();
+            }
+        }
+    }
+
+    Ok(true)
+}
+
+fn dispatch_stream_line(
+    kind: StreamKind,
+    line: &[u8],
+    context: &NativeCallContext,
+    stdout_cb: Option<&FnPtr>,
+    stderr_cb: Option<&FnPtr>,
+) -> RhaiResult<bool> {
+    let target = match kind {
+        StreamKind::Stdout => stdout_cb,
+        StreamKind::Stderr => stderr_cb,
+    };
+
+    if target.is_none() {
+        return dispatch_stream_chunk(kind, line, context, stdout_cb, stderr_cb);
+    }
+
+    let mut trimmed = line;
+    if let Some(rest) = trimmed.strip_suffix(b"\n") {
+        trimmed = rest;
+        if let Some(rest) = trimmed.strip_suffix(b"\r") {
+            trimmed = rest;
+        }
+    }
+    dispatch_stream_chunk(kind, trimmed, context, stdout_cb, stderr_cb)
 }
@@ -0,0 +1,368 @@
+use crate::platform::{self, ResourceLimits, ResourceUsage};
+use duct::{Expression, Handle};
+use std::io;
+use std::process::ExitStatus;
+use std::sync::{Arc, OnceLock};
+#[cfg(unix)]
+use std::sync::{Condvar, Mutex, MutexGuard};
+#[cfg(unix)]
+use std::time::Duration;
+
+/// A pipeline started as one duct child per stage, connected through our own
+/// pipes. duct's `pipe` only reports a single status for the whole pipeline;
+/// keeping the stages apart makes every stage's exit status observable.
+#[derive(Debug)]
+pub(crate) struct PipelineHandle {
+    stages: Vec<Arc<Stage>>,
+    /// The script asked for process groups, so `signal()` goes to the group.
+    grouped: bool,
+}
+
+/// One started stage. `usage` is settled right before the child is reaped,
+/// the last moment its resource usage can be read; it stays `None` where
+/// that is not possible.
+#[derive(Debug)]
+struct Stage {
+    handle: Handle,
+    usage: OnceLock<Option<ResourceUsage>>,
+    /// Taken to reap the child and to signal it, so its pid and the process
+    /// group it leads are only signalled while they still belong to it.
+    #[cfg(unix)]
+    reaping: Mutex<Reaping>,
+    /// Notified when the last termination of the stage is done.
+    #[cfg(unix)]
+    terminated: Condvar,
+    /// Holds everything the stage starts, so killing it reaches descendants
+    /// that `taskkill /T` cannot find once their parent has exited.
+    #[cfg(windows)]
+    job: platform::Job,
+}
+
+#[cfg(unix)]
+#[derive(Debug, Default)]
+struct Reaping {
+    /// Set once duct may have reaped the child; its pid can be reused by
+    /// another process from then on.
+    reaped: bool,
+    /// Terminations in progress. The child is not reaped until they are
+    /// done, so they can still reach its process group after it exits.
+    terminating: usize,
+}
+
+impl Stage {
+    /// Records the usage if the child has exited (or, with `block`, once it
+    /// has). Returns false while it is still running.
+    fn settle(&self, block: bool) -> bool {
+        if self.usage.get().is_some() {
+            return true;
+        }
+        let usage = match self.handle.pids().first() {
+            Some(pid) => match platform::exited_usage(*pid, block) {
+                Ok(None) => return false,
+                Ok(usage) => usage,
+                Err(_) => None,
+            },
+            None => None,
+        };
+        let _ = self.usage.set(usage);
+        true
+    }
+
+    fn wait(&self) -> io::Result<ExitStatus> {
+        self.settle(true);
+        #[cfg(unix)]
+        let mut reaping = self.reapable();
+        let status = self.handle.wait().map(|output| output.status);
+        #[cfg(unix)]
+        {
+            reaping.reaped = true;
+        }
+        status
+    }
+
+    fn try_wait(&self) -> io::Result<Option<ExitStatus>> {
+        if !self.settle(false) {
+            return Ok(None);
+        }
+        #[cfg(unix)]
+        let mut reaping = self.lock();
+        #[cfg(unix)]
+        if reaping.terminating > 0 {
+            return Ok(None);
+        }
+        let status = self
+            .handle
+            .try_wait()
+            .map(|output| output.map(|output| output.status));
+        #[cfg(unix)]
+        {
+            reaping.reaped |= !matches!(status, Ok(None));
+        }
+        status
+    }
+
+    fn kill(&self) -> io::Result<()> {
+        // duct reaps the child as part of killing it; signal it ourselves
+        // first so its usage can still be read.
+        #[cfg(unix)]
+        if matches!(self.signal(libc::SIGKILL, false), Ok(true)) {
+            self.settle(true);
+        }
+        #[cfg(unix)]
+        let mut reaping = self.reapable();
+        let result = self.handle.kill();
+        #[cfg(unix)]
+        {
+            reaping.reaped = true;
+        }
+        result
+    }
+
+    /// Sends `signal` to the child, or with `group` to the process group it
+    /// leads. Returns `Ok(false)` without sending anything once the child
+    /// has been reaped.
+    #[cfg(unix)]
+    fn signal(&self, signal: libc::c_int, group: bool) -> io::Result<bool> {
+        let reaping = self.lock();
+        let Some(pid) = self.handle.pids().first().map(|pid| *pid as libc::pid_t) else {
+            return Ok(false);
+        };
+        if reaping.reaped {
+            return Ok(false);
+        }
+        // A negative pid addresses the whole process group.
+        let target = if group { -pid } else { pid };
+        // SAFETY: kill takes no pointers. The child cannot be reaped while
+        // the lock is held, so neither its pid nor the id of the group it
+        // leads can have been handed to another process.
+        if unsafe { libc::kill(target, signal) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(true)
+    }
+
+    #[cfg(unix)]
+    fn lock(&self) -> MutexGuard<'_, Reaping> {
+        self.reaping.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    /// The lock, once no termination needs the child unreaped any more.
+    #[cfg(unix)]
+    fn reapable(&self) -> MutexGuard<'_, Reaping> {
+        self.terminated
+            .wait_while(self.lock(), |reaping| reaping.terminating > 0)
+            .unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+/// Keeps a stage from being reaped while a termination is in progress.
+#[cfg(unix)]
+struct Terminating<'a>(&'a Stage);
+
+#[cfg(unix)]
+impl<'a> Terminating<'a> {
+    fn new(stage: &'a Stage) -> Self {
+        stage.lock().terminating += 1;
+        Self(stage)
+    }
+}
+
+#[cfg(unix)]
+impl Drop for Terminating<'_> {
+    fn drop(&mut self) {
+        self.0.lock().terminating -= 1;
+        self.0.terminated.notify_all();
+    }
+}
+
+/// Why `PipelineHandle::start` failed, and at which stage.
+#[derive(Debug)]
+pub(crate) struct StartError {
+    pub(crate) stage: usize,
+    pub(crate) error: io::Error,
+}
+
+/// Exit statuses of every stage, in pipeline order.
+#[derive(Clone, Debug)]
+pub(crate) struct PipelineStatus {
+    pub(crate) stages: Vec<ExitStatus>,
+    pub(crate) usage: Vec<Option<ResourceUsage>>,
+}
+
+impl PipelineStatus {
+    fn new(stages: Vec<ExitStatus>, handle: &PipelineHandle) -> Self {
+        let usage = handle
+            .stages
+            .iter()
+            .map(|stage| stage.usage.get().copied().flatten())
+            .collect();
+        Self { stages, usage }
+    }
+}
+
+impl PipelineHandle {
+    /// Starts `stages`, feeding each stage's stdout into the next one's stdin.
+    /// The caller wires the first stage's stdin, the last stage's stdout and
+    /// every stage's stderr. Stages already started are killed and reaped if
+    /// a later one fails to start. `grouped` tells whether the stages were
+    /// set up to lead process groups of their own. On Windows, `limits` are
+    /// enforced through the job object of each stage.
+    pub(crate) fn start(
+        stages: &[Expression],
+        grouped: bool,
+        limits: ResourceLimits,
+    ) -> Result<Self, StartError> {
+        let mut started = Self {
+            stages: Vec::new(),
+            grouped,
+        };
+        match started.start_stages(stages, limits) {
+            Ok(()) => Ok(started),
+            Err(err) => {
+                started.kill().ok();
+                started.wait().ok();
+                Err(err)
+            }
+        }
+    }
+
+    #[cfg_attr(not(windows), allow(unused_variables))]
+    fn start_stages(
+        &mut self,
+        stages: &[Expression],
+        limits: ResourceLimits,
+    ) -> Result<(), StartError> {
+        let mut next_stdin = None;
+        for (index, stage) in stages.iter().enumerate() {
+            let mut expression = stage.unchecked();
+            if let Some(reader) = next_stdin.take() {
+                expression = expression.stdin_file(reader);
+            }
+            let failed = |error| StartError {
+                stage: index,
+                error,
+            };
+            if index + 1 < stages.len() {
+                let (reader, writer) = os_pipe::pipe().map_err(failed)?;
+                expression = expression.stdout_file(writer);
+                next_stdin = Some(reader);
+            }
+            // Dropping `expression` afterwards closes our copies of the pipe ends.
+            let handle = expression.start().map_err(failed)?;
+            #[cfg(windows)]
+            let job = match platform::Job::for_suspended(&handle.pids(), limits) {
+                Ok(job) => job,
+                Err(err) => {
+                    // Still suspended, so it has not run anything yet.
+                    let _ = handle.kill();
+                    return Err(failed(err));
+                }
+            };
+            self.stages.push(Arc::new(Stage {
+                handle,
+                usage: OnceLock::new(),
+                #[cfg(unix)]
+                reaping: Mutex::default(),
+                #[cfg(unix)]
+                terminated: Condvar::new(),
+                #[cfg(windows)]
+                job,
+            }));
+        }
+        Ok(())
+    }
+
+    /// A handle controlling only the stage at `index`.
+    pub(crate) fn stage(&self, index: usize) -> Option<PipelineHandle> {
+        let stage = self.stages.get(index)?;
+        Some(Self {
+            stages: vec![Arc::clone(stage)],
+            grouped: self.grouped,
+        })
+    }
+
+    /// Sends `signal` to every stage that has not been reaped yet, or to the
+    /// process groups they lead if the script asked for groups. Stages that
+    /// were reaped are skipped, as their pids may belong to other processes
+    /// by now.
+    #[cfg(unix)]
+    pub(crate) fn signal(&self, signal: libc::c_int) -> io::Result<()> {
+        let mut sent = false;
+        for stage in &self.stages {
+            sent |= stage.signal(signal, self.grouped)?;
+        }
+        if !sent {
+            return Err(io::Error::other("the process has already exited"));
+        }
+        Ok(())
+    }
+
+    pub(crate) fn pids(&self) -> Vec<u32> {
+        self.stages
+            .iter()
+            .flat_map(|stage| stage.handle.pids())
+            .collect()
+    }
+
+    pub(crate) fn try_wait(&self) -> io::Result<Option<PipelineStatus>> {
+        let mut statuses = Vec::with_capacity(self.stages.len());
+        for stage in &self.stages {
+            match stage.try_wait()? {
+                Some(status) => statuses.push(status),
+                None => return Ok(None),
+            }
+        }
+        Ok(Some(PipelineStatus::new(statuses, self)))
+    }
+
+    pub(crate) fn wait(&self) -> io::Result<PipelineStatus> {
+        // Reap every stage before reporting the first error.
+        let results: Vec<_> = self.stages.iter().map(|stage| stage.wait()).collect();
+        let stages = results.into_iter().collect::<io::Result<_>>()?;
+        Ok(PipelineStatus::new(stages, self))
+    }
+
+    /// Terminates every process in the stages' job objects.
+    #[cfg(windows)]
+    pub(crate) fn terminate_jobs(&self) {
+        for stage in &self.stages {
+            let _ = stage.job.terminate();
+        }
+    }
+
+    pub(crate) fn kill(&self) -> io::Result<()> {
+        let results: Vec<_> = self.stages.iter().map(|stage| stage.kill()).collect();
+        results.into_iter().collect()
+    }
+
+    /// Whether every stage has exited. On Unix this does not reap them.
+    pub(crate) fn has_exited(&self) -> bool {
+        #[cfg(unix)]
+        return self.stages.iter().all(|stage| stage.settle(false));
+        #[cfg(not(unix))]
+        return !matches!(self.try_wait(), Ok(None));
+    }
+
+    /// Kills the process group every stage leads, and with it everything the
+    /// stage started, including descendants reparented after their parent
+    /// exited. With `grace`, the groups get SIGTERM first and the SIGKILL
+    /// only once the grace period is over. No stage is reaped in between,
+    /// so the group ids cannot be reused by then.
+    #[cfg(unix)]
+    pub(crate) fn kill_groups(&self, grace: Option<Duration>) {
+        let _terminating: Vec<_> = self
+            .stages
+            .iter()
+            .map(|stage| Terminating::new(stage))
+            .collect();
+        if let Some(grace) = grace {
+            for stage in &self.stages {
+                let _ = stage.signal(libc::SIGTERM, true);
+            }
+            platform::wait_for_exit(self, grace);
+        }
+        for stage in &self.stages {
+            let _ = stage.signal(libc::SIGKILL, true);
+        }
+    }
+}
@@ -0,0 +1,1005 @@
+use crate::pipeline_handle::PipelineHandle;
+use std::collections::BTreeSet;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Detaches the child from the host by starting a new session. Windows
+/// detaches through [`DETACHED_PROCESS`] instead.
+#[cfg(unix)]
+pub(crate) fn detach(command: &mut Command) {
+    use std::os::unix::process::CommandExt;
+
+    unsafe {
+        command.pre_exec(|| {
+            if libc::setsid() == -1 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+}
+
+#[cfg(not(unix))]
+pub(crate) fn detach(_command: &mut Command) {}
+
+/// Starts the child without the host's console.
+pub(crate) const DETACHED_PROCESS: u32 = 0x0000_0008;
+
+/// Makes the child lead a process group of its own, or with `new_session`
+/// a session of its own, so signals meant for it reach everything it starts
+/// and a Ctrl-C at the host's terminal does not reach it.
+#[cfg(unix)]
+pub(crate) fn isolate(command: &mut Command, new_session: bool) -> io::Result<()> {
+    use std::os::unix::process::CommandExt;
+
+    if new_session {
+        detach(command);
+    } else {
+        command.process_group(0);
+    }
+    Ok(())
+}
+
+/// Windows process groups are requested through `CREATE_NEW_PROCESS_GROUP`.
+#[cfg(not(unix))]
+pub(crate) fn isolate(_command: &mut Command, new_session: bool) -> io::Result<()> {
+    if new_session {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "new sessions are only supported on Unix",
+        ));
+    }
+    Ok(())
+}
+
+/// The two ends of a pseudo-terminal: the host reads and writes the master
+/// side, the child gets the slave side as its terminal.
+pub(crate) struct Pty {
+    pub(crate) reader: os_pipe::PipeReader,
+    pub(crate) writer: os_pipe::PipeWriter,
+    pub(crate) terminal: std::fs::File,
+}
+
+/// Opens a 80x24 pseudo-terminal. Both ends are close-on-exec; the child
+/// only sees the slave side through its stdio.
+#[cfg(unix)]
+pub(crate) fn open_pty() -> io::Result<Pty> {
+    use std::os::unix::io::{FromRawFd, OwnedFd};
+
+    let mut master: libc::c_int = -1;
+    let mut slave: libc::c_int = -1;
+    let size = libc::winsize {
+        ws_row: 24,
+        ws_col: 80,
+        ws_xpixel: 0,
+        ws_ypixel: 0,
+    };
+    let opened = unsafe {
+        libc::openpty(
+            &mut master,
+            &mut slave,
+            std::ptr::null_mut(),
+            std::ptr::null(),
+            &size,
+        )
+    };
+    if opened == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    let (master, slave) = unsafe { (OwnedFd::from_raw_fd(master), OwnedFd::from_raw_fd(slave)) };
+    for fd in [&master, &slave] {
+        use std::os::unix::io::AsRawFd;
+        if unsafe { libc::fcntl(fd.as_raw_fd(), libc::F_SETFD, libc::FD_CLOEXEC) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    let writer = master.try_clone()?;
+    Ok(Pty {
+        reader: master.into(),
+        writer: writer.into(),
+        terminal: slave.into(),
+    })
+}
+
+#[cfg(not(unix))]
+pub(crate) fn open_pty() -> io::Result<Pty> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "pseudo-terminals are only supported on Unix",
+    ))
+}
+
+/// Gives the child a session of its own with its stderr, the slave side of
+/// a pseudo-terminal, as the controlling terminal. Only the first stage of a
+/// pipeline can claim the terminal; later stages keep just the session.
+#[cfg(unix)]
+pub(crate) fn attach_terminal(command: &mut Command) -> io::Result<()> {
+    use std::os::unix::process::CommandExt;
+
+    unsafe {
+        command.pre_exec(|| {
+            if libc::setsid() == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            libc::ioctl(libc::STDERR_FILENO, libc::TIOCSCTTY as _, 0);
+            Ok(())
+        });
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub(crate) fn attach_terminal(_command: &mut Command) -> io::Result<()> {
+    Ok(())
+}
+
+/// Reading the master side of a pseudo-terminal fails with `EIO` once every
+/// process holding the slave side has exited, where a pipe reports EOF.
+#[cfg(unix)]
+pub(crate) fn is_terminal_closed(err: &io::Error) -> bool {
+    err.raw_os_error() == Some(libc::EIO)
+}
+
+#[cfg(not(unix))]
+pub(crate) fn is_terminal_closed(_err: &io::Error) -> bool {
+    false
+}
+
+/// Starts the child in a new process group, so console Ctrl-C events for
+/// the host do not reach it.
+pub(crate) const CREATE_NEW_PROCESS_GROUP: u32 = 0x0000_0200;
+
+/// Adds `args` to `command`, passing those whose index is in `raw` on
+/// verbatim instead of applying the MSVC quoting rules.
+#[cfg(windows)]
+pub(crate) fn add_args(command: &mut Command, args: &[String], raw: &BTreeSet<usize>) {
+    use std::os::windows::process::CommandExt;
+
+    for (index, arg) in args.iter().enumerate() {
+        if raw.contains(&index) {
+            command.raw_arg(arg);
+        } else {
+            command.arg(arg);
+        }
+    }
+}
+
+/// Unix hands every argument to the program verbatim already.
+#[cfg(not(windows))]
+pub(crate) fn add_args(command: &mut Command, args: &[String], _raw: &BTreeSet<usize>) {
+    command.args(args);
+}
+
+/// Commands built into `cmd.exe`, which cannot be started on their own.
+const CMD_BUILTINS: &[&str] = &[
+    "assoc", "break", "call", "cd", "chdir", "cls", "color", "copy", "date", "del", "dir", "echo",
+    "erase", "ftype", "md", "mkdir", "mklink", "move", "path", "pause", "popd", "prompt", "pushd",
+    "rd", "rem", "ren", "rename", "rmdir", "set", "start", "time", "title", "type", "ver",
+    "verify", "vol",
+];
+
+/// Whether `program` names a `cmd.exe` builtin on this platform. Always
+/// false outside Windows, where the names are ordinary programs.
+pub(crate) fn is_cmd_builtin(program: &str) -> bool {
+    cfg!(windows)
+        && CMD_BUILTINS
+            .iter()
+            .any(|builtin| builtin.eq_ignore_ascii_case(program))
+}
+
+/// Starts the child with its main thread suspended, so it can be put into a
+/// job before it runs.
+pub(crate) const CREATE_SUSPENDED: u32 = 0x0000_0004;
+
+/// The Windows priority class closest to niceness `nice`, as a creation
+/// flag.
+pub(crate) fn priority_class(nice: i32) -> u32 {
+    const HIGH_PRIORITY_CLASS: u32 = 0x0000_0080;
+    const ABOVE_NORMAL_PRIORITY_CLASS: u32 = 0x0000_8000;
+    const NORMAL_PRIORITY_CLASS: u32 = 0x0000_0020;
+    const BELOW_NORMAL_PRIORITY_CLASS: u32 = 0x0000_4000;
+    const IDLE_PRIORITY_CLASS: u32 = 0x0000_0040;
+    match nice {
+        ..=-11 => HIGH_PRIORITY_CLASS,
+        -10..=-1 => ABOVE_NORMAL_PRIORITY_CLASS,
+        0 => NORMAL_PRIORITY_CLASS,
+        1..=10 => BELOW_NORMAL_PRIORITY_CLASS,
+        _ => IDLE_PRIORITY_CLASS,
+    }
+}
+
+/// Keeps a console program from opening a console window of its own.
+pub(crate) const CREATE_NO_WINDOW: u32 = 0x0800_0000;
+
+/// Applies Windows process creation flags. Each call replaces the flags set
+/// before, so all of them are combined into one call. Other platforms have
+/// no equivalent, so the flags are ignored there.
+#[cfg(windows)]
+pub(crate) fn set_creation_flags(command: &mut Command, flags: u32) {
+    use std::os::windows::process::CommandExt;
+
+    command.creation_flags(flags);
+}
+
+#[cfg(not(windows))]
+pub(crate) fn set_creation_flags(_command: &mut Command, _flags: u32) {}
+
+/// Resource caps and scheduling settings applied to every stage of a
+/// pipeline before it executes.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) struct ResourceLimits {
+    pub(crate) memory_mb: Option<u64>,
+    pub(crate) cpu_seconds: Option<u64>,
+    pub(crate) open_files: Option<u64>,
+    pub(crate) processes: Option<u64>,
+    pub(crate) nice: Option<i32>,
+    pub(crate) cpus: Option<CpuSet>,
+    pub(crate) io_priority: Option<IoPriority>,
+    pub(crate) uid: Option<u32>,
+    pub(crate) gid: Option<u32>,
+    pub(crate) umask: Option<u32>,
+}
+
+impl ResourceLimits {
+    pub(crate) fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+/// An I/O scheduling class with its level, as set by `ionice`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum IoPriority {
+    /// Only gets disk time when no other process wants it.
+    Idle,
+    /// The default class; level 0 is served first and 7 last.
+    BestEffort(u8),
+}
+
+impl IoPriority {
+    /// The value `ioprio_set` expects: the class in the top bits.
+    #[cfg(target_os = "linux")]
+    fn encode(self) -> libc::c_int {
+        const CLASS_SHIFT: libc::c_int = 13;
+        match self {
+            Self::Idle => 3 << CLASS_SHIFT,
+            Self::BestEffort(level) => (2 << CLASS_SHIFT) | libc::c_int::from(level),
+        }
+    }
+}
+
+/// The CPUs a child may run on, as a bitmask the size of Linux's `cpu_set_t`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct CpuSet([u64; 16]);
+
+impl CpuSet {
+    pub(crate) const CAPACITY: usize = 1024;
+
+    /// `None` if `cpus` is empty or names a CPU at or above `CAPACITY`.
+    pub(crate) fn new(cpus: impl IntoIterator<Item = usize>) -> Option<Self> {
+        let mut bits = [0u64; 16];
+        for cpu in cpus {
+            if cpu >= Self::CAPACITY {
+                return None;
+            }
+            bits[cpu / 64] |= 1 << (cpu % 64);
+        }
+        (bits != [0; 16]).then_some(Self(bits))
+    }
+
+    #[cfg(target_os = "linux")]
+    fn cpus(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..Self::CAPACITY).filter(|cpu| self.0[cpu / 64] & (1 << (cpu % 64)) != 0)
+    }
+
+    /// The set as a Windows affinity mask, or `None` if it names a CPU at
+    /// or above 64, which a mask cannot address.
+    #[cfg(windows)]
+    fn mask(&self) -> Option<usize> {
+        let beyond = self.0[1..].iter().any(|bits| *bits != 0);
+        let mask = usize::try_from(self.0[0]).ok()?;
+        (!beyond).then_some(mask)
+    }
+}
+
+/// Sets the soft and hard limit of `$resource` (both to the same value when
+/// only one is given). The resource constants differ in type between libc
+/// targets, hence a macro.
+#[cfg(unix)]
+macro_rules! set_rlimit {
+    ($resource:expr, $value:expr) => {
+        set_rlimit!($resource, $value, $value)
+    };
+    ($resource:expr, $soft:expr, $hard:expr) => {{
+        let limit = libc::rlimit {
+            rlim_cur: libc::rlim_t::try_from($soft).unwrap_or(libc::RLIM_INFINITY),
+            rlim_max: libc::rlim_t::try_from($hard).unwrap_or(libc::RLIM_INFINITY),
+        };
+        if libc::setrlimit($resource, &limit) == -1 {
+            return Err(io::Error::last_os_error());
+        }
+    }};
+}
+
+/// Installs `limits` so they take effect in the child right before `exec`.
+#[cfg(unix)]
+pub(crate) fn apply_limits(command: &mut Command, limits: ResourceLimits) -> io::Result<()> {
+    use std::os::unix::process::CommandExt;
+
+    if cfg!(not(target_os = "linux")) && limits.cpus.is_some() {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "cpu affinity is only supported on Linux",
+        ));
+    }
+    if cfg!(not(target_os = "linux")) && limits.io_priority.is_some() {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "io priority is only supported on Linux",
+        ));
+    }
+    // The user is switched at the end of the hook rather than through std,
+    // which would switch it first: raising the priority or the I/O class
+    // needs the privileges the host still has until then.
+    unsafe {
+        command.pre_exec(move || {
+            if let Some(megabytes) = limits.memory_mb {
+                set_rlimit!(libc::RLIMIT_AS, megabytes.saturating_mul(1024 * 1024));
+            }
+            if let Some(seconds) = limits.cpu_seconds {
+                // SIGXCPU at the soft limit lets the child clean up; the
+                // hard limit a second later sends SIGKILL.
+                set_rlimit!(libc::RLIMIT_CPU, seconds, seconds.saturating_add(1));
+            }
+            if let Some(files) = limits.open_files {
+                set_rlimit!(libc::RLIMIT_NOFILE, files);
+            }
+            if let Some(processes) = limits.processes {
+                set_rlimit!(libc::RLIMIT_NPROC, processes);
+            }
+            if let Some(mask) = limits.umask {
+                libc::umask(mask as libc::mode_t);
+            }
+            if let Some(nice) = limits.nice {
+                if libc::setpriority(libc::PRIO_PROCESS, 0, nice) == -1 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+            #[cfg(target_os = "linux")]
+            if let Some(cpus) = limits.cpus {
+                let mut set: libc::cpu_set_t = std::mem::zeroed();
+                for cpu in cpus.cpus() {
+                    libc::CPU_SET(cpu, &mut set);
+                }
+                if libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set) == -1 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+            #[cfg(target_os = "linux")]
+            if let Some(priority) = limits.io_priority {
+                const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+                let result = libc::syscall(
+                    libc::SYS_ioprio_set,
+                    IOPRIO_WHO_PROCESS,
+                    0,
+                    priority.encode(),
+                );
+                if result == -1 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+            switch_user(limits.uid, limits.gid)
+        });
+    }
+    Ok(())
+}
+
+/// Switches the calling process to `uid` and `gid`, dropping supplementary
+/// groups if root changes the user. Only called in the child before `exec`.
+#[cfg(unix)]
+unsafe fn switch_user(uid: Option<u32>, gid: Option<u32>) -> io::Result<()> {
+    if uid.is_some() && libc::getuid() == 0 && libc::setgroups(0, std::ptr::null()) == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    if let Some(gid) = gid {
+        if libc::setgid(gid) == -1 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    if let Some(uid) = uid {
+        if libc::setuid(uid) == -1 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// Windows enforces the limits through the job object of each stage, see
+/// [`Job::for_suspended`]; this only rejects those a job cannot enforce.
+#[cfg(windows)]
+pub(crate) fn apply_limits(_command: &mut Command, limits: ResourceLimits) -> io::Result<()> {
+    let unsupported = ResourceLimits {
+        memory_mb: None,
+        cpu_seconds: None,
+        nice: None,
+        cpus: None,
+        ..limits
+    };
+    if !unsupported.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "only memory, cpu time, priority and cpu affinity limits are supported on Windows",
+        ));
+    }
+    if limits.cpus.is_some_and(|cpus| cpus.mask().is_none()) {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "cpu affinity on Windows only covers the first 64 CPUs",
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(not(any(unix, windows)))]
+pub(crate) fn apply_limits(_command: &mut Command, _limits: ResourceLimits) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "resource limits are only supported on Unix and Windows",
+    ))
+}
+
+/// Confines the child to the directory tree under `root`, starting it in
+/// that tree's `/`. Switching to `uid` and `gid` has to happen afterwards,
+/// as an unprivileged process can no longer call `chroot`.
+#[cfg(unix)]
+pub(crate) fn confine(
+    command: &mut Command,
+    root: &Path,
+    uid: Option<u32>,
+    gid: Option<u32>,
+) -> io::Result<()> {
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::process::CommandExt;
+
+    let root = std::ffi::CString::new(root.as_os_str().as_bytes())
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+    unsafe {
+        command.pre_exec(move || {
+            if libc::chroot(root.as_ptr()) == -1 || libc::chdir(c"/".as_ptr()) == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            switch_user(uid, gid)
+        });
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub(crate) fn confine(
+    _command: &mut Command,
+    _root: &Path,
+    _uid: Option<u32>,
+    _gid: Option<u32>,
+) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "chroot is only supported on Unix",
+    ))
+}
+
+/// The uid and primary gid of the user `name`, or `None` if there is none.
+#[cfg(unix)]
+pub(crate) fn lookup_user(name: &str) -> io::Result<Option<(u32, u32)>> {
+    let name = std::ffi::CString::new(name)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+    let mut buffer: Vec<libc::c_char> = vec![0; 1024];
+    loop {
+        let mut entry: libc::passwd = unsafe { std::mem::zeroed() };
+        let mut found = std::ptr::null_mut();
+        let result = unsafe {
+            libc::getpwnam_r(
+                name.as_ptr(),
+                &mut entry,
+                buffer.as_mut_ptr(),
+                buffer.len(),
+                &mut found,
+            )
+        };
+        match result {
+            0 if found.is_null() => return Ok(None),
+            0 => return Ok(Some((entry.pw_uid, entry.pw_gid))),
+            libc::ERANGE => buffer.resize(buffer.len() * 2, 0),
+            code => return Err(io::Error::from_raw_os_error(code)),
+        }
+    }
+}
+
+#[cfg(not(unix))]
+pub(crate) fn lookup_user(_name: &str) -> io::Result<Option<(u32, u32)>> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "changing the user is only supported on Unix",
+    ))
+}
+
+/// Kills every process of a pipeline together with all of its descendants,
+/// so grandchildren started through e.g. `sh -c` do not outlive a timeout.
+pub(crate) fn kill_tree(handle: &PipelineHandle) -> io::Result<()> {
+    terminate_tree(handle, None)
+}
+
+/// Like `kill_tree`, but when a grace period is given the processes are first
+/// asked to exit (SIGTERM on Unix, a non-forced `taskkill` on Windows) and
+/// only killed once the grace period has elapsed.
+#[cfg(unix)]
+pub(crate) fn terminate_tree(handle: &PipelineHandle, grace: Option<Duration>) -> io::Result<()> {
+    // Every stage leads a process group of its own, which holds its
+    // descendants unless they moved to a group of their own.
+    handle.kill_groups(grace);
+    handle.kill()
+}
+
+#[cfg(windows)]
+pub(crate) fn terminate_tree(handle: &PipelineHandle, grace: Option<Duration>) -> io::Result<()> {
+    if let Some(grace) = grace {
+        taskkill(handle, false);
+        wait_for_exit(handle, grace);
+    }
+    taskkill(handle, true);
+    handle.terminate_jobs();
+    handle.kill()
+}
+
+/// A Windows job object, which every process started by a member process
+/// joins as well.
+#[cfg(windows)]
+#[derive(Debug)]
+pub(crate) struct Job(job_ffi::Handle);
+
+// A job handle may be used from any thread.
+#[cfg(windows)]
+unsafe impl Send for Job {}
+#[cfg(windows)]
+unsafe impl Sync for Job {}
+
+#[cfg(windows)]
+impl Job {
+    /// Creates a job holding the processes `pids`, which were started with
+    /// [`CREATE_SUSPENDED`], and resumes them once they are in it. Nothing
+    /// they start can escape the job, or its `limits`, that way.
+    pub(crate) fn for_suspended(pids: &[u32], limits: ResourceLimits) -> io::Result<Self> {
+        let handle = unsafe { job_ffi::CreateJobObjectW(std::ptr::null_mut(), std::ptr::null()) };
+        if handle.is_null() {
+            return Err(io::Error::last_os_error());
+        }
+        let job = Self(handle);
+        job.limit(limits)?;
+        for pid in pids {
+            job.assign(*pid)?;
+        }
+        for pid in pids {
+            resume(*pid)?;
+        }
+        Ok(job)
+    }
+
+    /// Applies `limits` to every process in the job.
+    fn limit(&self, limits: ResourceLimits) -> io::Result<()> {
+        let mut info = job_ffi::ExtendedLimitInformation::default();
+        if let Some(megabytes) = limits.memory_mb {
+            info.basic.limit_flags |= job_ffi::JOB_OBJECT_LIMIT_PROCESS_MEMORY;
+            info.process_memory_limit =
+                usize::try_from(megabytes.saturating_mul(1024 * 1024)).unwrap_or(usize::MAX);
+        }
+        if let Some(seconds) = limits.cpu_seconds {
+            // Counted in 100ns units of user-mode time; Windows terminates a
+            // process once it is used up.
+            info.basic.limit_flags |= job_ffi::JOB_OBJECT_LIMIT_PROCESS_TIME;
+            info.basic.per_process_user_time_limit =
+                i64::try_from(seconds.saturating_mul(10_000_000)).unwrap_or(i64::MAX);
+        }
+        if let Some(mask) = limits.cpus.and_then(|cpus| cpus.mask()) {
+            info.basic.limit_flags |= job_ffi::JOB_OBJECT_LIMIT_AFFINITY;
+            info.basic.affinity = mask;
+        }
+        if info.basic.limit_flags == 0 {
+            return Ok(());
+        }
+        let set = unsafe {
+            job_ffi::SetInformationJobObject(
+                self.0,
+                job_ffi::JOB_OBJECT_EXTENDED_LIMIT_INFORMATION,
+                (&info as *const job_ffi::ExtendedLimitInformation).cast(),
+                std::mem::size_of::<job_ffi::ExtendedLimitInformation>() as u32,
+            )
+        };
+        if set == 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    fn assign(&self, pid: u32) -> io::Result<()> {
+        unsafe {
+            let process = job_ffi::OpenProcess(
+                job_ffi::PROCESS_SET_QUOTA | job_ffi::PROCESS_TERMINATE,
+                0,
+                pid,
+            );
+            if process.is_null() {
+                return Err(io::Error::last_os_error());
+            }
+            let assigned = job_ffi::AssignProcessToJobObject(self.0, process);
+            let err = io::Error::last_os_error();
+            job_ffi::CloseHandle(process);
+            if assigned == 0 {
+                return Err(err);
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn terminate(&self) -> io::Result<()> {
+        if unsafe { job_ffi::TerminateJobObject(self.0, 1) } == 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+/// Resumes every thread of the process `pid`.
+#[cfg(windows)]
+fn resume(pid: u32) -> io::Result<()> {
+    unsafe {
+        let snapshot = job_ffi::CreateToolhelp32Snapshot(job_ffi::TH32CS_SNAPTHREAD, 0);
+        if snapshot == job_ffi::INVALID_HANDLE_VALUE {
+            return Err(io::Error::last_os_error());
+        }
+        let mut entry = job_ffi::ThreadEntry32 {
+            size: std::mem::size_of::<job_ffi::ThreadEntry32>() as u32,
+            ..Default::default()
+        };
+        let mut result = Ok(());
+        let mut more = job_ffi::Thread32First(snapshot, &mut entry) != 0;
+        while more {
+            if entry.owner_process_id == pid {
+                let thread =
+                    job_ffi::OpenThread(job_ffi::THREAD_SUSPEND_RESUME, 0, entry.thread_id);
+                if thread.is_null() || job_ffi::ResumeThread(thread) == u32::MAX {
+                    result = Err(io::Error::last_os_error());
+                }
+                if !thread.is_null() {
+                    job_ffi::CloseHandle(thread);
+                }
+                if result.is_err() {
+                    break;
+                }
+            }
+            more = job_ffi::Thread32Next(snapshot, &mut entry) != 0;
+        }
+        job_ffi::CloseHandle(snapshot);
+        result
+    }
+}
+
+#[cfg(windows)]
+impl Drop for Job {
+    fn drop(&mut self) {
+        unsafe {
+            job_ffi::CloseHandle(self.0);
+        }
+    }
+}
+
+#[cfg(windows)]
+#[allow(non_snake_case)]
+mod job_ffi {
+    use std::ffi::c_void;
+
+    pub(super) type Handle = *mut c_void;
+
+    pub(super) const PROCESS_TERMINATE: u32 = 0x0001;
+    pub(super) const PROCESS_SET_QUOTA: u32 = 0x0100;
+    pub(super) const THREAD_SUSPEND_RESUME: u32 = 0x0002;
+    pub(super) const TH32CS_SNAPTHREAD: u32 = 0x0000_0004;
+    pub(super) const INVALID_HANDLE_VALUE: Handle = -1isize as Handle;
+    pub(super) const JOB_OBJECT_EXTENDED_LIMIT_INFORMATION: i32 = 9;
+    pub(super) const JOB_OBJECT_LIMIT_PROCESS_TIME: u32 = 0x0000_0002;
+    pub(super) const JOB_OBJECT_LIMIT_AFFINITY: u32 = 0x0000_0010;
+    pub(super) const JOB_OBJECT_LIMIT_PROCESS_MEMORY: u32 = 0x0000_0100;
+
+    #[repr(C)]
+    #[derive(Default)]
+    pub(super) struct BasicLimitInformation {
+        pub(super) per_process_user_time_limit: i64,
+        pub(super) per_job_user_time_limit: i64,
+        pub(super) limit_flags: u32,
+        pub(super) minimum_working_set_size: usize,
+        pub(super) maximum_working_set_size: usize,
+        pub(super) active_process_limit: u32,
+        pub(super) affinity: usize,
+        pub(super) priority_class: u32,
+        pub(super) scheduling_class: u32,
+    }
+
+    #[repr(C)]
+    #[derive(Default)]
+    pub(super) struct ExtendedLimitInformation {
+        pub(super) basic: BasicLimitInformation,
+        pub(super) io_counters: [u64; 6],
+        pub(super) process_memory_limit: usize,
+        pub(super) job_memory_limit: usize,
+        pub(super) peak_process_memory_used: usize,
+        pub(super) peak_job_memory_used: usize,
+    }
+
+    #[repr(C)]
+    #[derive(Default)]
+    pub(super) struct ThreadEntry32 {
+        pub(super) size: u32,
+        pub(super) usage: u32,
+        pub(super) thread_id: u32,
+        pub(super) owner_process_id: u32,
+        pub(super) base_priority: i32,
+        pub(super) delta_priority: i32,
+        pub(super) flags: u32,
+    }
+
+    #[link(name = "kernel32")]
+    extern "system" {
+        pub(super) fn CreateJobObjectW(attributes: *mut c_void, name: *const u16) -> Handle;
+        pub(super) fn AssignProcessToJobObject(job: Handle, process: Handle) -> i32;
+        pub(super) fn SetInformationJobObject(
+            job: Handle,
+            class: i32,
+            info: *const c_void,
+            length: u32,
+        ) -> i32;
+        pub(super) fn TerminateJobObject(job: Handle, exit_code: u32) -> i32;
+        pub(super) fn OpenProcess(access: u32, inherit: i32, pid: u32) -> Handle;
+        pub(super) fn CloseHandle(handle: Handle) -> i32;
+        pub(super) fn CreateToolhelp32Snapshot(flags: u32, pid: u32) -> Handle;
+        pub(super) fn Thread32First(snapshot: Handle, entry: *mut ThreadEntry32) -> i32;
+        pub(super) fn Thread32Next(snapshot: Handle, entry: *mut ThreadEntry32) -> i32;
+        pub(super) fn OpenThread(access: u32, inherit: i32, thread_id: u32) -> Handle;
+        pub(super) fn ResumeThread(thread: Handle) -> u32;
+    }
+}
+
+#[cfg(not(any(unix, windows)))]
+pub(crate) fn terminate_tree(handle: &PipelineHandle, grace: Option<Duration>) -> io::Result<()> {
+    if let Some(grace) = grace {
+        wait_for_exit(handle, grace);
+    }
+    handle.kill()
+}
+
+/// Waits up to `grace` for every stage to exit.
+pub(crate) fn wait_for_exit(handle: &PipelineHandle, grace: Duration) {
+    let deadline = Instant::now() + grace;
+    while Instant::now() < deadline {
+        if handle.has_exited() {
+            return;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+#[cfg(windows)]
+fn taskkill(handle: &PipelineHandle, force: bool) {
+    for pid in handle.pids() {
+        let pid = pid.to_string();
+        let mut args = vec!["/T", "/PID", pid.as_str()];
+        if force {
+            args.push("/F");
+        }
+        let _ = Command::new("taskkill")
+            .args(args)
+            .stdout(std::process::Stdio::null())
+            .stderr(std::process::Stdio::null())
+            .status();
+    }
+}
+
+#[cfg(unix)]
+const SIGNALS: &[(&str, libc::c_int)] = &[
+    ("HUP", libc::SIGHUP),
+    ("INT", libc::SIGINT),
+    ("QUIT", libc::SIGQUIT),
+    ("ILL", libc::SIGILL),
+    ("TRAP", libc::SIGTRAP),
+    ("ABRT", libc::SIGABRT),
+    ("BUS", libc::SIGBUS),
+    ("FPE", libc::SIGFPE),
+    ("KILL", libc::SIGKILL),
+    ("USR1", libc::SIGUSR1),
+    ("SEGV", libc::SIGSEGV),
+    ("USR2", libc::SIGUSR2),
+    ("PIPE", libc::SIGPIPE),
+    ("ALRM", libc::SIGALRM),
+    ("TERM", libc::SIGTERM),
+    ("CHLD", libc::SIGCHLD),
+    ("CONT", libc::SIGCONT),
+    ("STOP", libc::SIGSTOP),
+    ("TSTP", libc::SIGTSTP),
+    ("XCPU", libc::SIGXCPU),
+    ("XFSZ", libc::SIGXFSZ),
+    ("WINCH", libc::SIGWINCH),
+];
+
+/// Resolves `"SIGHUP"`, `"hup"` and similar names to a signal number.
+#[cfg(unix)]
+pub(crate) fn signal_number(name: &str) -> Option<i32> {
+    let upper = name.to_ascii_uppercase();
+    let short = upper.strip_prefix("SIG").unwrap_or(&upper);
+    SIGNALS
+        .iter()
+        .find(|(name, _)| *name == short)
+        .map(|(_, number)| *number)
+}
+
+/// The number and name (e.g. `"SIGKILL"`) of the signal that terminated a
+/// process, if one did.
+#[cfg(unix)]
+pub(crate) fn termination_signal(status: &ExitStatus) -> Option<(i32, String)> {
+    use std::os::unix::process::ExitStatusExt;
+    let number = status.signal()?;
+    let name = SIGNALS
+        .iter()
+        .find(|(_, known)| *known == number)
+        .map_or_else(|| format!("SIG{number}"), |(name, _)| format!("SIG{name}"));
+    Some((number, name))
+}
+
+#[cfg(not(unix))]
+pub(crate) fn termination_signal(_status: &ExitStatus) -> Option<(i32, String)> {
+    None
+}
+
+/// Peak memory and CPU time of an exited child, including the descendants
+/// it waited for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct ResourceUsage {
+    pub(crate) max_rss_kb: u64,
+    pub(crate) cpu_time_ms: u64,
+}
+
+/// Reads the usage of child `pid` once it has exited, without reaping it so
+/// duct still can. `Ok(None)` means it is still running, which only happens
+/// when `block` is false.
+#[cfg(target_os = "linux")]
+pub(crate) fn exited_usage(pid: u32, block: bool) -> io::Result<Option<ResourceUsage>> {
+    let mut options = libc::WEXITED | libc::WNOWAIT;
+    if !block {
+        options |= libc::WNOHANG;
+    }
+    let mut info: libc::siginfo_t = unsafe { std::mem::zeroed() };
+    let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+    // The libc wrapper drops the kernel's fifth argument, which reports the
+    // usage of zombies as well.
+    loop {
+        let result = unsafe {
+            libc::syscall(
+                libc::SYS_waitid,
+                libc::P_PID,
+                pid as libc::id_t,
+                &mut info as *mut libc::siginfo_t,
+                options,
+                &mut usage as *mut libc::rusage,
+            )
+        };
+        if result == 0 {
+            break;
+        }
+        let err = io::Error::last_os_error();
+        if err.kind() != io::ErrorKind::Interrupted {
+            return Err(err);
+        }
+    }
+    if unsafe { info.si_pid() } == 0 {
+        return Ok(None);
+    }
+    let millis = |time: libc::timeval| time.tv_sec as u64 * 1000 + time.tv_usec as u64 / 1000;
+    Ok(Some(ResourceUsage {
+        max_rss_kb: usage.ru_maxrss as u64,
+        cpu_time_ms: millis(usage.ru_utime) + millis(usage.ru_stime),
+    }))
+}
+
+/// Elsewhere on Unix this only waits for the exit, still without reaping,
+/// and then reports that the usage is unavailable.
+#[cfg(all(unix, not(target_os = "linux")))]
+pub(crate) fn exited_usage(pid: u32, block: bool) -> io::Result<Option<ResourceUsage>> {
+    let mut options = libc::WEXITED | libc::WNOWAIT;
+    if !block {
+        options |= libc::WNOHANG;
+    }
+    let mut info: libc::siginfo_t = unsafe { std::mem::zeroed() };
+    loop {
+        if unsafe { libc::waitid(libc::P_PID, pid as libc::id_t, &mut info, options) } == 0 {
+            break;
+        }
+        let err = io::Error::last_os_error();
+        if err.kind() != io::ErrorKind::Interrupted {
+            return Err(err);
+        }
+    }
+    if unsafe { info.si_pid() } == 0 {
+        return Ok(None);
+    }
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "resource usage is only reported on Linux",
+    ))
+}
+
+#[cfg(not(unix))]
+pub(crate) fn exited_usage(_pid: u32, _block: bool) -> io::Result<Option<ResourceUsage>> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "resource usage is only reported on Linux",
+    ))
+}
+
+#[cfg(not(unix))]
+pub(crate) fn signal_number(_name: &str) -> Option<i32> {
+    None
+}
+
+#[cfg(unix)]
+pub(crate) fn send_signal(handle: &PipelineHandle, signal: i32) -> io::Result<()> {
+    handle.signal(signal)
+}
+
+#[cfg(not(unix))]
+pub(crate) fn send_signal(_handle: &PipelineHandle, _signal: i32) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "signals are only supported on Unix",
+    ))
+}
+
+/// Finds `program` the way a shell would: a name containing a path separator
+/// is used as is, anything else is looked up in `dirs` in order.
+pub(crate) fn find_program<I>(program: &str, dirs: I) -> Option<PathBuf>
+where
+    I: IntoIterator<Item = PathBuf>,
+{
+    let path = Path::new(program);
+    if path.components().count() > 1 {
+        return is_executable(path).then(|| path.to_path_buf());
+    }
+    dirs.into_iter()
+        .flat_map(|dir| candidates(&dir, program))
+        .find(|candidate| is_executable(candidate))
+}
+
+#[cfg(windows)]
+fn candidates(dir: &Path, program: &str) -> Vec<PathBuf> {
+    let mut candidates = vec![dir.join(program)];
+    if Path::new(program).extension().is_none() {
+        for extension in ["exe", "com", "cmd", "bat"] {
+            candidates.push(dir.join(program).with_extension(extension));
+        }
+    }
+    candidates
+}
+
+#[cfg(not(windows))]
+fn candidates(dir: &Path, program: &str) -> Vec<PathBuf> {
+    vec![dir.join(program)]
+}
+
+#[cfg(unix)]
+fn is_executable(path: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    path.metadata()
+        .is_ok_and(|meta| meta.is_file() && meta.permissions().mode() & 0o111 != 0)
+}
+
+#[cfg(not(unix))]
+fn is_executable(path: &Path) -> bool {
+    path.is_file()
+}
@@ -0,0 +1,235 @@
+use crate::pipeline_executor::{PipelineExecutor, RunningPipeline, StreamKind, StreamMode};
+use crate::pipeline_handle::PipelineHandle;
+use crate::platform;
+use crate::process_result::ProcessResult;
+use crate::util::{map_io_err, runtime_error};
+use crate::RhaiResult;
+use os_pipe::PipeWriter;
+use rhai::{Dynamic, NativeCallContext, INT};
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[derive(Clone, Debug)]
+pub struct ProcessHandle {
+    pub(crate) id: INT,
+    pub(crate) executor: PipelineExecutor,
+    pub(crate) handle: Arc<PipelineHandle>,
+    pub(crate) stdin: Arc<Mutex<Option<PipeWriter>>>,
+    pub(crate) state: Arc<Mutex<HandleState>>,
+    pub(crate) guard: Arc<DropGuard>,
+}
+
+#[derive(Debug)]
+pub(crate) struct HandleState {
+    running: RunningPipeline,
+    result: Option<ProcessResult>,
+}
+
+/// Terminates the pipeline once the last clone of its handle is dropped, if
+/// the kill-on-drop policy is enabled.
+#[derive(Debug)]
+pub(crate) struct DropGuard {
+    handle: Arc<PipelineHandle>,
+    kill_on_drop: Arc<AtomicBool>,
+}
+
+impl Drop for DropGuard {
+    fn drop(&mut self) {
+        if self.kill_on_drop.load(Ordering::Relaxed) {
+            kill_if_running(&self.handle);
+        }
+    }
+}
+
+pub(crate) fn kill_if_running(handle: &PipelineHandle) {
+    if matches!(handle.try_wait(), Ok(None)) {
+        platform::kill_tree(handle).ok();
+        let _ = handle.wait();
+    }
+}
+
+impl ProcessHandle {
+    pub(crate) fn new(
+        executor: PipelineExecutor,
+        mut running: RunningPipeline,
+        id: INT,
+        kill_on_drop: Arc<AtomicBool>,
+    ) -> Self {
+        Self {
+            id,
+            executor,
+            guard: Arc::new(DropGuard {
+                handle: Arc::clone(&running.handle),
+                kill_on_drop,
+            }),
+            handle: Arc::clone(&running.handle),
+            stdin: Arc::new(Mutex::new(running.stdin.take())),
+            state: Arc::new(Mutex::new(HandleState {
+                running,
+                result: None,
+            })),
+        }
+    }
+
+    pub fn wait(&self, context: &NativeCallContext) -> RhaiResult<ProcessResult> {
+        // Nothing can be written while blocked here, so let the children see EOF.
+        self.close_stdin();
+        let mut state = self.state.lock().unwrap_or_else(|err| err.into_inner());
+        if let Some(result) = state.result.as_ref() {
+            return Ok(result.clone());
+        }
+        let result = state.running.drive(
+            &self.executor,
+            Some(context),
+            StreamMode::Capture,
+            None,
+            None,
+        )?;
+        state.result = Some(result.clone());
+        Ok(result)
+    }
+
+    /// Whether `wait()` would return without waiting on the pipeline.
+    pub(crate) fn is_due(&self) -> RhaiResult<bool> {
+        let mut state = self.state.lock().unwrap_or_else(|err| err.into_inner());
+        if state.result.is_some() {
+            return Ok(true);
+        }
+        state.running.is_due(&self.executor)
+    }
+
+    pub fn try_wait(&self, context: &NativeCallContext) -> RhaiResult<Dynamic> {
+        if self.handle.try_wait().map_err(map_io_err)?.is_none() {
+            return Ok(Dynamic::UNIT);
+        }
+        self.wait(context).map(Dynamic::from)
+    }
+
+    pub fn wait_timeout(
+        &self,
+        context: &NativeCallContext,
+        timeout_ms: INT,
+    ) -> RhaiResult<Dynamic> {
+        let timeout_ms = u64::try_from(timeout_ms)
+            .map_err(|_| runtime_error("wait_timeout must be a non-negative integer"))?;
+        let deadline = Instant::now() + Duration::from_millis(timeout_ms);
+        let poll_interval = Duration::from_millis(self.executor.config.stream_poll_interval_ms);
+        while self.handle.try_wait().map_err(map_io_err)?.is_none() {
+            let now = Instant::now();
+            if now >= deadline {
+                return Ok(Dynamic::UNIT);
+            }
+            thread::sleep(poll_interval.min(deadline - now));
+        }
+        self.wait(context).map(Dynamic::from)
+    }
+
+    pub fn read_stdout(&self) -> RhaiResult<String> {
+        self.read_available(StreamKind::Stdout)
+    }
+
+    pub fn read_stderr(&self) -> RhaiResult<String> {
+        self.read_available(StreamKind::Stderr)
+    }
+
+    pub fn read_stdout_line(&self) -> RhaiResult<Dynamic> {
+        self.read_line(StreamKind::Stdout)
+    }
+
+    pub fn read_stderr_line(&self) -> RhaiResult<Dynamic> {
+        self.read_line(StreamKind::Stderr)
+    }
+
+    fn read_available(&self, kind: StreamKind) -> RhaiResult<String> {
+        let mut state = self.state.lock().unwrap_or_else(|err| err.into_inner());
+        state.running.pump(&self.executor)?;
+        Ok(state.running.read_available(kind))
+    }
+
+    fn read_line(&self, kind: StreamKind) -> RhaiResult<Dynamic> {
+        let mut state = self.state.lock().unwrap_or_else(|err| err.into_inner());
+        state.running.pump(&self.executor)?;
+        Ok(state
+            .running
+            .read_line(kind)
+            .map(Dynamic::from)
+            .unwrap_or(Dynamic::UNIT))
+    }
+
+    /// Waits up to `timeout_ms` for stdout to show `pattern`, like `expect(1)`,
+    /// and returns the unread output up to the end of the match. A `re:`
+    /// prefix makes the pattern a regex; otherwise it is matched literally.
+    pub fn expect(&self, pattern: &str, timeout_ms: INT) -> RhaiResult<String> {
+        let timeout_ms = u64::try_from(timeout_ms)
+            .map_err(|_| runtime_error("expect timeout must be a non-negative integer"))?;
+        let regex = match pattern.strip_prefix("re:") {
+            Some(regex) => regex.to_string(),
+            None => regex::escape(pattern),
+        };
+        let regex = regex::bytes::Regex::new(&regex)
+            .map_err(|err| runtime_error(format!("invalid pattern '{pattern}': {err}")))?;
+        let deadline = Instant::now() + Duration::from_millis(timeout_ms);
+        let mut state = self.state.lock().unwrap_or_else(|err| err.into_inner());
+        state
+            .running
+            .expect(&self.executor, &regex, pattern, deadline)
+    }
+
+    /// Writes `text` followed by a newline, e.g. to answer a prompt seen
+    /// with `expect()`.
+    pub fn send_line(&self, text: &str) -> RhaiResult<()> {
+        self.write_stdin(&format!("{text}\n"))
+    }
+
+    pub fn write_stdin(&self, text: &str) -> RhaiResult<()> {
+        let mut stdin = self.stdin.lock().unwrap_or_else(|err| err.into_inner());
+        let writer = stdin
+            .as_mut()
+            .ok_or_else(|| runtime_error("stdin has already been closed"))?;
+        writer.write_all(text.as_bytes()).map_err(map_io_err)?;
+        writer.flush().map_err(map_io_err)
+    }
+
+    pub fn close_stdin(&self) {
+        let mut stdin = self.stdin.lock().unwrap_or_else(|err| err.into_inner());
+        stdin.take();
+    }
+
+    pub fn signal(&self, signal: INT) -> RhaiResult<()> {
+        let signal = i32::try_from(signal).map_err(|_| runtime_error("invalid signal number"))?;
+        platform::send_signal(&self.handle, signal).map_err(map_io_err)
+    }
+
+    pub fn signal_named(&self, name: &str) -> RhaiResult<()> {
+        let signal = platform::signal_number(name)
+            .ok_or_else(|| runtime_error(format!("unknown signal '{name}'")))?;
+        platform::send_signal(&self.handle, signal).map_err(map_io_err)
+    }
+
+    pub fn kill(&self) -> RhaiResult<()> {
+        platform::kill_tree(&self.handle).map_err(map_io_err)
+    }
+
+    pub fn is_running(&self) -> RhaiResult<bool> {
+        Ok(self.handle.try_wait().map_err(map_io_err)?.is_none())
+    }
+
+    pub fn kill_on_drop(&self, enabled: bool) {
+        self.guard.kill_on_drop.store(enabled, Ordering::Relaxed);
+    }
+
+    pub fn id(&self) -> INT {
+        self.id
+    }
+
+    pub fn pid(&self) -> INT {
+        self.handle
+            .pids()
+            .first()
+            .map(|pid| *pid as INT)
+            .unwrap_or(-1)
+    }
+}
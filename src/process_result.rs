@@ -0,0 +1,374 @@
+use crate::util::{process_error, ErrorKind};
+use crate::{RhaiArray, RhaiResult};
+use rhai::{Dynamic, Map as RhaiMap, INT};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The outcome of a pipeline or sequence, as returned by `run()`, the
+/// streaming runs and `ProcessHandle::wait()`. Scripts read it through
+/// properties named like the keys of the former result map.
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+pub struct ProcessResult {
+    pub(crate) command: String,
+    pub(crate) success: bool,
+    pub(crate) status: i64,
+    pub(crate) pid: i64,
+    pub(crate) started_at: SystemTime,
+    pub(crate) finished_at: SystemTime,
+    pub(crate) stdout: String,
+    pub(crate) stderr: String,
+    pub(crate) duration_ms: u64,
+    pub(crate) cancelled: bool,
+    pub(crate) timed_out: bool,
+    pub(crate) combined: Option<String>,
+    pub(crate) signal: Option<(i32, String)>,
+    pub(crate) max_rss_kb: Option<u64>,
+    pub(crate) cpu_time_ms: Option<u64>,
+    pub(crate) stage_statuses: Vec<i64>,
+    pub(crate) stages: Vec<StageResult>,
+    pub(crate) steps: Option<Vec<ProcessResult>>,
+}
+
+impl ProcessResult {
+    /// The pipeline that produced this result, as a shell-like command line.
+    pub fn command(&self) -> &str {
+        &self.command
+    }
+
+    pub fn success(&self) -> bool {
+        self.success
+    }
+
+    pub fn status(&self) -> i64 {
+        self.status
+    }
+
+    /// OS pid of the first pipeline stage.
+    pub fn pid(&self) -> i64 {
+        self.pid
+    }
+
+    pub fn started_at(&self) -> SystemTime {
+        self.started_at
+    }
+
+    pub fn finished_at(&self) -> SystemTime {
+        self.finished_at
+    }
+
+    /// Number and name of the signal that killed the deciding stage (Unix).
+    pub fn signal(&self) -> Option<(i32, &str)> {
+        self.signal
+            .as_ref()
+            .map(|(number, name)| (*number, name.as_str()))
+    }
+
+    pub fn stdout(&self) -> &str {
+        &self.stdout
+    }
+
+    pub fn stderr(&self) -> &str {
+        &self.stderr
+    }
+
+    pub fn duration_ms(&self) -> u64 {
+        self.duration_ms
+    }
+
+    pub fn cancelled(&self) -> bool {
+        self.cancelled
+    }
+
+    /// Whether a timeout ended the run, with `on_timeout_return_result(true)`.
+    pub fn timed_out(&self) -> bool {
+        self.timed_out
+    }
+
+    /// Interleaved stdout and stderr, if `capture_combined()` was set.
+    pub fn combined(&self) -> Option<&str> {
+        self.combined.as_deref()
+    }
+
+    /// Peak resident memory of the largest stage, in KiB (Linux).
+    pub fn max_rss_kb(&self) -> Option<u64> {
+        self.max_rss_kb
+    }
+
+    /// User plus system CPU time of all stages (Linux).
+    pub fn cpu_time_ms(&self) -> Option<u64> {
+        self.cpu_time_ms
+    }
+
+    pub fn stage_statuses(&self) -> &[i64] {
+        &self.stage_statuses
+    }
+
+    /// How every stage ended, in pipeline order.
+    pub fn stages(&self) -> &[StageResult] {
+        &self.stages
+    }
+
+    /// The results of every pipeline that ran, for results of a sequence.
+    pub fn steps(&self) -> Option<&[ProcessResult]> {
+        self.steps.as_deref()
+    }
+
+    /// Passes a successful result through and raises a `"failed"` error for
+    /// any other, naming the command and quoting the end of its stderr.
+    pub(crate) fn check(self, context: Option<&str>) -> RhaiResult<Self> {
+        if self.success {
+            return Ok(self);
+        }
+        let outcome = match &self.signal {
+            _ if self.timed_out => "timed out".to_string(),
+            _ if self.cancelled => "was cancelled".to_string(),
+            Some((_, name)) => format!("was killed by {name}"),
+            None => format!("exited with status {}", self.status),
+        };
+        let mut message = format!("command '{}' {outcome}", self.command);
+        if let Some(context) = context {
+            message = format!("{context}: {message}");
+        }
+        let stderr = self.stderr.trim();
+        // The last lines of a long log are the ones explaining the failure.
+        let tail = stderr
+            .rmatch_indices('\n')
+            .nth(STDERR_TAIL_LINES - 1)
+            .map_or(stderr, |(index, _)| &stderr[index + 1..]);
+        if !tail.is_empty() {
+            message.push_str(":\n");
+            message.push_str(tail);
+        }
+        Err(process_error(
+            ErrorKind::Failed,
+            Some(&self.command),
+            Some(self.status),
+            message,
+        ))
+    }
+
+    /// Merges the output and duration of a pipeline that ran before this one,
+    /// the way a shell shows the output of a command sequence.
+    pub(crate) fn after(mut self, earlier: &ProcessResult) -> Self {
+        self.stdout.insert_str(0, &earlier.stdout);
+        self.stderr.insert_str(0, &earlier.stderr);
+        self.duration_ms = self.duration_ms.saturating_add(earlier.duration_ms);
+        self.started_at = earlier.started_at;
+        self.max_rss_kb = self
+            .max_rss_kb
+            .zip(earlier.max_rss_kb)
+            .map(|(a, b)| a.max(b));
+        self.cpu_time_ms = self
+            .cpu_time_ms
+            .zip(earlier.cpu_time_ms)
+            .map(|(a, b)| a + b);
+        if let Some(combined) = self.combined.as_mut() {
+            if let Some(before) = &earlier.combined {
+                combined.insert_str(0, before);
+            }
+        }
+        self
+    }
+
+    /// The result as `#{ command, success, status, pid, started_at, finished_at,
+    /// stdout, stderr, duration_ms, cancelled, timed_out, signal, max_rss_kb,
+    /// cpu_time_ms, stage_statuses, stages }`, plus `combined` and `steps`
+    /// when set.
+    pub fn to_map(&self) -> RhaiMap {
+        let mut map = RhaiMap::new();
+        map.insert("command".into(), Dynamic::from(self.command.clone()));
+        map.insert("success".into(), Dynamic::from_bool(self.success));
+        map.insert("status".into(), Dynamic::from_int(self.status as INT));
+        map.insert("pid".into(), Dynamic::from_int(self.pid as INT));
+        map.insert(
+            "started_at".into(),
+            Dynamic::from_int(epoch_ms(self.started_at)),
+        );
+        map.insert(
+            "finished_at".into(),
+            Dynamic::from_int(epoch_ms(self.finished_at)),
+        );
+        map.insert("stdout".into(), Dynamic::from(self.stdout.clone()));
+        map.insert("stderr".into(), Dynamic::from(self.stderr.clone()));
+        map.insert("duration_ms".into(), Dynamic::from_int(self.duration_int()));
+        map.insert("cancelled".into(), Dynamic::from_bool(self.cancelled));
+        map.insert("timed_out".into(), Dynamic::from_bool(self.timed_out));
+        map.insert("signal".into(), signal_to_dynamic(&self.signal));
+        map.insert("max_rss_kb".into(), optional_int(self.max_rss_kb));
+        map.insert("cpu_time_ms".into(), optional_int(self.cpu_time_ms));
+        if let Some(combined) = &self.combined {
+            map.insert("combined".into(), Dynamic::from(combined.clone()));
+        }
+        map.insert(
+            "stage_statuses".into(),
+            Dynamic::from(self.stage_statuses_array()),
+        );
+        map.insert("stages".into(), Dynamic::from(self.stages_array()));
+        if let Some(steps) = &self.steps {
+            let steps: RhaiArray = steps
+                .iter()
+                .map(|step| Dynamic::from_map(step.to_map()))
+                .collect();
+            map.insert("steps".into(), Dynamic::from(steps));
+        }
+        map
+    }
+
+    pub(crate) fn stages_array(&self) -> RhaiArray {
+        self.stages
+            .iter()
+            .map(|stage| Dynamic::from_map(stage.to_map()))
+            .collect()
+    }
+
+    pub(crate) fn duration_int(&self) -> INT {
+        self.duration_ms.try_into().unwrap_or(INT::MAX)
+    }
+
+    pub(crate) fn stage_statuses_array(&self) -> RhaiArray {
+        self.stage_statuses
+            .iter()
+            .map(|code| Dynamic::from_int(*code as INT))
+            .collect()
+    }
+}
+
+/// How many lines of stderr a `check()` error quotes.
+const STDERR_TAIL_LINES: usize = 20;
+
+/// How one stage of a pipeline ended.
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+pub struct StageResult {
+    pub(crate) program: String,
+    pub(crate) args: Vec<String>,
+    pub(crate) status: i64,
+    pub(crate) signal: Option<(i32, String)>,
+    pub(crate) duration_ms: u64,
+}
+
+impl StageResult {
+    pub fn program(&self) -> &str {
+        &self.program
+    }
+
+    pub fn args(&self) -> &[String] {
+        &self.args
+    }
+
+    pub fn status(&self) -> i64 {
+        self.status
+    }
+
+    /// Number and name of the signal that killed this stage (Unix).
+    pub fn signal(&self) -> Option<(i32, &str)> {
+        self.signal
+            .as_ref()
+            .map(|(number, name)| (*number, name.as_str()))
+    }
+
+    /// Time from the start of the pipeline until this stage exited.
+    pub fn duration_ms(&self) -> u64 {
+        self.duration_ms
+    }
+
+    /// The stage as `#{ program, args, status, signal, duration_ms }`.
+    pub fn to_map(&self) -> RhaiMap {
+        let args: RhaiArray = self.args.iter().cloned().map(Dynamic::from).collect();
+        let mut map = RhaiMap::new();
+        map.insert("program".into(), Dynamic::from(self.program.clone()));
+        map.insert("args".into(), Dynamic::from(args));
+        map.insert("status".into(), Dynamic::from_int(self.status as INT));
+        map.insert("signal".into(), signal_to_dynamic(&self.signal));
+        map.insert(
+            "duration_ms".into(),
+            Dynamic::from_int(self.duration_ms.try_into().unwrap_or(INT::MAX)),
+        );
+        map
+    }
+}
+
+/// `#{ number, name }` for a terminating signal, or `()` if there was none.
+pub(crate) fn signal_to_dynamic(signal: &Option<(i32, String)>) -> Dynamic {
+    match signal {
+        Some((number, name)) => {
+            let mut map = RhaiMap::new();
+            map.insert("number".into(), Dynamic::from_int(*number as INT));
+            map.insert("name".into(), Dynamic::from(name.clone()));
+            Dynamic::from_map(map)
+        }
+        None => Dynamic::UNIT,
+    }
+}
+
+/// An integer, or `()` for a measurement that is not available.
+pub(crate) fn optional_int(value: Option<u64>) -> Dynamic {
+    value.map_or(Dynamic::UNIT, |value| {
+        Dynamic::from_int(value.try_into().unwrap_or(INT::MAX))
+    })
+}
+
+/// Milliseconds since the Unix epoch, as scripts see timestamps.
+pub(crate) fn epoch_ms(time: SystemTime) -> INT {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_millis().try_into().unwrap_or(INT::MAX))
+}
+
+/// Splits captured output into lines the way `str::lines` does, dropping a
+/// `\r` before each `\n`. Output ending in a newline only yields a trailing
+/// empty line when `keep_trailing_empty` is set.
+pub(crate) fn split_lines(text: &str, keep_trailing_empty: bool) -> RhaiArray {
+    let mut lines: RhaiArray = text
+        .lines()
+        .map(|line| Dynamic::from(line.to_string()))
+        .collect();
+    if keep_trailing_empty && text.ends_with('\n') {
+        lines.push(Dynamic::from(String::new()));
+    }
+    lines
+}
+
+/// Parses `text` as JSON into maps, arrays and scalars. The error names the
+/// position and quotes the text around it.
+#[cfg(feature = "json")]
+pub(crate) fn parse_json(text: &str) -> crate::RhaiResult<Dynamic> {
+    let value: serde_json::Value = serde_json::from_str(text).map_err(|err| {
+        let line = text.lines().nth(err.line().saturating_sub(1)).unwrap_or("");
+        let snippet: String = line
+            .chars()
+            .skip(err.column().saturating_sub(20))
+            .take(40)
+            .collect();
+        let mut message = format!("stdout is not valid JSON: {err}");
+        if !snippet.trim().is_empty() {
+            message.push_str(&format!(" near '{}'", snippet.trim()));
+        }
+        crate::util::runtime_error(message)
+    })?;
+    Ok(json_to_dynamic(value))
+}
+
+#[cfg(feature = "json")]
+fn json_to_dynamic(value: serde_json::Value) -> Dynamic {
+    use serde_json::Value;
+    match value {
+        Value::Null => Dynamic::UNIT,
+        Value::Bool(value) => Dynamic::from_bool(value),
+        Value::Number(number) => match number.as_i64().and_then(|n| INT::try_from(n).ok()) {
+            Some(int) => Dynamic::from_int(int),
+            // `Dynamic::from` keeps this a float unless Rhai is built with `no_float`.
+            None => Dynamic::from(number.as_f64().unwrap_or(f64::NAN)),
+        },
+        Value::String(text) => Dynamic::from(text),
+        Value::Array(items) => {
+            let array: RhaiArray = items.into_iter().map(json_to_dynamic).collect();
+            Dynamic::from(array)
+        }
+        Value::Object(fields) => Dynamic::from_map(
+            fields
+                .into_iter()
+                .map(|(key, value)| (key.into(), json_to_dynamic(value)))
+                .collect(),
+        ),
+    }
+}
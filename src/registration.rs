@@ -1,13 +1,92 @@
+use crate::cancellation::CancellationHandle;
 use crate::command_builder::CommandBuilder;
+use crate::command_sequence::{executor_from, CommandSequence};
+use crate::command_spec::{describe_pipeline, CommandSpec};
 use crate::config::Config;
+use crate::parallel;
+use crate::periodic::{self, Periodic};
 use crate::pipe_builder::PipeBuilder;
 use crate::pipeline_executor::PipelineExecutor;
+use crate::process_handle::ProcessHandle;
+use crate::process_result::{
+    epoch_ms, optional_int, signal_to_dynamic, split_lines, ProcessResult,
+};
+use crate::shell_words;
+use crate::supervisor::Supervisor;
+use crate::util::{argument_to_string, runtime_error};
 use crate::RhaiArray;
 use rhai::packages::Package;
 use rhai::plugin::*;
-use rhai::{Engine, FnPtr, ImmutableString, Map as RhaiMap, Module, NativeCallContext, Shared};
+use rhai::{
+    Engine, FnPtr, FuncRegistration, ImmutableString, Map, Module, NativeCallContext, Shared, INT,
+};
 use std::sync::Arc;
 
+// The exported builder functions spell their types the way Rhai's
+// definitions writer reads them: it unwraps `RhaiResultOf<T>` and knows
+// `Array`, `Map` and `INT` by these names.
+use crate::RhaiArray as Array;
+type RhaiResultOf<T> = crate::RhaiResult<T>;
+
+/// Registers `$name(first, a1, ..., an)` for n = 1..=7 on top of the
+/// `$name(first, [..])` form, since Rhai has no variadic functions.
+macro_rules! register_variadic {
+    ($module:ident, $shared:ident, $name:literal, $first:literal, $doc:literal, $build:path) => {
+        register_variadic!(@arity $module, $shared, $name, $first, $doc, $build, a1);
+        register_variadic!(@arity $module, $shared, $name, $first, $doc, $build, a1, a2);
+        register_variadic!(@arity $module, $shared, $name, $first, $doc, $build, a1, a2, a3);
+        register_variadic!(@arity $module, $shared, $name, $first, $doc, $build, a1, a2, a3, a4);
+        register_variadic!(
+            @arity $module, $shared, $name, $first, $doc, $build, a1, a2, a3, a4, a5
+        );
+        register_variadic!(
+            @arity $module, $shared, $name, $first, $doc, $build, a1, a2, a3, a4, a5, a6
+        );
+        register_variadic!(
+            @arity $module, $shared, $name, $first, $doc, $build, a1, a2, a3, a4, a5, a6, a7
+        );
+    };
+    (@arity $module:ident, $shared:ident, $name:literal, $first:literal, $doc:literal,
+     $build:path, $($arg:ident),+) => {{
+        let config = Arc::clone(&$shared);
+        native_fn(
+            $name,
+            &[$first, $(concat!(stringify!($arg), ": ?")),+, "CommandBuilder"],
+            $doc,
+        )
+        .set_into_module(&mut $module, move |first: ImmutableString, $($arg: Dynamic),+| {
+            $build(Arc::clone(&config), first, vec![$($arg),+])
+        });
+    }};
+}
+
+/// A registration for a `module()` function. Under the `metadata` feature it
+/// carries the parameter names, return type (last in `params`) and doc
+/// comment that `definitions()` writes out. The functions start processes
+/// or read the host, so they are volatile: the optimizer must not run them
+/// while compiling a script, even with constant arguments.
+fn native_fn(name: &str, params: &[&str], comment: &str) -> FuncRegistration {
+    registration(name, params, comment).with_volatility(true)
+}
+
+/// Like [`native_fn`], for helpers whose result only depends on their
+/// arguments, which the optimizer may evaluate ahead of time.
+fn pure_fn(name: &str, params: &[&str], comment: &str) -> FuncRegistration {
+    registration(name, params, comment).with_volatility(false)
+}
+
+#[cfg_attr(not(feature = "metadata"), allow(unused_variables))]
+fn registration(name: &str, params: &[&str], comment: &str) -> FuncRegistration {
+    let registration = FuncRegistration::new(name)
+        .in_internal_namespace()
+        .with_purity(true);
+    #[cfg(feature = "metadata")]
+    let registration = registration
+        .with_params_info(params)
+        .with_comments([format!("/// {comment}")]);
+    registration
+}
+
 pub fn module(config: Config) -> Module {
     let shared = Arc::new(config);
     let mut module = Module::new();
@@ -15,14 +94,293 @@ pub fn module(config: Config) -> Module {
 
     {
         let config = Arc::clone(&shared);
-        module.set_native_fn("cmd", move |args: RhaiArray| {
-            CommandBuilder::new(Arc::clone(&config), args)
+        native_fn(
+            "cmd",
+            &["argv: Array", "CommandBuilder"],
+            "Creates a command from `[program, arg, ...]`.",
+        )
+        .set_into_module(&mut module, move |argv: RhaiArray| {
+            CommandBuilder::new(Arc::clone(&config), argv)
+        });
+    }
+
+    {
+        let config = Arc::clone(&shared);
+        native_fn(
+            "cmd",
+            &["program: String", "CommandBuilder"],
+            "Creates a command running `program` without arguments.",
+        )
+        .set_into_module(&mut module, move |program: ImmutableString| {
+            CommandBuilder::new(Arc::clone(&config), vec![Dynamic::from(program)])
+        });
+    }
+
+    {
+        let config = Arc::clone(&shared);
+        native_fn(
+            "cmd",
+            &["program: String", "args: Array", "CommandBuilder"],
+            "Creates a command running `program` with `args`.",
+        )
+        .set_into_module(
+            &mut module,
+            move |program: ImmutableString, args: RhaiArray| {
+                cmd_with_args(Arc::clone(&config), program, args)
+            },
+        );
+    }
+    register_variadic!(
+        module,
+        shared,
+        "cmd",
+        "program: String",
+        "Creates a command running `program` with the arguments that follow.",
+        cmd_with_args
+    );
+
+    {
+        let config = Arc::clone(&shared);
+        native_fn(
+            "shell",
+            &["script: String", "CommandBuilder"],
+            "Creates a command running `script` through the system shell, if the host allows it.",
+        )
+        .set_into_module(&mut module, move |script: ImmutableString| {
+            CommandBuilder::shell(Arc::clone(&config), script.into())
+        });
+    }
+
+    {
+        let config = Arc::clone(&shared);
+        native_fn(
+            "cmd_fmt",
+            &["template: String", "CommandBuilder"],
+            "Creates a command from `template`, split on whitespace.",
+        )
+        .set_into_module(&mut module, move |template: ImmutableString| {
+            CommandBuilder::from_template(Arc::clone(&config), template, RhaiArray::new())
+        });
+    }
+
+    {
+        let config = Arc::clone(&shared);
+        native_fn(
+            "cmd_fmt",
+            &["template: String", "values: Array", "CommandBuilder"],
+            "Creates a command from `template`, replacing each `{}` with the next of `values`.",
+        )
+        .set_into_module(
+            &mut module,
+            move |template: ImmutableString, values: RhaiArray| {
+                CommandBuilder::from_template(Arc::clone(&config), template, values)
+            },
+        );
+    }
+    register_variadic!(
+        module,
+        shared,
+        "cmd_fmt",
+        "template: String",
+        "Creates a command from `template`, replacing each `{}` with the next argument.",
+        CommandBuilder::from_template
+    );
+
+    {
+        let config = Arc::clone(&shared);
+        native_fn(
+            "pipeline",
+            &["stages: Array", "PipeBuilder"],
+            "Creates a pipeline from an array of `[program, arg, ...]` arrays.",
+        )
+        .set_into_module(&mut module, move |stages: RhaiArray| {
+            PipeBuilder::from_arrays(Arc::clone(&config), stages)
         });
     }
 
+    pure_fn(
+        "split",
+        &["input: String", "Array"],
+        "Splits a command line into arguments using POSIX shell-word rules.",
+    )
+    .set_into_module(&mut module, |input: ImmutableString| {
+        shell_words::split(&input)
+            .map(|words| words.into_iter().map(Dynamic::from).collect::<RhaiArray>())
+            .map_err(|err| runtime_error(format!("process::split: {err}")))
+    });
+
+    pure_fn(
+        "quote",
+        &["value: String", "String"],
+        "Quotes `value` so a POSIX shell reads it back as exactly one word.",
+    )
+    .set_into_module(&mut module, |value: ImmutableString| {
+        shell_words::quote(&value)
+    });
+
+    pure_fn(
+        "quote_all",
+        &["values: Array", "String"],
+        "Quotes every value and joins them with spaces.",
+    )
+    .set_into_module(
+        &mut module,
+        |values: RhaiArray| -> crate::RhaiResult<String> {
+            let mut quoted = Vec::with_capacity(values.len());
+            for value in values {
+                quoted.push(shell_words::quote(&argument_to_string(value, false)?));
+            }
+            Ok(quoted.join(" "))
+        },
+    );
+
+    {
+        let config = Arc::clone(&shared);
+        native_fn(
+            "env",
+            &["key: String", "?"],
+            "Reads a variable from the host's environment, or `()` if it is unset.",
+        )
+        .set_into_module(
+            &mut module,
+            move |key: ImmutableString| -> crate::RhaiResult<Dynamic> {
+                config.ensure_env_key_allowed(&key)?;
+                Ok(std::env::var(key.as_str())
+                    .map(Dynamic::from)
+                    .unwrap_or(Dynamic::UNIT))
+            },
+        );
+    }
+
+    native_fn(
+        "parallel",
+        &["executors: Array", "Array"],
+        "Runs the pipelines at the same time and returns their results in order.",
+    )
+    .set_into_module(
+        &mut module,
+        |context: NativeCallContext, executors: RhaiArray| {
+            parallel::run_parallel(&context, executors, None)
+        },
+    );
+
+    native_fn(
+        "parallel_limit",
+        &["limit: int", "executors: Array", "Array"],
+        "Like `parallel`, with at most `limit` pipelines running at a time.",
+    )
+    .set_into_module(
+        &mut module,
+        |context: NativeCallContext, limit: rhai::INT, executors: RhaiArray| {
+            let limit = usize::try_from(limit)
+                .ok()
+                .filter(|limit| *limit > 0)
+                .ok_or_else(|| runtime_error("parallel_limit must be greater than zero"))?;
+            parallel::run_parallel(&context, executors, Some(limit))
+        },
+    );
+
+    native_fn(
+        "race",
+        &["executors: Array", "ProcessResult"],
+        "Runs the pipelines at the same time and returns the result of the first to finish.",
+    )
+    .set_into_module(
+        &mut module,
+        |context: NativeCallContext, executors: RhaiArray| parallel::race(&context, executors),
+    );
+
+    native_fn(
+        "wait_all",
+        &["handles: Array", "Array"],
+        "Waits for every handle and returns their results in order.",
+    )
+    .set_into_module(
+        &mut module,
+        |context: NativeCallContext, handles: RhaiArray| parallel::wait_all(&context, handles),
+    );
+
+    native_fn(
+        "wait_any",
+        &["handles: Array", "Map"],
+        "Waits for the first handle to finish and returns `#{ index, result }`.",
+    )
+    .set_into_module(
+        &mut module,
+        |context: NativeCallContext, handles: RhaiArray| parallel::wait_any(&context, handles),
+    );
+
+    native_fn(
+        "every",
+        &[
+            "interval_ms: int",
+            "executor: ?",
+            "callback: FnPtr",
+            "Periodic",
+        ],
+        "Runs `executor` every `interval_ms` milliseconds, passing each result to `callback` when the script polls the returned handle.",
+    )
+    .set_into_module(
+        &mut module,
+        |context: NativeCallContext, interval_ms: rhai::INT, executor: Dynamic, callback: FnPtr| {
+            periodic::every(&context, interval_ms, executor, callback)
+        },
+    );
+
+    {
+        let config = Arc::clone(&shared);
+        native_fn(
+            "jobs",
+            &["Array"],
+            "`#{ id, pid, command, elapsed_ms }` for every started pipeline still running.",
+        )
+        .set_into_module(&mut module, move || config.jobs.list());
+    }
+
+    {
+        let config = Arc::clone(&shared);
+        native_fn(
+            "kill_job",
+            &["id: int", "bool"],
+            "Kills a running job, returning `false` if no running job has that id.",
+        )
+        .set_into_module(&mut module, move |id: rhai::INT| config.jobs.kill(id));
+    }
+
     module
 }
 
+fn cmd_with_args(
+    config: Arc<Config>,
+    program: ImmutableString,
+    args: RhaiArray,
+) -> crate::RhaiResult<CommandBuilder> {
+    let mut argv = vec![Dynamic::from(program)];
+    argv.extend(args);
+    CommandBuilder::new(config, argv)
+}
+
+fn stage_programs(commands: &[CommandSpec]) -> RhaiArray {
+    commands
+        .iter()
+        .map(|command| Dynamic::from(command.program.clone()))
+        .collect()
+}
+
+fn stage_args(commands: &[CommandSpec]) -> RhaiArray {
+    commands
+        .iter()
+        .map(|command| Dynamic::from(command.args_array()))
+        .collect()
+}
+
+fn stage_envs(config: &Config, commands: &[CommandSpec]) -> RhaiArray {
+    commands
+        .iter()
+        .map(|command| Dynamic::from_map(command.env_map(config)))
+        .collect()
+}
+
 pub fn register(engine: &mut Engine, config: Config) {
     ProcessPackage::new(config).register_into_engine(engine);
 }
@@ -33,19 +391,39 @@ pub fn builder_module() -> Module {
     module
 }
 
+/// The `.d.rhai` type definitions of everything [`register`] adds to an
+/// engine, so editors running the Rhai language server can autocomplete
+/// scripts. Write the string to e.g. `process.d.rhai` next to the scripts.
+#[cfg(feature = "definitions")]
+pub fn definitions() -> String {
+    let mut engine = Engine::new_raw();
+    register(&mut engine, Config::default());
+    engine
+        .definitions()
+        .include_standard_packages(false)
+        .single_file()
+}
+
 #[derive(Clone)]
 pub struct ProcessPackage {
     builder_module: Shared<Module>,
     process_module: Shared<Module>,
+    config: Config,
 }
 
 impl ProcessPackage {
     pub fn new(config: Config) -> Self {
         Self {
             builder_module: builder_module().into(),
-            process_module: module(config).into(),
+            process_module: module(config.clone()).into(),
+            config,
         }
     }
+
+    /// Same as [`Config::cancellation_handle`] for the package's config.
+    pub fn cancellation_handle(&self) -> CancellationHandle {
+        self.config.cancellation_handle()
+    }
 }
 
 impl Package for ProcessPackage {
@@ -58,6 +436,7 @@ impl Package for ProcessPackage {
     fn register_into_engine(&self, engine: &mut Engine) -> &Self {
         engine.register_global_module(self.builder_module.clone());
         engine.register_global_module(self.process_module.clone());
+        self.config.install_progress(engine);
         self
     }
 }
@@ -66,105 +445,1128 @@ fn attach_custom_types(module: &mut Module) {
     module.set_custom_type::<CommandBuilder>("CommandBuilder");
     module.set_custom_type::<PipeBuilder>("PipeBuilder");
     module.set_custom_type::<PipelineExecutor>("PipelineExecutor");
+    module.set_custom_type::<CommandSequence>("CommandSequence");
+    module.set_custom_type::<ProcessHandle>("ProcessHandle");
+    module.set_custom_type::<ProcessResult>("ProcessResult");
+    module.set_custom_type::<Supervisor>("Supervisor");
+    module.set_custom_type::<Periodic>("Periodic");
 }
 
 #[export_module]
 pub mod builder_api_module {
     use super::*;
 
+    /// Adds the environment variables in `map` to the command.
     #[rhai_fn(name = "env", return_raw)]
-    pub fn builder_env(builder: CommandBuilder, map: RhaiMap) -> crate::RhaiResult<CommandBuilder> {
+    pub fn builder_env(builder: CommandBuilder, map: Map) -> RhaiResultOf<CommandBuilder> {
         builder.with_env_map(map)
     }
 
+    /// Sets one environment variable for the command.
     #[rhai_fn(name = "env_var", return_raw)]
     pub fn builder_env_var(
         builder: CommandBuilder,
         key: ImmutableString,
         value: ImmutableString,
-    ) -> crate::RhaiResult<CommandBuilder> {
+    ) -> RhaiResultOf<CommandBuilder> {
         builder.with_env_var(key.into(), value.into())
     }
 
+    /// Loads environment variables for the command from a dotenv file.
+    #[rhai_fn(name = "env_file", return_raw)]
+    pub fn builder_env_file(
+        builder: CommandBuilder,
+        path: ImmutableString,
+    ) -> RhaiResultOf<CommandBuilder> {
+        builder.env_file(path.into())
+    }
+
+    /// Copies the named variables from the host's environment into the command.
+    #[rhai_fn(name = "passthrough_env", return_raw)]
+    pub fn builder_passthrough_env(
+        builder: CommandBuilder,
+        keys: Array,
+    ) -> RhaiResultOf<CommandBuilder> {
+        builder.passthrough_env(keys)
+    }
+
+    /// Unsets an inherited environment variable for this command only.
+    #[rhai_fn(name = "env_remove")]
+    pub fn builder_env_remove(builder: CommandBuilder, key: ImmutableString) -> CommandBuilder {
+        builder.env_remove(key.into())
+    }
+
+    /// Starts the command without inheriting the host's environment.
+    #[rhai_fn(name = "env_clear")]
+    pub fn builder_env_clear(builder: CommandBuilder) -> CommandBuilder {
+        builder.env_clear()
+    }
+
+    /// Appends one argument.
+    #[rhai_fn(name = "arg", return_raw)]
+    pub fn builder_arg(builder: CommandBuilder, arg: Dynamic) -> RhaiResultOf<CommandBuilder> {
+        builder.with_arg(arg)
+    }
+
+    /// Appends an argument that Windows passes to the program exactly as written.
+    #[rhai_fn(name = "raw_arg", return_raw)]
+    pub fn builder_raw_arg(builder: CommandBuilder, arg: String) -> RhaiResultOf<CommandBuilder> {
+        builder.with_raw_arg(arg)
+    }
+
+    /// Appends every value in `args` as an argument.
+    #[rhai_fn(name = "args", return_raw)]
+    pub fn builder_args(builder: CommandBuilder, args: Array) -> RhaiResultOf<CommandBuilder> {
+        builder.with_args(args)
+    }
+
+    /// Runs this command in `path`; an empty string clears it.
+    #[rhai_fn(name = "cwd", return_raw)]
+    pub fn builder_cwd(
+        builder: CommandBuilder,
+        path: ImmutableString,
+    ) -> RhaiResultOf<CommandBuilder> {
+        builder.with_cwd(path.into())
+    }
+
+    /// Kills this stage if it runs longer than `timeout` milliseconds.
+    #[rhai_fn(name = "timeout", return_raw)]
+    pub fn builder_timeout(builder: CommandBuilder, timeout: INT) -> RhaiResultOf<CommandBuilder> {
+        builder.with_timeout(timeout)
+    }
+
+    /// The program name.
+    #[rhai_fn(name = "program")]
+    pub fn builder_program(builder: &mut CommandBuilder) -> String {
+        builder.command.program.clone()
+    }
+
+    /// The arguments after the program name.
+    #[rhai_fn(name = "args")]
+    pub fn builder_args_get(builder: &mut CommandBuilder) -> Array {
+        builder.command.args_array()
+    }
+
+    /// The environment overrides, with secrets redacted.
+    #[rhai_fn(name = "env_map")]
+    pub fn builder_env_map(builder: &mut CommandBuilder) -> Map {
+        builder.command.env_map(&builder.config)
+    }
+
+    /// The command as a shell-quoted line.
+    #[rhai_fn(name = "to_string")]
+    pub fn builder_to_string(builder: &mut CommandBuilder) -> String {
+        builder.command.to_string()
+    }
+
+    /// Pipes this command's stdout into `next`.
     #[rhai_fn(name = "pipe", return_raw)]
     pub fn builder_pipe(
         builder: CommandBuilder,
         next: CommandBuilder,
-    ) -> crate::RhaiResult<PipeBuilder> {
+    ) -> RhaiResultOf<PipeBuilder> {
+        builder.pipe(next)
+    }
+
+    /// Pipes this command's stderr into `next`.
+    #[rhai_fn(name = "pipe_stderr", return_raw)]
+    pub fn builder_pipe_stderr(
+        builder: CommandBuilder,
+        next: CommandBuilder,
+    ) -> RhaiResultOf<PipeBuilder> {
+        builder.pipe_stderr(next)
+    }
+
+    /// Pipes this command's stdout and stderr, merged, into `next`.
+    #[rhai_fn(name = "pipe_both", return_raw)]
+    pub fn builder_pipe_both(
+        builder: CommandBuilder,
+        next: CommandBuilder,
+    ) -> RhaiResultOf<PipeBuilder> {
+        builder.pipe_both(next)
+    }
+
+    /// `a | b` is shorthand for `a.pipe(b)`.
+    #[rhai_fn(name = "|", return_raw)]
+    pub fn builder_pipe_operator(
+        builder: CommandBuilder,
+        next: CommandBuilder,
+    ) -> RhaiResultOf<PipeBuilder> {
         builder.pipe(next)
     }
 
+    /// A copy of the command with `args` appended.
+    #[rhai_fn(name = "with_args", return_raw)]
+    pub fn builder_with_args(builder: CommandBuilder, args: Array) -> RhaiResultOf<CommandBuilder> {
+        builder.with_args(args)
+    }
+
+    /// A copy of the command with the variables in `env` added.
+    #[rhai_fn(name = "with_env", return_raw)]
+    pub fn builder_with_env(builder: CommandBuilder, env: Map) -> RhaiResultOf<CommandBuilder> {
+        builder.with_env_map(env)
+    }
+
+    /// Same as `build()`, for use on a template's copy.
+    #[rhai_fn(name = "exec")]
+    pub fn builder_exec(builder: CommandBuilder) -> PipelineExecutor {
+        builder.build()
+    }
+
+    /// Turns the command into an executor.
     #[rhai_fn(name = "build")]
     pub fn builder_build(builder: CommandBuilder) -> PipelineExecutor {
         builder.build()
     }
 
+    /// Pipes the last stage's stdout into `next`.
     #[rhai_fn(name = "pipe", return_raw)]
-    pub fn pipeline_pipe(
+    pub fn pipeline_pipe(pipeline: PipeBuilder, next: CommandBuilder) -> RhaiResultOf<PipeBuilder> {
+        pipeline.pipe(next)
+    }
+
+    /// Pipes the last stage's stderr into `next`.
+    #[rhai_fn(name = "pipe_stderr", return_raw)]
+    pub fn pipeline_pipe_stderr(
+        pipeline: PipeBuilder,
+        next: CommandBuilder,
+    ) -> RhaiResultOf<PipeBuilder> {
+        pipeline.pipe_stderr(next)
+    }
+
+    /// Pipes the last stage's stdout and stderr, merged, into `next`.
+    #[rhai_fn(name = "pipe_both", return_raw)]
+    pub fn pipeline_pipe_both(
+        pipeline: PipeBuilder,
+        next: CommandBuilder,
+    ) -> RhaiResultOf<PipeBuilder> {
+        pipeline.pipe_both(next)
+    }
+
+    /// `a | b` is shorthand for `a.pipe(b)`.
+    #[rhai_fn(name = "|", return_raw)]
+    pub fn pipeline_pipe_operator(
         pipeline: PipeBuilder,
         next: CommandBuilder,
-    ) -> crate::RhaiResult<PipeBuilder> {
+    ) -> RhaiResultOf<PipeBuilder> {
         pipeline.pipe(next)
     }
 
+    /// The program name of every stage.
+    #[rhai_fn(name = "program")]
+    pub fn pipeline_program(pipeline: &mut PipeBuilder) -> Array {
+        stage_programs(&pipeline.commands)
+    }
+
+    /// The arguments of every stage.
+    #[rhai_fn(name = "args")]
+    pub fn pipeline_args(pipeline: &mut PipeBuilder) -> Array {
+        stage_args(&pipeline.commands)
+    }
+
+    /// The environment overrides of every stage, with secrets redacted.
+    #[rhai_fn(name = "env_map")]
+    pub fn pipeline_env_map(pipeline: &mut PipeBuilder) -> Array {
+        stage_envs(&pipeline.config, &pipeline.commands)
+    }
+
+    /// The pipeline as a shell-quoted line.
+    #[rhai_fn(name = "to_string")]
+    pub fn pipeline_to_string(pipeline: &mut PipeBuilder) -> String {
+        describe_pipeline(&pipeline.commands)
+    }
+
+    /// Turns the pipeline into an executor.
     #[rhai_fn(name = "build")]
     pub fn pipeline_build(pipeline: PipeBuilder) -> PipelineExecutor {
         pipeline.build()
     }
 
+    /// The program name of every stage.
+    #[rhai_fn(name = "program")]
+    pub fn executor_program(executor: &mut PipelineExecutor) -> Array {
+        stage_programs(&executor.commands)
+    }
+
+    /// The arguments of every stage.
+    #[rhai_fn(name = "args")]
+    pub fn executor_args(executor: &mut PipelineExecutor) -> Array {
+        stage_args(&executor.commands)
+    }
+
+    /// The environment overrides of every stage, with secrets redacted.
+    #[rhai_fn(name = "env_map")]
+    pub fn executor_env_map(executor: &mut PipelineExecutor) -> Array {
+        stage_envs(&executor.config, &executor.commands)
+    }
+
+    /// The pipeline as a shell-quoted line.
+    #[rhai_fn(name = "to_string")]
+    pub fn executor_to_string(executor: &mut PipelineExecutor) -> String {
+        describe_pipeline(&executor.commands)
+    }
+
+    /// A copy of the executor with `args` appended to the first stage.
+    #[rhai_fn(name = "with_args", return_raw)]
+    pub fn executor_with_args(
+        executor: PipelineExecutor,
+        args: Array,
+    ) -> RhaiResultOf<PipelineExecutor> {
+        executor.with_args(args)
+    }
+
+    /// A copy of the executor with the variables in `env` added to every stage.
+    #[rhai_fn(name = "with_env", return_raw)]
+    pub fn executor_with_env(
+        executor: PipelineExecutor,
+        env: Map,
+    ) -> RhaiResultOf<PipelineExecutor> {
+        executor.with_env(env)
+    }
+
+    /// Starts every stage without inheriting the host's environment.
+    #[rhai_fn(name = "env_clear", return_raw)]
+    pub fn executor_env_clear(executor: PipelineExecutor) -> RhaiResultOf<PipelineExecutor> {
+        executor.env_clear()
+    }
+
+    /// Sets the working directory of every stage without a `cwd()` of its own.
     #[rhai_fn(name = "cwd", return_raw)]
     pub fn executor_cwd(
         executor: PipelineExecutor,
         path: ImmutableString,
-    ) -> crate::RhaiResult<PipelineExecutor> {
+    ) -> RhaiResultOf<PipelineExecutor> {
         executor.cwd(path.into())
     }
 
+    /// Kills the pipeline if it runs longer than `timeout` milliseconds.
     #[rhai_fn(name = "timeout", return_raw)]
     pub fn executor_timeout(
         executor: PipelineExecutor,
-        timeout: rhai::INT,
-    ) -> crate::RhaiResult<PipelineExecutor> {
+        timeout: INT,
+    ) -> RhaiResultOf<PipelineExecutor> {
         executor.timeout(timeout)
     }
 
+    /// Fails the pipeline if any stage fails, not just the last one. On by
+    /// default.
+    #[rhai_fn(name = "pipefail", return_raw)]
+    pub fn executor_pipefail(
+        executor: PipelineExecutor,
+        enabled: bool,
+    ) -> RhaiResultOf<PipelineExecutor> {
+        executor.pipefail(enabled)
+    }
+
+    /// Judges the pipeline by its last stage only, like a shell without
+    /// `set -o pipefail`.
+    #[rhai_fn(name = "last_stage_status", return_raw)]
+    pub fn executor_last_stage_status(
+        executor: PipelineExecutor,
+    ) -> RhaiResultOf<PipelineExecutor> {
+        executor.last_stage_status()
+    }
+
+    /// Caps the memory of every stage at `megabytes` MiB.
+    #[rhai_fn(name = "limit_memory_mb", return_raw)]
+    pub fn executor_limit_memory_mb(
+        executor: PipelineExecutor,
+        megabytes: INT,
+    ) -> RhaiResultOf<PipelineExecutor> {
+        executor.limit_memory_mb(megabytes)
+    }
+
+    /// Caps the CPU time of every stage at `seconds`.
+    #[rhai_fn(name = "limit_cpu_seconds", return_raw)]
+    pub fn executor_limit_cpu_seconds(
+        executor: PipelineExecutor,
+        seconds: INT,
+    ) -> RhaiResultOf<PipelineExecutor> {
+        executor.limit_cpu_seconds(seconds)
+    }
+
+    /// Caps the file descriptors each stage may hold open. Unix only.
+    #[rhai_fn(name = "limit_open_files", return_raw)]
+    pub fn executor_limit_open_files(
+        executor: PipelineExecutor,
+        files: INT,
+    ) -> RhaiResultOf<PipelineExecutor> {
+        executor.limit_open_files(files)
+    }
+
+    /// Caps the processes each stage's user may run. Unix only.
+    #[rhai_fn(name = "limit_processes", return_raw)]
+    pub fn executor_limit_processes(
+        executor: PipelineExecutor,
+        processes: INT,
+    ) -> RhaiResultOf<PipelineExecutor> {
+        executor.limit_processes(processes)
+    }
+
+    /// Runs every stage at niceness `nice`, from -20 to 19.
+    #[rhai_fn(name = "nice", return_raw)]
+    pub fn executor_nice(executor: PipelineExecutor, nice: INT) -> RhaiResultOf<PipelineExecutor> {
+        executor.nice(nice)
+    }
+
+    /// Runs every stage at `"low"`, `"normal"` or `"high"` priority.
+    #[rhai_fn(name = "priority", return_raw)]
+    pub fn executor_priority(
+        executor: PipelineExecutor,
+        level: &str,
+    ) -> RhaiResultOf<PipelineExecutor> {
+        executor.priority(level)
+    }
+
+    /// Pins every stage to the given CPU numbers. Linux and Windows only.
+    #[rhai_fn(name = "cpu_affinity", return_raw)]
+    pub fn executor_cpu_affinity(
+        executor: PipelineExecutor,
+        cpus: Array,
+    ) -> RhaiResultOf<PipelineExecutor> {
+        executor.cpu_affinity(cpus)
+    }
+
+    /// Sets the I/O scheduling class of every stage. Linux only.
+    #[rhai_fn(name = "io_priority", return_raw)]
+    pub fn executor_io_priority(
+        executor: PipelineExecutor,
+        class: &str,
+    ) -> RhaiResultOf<PipelineExecutor> {
+        executor.io_priority(class, None)
+    }
+
+    /// Sets the I/O scheduling class and level of every stage. Linux only.
+    #[rhai_fn(name = "io_priority", return_raw)]
+    pub fn executor_io_priority_level(
+        executor: PipelineExecutor,
+        class: &str,
+        level: INT,
+    ) -> RhaiResultOf<PipelineExecutor> {
+        executor.io_priority(class, Some(level))
+    }
+
+    /// Starts every stage in a process group of its own.
+    #[rhai_fn(name = "process_group", return_raw)]
+    pub fn executor_process_group(
+        executor: PipelineExecutor,
+        enabled: bool,
+    ) -> RhaiResultOf<PipelineExecutor> {
+        executor.process_group(enabled)
+    }
+
+    /// Starts every stage in a session of its own. Unix only.
+    #[rhai_fn(name = "new_session", return_raw)]
+    pub fn executor_new_session(
+        executor: PipelineExecutor,
+        enabled: bool,
+    ) -> RhaiResultOf<PipelineExecutor> {
+        executor.new_session(enabled)
+    }
+
+    /// Keeps Windows from opening a console window for every stage.
+    #[rhai_fn(name = "hide_window", return_raw)]
+    pub fn executor_hide_window(
+        executor: PipelineExecutor,
+        hide: bool,
+    ) -> RhaiResultOf<PipelineExecutor> {
+        executor.hide_window(hide)
+    }
+
+    /// Adds raw Windows process creation flags to every stage.
+    #[rhai_fn(name = "creation_flags", return_raw)]
+    pub fn executor_creation_flags(
+        executor: PipelineExecutor,
+        flags: INT,
+    ) -> RhaiResultOf<PipelineExecutor> {
+        executor.creation_flags(flags)
+    }
+
+    /// Confines every stage to the directory tree under `path`. Unix only.
+    #[rhai_fn(name = "chroot", return_raw)]
+    pub fn executor_chroot(
+        executor: PipelineExecutor,
+        path: String,
+    ) -> RhaiResultOf<PipelineExecutor> {
+        executor.chroot(path)
+    }
+
+    /// Sets the file mode creation mask of every stage. Unix only.
+    #[rhai_fn(name = "umask", return_raw)]
+    pub fn executor_umask(executor: PipelineExecutor, mask: INT) -> RhaiResultOf<PipelineExecutor> {
+        executor.umask(mask)
+    }
+
+    /// Runs every stage as the named user. Unix only.
+    #[rhai_fn(name = "run_as_user", return_raw)]
+    pub fn executor_run_as_user(
+        executor: PipelineExecutor,
+        name: &str,
+    ) -> RhaiResultOf<PipelineExecutor> {
+        executor.run_as_user(name)
+    }
+
+    /// Runs every stage with user ID `uid`. Unix only.
+    #[rhai_fn(name = "uid", return_raw)]
+    pub fn executor_uid(executor: PipelineExecutor, uid: INT) -> RhaiResultOf<PipelineExecutor> {
+        executor.uid(uid)
+    }
+
+    /// Runs every stage with group ID `gid`. Unix only.
+    #[rhai_fn(name = "gid", return_raw)]
+    pub fn executor_gid(executor: PipelineExecutor, gid: INT) -> RhaiResultOf<PipelineExecutor> {
+        executor.gid(gid)
+    }
+
+    /// Runs the pipeline on a pseudo-terminal instead of pipes. Unix only.
+    #[rhai_fn(name = "pty", return_raw)]
+    pub fn executor_pty(
+        executor: PipelineExecutor,
+        enabled: bool,
+    ) -> RhaiResultOf<PipelineExecutor> {
+        executor.pty(enabled)
+    }
+
+    /// Returns a result with `timed_out` set instead of raising on timeout.
+    #[rhai_fn(name = "on_timeout_return_result", return_raw)]
+    pub fn executor_on_timeout_return_result(
+        executor: PipelineExecutor,
+        enabled: bool,
+    ) -> RhaiResultOf<PipelineExecutor> {
+        executor.on_timeout_return_result(enabled)
+    }
+
+    /// Also captures output streamed by `run_stream*()`.
+    #[rhai_fn(name = "capture_while_streaming", return_raw)]
+    pub fn executor_capture_while_streaming(
+        executor: PipelineExecutor,
+        enabled: bool,
+    ) -> RhaiResultOf<PipelineExecutor> {
+        executor.capture_while_streaming(enabled)
+    }
+
+    /// Asks the pipeline to exit and waits `grace` milliseconds before killing it.
+    #[rhai_fn(name = "grace_period", return_raw)]
+    pub fn executor_grace_period(
+        executor: PipelineExecutor,
+        grace: INT,
+    ) -> RhaiResultOf<PipelineExecutor> {
+        executor.grace_period(grace)
+    }
+
+    /// Kills the pipeline if it produces no output for `timeout` milliseconds.
+    #[rhai_fn(name = "idle_timeout", return_raw)]
+    pub fn executor_idle_timeout(
+        executor: PipelineExecutor,
+        timeout: INT,
+    ) -> RhaiResultOf<PipelineExecutor> {
+        executor.idle_timeout(timeout)
+    }
+
+    /// Adds stdout and stderr interleaved as the result's `combined`.
+    #[rhai_fn(name = "capture_combined", return_raw)]
+    pub fn executor_capture_combined(executor: PipelineExecutor) -> RhaiResultOf<PipelineExecutor> {
+        executor.capture_combined()
+    }
+
+    /// Treats the listed exit codes as successes.
     #[rhai_fn(name = "allow_exit_codes", return_raw)]
     pub fn executor_exit_codes(
         executor: PipelineExecutor,
-        codes: RhaiArray,
-    ) -> crate::RhaiResult<PipelineExecutor> {
+        codes: Array,
+    ) -> RhaiResultOf<PipelineExecutor> {
         executor.allow_exit_codes(codes)
     }
 
+    /// Calls `callback(pid)` for every stage once it has started.
+    #[rhai_fn(name = "on_spawn", return_raw)]
+    pub fn executor_on_spawn(
+        executor: PipelineExecutor,
+        callback: FnPtr,
+    ) -> RhaiResultOf<PipelineExecutor> {
+        executor.on_spawn(callback)
+    }
+
+    /// Calls `callback(#{ success, status, duration_ms })` once the pipeline exits.
+    #[rhai_fn(name = "on_exit", return_raw)]
+    pub fn executor_on_exit(
+        executor: PipelineExecutor,
+        callback: FnPtr,
+    ) -> RhaiResultOf<PipelineExecutor> {
+        executor.on_exit(callback)
+    }
+
+    /// Runs the pipeline and returns its result.
     #[rhai_fn(name = "run", return_raw)]
-    pub fn executor_run(executor: PipelineExecutor) -> crate::RhaiResult<RhaiMap> {
-        executor.run()
+    pub fn executor_run(
+        context: NativeCallContext,
+        executor: PipelineExecutor,
+    ) -> RhaiResultOf<ProcessResult> {
+        executor.run(&context)
+    }
+
+    /// Runs the pipeline, raising a `"failed"` error unless it succeeds.
+    #[rhai_fn(name = "check", return_raw)]
+    pub fn executor_check(
+        context: NativeCallContext,
+        executor: PipelineExecutor,
+    ) -> RhaiResultOf<ProcessResult> {
+        executor.check(&context)
+    }
+
+    /// Runs the pipeline, raising `message` unless it succeeds.
+    #[rhai_fn(name = "expect", return_raw)]
+    pub fn executor_expect(
+        context: NativeCallContext,
+        executor: PipelineExecutor,
+        message: &str,
+    ) -> RhaiResultOf<ProcessResult> {
+        executor.expect(&context, message)
+    }
+
+    /// Describes what `run()` would execute, without starting anything.
+    #[rhai_fn(name = "dry_run")]
+    pub fn executor_dry_run(executor: &mut PipelineExecutor) -> Map {
+        executor.dry_run()
+    }
+
+    /// Starts the pipeline in the background and returns its handle.
+    #[rhai_fn(name = "start", return_raw)]
+    pub fn executor_spawn(
+        context: NativeCallContext,
+        executor: PipelineExecutor,
+    ) -> RhaiResultOf<ProcessHandle> {
+        executor.spawn(&context)
+    }
+
+    /// Starts the pipeline in the background on a pseudo-terminal.
+    #[rhai_fn(name = "spawn_pty", return_raw)]
+    pub fn executor_spawn_pty(
+        context: NativeCallContext,
+        executor: PipelineExecutor,
+    ) -> RhaiResultOf<ProcessHandle> {
+        executor.spawn_pty(&context)
+    }
+
+    /// Starts the pipeline in the background, restarting it when it fails.
+    #[rhai_fn(name = "supervise", return_raw)]
+    pub fn executor_supervise(
+        context: NativeCallContext,
+        executor: PipelineExecutor,
+        options: Map,
+    ) -> RhaiResultOf<Supervisor> {
+        executor.supervise(&context, options)
+    }
+
+    /// Starts the pipeline detached from the host and returns its first pid.
+    #[rhai_fn(name = "run_detached", return_raw)]
+    pub fn executor_run_detached(
+        context: NativeCallContext,
+        executor: PipelineExecutor,
+    ) -> RhaiResultOf<INT> {
+        executor.run_detached(&context)
+    }
+
+    /// Runs `next` after this command only if it succeeds.
+    #[rhai_fn(name = "then", return_raw)]
+    pub fn builder_then(builder: CommandBuilder, next: Dynamic) -> RhaiResultOf<CommandSequence> {
+        CommandSequence::new(builder.build()).then(executor_from(next)?)
+    }
+
+    /// Runs `next` after this pipeline only if it succeeds.
+    #[rhai_fn(name = "then", return_raw)]
+    pub fn pipeline_then(pipeline: PipeBuilder, next: Dynamic) -> RhaiResultOf<CommandSequence> {
+        CommandSequence::new(pipeline.build()).then(executor_from(next)?)
+    }
+
+    /// Runs `next` after this pipeline only if it succeeds.
+    #[rhai_fn(name = "then", return_raw)]
+    pub fn executor_then(
+        executor: PipelineExecutor,
+        next: Dynamic,
+    ) -> RhaiResultOf<CommandSequence> {
+        CommandSequence::new(executor).then(executor_from(next)?)
+    }
+
+    /// Runs `next` only if everything before it succeeds.
+    #[rhai_fn(name = "then", return_raw)]
+    pub fn sequence_then(
+        sequence: CommandSequence,
+        next: Dynamic,
+    ) -> RhaiResultOf<CommandSequence> {
+        sequence.then(executor_from(next)?)
+    }
+
+    /// Runs `next` after this command only if it fails.
+    #[rhai_fn(name = "or_else", return_raw)]
+    pub fn builder_or_else(
+        builder: CommandBuilder,
+        next: Dynamic,
+    ) -> RhaiResultOf<CommandSequence> {
+        CommandSequence::new(builder.build()).or_else(executor_from(next)?)
+    }
+
+    /// Runs `next` after this pipeline only if it fails.
+    #[rhai_fn(name = "or_else", return_raw)]
+    pub fn pipeline_or_else(pipeline: PipeBuilder, next: Dynamic) -> RhaiResultOf<CommandSequence> {
+        CommandSequence::new(pipeline.build()).or_else(executor_from(next)?)
+    }
+
+    /// Runs `next` after this pipeline only if it fails.
+    #[rhai_fn(name = "or_else", return_raw)]
+    pub fn executor_or_else(
+        executor: PipelineExecutor,
+        next: Dynamic,
+    ) -> RhaiResultOf<CommandSequence> {
+        CommandSequence::new(executor).or_else(executor_from(next)?)
+    }
+
+    /// Runs `next` only if something before it fails.
+    #[rhai_fn(name = "or_else", return_raw)]
+    pub fn sequence_or_else(
+        sequence: CommandSequence,
+        next: Dynamic,
+    ) -> RhaiResultOf<CommandSequence> {
+        sequence.or_else(executor_from(next)?)
+    }
+
+    /// Runs the sequence and returns the result of the last pipeline that ran.
+    #[rhai_fn(name = "run", return_raw)]
+    pub fn sequence_run(
+        context: NativeCallContext,
+        sequence: CommandSequence,
+    ) -> RhaiResultOf<ProcessResult> {
+        sequence.run(&context)
+    }
+
+    #[rhai_fn(name = "wait", return_raw)]
+    pub fn handle_wait(
+        context: NativeCallContext,
+        handle: &mut ProcessHandle,
+    ) -> RhaiResultOf<ProcessResult> {
+        handle.wait(&context)
+    }
+
+    #[rhai_fn(name = "try_wait", return_raw)]
+    pub fn handle_try_wait(
+        context: NativeCallContext,
+        handle: &mut ProcessHandle,
+    ) -> RhaiResultOf<Dynamic> {
+        handle.try_wait(&context)
+    }
+
+    #[rhai_fn(name = "wait_timeout", return_raw)]
+    pub fn handle_wait_timeout(
+        context: NativeCallContext,
+        handle: &mut ProcessHandle,
+        timeout_ms: INT,
+    ) -> RhaiResultOf<Dynamic> {
+        handle.wait_timeout(&context, timeout_ms)
+    }
+
+    #[rhai_fn(name = "read_stdout", return_raw)]
+    pub fn handle_read_stdout(handle: &mut ProcessHandle) -> RhaiResultOf<String> {
+        handle.read_stdout()
+    }
+
+    #[rhai_fn(name = "read_stderr", return_raw)]
+    pub fn handle_read_stderr(handle: &mut ProcessHandle) -> RhaiResultOf<String> {
+        handle.read_stderr()
+    }
+
+    #[rhai_fn(name = "read_stdout_line", return_raw)]
+    pub fn handle_read_stdout_line(handle: &mut ProcessHandle) -> RhaiResultOf<Dynamic> {
+        handle.read_stdout_line()
+    }
+
+    #[rhai_fn(name = "read_stderr_line", return_raw)]
+    pub fn handle_read_stderr_line(handle: &mut ProcessHandle) -> RhaiResultOf<Dynamic> {
+        handle.read_stderr_line()
+    }
+
+    #[rhai_fn(name = "write_stdin", return_raw)]
+    pub fn handle_write_stdin(
+        handle: &mut ProcessHandle,
+        text: ImmutableString,
+    ) -> RhaiResultOf<()> {
+        handle.write_stdin(&text)
+    }
+
+    #[rhai_fn(name = "expect", return_raw)]
+    pub fn handle_expect(
+        handle: &mut ProcessHandle,
+        pattern: ImmutableString,
+        timeout_ms: INT,
+    ) -> RhaiResultOf<String> {
+        handle.expect(&pattern, timeout_ms)
+    }
+
+    #[rhai_fn(name = "send_line", return_raw)]
+    pub fn handle_send_line(handle: &mut ProcessHandle, text: ImmutableString) -> RhaiResultOf<()> {
+        handle.send_line(&text)
+    }
+
+    #[rhai_fn(name = "close_stdin")]
+    pub fn handle_close_stdin(handle: &mut ProcessHandle) {
+        handle.close_stdin()
+    }
+
+    #[rhai_fn(name = "signal", return_raw)]
+    pub fn handle_signal(handle: &mut ProcessHandle, signal: INT) -> RhaiResultOf<()> {
+        handle.signal(signal)
+    }
+
+    #[rhai_fn(name = "signal", return_raw)]
+    pub fn handle_signal_named(
+        handle: &mut ProcessHandle,
+        name: ImmutableString,
+    ) -> RhaiResultOf<()> {
+        handle.signal_named(&name)
+    }
+
+    #[rhai_fn(name = "kill", return_raw)]
+    pub fn handle_kill(handle: &mut ProcessHandle) -> RhaiResultOf<()> {
+        handle.kill()
+    }
+
+    #[rhai_fn(name = "is_running", return_raw)]
+    pub fn handle_is_running(handle: &mut ProcessHandle) -> RhaiResultOf<bool> {
+        handle.is_running()
+    }
+
+    #[rhai_fn(name = "kill_on_drop")]
+    pub fn handle_kill_on_drop(handle: &mut ProcessHandle, enabled: bool) {
+        handle.kill_on_drop(enabled)
+    }
+
+    #[rhai_fn(name = "id")]
+    pub fn handle_id(handle: &mut ProcessHandle) -> INT {
+        handle.id()
+    }
+
+    #[rhai_fn(name = "pid")]
+    pub fn handle_pid(handle: &mut ProcessHandle) -> INT {
+        handle.pid()
+    }
+
+    #[rhai_fn(name = "wait", return_raw)]
+    pub fn supervisor_wait(
+        context: NativeCallContext,
+        supervisor: &mut Supervisor,
+    ) -> RhaiResultOf<ProcessResult> {
+        supervisor.wait(&context)
+    }
+
+    #[rhai_fn(name = "stop", return_raw)]
+    pub fn supervisor_stop(
+        context: NativeCallContext,
+        supervisor: &mut Supervisor,
+    ) -> RhaiResultOf<ProcessResult> {
+        supervisor.stop(&context)
+    }
+
+    #[rhai_fn(name = "restarts", return_raw)]
+    pub fn supervisor_restarts(
+        context: NativeCallContext,
+        supervisor: &mut Supervisor,
+    ) -> RhaiResultOf<INT> {
+        supervisor.restarts(&context)
+    }
+
+    #[rhai_fn(name = "is_running", return_raw)]
+    pub fn supervisor_is_running(
+        context: NativeCallContext,
+        supervisor: &mut Supervisor,
+    ) -> RhaiResultOf<bool> {
+        supervisor.is_running(&context)
+    }
+
+    #[rhai_fn(name = "kill_on_drop")]
+    pub fn supervisor_kill_on_drop(supervisor: &mut Supervisor, enabled: bool) {
+        supervisor.kill_on_drop(enabled)
+    }
+
+    #[rhai_fn(name = "pid")]
+    pub fn supervisor_pid(supervisor: &mut Supervisor) -> INT {
+        supervisor.pid()
+    }
+
+    #[rhai_fn(name = "wait", return_raw)]
+    pub fn periodic_wait(context: NativeCallContext, periodic: &mut Periodic) -> RhaiResultOf<()> {
+        periodic.wait(&context)
+    }
+
+    #[rhai_fn(name = "stop", return_raw)]
+    pub fn periodic_stop(context: NativeCallContext, periodic: &mut Periodic) -> RhaiResultOf<()> {
+        periodic.stop(&context)
+    }
+
+    #[rhai_fn(name = "runs", return_raw)]
+    pub fn periodic_runs(context: NativeCallContext, periodic: &mut Periodic) -> RhaiResultOf<INT> {
+        periodic.runs(&context)
+    }
+
+    #[rhai_fn(name = "is_running", return_raw)]
+    pub fn periodic_is_running(
+        context: NativeCallContext,
+        periodic: &mut Periodic,
+    ) -> RhaiResultOf<bool> {
+        periodic.is_running(&context)
+    }
+
+    #[rhai_fn(get = "command", pure)]
+    pub fn result_command(result: &mut ProcessResult) -> String {
+        result.command.clone()
+    }
+
+    #[rhai_fn(get = "success", pure)]
+    pub fn result_success(result: &mut ProcessResult) -> bool {
+        result.success
+    }
+
+    #[rhai_fn(get = "status", pure)]
+    pub fn result_status(result: &mut ProcessResult) -> INT {
+        result.status as INT
+    }
+
+    #[rhai_fn(get = "pid", pure)]
+    pub fn result_pid(result: &mut ProcessResult) -> INT {
+        result.pid as INT
+    }
+
+    #[rhai_fn(get = "started_at", pure)]
+    pub fn result_started_at(result: &mut ProcessResult) -> INT {
+        epoch_ms(result.started_at)
+    }
+
+    #[rhai_fn(get = "finished_at", pure)]
+    pub fn result_finished_at(result: &mut ProcessResult) -> INT {
+        epoch_ms(result.finished_at)
+    }
+
+    #[rhai_fn(get = "stdout", pure)]
+    pub fn result_stdout(result: &mut ProcessResult) -> String {
+        result.stdout.clone()
+    }
+
+    #[rhai_fn(set = "stdout")]
+    pub fn result_set_stdout(result: &mut ProcessResult, stdout: String) {
+        result.stdout = stdout;
+    }
+
+    #[rhai_fn(get = "stderr", pure)]
+    pub fn result_stderr(result: &mut ProcessResult) -> String {
+        result.stderr.clone()
+    }
+
+    #[rhai_fn(set = "stderr")]
+    pub fn result_set_stderr(result: &mut ProcessResult, stderr: String) {
+        result.stderr = stderr;
+    }
+
+    #[rhai_fn(get = "combined", pure)]
+    pub fn result_combined(result: &mut ProcessResult) -> Dynamic {
+        result.combined.clone().map_or(Dynamic::UNIT, Dynamic::from)
+    }
+
+    #[rhai_fn(set = "combined")]
+    pub fn result_set_combined(result: &mut ProcessResult, combined: String) {
+        result.combined = Some(combined);
+    }
+
+    #[rhai_fn(get = "duration_ms", pure)]
+    pub fn result_duration_ms(result: &mut ProcessResult) -> INT {
+        result.duration_int()
+    }
+
+    #[rhai_fn(get = "cancelled", pure)]
+    pub fn result_cancelled(result: &mut ProcessResult) -> bool {
+        result.cancelled
+    }
+
+    #[rhai_fn(get = "timed_out", pure)]
+    pub fn result_timed_out(result: &mut ProcessResult) -> bool {
+        result.timed_out
+    }
+
+    #[rhai_fn(get = "signal", pure)]
+    pub fn result_signal(result: &mut ProcessResult) -> Dynamic {
+        signal_to_dynamic(&result.signal)
+    }
+
+    #[rhai_fn(get = "max_rss_kb", pure)]
+    pub fn result_max_rss_kb(result: &mut ProcessResult) -> Dynamic {
+        optional_int(result.max_rss_kb)
+    }
+
+    #[rhai_fn(get = "cpu_time_ms", pure)]
+    pub fn result_cpu_time_ms(result: &mut ProcessResult) -> Dynamic {
+        optional_int(result.cpu_time_ms)
     }
 
+    #[rhai_fn(get = "stage_statuses", pure)]
+    pub fn result_stage_statuses(result: &mut ProcessResult) -> Array {
+        result.stage_statuses_array()
+    }
+
+    #[rhai_fn(get = "stages", pure)]
+    pub fn result_stages(result: &mut ProcessResult) -> Array {
+        result.stages_array()
+    }
+
+    #[rhai_fn(get = "steps", pure)]
+    pub fn result_steps(result: &mut ProcessResult) -> Dynamic {
+        match &result.steps {
+            Some(steps) => {
+                let steps: Array = steps.iter().cloned().map(Dynamic::from).collect();
+                Dynamic::from(steps)
+            }
+            None => Dynamic::UNIT,
+        }
+    }
+
+    #[rhai_fn(name = "stdout_lines", pure)]
+    pub fn result_stdout_lines(result: &mut ProcessResult) -> Array {
+        split_lines(&result.stdout, false)
+    }
+
+    #[rhai_fn(name = "stdout_lines", pure)]
+    pub fn result_stdout_lines_keep(
+        result: &mut ProcessResult,
+        keep_trailing_empty: bool,
+    ) -> Array {
+        split_lines(&result.stdout, keep_trailing_empty)
+    }
+
+    #[rhai_fn(name = "stderr_lines", pure)]
+    pub fn result_stderr_lines(result: &mut ProcessResult) -> Array {
+        split_lines(&result.stderr, false)
+    }
+
+    #[rhai_fn(name = "stderr_lines", pure)]
+    pub fn result_stderr_lines_keep(
+        result: &mut ProcessResult,
+        keep_trailing_empty: bool,
+    ) -> Array {
+        split_lines(&result.stderr, keep_trailing_empty)
+    }
+
+    #[cfg(feature = "json")]
+    #[rhai_fn(name = "json", return_raw, pure)]
+    pub fn result_json(result: &mut ProcessResult) -> RhaiResultOf<Dynamic> {
+        crate::process_result::parse_json(&result.stdout)
+    }
+
+    #[rhai_fn(name = "check", return_raw)]
+    pub fn result_check(result: ProcessResult) -> RhaiResultOf<ProcessResult> {
+        result.check(None)
+    }
+
+    #[rhai_fn(name = "expect", return_raw)]
+    pub fn result_expect(result: ProcessResult, message: &str) -> RhaiResultOf<ProcessResult> {
+        result.check(Some(message))
+    }
+
+    #[rhai_fn(name = "to_map", pure)]
+    pub fn result_to_map(result: &mut ProcessResult) -> Map {
+        result.to_map()
+    }
+
+    #[rhai_fn(name = "to_string", name = "to_debug", pure)]
+    pub fn result_to_string(result: &mut ProcessResult) -> String {
+        Dynamic::from_map(result.to_map()).to_string()
+    }
+
+    /// Runs the pipeline, printing its output as it arrives.
     #[rhai_fn(name = "run_stream", return_raw)]
     pub fn executor_run_stream_default(
         context: NativeCallContext,
         executor: PipelineExecutor,
-    ) -> crate::RhaiResult<RhaiMap> {
+    ) -> RhaiResultOf<ProcessResult> {
         executor.run_stream(&context, None, None)
     }
 
+    /// Runs the pipeline, passing stdout chunks to `stdout_cb` as they arrive.
     #[rhai_fn(name = "run_stream", return_raw)]
     pub fn executor_run_stream_stdout(
         context: NativeCallContext,
         executor: PipelineExecutor,
         stdout_cb: FnPtr,
-    ) -> crate::RhaiResult<RhaiMap> {
+    ) -> RhaiResultOf<ProcessResult> {
         executor.run_stream(&context, Some(stdout_cb), None)
     }
 
+    /// Runs the pipeline, passing output chunks to the callbacks as they arrive.
     #[rhai_fn(name = "run_stream", return_raw)]
     pub fn executor_run_stream_both(
         context: NativeCallContext,
         executor: PipelineExecutor,
         stdout_cb: FnPtr,
         stderr_cb: FnPtr,
-    ) -> crate::RhaiResult<RhaiMap> {
+    ) -> RhaiResultOf<ProcessResult> {
         executor.run_stream(&context, Some(stdout_cb), Some(stderr_cb))
     }
+
+    /// Runs the pipeline, printing its output line by line.
+    #[rhai_fn(name = "run_stream_lines", return_raw)]
+    pub fn executor_run_stream_lines_default(
+        context: NativeCallContext,
+        executor: PipelineExecutor,
+    ) -> RhaiResultOf<ProcessResult> {
+        executor.run_stream_lines(&context, None, None)
+    }
+
+    /// Runs the pipeline, passing each stdout line to `stdout_cb`.
+    #[rhai_fn(name = "run_stream_lines", return_raw)]
+    pub fn executor_run_stream_lines_stdout(
+        context: NativeCallContext,
+        executor: PipelineExecutor,
+        stdout_cb: FnPtr,
+    ) -> RhaiResultOf<ProcessResult> {
+        executor.run_stream_lines(&context, Some(stdout_cb), None)
+    }
+
+    /// Runs the pipeline, passing each output line to the callbacks.
+    #[rhai_fn(name = "run_stream_lines", return_raw)]
+    pub fn executor_run_stream_lines_both(
+        context: NativeCallContext,
+        executor: PipelineExecutor,
+        stdout_cb: FnPtr,
+        stderr_cb: FnPtr,
+    ) -> RhaiResultOf<ProcessResult> {
+        executor.run_stream_lines(&context, Some(stdout_cb), Some(stderr_cb))
+    }
+
+    #[cfg(not(feature = "no_index"))]
+    /// Runs the pipeline, printing its output as it arrives.
+    #[rhai_fn(name = "run_stream_bytes", return_raw)]
+    pub fn executor_run_stream_bytes_default(
+        context: NativeCallContext,
+        executor: PipelineExecutor,
+    ) -> RhaiResultOf<ProcessResult> {
+        executor.run_stream_bytes(&context, None, None)
+    }
+
+    #[cfg(not(feature = "no_index"))]
+    /// Runs the pipeline, passing stdout chunks to `stdout_cb` as blobs.
+    #[rhai_fn(name = "run_stream_bytes", return_raw)]
+    pub fn executor_run_stream_bytes_stdout(
+        context: NativeCallContext,
+        executor: PipelineExecutor,
+        stdout_cb: FnPtr,
+    ) -> RhaiResultOf<ProcessResult> {
+        executor.run_stream_bytes(&context, Some(stdout_cb), None)
+    }
+
+    #[cfg(not(feature = "no_index"))]
+    /// Runs the pipeline, passing output chunks to the callbacks as blobs.
+    #[rhai_fn(name = "run_stream_bytes", return_raw)]
+    pub fn executor_run_stream_bytes_both(
+        context: NativeCallContext,
+        executor: PipelineExecutor,
+        stdout_cb: FnPtr,
+        stderr_cb: FnPtr,
+    ) -> RhaiResultOf<ProcessResult> {
+        executor.run_stream_bytes(&context, Some(stdout_cb), Some(stderr_cb))
+    }
 }
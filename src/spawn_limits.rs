@@ -0,0 +1,122 @@
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Counts the pipelines running under one `Config`, so `max_concurrent` can
+/// hold further starts back until one of them exits.
+#[derive(Debug, Default)]
+pub(crate) struct ProcessSlots {
+    running: Mutex<usize>,
+    freed: Condvar,
+}
+
+impl ProcessSlots {
+    /// Blocks until fewer than `limit` pipelines are running and claims a
+    /// slot, which is given back when the returned guard is dropped.
+    pub(crate) fn acquire(self: &Arc<Self>, limit: usize) -> SlotGuard {
+        let mut running = self.running.lock().unwrap_or_else(|err| err.into_inner());
+        while *running >= limit {
+            running = self
+                .freed
+                .wait(running)
+                .unwrap_or_else(|err| err.into_inner());
+        }
+        *running += 1;
+        SlotGuard {
+            slots: Arc::clone(self),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct SlotGuard {
+    slots: Arc<ProcessSlots>,
+}
+
+impl Drop for SlotGuard {
+    fn drop(&mut self) {
+        let mut running = self
+            .slots
+            .running
+            .lock()
+            .unwrap_or_else(|err| err.into_inner());
+        *running -= 1;
+        self.slots.freed.notify_one();
+    }
+}
+
+/// Wall-clock time spent by the pipelines run under one `Config`, checked
+/// against `total_runtime_budget_ms` before each start.
+#[derive(Debug, Default)]
+pub(crate) struct RuntimeBudget {
+    spent_ms: AtomicU64,
+}
+
+impl RuntimeBudget {
+    pub(crate) fn spent_ms(&self) -> u64 {
+        self.spent_ms.load(Ordering::Relaxed)
+    }
+}
+
+/// The run time of one pipeline, charged to its budget exactly once by
+/// whichever sees the pipeline end first: its result or its exit watcher.
+#[derive(Debug)]
+pub(crate) struct BudgetCharge {
+    budget: Arc<RuntimeBudget>,
+    start: Instant,
+    charged: AtomicBool,
+}
+
+impl BudgetCharge {
+    pub(crate) fn new(budget: &Arc<RuntimeBudget>, start: Instant) -> Self {
+        Self {
+            budget: Arc::clone(budget),
+            start,
+            charged: AtomicBool::new(false),
+        }
+    }
+
+    pub(crate) fn settle(&self) {
+        if !self.charged.swap(true, Ordering::Relaxed) {
+            let elapsed = self
+                .start
+                .elapsed()
+                .as_millis()
+                .try_into()
+                .unwrap_or(u64::MAX);
+            self.budget.spent_ms.fetch_add(elapsed, Ordering::Relaxed);
+        }
+    }
+}
+
+/// When the most recent pipelines under one `Config` were started, so
+/// `max_spawns_per_second` can space out further starts.
+#[derive(Debug, Default)]
+pub(crate) struct SpawnRate {
+    recent: Mutex<VecDeque<Instant>>,
+}
+
+impl SpawnRate {
+    /// Sleeps until fewer than `limit` pipelines were started within the
+    /// last second. Each caller books its start time under the lock and
+    /// sleeps after releasing it, so starts are still let through in order
+    /// but no caller holds the lock while it waits.
+    pub(crate) fn throttle(&self, limit: usize) {
+        let start = {
+            let mut recent = self.recent.lock().unwrap_or_else(|err| err.into_inner());
+            let now = Instant::now();
+            let start = if recent.len() >= limit {
+                recent
+                    .pop_front()
+                    .map_or(now, |oldest| (oldest + Duration::from_secs(1)).max(now))
+            } else {
+                now
+            };
+            recent.push_back(start);
+            start
+        };
+        thread::sleep(start.saturating_duration_since(Instant::now()));
+    }
+}
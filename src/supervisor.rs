@@ -0,0 +1,276 @@
+use crate::command_spec::describe_pipeline;
+use crate::config::Config;
+use crate::pipeline_executor::{PipelineExecutor, RunningPipeline, StreamMode};
+use crate::pipeline_handle::PipelineHandle;
+use crate::platform;
+use crate::process_result::ProcessResult;
+use crate::util::{error_parts, process_error, runtime_error, ErrorKind};
+use crate::RhaiResult;
+use rhai::{FnPtr, Map as RhaiMap, NativeCallContext, INT};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::Duration;
+
+/// Keeps a pipeline started with `supervise()` running, starting it again
+/// each time it exits unsuccessfully until its restarts are used up. The
+/// pipeline runs and restarts on a background thread; `on_restart` callbacks
+/// need the script, so they run the next time it calls `wait()`, `stop()`,
+/// `restarts()` or `is_running()` on the handle.
+#[derive(Clone, Debug)]
+pub struct Supervisor {
+    config: Arc<Config>,
+    command: String,
+    on_restart: Option<FnPtr>,
+    shared: Arc<Shared>,
+    guard: Arc<StopGuard>,
+}
+
+#[derive(Debug, Default)]
+struct Shared {
+    state: Mutex<Supervision>,
+    changed: Condvar,
+}
+
+#[derive(Debug, Default)]
+struct Supervision {
+    /// The pipeline currently running; `None` while waiting to restart it.
+    current: Option<Arc<PipelineHandle>>,
+    restarts: usize,
+    /// Exits that led to a restart, not yet passed to `on_restart`.
+    pending: Vec<(usize, ProcessResult)>,
+    stopping: bool,
+    /// How supervision ended: the last exit, or why the pipeline could not
+    /// be run. An error is kept as its parts because it cannot cross threads.
+    outcome: Option<Result<ProcessResult, (ErrorKind, String)>>,
+}
+
+/// Stops supervision once the last clone of the handle is dropped, if the
+/// kill-on-drop policy is enabled.
+#[derive(Debug)]
+struct StopGuard {
+    shared: Arc<Shared>,
+    enabled: AtomicBool,
+}
+
+impl Drop for StopGuard {
+    fn drop(&mut self) {
+        if self.enabled.load(Ordering::Relaxed) {
+            self.shared.stop();
+        }
+    }
+}
+
+/// The `supervise()` options map.
+struct RestartPolicy {
+    restarts: usize,
+    backoff: Duration,
+    on_restart: Option<FnPtr>,
+}
+
+impl RestartPolicy {
+    fn from_map(options: RhaiMap) -> RhaiResult<Self> {
+        let mut policy = Self {
+            restarts: 3,
+            backoff: Duration::from_millis(1000),
+            on_restart: None,
+        };
+        for (key, value) in options {
+            match key.as_str() {
+                "restarts" => {
+                    policy.restarts = value
+                        .as_int()
+                        .ok()
+                        .and_then(|n| usize::try_from(n).ok())
+                        .ok_or_else(|| runtime_error("restarts must be a non-negative integer"))?;
+                }
+                "backoff_ms" => {
+                    let ms = value
+                        .as_int()
+                        .ok()
+                        .and_then(|n| u64::try_from(n).ok())
+                        .ok_or_else(|| {
+                            runtime_error("backoff_ms must be a non-negative integer")
+                        })?;
+                    policy.backoff = Duration::from_millis(ms);
+                }
+                "on_restart" => {
+                    policy.on_restart = Some(
+                        value
+                            .try_cast::<FnPtr>()
+                            .ok_or_else(|| runtime_error("on_restart must be a function"))?,
+                    );
+                }
+                other => {
+                    return Err(runtime_error(format!(
+                        "unknown supervise() option '{other}'"
+                    )))
+                }
+            }
+        }
+        Ok(policy)
+    }
+}
+
+impl Supervisor {
+    pub(crate) fn start(
+        executor: PipelineExecutor,
+        context: &NativeCallContext,
+        options: RhaiMap,
+    ) -> RhaiResult<Self> {
+        let policy = RestartPolicy::from_map(options)?;
+        let executor = executor.into_background("supervise")?;
+        let running = RunningPipeline::start(&executor.0, Some(context), false)?;
+        let shared = Arc::new(Shared::default());
+        shared.lock().current = Some(Arc::clone(&running.handle));
+
+        let config = Arc::clone(&executor.0.config);
+        let command = describe_pipeline(&executor.0.commands);
+        let supervised = Arc::clone(&shared);
+        config.workers.execute(move || {
+            let executor = executor;
+            supervised.run(&executor.0, running, policy.restarts, policy.backoff);
+        });
+        Ok(Self {
+            guard: Arc::new(StopGuard {
+                shared: Arc::clone(&shared),
+                enabled: AtomicBool::new(config.kill_on_drop),
+            }),
+            config,
+            command,
+            on_restart: policy.on_restart,
+            shared,
+        })
+    }
+
+    /// Waits until the pipeline exits successfully or runs out of restarts
+    /// and returns its last result.
+    pub fn wait(&self, context: &NativeCallContext) -> RhaiResult<ProcessResult> {
+        let poll_interval = Duration::from_millis(self.config.stream_poll_interval_ms);
+        loop {
+            // Read before delivering, so every restart before the end is reported.
+            let outcome = self.shared.lock().outcome.clone();
+            self.deliver(context)?;
+            if let Some(outcome) = outcome {
+                return outcome.map_err(|(kind, message)| {
+                    process_error(kind, Some(&self.command), None, message)
+                });
+            }
+            self.config.check_progress()?;
+            let state = self.shared.lock();
+            if state.outcome.is_none() && state.pending.is_empty() {
+                let _ = self.shared.changed.wait_timeout(state, poll_interval);
+            }
+        }
+    }
+
+    /// Kills the pipeline without restarting it and returns its last result.
+    pub fn stop(&self, context: &NativeCallContext) -> RhaiResult<ProcessResult> {
+        self.shared.stop();
+        self.wait(context)
+    }
+
+    /// How many times the pipeline has been restarted so far.
+    pub fn restarts(&self, context: &NativeCallContext) -> RhaiResult<INT> {
+        self.deliver(context)?;
+        Ok(self.shared.lock().restarts as INT)
+    }
+
+    /// Whether the pipeline is running or waiting to be restarted.
+    pub fn is_running(&self, context: &NativeCallContext) -> RhaiResult<bool> {
+        self.deliver(context)?;
+        Ok(self.shared.lock().outcome.is_none())
+    }
+
+    /// Overrides the config's `kill_on_drop` policy for this supervisor: stop
+    /// it once its last handle is dropped.
+    pub fn kill_on_drop(&self, enabled: bool) {
+        self.guard.enabled.store(enabled, Ordering::Relaxed);
+    }
+
+    /// The process ID of the first stage currently running, or -1 between
+    /// restarts and after supervision has ended.
+    pub fn pid(&self) -> INT {
+        self.shared
+            .lock()
+            .current
+            .as_ref()
+            .and_then(|handle| handle.pids().first().map(|pid| *pid as INT))
+            .unwrap_or(-1)
+    }
+
+    /// Calls `on_restart(attempt, result)` for every restart not reported yet.
+    fn deliver(&self, context: &NativeCallContext) -> RhaiResult<()> {
+        let pending = std::mem::take(&mut self.shared.lock().pending);
+        let Some(callback) = self.on_restart.as_ref() else {
+            return Ok(());
+        };
+        for (attempt, result) in pending {
+            let _ =
+                callback.call_within_context::<rhai::Dynamic>(context, (attempt as INT, result))?;
+        }
+        Ok(())
+    }
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, Supervision> {
+        self.state.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    fn stop(&self) {
+        let mut state = self.lock();
+        state.stopping = true;
+        if let Some(handle) = state.current.as_ref() {
+            if matches!(handle.try_wait(), Ok(None)) {
+                platform::kill_tree(handle).ok();
+            }
+        }
+        self.changed.notify_all();
+    }
+
+    /// Drives `running` to completion, restarting it after `backoff` each
+    /// time it fails, at most `restarts` times.
+    fn run(
+        &self,
+        executor: &PipelineExecutor,
+        mut running: RunningPipeline,
+        restarts: usize,
+        backoff: Duration,
+    ) {
+        let outcome = loop {
+            let result = match running.drive(executor, None, StreamMode::Capture, None, None) {
+                Ok(result) => result,
+                Err(err) => break Err(error_parts(&err)),
+            };
+            let mut state = self.lock();
+            state.current = None;
+            if state.stopping || result.success || state.restarts >= restarts {
+                break Ok(result);
+            }
+            state.restarts += 1;
+            let attempt = state.restarts;
+            state.pending.push((attempt, result.clone()));
+            self.changed.notify_all();
+            let (state, _) = self
+                .changed
+                .wait_timeout_while(state, backoff, |state| !state.stopping)
+                .unwrap_or_else(|err| err.into_inner());
+            if state.stopping {
+                break Ok(result);
+            }
+            drop(state);
+            running = match RunningPipeline::start(executor, None, false) {
+                Ok(running) => running,
+                Err(err) => break Err(error_parts(&err)),
+            };
+            let mut state = self.lock();
+            state.current = Some(Arc::clone(&running.handle));
+            if state.stopping {
+                platform::kill_tree(&running.handle).ok();
+            }
+        };
+        let mut state = self.lock();
+        state.outcome = Some(outcome);
+        self.changed.notify_all();
+    }
+}
@@ -0,0 +1,89 @@
+use crate::command_spec::CommandSpec;
+use crate::process_result::ProcessResult;
+use crate::util::error_parts;
+use crate::RhaiResult;
+use rhai::EvalAltResult;
+use tracing::Span;
+
+/// The target of every span and event, so subscribers can filter on it.
+const TARGET: &str = "rhai_process";
+
+/// The span covering one pipeline from spawn to exit. Every event of the
+/// pipeline is recorded under it, whichever thread drives the pipeline.
+pub(crate) fn pipeline_span(commands: &[CommandSpec]) -> Span {
+    tracing::info_span!(target: TARGET, "pipeline", stages = commands.len())
+}
+
+/// Records a `"stage spawned"` event for every stage that started.
+pub(crate) fn spawned(span: &Span, commands: &[CommandSpec], pids: &[u32]) {
+    for (index, (command, pid)) in commands.iter().zip(pids).enumerate() {
+        tracing::info!(
+            target: TARGET,
+            parent: span,
+            stage = index,
+            program = %command.program,
+            args_hash = args_hash(&command.args),
+            pid,
+            "stage spawned"
+        );
+    }
+}
+
+/// Records how a pipeline ended: a `"stage exited"` event for every stage
+/// and a `"pipeline exited"` event, or a `"pipeline failed"` warning with
+/// the error kind when it raised an error instead of returning a result.
+pub(crate) fn finished(span: &Span, outcome: &RhaiResult<ProcessResult>) {
+    let result = match outcome {
+        Ok(result) => result,
+        Err(err) => return failed(span, err),
+    };
+    for (index, stage) in result.stages.iter().enumerate() {
+        tracing::info!(
+            target: TARGET,
+            parent: span,
+            stage = index,
+            program = %stage.program,
+            args_hash = args_hash(&stage.args),
+            status = stage.status,
+            duration_ms = stage.duration_ms,
+            "stage exited"
+        );
+    }
+    tracing::info!(
+        target: TARGET,
+        parent: span,
+        success = result.success,
+        status = result.status,
+        duration_ms = result.duration_ms,
+        timed_out = result.timed_out,
+        cancelled = result.cancelled,
+        "pipeline exited"
+    );
+}
+
+/// Records a `"pipeline failed"` warning with the error's kind and message.
+pub(crate) fn failed(span: &Span, err: &EvalAltResult) {
+    let (kind, message) = error_parts(err);
+    tracing::warn!(
+        target: TARGET,
+        parent: span,
+        kind = kind.as_str(),
+        error = %message,
+        "pipeline failed"
+    );
+}
+
+/// A 64-bit FNV-1a hash of the arguments, so runs of the same command line
+/// can be correlated without writing arguments, which may hold secrets, to
+/// the logs. Stable across runs and builds, unlike `DefaultHasher`.
+fn args_hash(args: &[String]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for arg in args {
+        // The terminator keeps `["ab"]` and `["a", "b"]` apart.
+        for byte in arg.bytes().chain([0]) {
+            hash ^= u64::from(byte);
+            hash = hash.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+    hash
+}
@@ -1,16 +1,121 @@
 use crate::config::Config;
 use crate::RhaiResult;
-use rhai::{Dynamic, EvalAltResult, ImmutableString, Position};
+use rhai::{Dynamic, EvalAltResult, ImmutableString, Map as RhaiMap, Position, INT};
 use std::collections::HashSet;
 use std::io;
 use std::sync::Arc;
 
+/// What went wrong, reported to scripts as the `kind` of an error map.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum ErrorKind {
+    /// A timeout or idle timeout expired.
+    Timeout,
+    /// The program or working directory could not be found.
+    NotFound,
+    /// The OS refused to run the program.
+    PermissionDenied,
+    /// The config's policy rejected the request.
+    Denied,
+    /// The OS reported an error while running the pipeline.
+    Io,
+    /// The script passed a value the API cannot use.
+    Invalid,
+    /// A pipeline ran but did not succeed, reported by `check()`.
+    Failed,
+    /// The config's runtime budget is used up.
+    Budget,
+    /// The host cancelled the pipeline through a `CancellationHandle`.
+    Cancelled,
+}
+
+impl ErrorKind {
+    const ALL: [Self; 9] = [
+        Self::Timeout,
+        Self::NotFound,
+        Self::PermissionDenied,
+        Self::Denied,
+        Self::Io,
+        Self::Invalid,
+        Self::Failed,
+        Self::Budget,
+        Self::Cancelled,
+    ];
+
+    pub(crate) fn as_str(self) -> &'static str {
+        match self {
+            Self::Timeout => "timeout",
+            Self::NotFound => "not_found",
+            Self::PermissionDenied => "permission_denied",
+            Self::Denied => "denied",
+            Self::Io => "io",
+            Self::Invalid => "invalid",
+            Self::Failed => "failed",
+            Self::Budget => "budget",
+            Self::Cancelled => "cancelled",
+        }
+    }
+}
+
+/// Raises `#{ kind, command, message, status }` as a runtime error, so scripts
+/// can branch on `err.kind` in `catch`. Unknown fields are `()`.
+pub(crate) fn process_error(
+    kind: ErrorKind,
+    command: Option<&str>,
+    status: Option<i64>,
+    message: impl Into<String>,
+) -> Box<EvalAltResult> {
+    let mut map = RhaiMap::new();
+    map.insert("kind".into(), Dynamic::from(kind.as_str()));
+    map.insert(
+        "command".into(),
+        command.map_or(Dynamic::UNIT, |command| Dynamic::from(command.to_string())),
+    );
+    map.insert("message".into(), Dynamic::from(message.into()));
+    map.insert(
+        "status".into(),
+        status.map_or(Dynamic::UNIT, |status| Dynamic::from_int(status as INT)),
+    );
+    EvalAltResult::ErrorRuntime(Dynamic::from_map(map), Position::NONE).into()
+}
+
+/// Splits an error into its kind and message so it can be carried to
+/// another thread and raised there again with [`process_error`]. Errors not
+/// raised by this crate count as `Failed`.
+pub(crate) fn error_parts(err: &EvalAltResult) -> (ErrorKind, String) {
+    let map = match err {
+        EvalAltResult::ErrorRuntime(value, _) => value.read_lock::<RhaiMap>(),
+        _ => None,
+    };
+    let field = |name: &str| {
+        map.as_ref()
+            .and_then(|map| map.get(name))
+            .and_then(|value| value.clone().into_string().ok())
+    };
+    let kind = field("kind")
+        .and_then(|kind| ErrorKind::ALL.into_iter().find(|k| k.as_str() == kind))
+        .unwrap_or(ErrorKind::Failed);
+    (kind, field("message").unwrap_or_else(|| err.to_string()))
+}
+
 pub(crate) fn runtime_error(msg: impl Into<String>) -> Box<EvalAltResult> {
-    EvalAltResult::ErrorRuntime(Dynamic::from(msg.into()), Position::NONE).into()
+    process_error(ErrorKind::Invalid, None, None, msg)
+}
+
+pub(crate) fn denied_error(command: Option<&str>, msg: impl Into<String>) -> Box<EvalAltResult> {
+    process_error(ErrorKind::Denied, command, None, msg)
 }
 
 pub(crate) fn map_io_err(err: io::Error) -> Box<EvalAltResult> {
-    runtime_error(format!("process I/O error: {err}"))
+    io_error(err, None)
+}
+
+/// Like [`map_io_err`], naming the pipeline `command` that failed.
+pub(crate) fn io_error(err: io::Error, command: Option<&str>) -> Box<EvalAltResult> {
+    let kind = match err.kind() {
+        io::ErrorKind::TimedOut => ErrorKind::Timeout,
+        _ => ErrorKind::Io,
+    };
+    process_error(kind, command, None, format!("process I/O error: {err}"))
 }
 
 pub(crate) fn dynamic_to_string(value: Dynamic, label: &str) -> RhaiResult<String> {
@@ -20,6 +125,20 @@ pub(crate) fn dynamic_to_string(value: Dynamic, label: &str) -> RhaiResult<Strin
         .ok_or_else(|| runtime_error(format!("{label} must be a string")))
 }
 
+/// Converts a command argument to a string. Unless `strict`, numbers, bools
+/// and chars are accepted in their canonical form so `cmd(["sleep", 2])` works.
+pub(crate) fn argument_to_string(value: Dynamic, strict: bool) -> RhaiResult<String> {
+    let scalar = value.is_int()
+        || value.is_bool()
+        || value.is_char()
+        // Matched by name because `Dynamic::is_float` does not exist under `no_float`.
+        || matches!(value.type_name(), "f32" | "f64");
+    if scalar && !strict {
+        return Ok(value.to_string());
+    }
+    dynamic_to_string(value, "command argument")
+}
+
 pub(crate) fn ensure_same_config(a: &Arc<Config>, b: &Arc<Config>) -> RhaiResult<()> {
     if Arc::ptr_eq(a, b) {
         Ok(())
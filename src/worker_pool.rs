@@ -0,0 +1,73 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// How long a worker waits for another job before its thread exits.
+const KEEP_ALIVE: Duration = Duration::from_secs(10);
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// Threads that read pipes and wait on children for every pipeline started
+/// under one `Config`, reused across runs instead of spawned per run. Jobs
+/// block for as long as a child runs, so the pool grows whenever no worker is
+/// free; idle workers exit after [`KEEP_ALIVE`].
+#[derive(Default)]
+pub(crate) struct WorkerPool {
+    state: Mutex<PoolState>,
+    queued: Condvar,
+}
+
+#[derive(Default)]
+struct PoolState {
+    jobs: VecDeque<Job>,
+    idle: usize,
+}
+
+impl fmt::Debug for WorkerPool {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = self.state.lock().unwrap_or_else(|err| err.into_inner());
+        f.debug_struct("WorkerPool")
+            .field("queued", &state.jobs.len())
+            .field("idle", &state.idle)
+            .finish()
+    }
+}
+
+impl WorkerPool {
+    /// Runs `job` on an idle worker, or on a new one if all are busy.
+    pub(crate) fn execute(self: &Arc<Self>, job: impl FnOnce() + Send + 'static) {
+        let mut state = self.state.lock().unwrap_or_else(|err| err.into_inner());
+        state.jobs.push_back(Box::new(job));
+        if state.idle >= state.jobs.len() {
+            self.queued.notify_one();
+            return;
+        }
+        drop(state);
+        let pool = Arc::clone(self);
+        thread::spawn(move || pool.work());
+    }
+
+    fn work(&self) {
+        let mut state = self.state.lock().unwrap_or_else(|err| err.into_inner());
+        loop {
+            if let Some(job) = state.jobs.pop_front() {
+                drop(state);
+                job();
+                state = self.state.lock().unwrap_or_else(|err| err.into_inner());
+                continue;
+            }
+            state.idle += 1;
+            let (next, wait) = self
+                .queued
+                .wait_timeout(state, KEEP_ALIVE)
+                .unwrap_or_else(|err| err.into_inner());
+            state = next;
+            state.idle -= 1;
+            if wait.timed_out() && state.jobs.is_empty() {
+                return;
+            }
+        }
+    }
+}
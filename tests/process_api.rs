@@ -1,5 +1,7 @@
 use rhai::{Engine, EvalAltResult, ImmutableString};
-use rhai_process::{module, register, Config};
+use rhai_process::{module, register, CommandMatch, Config};
+#[cfg(feature = "json")]
+use rhai_process::{PipeBuilder, ProcessResult};
 use std::sync::{Arc, Mutex};
 use tempfile::tempdir;
 
@@ -45,224 +47,2747 @@ fn pipeline_passes_stdout() -> Result<(), Box<EvalAltResult>> {
 }
 
 #[test]
-fn global_cmd_alias_available() -> Result<(), Box<EvalAltResult>> {
+fn pipeline_reports_stage_statuses() -> Result<(), Box<EvalAltResult>> {
     let engine = engine_with(Config::default());
     let script = r#"
-        let result = cmd(["python3", "-c", "print('hi')"]).build().run();
-        result.success && result.stdout.contains("hi")
+        let result = process::cmd(["python3", "-c", "import sys; print('x'); sys.exit(3)"])
+            .pipe(process::cmd(["python3", "-c", "import sys; sys.stdin.read()"]))
+            .pipe(process::cmd(["python3", "-c", "print('ok')"]))
+            .build()
+            .run();
+        let single = process::cmd(["python3", "-c", "pass"]).build().run();
+        result.stage_statuses == [3, 0, 0] && result.status == 3 && !result.success
+            && single.stage_statuses == [0]
     "#;
     assert!(eval_bool(&engine, script)?);
     Ok(())
 }
 
 #[test]
-fn allow_commands_whitelist() {
-    let engine = engine_with(Config::default().allow_commands(["python3"]));
+fn pipefail_fails_on_any_stage() -> Result<(), Box<EvalAltResult>> {
+    let engine = engine_with(Config::default());
     let script = r#"
-        process::cmd(["ls"]).build().run();
-        true
+        let producer = process::cmd(["python3", "-c", "import sys; sys.exit(4)"]);
+        let consumer = process::cmd(["python3", "-c", "import sys; sys.stdin.read()"]);
+        let plain = producer.pipe(consumer).build().run();
+        let last = producer.pipe(consumer).build().last_stage_status().run();
+        let lenient = producer.pipe(consumer).build().pipefail(false).run();
+        let allowed = producer.pipe(consumer).build().allow_exit_codes([4]).run();
+        !plain.success && plain.status == 4 && last.success && last.status == 0
+            && lenient.success && allowed.success
     "#;
-    let err = engine
-        .eval::<bool>(script)
-        .expect_err("ls should be blocked");
-    assert!(err.to_string().contains("not permitted"));
+    assert!(eval_bool(&engine, script)?);
+    Ok(())
 }
 
 #[test]
-fn deny_commands_blacklist() {
-    let engine = engine_with(Config::default().deny_commands(["ls"]));
+fn then_runs_next_only_on_success() -> Result<(), Box<EvalAltResult>> {
+    let engine = engine_with(Config::default());
     let script = r#"
-        process::cmd(["ls"]).build().run();
-        true
+        let ok = process::cmd(["python3", "-c", "print('one')"])
+            .then(process::cmd(["python3", "-c", "print('two')"]).build().timeout(5000))
+            .run();
+        let failed = process::cmd(["python3", "-c", "import sys; sys.exit(2)"])
+            .then(process::cmd(["python3", "-c", "print('never')"]))
+            .then(process::cmd(["python3", "-c", "print('nor this')"]))
+            .run();
+        ok.success && ok.stdout.contains("one") && ok.stdout.contains("two") && ok.steps.len() == 2
+            && !failed.success && failed.status == 2 && failed.steps.len() == 1
+            && !failed.stdout.contains("never")
     "#;
-    let err = engine
-        .eval::<bool>(script)
-        .expect_err("ls should be denied");
-    assert!(err.to_string().contains("not permitted"));
+    assert!(eval_bool(&engine, script)?);
+    Ok(())
 }
 
 #[test]
-fn env_injection_and_whitelist() -> Result<(), Box<EvalAltResult>> {
-    let engine = engine_with(Config::default().allow_env_vars(["RHAI_PROCESS_TEST"]));
+fn results_are_typed_with_map_conversion() -> Result<(), Box<EvalAltResult>> {
+    let engine = engine_with(Config::default());
     let script = r#"
-        let result = process::cmd(["env"]).env(#{ "RHAI_PROCESS_TEST": "ok" }).build().run();
-        result.stdout.contains("RHAI_PROCESS_TEST=ok")
+        let result = process::cmd(["python3", "-c", "print(' hi ')"]).build().run();
+        let map = result.to_map();
+        result.stdout.trim();
+        let sequence = process::cmd(["python3", "-c", "print('one')"])
+            .then(process::cmd(["python3", "-c", "print('two')"]))
+            .run();
+        type_of(result) == "ProcessResult" && result.stdout == "hi" && result.combined == ()
+            && type_of(map) == "map" && map.stdout == " hi \n" && !("combined" in map)
+            && !("steps" in map) && result.steps == ()
+            && type_of(sequence.steps[1]) == "ProcessResult" && sequence.steps[1].stdout == "two\n"
+            && sequence.to_map().steps[0].stdout == "one\n"
     "#;
     assert!(eval_bool(&engine, script)?);
+    Ok(())
+}
 
-    let forbidden = r#"
-        process::cmd(["env"]).env(#{ "OTHER": "nope" }).build().run();
-        true
+#[test]
+fn result_lines_split_output() -> Result<(), Box<EvalAltResult>> {
+    let engine = engine_with(Config::default());
+    let script = r#"
+        let result = process::cmd([
+            "python3", "-c",
+            "import sys; sys.stdout.write('a\\r\\n\\nb\\n'); sys.stderr.write('oops')"
+        ]).build().run();
+        let lines = result.stdout_lines();
+        let kept = result.stdout_lines(true);
+        lines.len() == 3 && lines[0] == "a" && lines[1] == "" && lines[2] == "b"
+            && kept.len() == 4 && kept[3] == ""
+            && result.stderr_lines() == ["oops"] && result.stderr_lines(true) == ["oops"]
     "#;
-    let err = engine
-        .eval::<bool>(forbidden)
-        .expect_err("OTHER should be blocked");
-    assert!(err.to_string().contains("not permitted"));
+    assert!(eval_bool(&engine, script)?);
     Ok(())
 }
 
 #[test]
-fn deny_env_vars_blocks_key() {
-    let engine = engine_with(Config::default().deny_env_vars(["BLOCKED"]));
+fn or_else_falls_back_on_failure() -> Result<(), Box<EvalAltResult>> {
+    let engine = engine_with(Config::default());
     let script = r#"
-        process::cmd(["env"]).env(#{ "BLOCKED": "1" }).build().run();
-        true
+        let missing = process::cmd(["rhai-process-missing-program"])
+            .or_else(process::cmd(["python3", "-c", "print('fallback')"]))
+            .run();
+        let failed = process::cmd(["python3", "-c", "import sys; print('first'); sys.exit(1)"])
+            .or_else(process::cmd(["python3", "-c", "print('second')"]))
+            .run();
+        let skipped = process::cmd(["python3", "-c", "print('first')"])
+            .or_else(process::cmd(["python3", "-c", "print('second')"]))
+            .run();
+        missing.success && missing.stdout.contains("fallback")
+            && failed.success && failed.stdout == "second\n" && failed.steps.len() == 2
+            && skipped.stdout == "first\n" && skipped.steps.len() == 1
+    "#;
+    assert!(eval_bool(&engine, script)?);
+    Ok(())
+}
+
+#[test]
+fn pipe_stderr_and_both_feed_next_stage() -> Result<(), Box<EvalAltResult>> {
+    let engine = engine_with(Config::default());
+    let script = r#"
+        let app = process::cmd(["python3", "-c", "import sys; print('out'); sys.stdout.flush(); print('ERROR bad', file=sys.stderr)"]);
+        let upper = process::cmd(["python3", "-c", "import sys; sys.stdout.write(sys.stdin.read().upper())"]);
+        let errors = app.pipe_stderr(upper).build().run();
+        let both = app.pipe_both(upper).build().run();
+        let nested = app.pipe(upper).pipe_both(upper).build().run();
+        errors.stdout == "ERROR BAD\n" && errors.stderr == "out\n"
+            && both.stdout.contains("OUT") && both.stdout.contains("ERROR BAD") && both.stderr == ""
+            && nested.stdout == "OUT\n" && nested.stderr == "ERROR bad\n"
     "#;
+    assert!(eval_bool(&engine, script)?);
+    Ok(())
+}
+
+#[test]
+fn pipeline_from_arrays() -> Result<(), Box<EvalAltResult>> {
+    let engine = engine_with(Config::default());
+    let script = r#"
+        let stages = [["python3", "-c", "print('foo')"]];
+        for i in 0..2 {
+            stages.push(["python3", "-c", "import sys; sys.stdout.write(sys.stdin.read() * 2)"]);
+        }
+        let result = process::pipeline(stages).build().run();
+        result.stdout == "foo\nfoo\nfoo\nfoo\n" && result.stage_statuses.len() == 3
+    "#;
+    assert!(eval_bool(&engine, script)?);
+
     let err = engine
+        .eval::<bool>(r#"process::pipeline([]); true"#)
+        .expect_err("empty pipeline should fail");
+    assert!(err.to_string().contains("at least one command"));
+    Ok(())
+}
+
+#[test]
+fn pipe_operator_builds_pipelines() -> Result<(), Box<EvalAltResult>> {
+    let engine = engine_with(Config::default());
+    let script = r#"
+        let result = (process::cmd(["python3", "-c", "print('foo')"])
+            | process::cmd(["python3", "-c", "import sys; sys.stdout.write(sys.stdin.read().upper())"])
+            | process::cmd(["python3", "-c", "import sys; sys.stdout.write(sys.stdin.read() + '!')"]))
+            .build()
+            .run();
+        result.stdout == "FOO\n!" && result.stage_statuses.len() == 3
+    "#;
+    assert!(eval_bool(&engine, script)?);
+    Ok(())
+}
+
+#[test]
+fn arg_and_args_append_arguments() -> Result<(), Box<EvalAltResult>> {
+    let engine = engine_with(Config::default());
+    let script = r#"
+        let extra = ["b", "c"];
+        let result = process::cmd(["python3", "-c", "import sys; print(' '.join(sys.argv[1:]))"])
+            .arg("a")
+            .args(extra)
+            .args([])
+            .build()
+            .run();
+        result.stdout == "a b c\n"
+    "#;
+    assert!(eval_bool(&engine, script)?);
+    Ok(())
+}
+
+#[test]
+fn cmd_overloads_build_commands() -> Result<(), Box<EvalAltResult>> {
+    let engine = engine_with(Config::default());
+    let script = r#"
+        let show = "import sys; print(sys.argv[1:])";
+        let single = process::cmd("python3").args(["-c", "print('solo')"]).build().run();
+        let split = process::cmd("python3", ["-c", show, "a"]).build().run();
+        let variadic = process::cmd("python3", "-c", show, "a", "b").build().run();
+        single.stdout == "solo\n" && split.stdout == "['a']\n" && variadic.stdout == "['a', 'b']\n"
+    "#;
+    assert!(eval_bool(&engine, script)?);
+    Ok(())
+}
+
+#[test]
+fn scalar_arguments_are_stringified() -> Result<(), Box<EvalAltResult>> {
+    let script = r#"
+        let result = process::cmd(["python3", "-c", "import sys; print(sys.argv[1:])", 2, true, 'x'])
+            .arg(1.5)
+            .args([-3])
+            .build()
+            .run();
+        result.stdout == "['2', 'true', 'x', '1.5', '-3']\n"
+    "#;
+    assert!(eval_bool(&engine_with(Config::default()), script)?);
+
+    let strict = engine_with(Config::default().strict_argument_types(true));
+    let err = strict
         .eval::<bool>(script)
-        .expect_err("BLOCKED should be denied");
-    assert!(err.to_string().contains("not permitted"));
+        .expect_err("strict mode should reject non-string arguments");
+    assert!(err
+        .to_string()
+        .contains("command argument must be a string"));
+    Ok(())
 }
 
 #[test]
-fn env_var_sets_single_entry() -> Result<(), Box<EvalAltResult>> {
-    let engine = engine_with(Config::default().allow_env_vars(["SINGLE_VAR"]));
+fn cmd_fmt_substitutes_whole_arguments() -> Result<(), Box<EvalAltResult>> {
+    let engine = engine_with(Config::default());
     let script = r#"
-        let result = process::cmd(["env"]).env_var("SINGLE_VAR", "value").build().run();
-        result.stdout.contains("SINGLE_VAR=value")
+        let show = "import sys; print(sys.argv[1:])";
+        let result = process::cmd_fmt("python3 -c {} {} --n={} {{}}", show, "a b; rm -rf /", 3)
+            .build()
+            .run();
+        result.stdout == "['a b; rm -rf /', '--n=3', '{}']\n"
     "#;
     assert!(eval_bool(&engine, script)?);
+
+    for (script, message) in [
+        (
+            r#"process::cmd_fmt("echo {} {}", "a"); true"#,
+            "more placeholders",
+        ),
+        (
+            r#"process::cmd_fmt("echo {}", ["a", "b"]); true"#,
+            "more values",
+        ),
+    ] {
+        let err = engine
+            .eval::<bool>(script)
+            .expect_err("mismatch should fail");
+        assert!(err.to_string().contains(message));
+    }
     Ok(())
 }
 
 #[test]
-fn allow_exit_codes_mark_success() -> Result<(), Box<EvalAltResult>> {
+fn split_follows_shell_word_rules() -> Result<(), Box<EvalAltResult>> {
     let engine = engine_with(Config::default());
     let script = r#"
-        let result = process::cmd(["false"]).build().allow_exit_codes([1]).run();
-        result.success
+        process::split("ls -la 'my dir'") == ["ls", "-la", "my dir"]
+            && process::split(`a\ b "c \"d\" $e" '' x\
+y # comment`) == ["a b", "c \"d\" $e", "", "xy"]
+            && process::split("  ") == []
     "#;
-    assert!(
-        eval_bool(&engine, script)?,
-        "exit code 1 should be tolerated"
-    );
+    assert!(eval_bool(&engine, script)?);
+
+    let err = engine
+        .eval::<bool>(r#"process::split("echo 'oops"); true"#)
+        .expect_err("unterminated quote should fail");
+    assert!(err.to_string().contains("missing closing single quote"));
     Ok(())
 }
 
 #[test]
-fn default_timeout_triggers_error() {
-    let engine = engine_with(Config::default().default_timeout_ms(100));
+fn quote_round_trips_through_sh() -> Result<(), Box<EvalAltResult>> {
+    let engine = engine_with(Config::default());
     let script = r#"
-        process::cmd(["python3", "-c", "import time; time.sleep(1)"]).build().run();
-        true
+        let values = ["plain", "", "it's", "a b; $(rm -rf /) `x` \"q\"", 3];
+        let line = process::quote_all(values);
+        let result = process::cmd(["sh", "-c", "python3 -c 'import sys; print(sys.argv[1:])' " + line]).build().run();
+        process::quote("safe/path-1.txt") == "safe/path-1.txt"
+            && process::quote("it's") == "'it'\\''s'"
+            && result.stdout == "['plain', '', \"it's\", 'a b; $(rm -rf /) `x` \"q\"', '3']\n"
     "#;
-    let err = engine.eval::<bool>(script).expect_err("should time out");
-    assert!(err.to_string().contains("timed out") || err.to_string().contains("I/O error"));
+    assert!(eval_bool(&engine, script)?);
+    Ok(())
 }
 
+#[cfg(unix)]
 #[test]
-fn capture_reports_duration() -> Result<(), Box<EvalAltResult>> {
+fn shell_requires_opt_in() -> Result<(), Box<EvalAltResult>> {
+    let dir = tempdir().expect("tempdir");
+    std::fs::write(dir.path().join("a.toml"), "").expect("write temp file");
+    std::fs::write(dir.path().join("b.toml"), "").expect("write temp file");
+    let script = format!(
+        r#"
+        let result = process::shell("ls *.toml | wc -l").build().cwd("{dir}").run();
+        result.stdout.trim();
+        result.stdout == "2"
+        "#,
+        dir = dir.path().to_str().unwrap()
+    );
+    let engine = engine_with(Config::default().allow_shell(true));
+    assert!(eval_bool(&engine, &script)?);
+
+    let err = engine_with(Config::default())
+        .eval::<bool>(&script)
+        .expect_err("shell should be disabled by default");
+    assert!(err.to_string().contains("shell execution is disabled"));
+
+    let err = engine_with(Config::default().allow_shell(true).deny_commands(["sh"]))
+        .eval::<bool>(&script)
+        .expect_err("a denied shell should not run");
+    assert!(err.to_string().contains("command 'sh' is not permitted"));
+
+    let err = engine_with(
+        Config::default()
+            .allow_shell(true)
+            .search_path([dir.path()]),
+    )
+    .eval::<bool>(&script)
+    .expect_err("the shell should be looked up in the search path");
+    assert!(err.to_string().contains("not found in the search path"));
+    Ok(())
+}
+
+#[test]
+fn builders_can_be_inspected() -> Result<(), Box<EvalAltResult>> {
     let engine = engine_with(Config::default());
     let script = r#"
-        let result = process::cmd(["python3", "-c", "print('ok')"]).build().run();
-        result.duration_ms >= 0
+        let grep = process::cmd(["grep", "two words"]).env_var("LC_ALL", "C");
+        let pipeline = process::cmd(["ls", "-la"]).pipe(grep);
+        let executor = pipeline.build();
+        grep.program() == "grep" && grep.args() == ["two words"] && grep.env_map().LC_ALL == "C"
+            && grep.to_string() == "grep 'two words'"
+            && pipeline.program() == ["ls", "grep"] && pipeline.args() == [["-la"], ["two words"]]
+            && executor.env_map()[1].LC_ALL == "C"
+            && executor.to_string() == "ls -la | grep 'two words'"
+            && `${executor}` == executor.to_string()
     "#;
     assert!(eval_bool(&engine, script)?);
     Ok(())
 }
 
 #[test]
-fn cwd_switches_directory() -> Result<(), Box<EvalAltResult>> {
+fn dry_run_describes_without_running() -> Result<(), Box<EvalAltResult>> {
     let dir = tempdir().expect("tempdir");
-    let file_path = dir.path().join("hello.txt");
-    std::fs::write(&file_path, "hi").expect("write temp file");
-    let dir_str = dir.path().to_str().unwrap();
+    let marker = dir.path().join("ran");
     let script = format!(
         r#"
-        let result = process::cmd(["ls"])
+        let plan = process::cmd(["touch", "{marker}"])
+            .env_var("A", "1")
+            .timeout(500)
+            .pipe(process::cmd(["cat"]).cwd("/"))
             .build()
             .cwd("{dir}")
-            .run();
-        result.stdout.contains("hello.txt")
+            .timeout(2000)
+            .dry_run();
+        plan.command == "touch {marker} | cat" && plan.timeout_ms == 2000 && plan.idle_timeout_ms == ()
+            && plan.stages[0].env.A == "1" && plan.stages[0].timeout_ms == 500
+            && plan.stages[0].cwd == "{dir}" && plan.stages[1].cwd == "/"
+            && plan.stages[1].timeout_ms == ()
         "#,
-        dir = dir_str
+        marker = marker.to_str().unwrap(),
+        dir = dir.path().to_str().unwrap()
     );
     let engine = engine_with(Config::default());
     assert!(eval_bool(&engine, &script)?);
+    assert!(!marker.exists(), "dry_run must not execute anything");
     Ok(())
 }
 
 #[test]
-fn cwd_invalid_directory_errors() {
+fn templates_can_be_reused() -> Result<(), Box<EvalAltResult>> {
     let engine = engine_with(Config::default());
     let script = r#"
-        process::cmd(["ls"])
-            .build()
-            .cwd("/definitely/not/a/dir")
-            .run();
-        true
+        let show = "import os, sys; print(sys.argv[1:], os.environ.get('MODE'))";
+        let tpl = process::cmd(["python3", "-c", show, "base"]);
+        let runner = tpl.exec().timeout(5000);
+        let outputs = [];
+        for name in ["a", "b"] {
+            outputs.push(tpl.with_args([name]).with_env(#{ MODE: "cmd" }).exec().run().stdout);
+            outputs.push(runner.with_args([name, 1]).with_env(#{ MODE: "exec" }).run().stdout);
+        }
+        outputs == [
+            "['base', 'a'] cmd\n", "['base', 'a', '1'] exec\n",
+            "['base', 'b'] cmd\n", "['base', 'b', '1'] exec\n",
+        ] && tpl.args() == ["-c", show, "base"]
     "#;
-    let err = engine
-        .eval::<bool>(script)
-        .expect_err("invalid cwd should fail");
-    assert!(err.to_string().contains("I/O error") || err.to_string().contains("timed out"));
+    assert!(eval_bool(&engine, script)?);
+    Ok(())
 }
 
 #[test]
-fn per_command_timeout_applies() {
+fn global_cmd_alias_available() -> Result<(), Box<EvalAltResult>> {
     let engine = engine_with(Config::default());
     let script = r#"
-        process::cmd(["python3", "-c", "import time; time.sleep(1)"])
-            .build()
-            .timeout(100)
-            .run();
+        let result = cmd(["python3", "-c", "print('hi')"]).build().run();
+        result.success && result.stdout.contains("hi")
+    "#;
+    assert!(eval_bool(&engine, script)?);
+    Ok(())
+}
+
+#[test]
+fn allow_commands_whitelist() {
+    let engine = engine_with(Config::default().allow_commands(["python3"]));
+    let script = r#"
+        process::cmd(["ls"]).build().run();
         true
     "#;
     let err = engine
         .eval::<bool>(script)
-        .expect_err("per-command timeout should trigger");
-    assert!(err.to_string().contains("timed out") || err.to_string().contains("I/O error"));
+        .expect_err("ls should be blocked");
+    assert!(err.to_string().contains("not permitted"));
 }
 
 #[test]
-#[should_panic(expected = "default_timeout_ms must be greater than zero")]
-fn default_timeout_zero_rejected() {
-    let _ = Config::default().default_timeout_ms(0);
+fn deny_commands_blacklist() {
+    let engine = engine_with(Config::default().deny_commands(["ls"]));
+    let script = r#"
+        process::cmd(["ls"]).build().run();
+        true
+    "#;
+    let err = engine
+        .eval::<bool>(script)
+        .expect_err("ls should be denied");
+    assert!(err.to_string().contains("not permitted"));
 }
 
 #[test]
-fn run_stream_returns_empty_buffers() -> Result<(), Box<EvalAltResult>> {
-    let engine = engine_with(Config::default());
-    let script = r#"
-        let result = process::cmd(["python3", "-c", "print('hi')"])
-            .build()
-            .run_stream();
-        result.stdout == "" && result.stderr == "" && result.success
+fn deny_commands_take_precedence_over_allow() -> Result<(), Box<EvalAltResult>> {
+    let engine = engine_with(
+        Config::default()
+            .deny_commands(["ls"])
+            .allow_commands(["python3", "ls"]),
+    );
+    let allowed = r#"
+        process::cmd(["python3", "-c", "print('ok')"]).build().run().stdout == "ok\n"
     "#;
-    assert!(eval_bool(&engine, script)?);
+    assert!(eval_bool(&engine, allowed)?);
+
+    for script in [
+        r#"process::cmd(["ls"]).build().run(); true"#,
+        r#"process::cmd(["env"]).build().run(); true"#,
+    ] {
+        let err = engine
+            .eval::<bool>(script)
+            .expect_err("command should be blocked");
+        assert!(err.to_string().contains("not permitted"));
+    }
     Ok(())
 }
 
 #[test]
-fn run_stream_invokes_callbacks() -> Result<(), Box<EvalAltResult>> {
-    let stdout_log = Arc::new(Mutex::new(Vec::<String>::new()));
-    let stderr_log = Arc::new(Mutex::new(Vec::<String>::new()));
-    let mut engine = engine_with(Config::default());
+fn command_policy_accepts_globs_and_regexes() -> Result<(), Box<EvalAltResult>> {
+    let engine = engine_with(
+        Config::default()
+            .allow_commands(["/usr/bin/*", r"re:python3(\.\d+)?"])
+            .deny_commands(["/usr/bin/e[nx]?"]),
+    );
+    let allowed = r#"
+        process::cmd(["/usr/bin/true"]).build().run().success
+            && process::cmd(["python3", "-c", "print('ok')"]).build().run().stdout == "ok\n"
+    "#;
+    assert!(eval_bool(&engine, allowed)?);
 
-    {
-        let log = stdout_log.clone();
-        engine.register_fn("record_out", move |text: ImmutableString| {
-            log.lock().unwrap().push(text.into());
-        });
+    for script in [
+        r#"process::cmd(["/usr/bin/env"]).build().run(); true"#,
+        r#"process::cmd(["ls"]).build().run(); true"#,
+        r#"process::cmd(["python3x"]).build().run(); true"#,
+    ] {
+        let err = engine
+            .eval::<bool>(script)
+            .expect_err("command should be blocked");
+        assert!(err.to_string().contains("not permitted"));
     }
+    Ok(())
+}
 
-    {
-        let log = stderr_log.clone();
-        engine.register_fn("record_err", move |text: ImmutableString| {
-            log.lock().unwrap().push(text.into());
-        });
+#[test]
+fn invalid_policy_pattern_denies_commands() {
+    for config in [
+        Config::default().allow_commands(["echo", "re:("]),
+        Config::default().deny_commands(["re:[z-a]"]),
+        Config::default().allow_command_with_args("echo", ["re:("]),
+        Config::default().deny_env_vars(["re:("]),
+    ] {
+        let err = engine_with(config)
+            .eval::<bool>(r#"process::cmd(["echo", "hi"]).build().run().success"#)
+            .expect_err("a broken policy should deny every command");
+        assert!(err.to_string().contains("invalid pattern 're:"), "{err}");
     }
+}
 
-    let script = r#"
-        fn out_cb(text) { record_out(text); }
-        fn err_cb(text) { record_err(text); }
-        let result = process::cmd(["python3", "-c", "import sys; sys.stdout.write('foo'); sys.stderr.write('bar')"])
-            .build()
-            .run_stream(out_cb, err_cb);
-        result.success
+#[test]
+fn allow_command_with_args_restricts_arguments() -> Result<(), Box<EvalAltResult>> {
+    let engine = engine_with(
+        Config::default()
+            .allow_commands(["ls", "python3"])
+            .allow_command_with_args("python3", ["-c", "re:print\\('[a-z]+'\\)"]),
+    );
+    let allowed = r#"
+        process::cmd("python3", ["-c", "print('ok')"]).build().run().stdout == "ok\n"
     "#;
-    assert!(eval_bool(&engine, script)?);
-    assert!(!stdout_log.lock().unwrap().is_empty());
-    assert!(!stderr_log.lock().unwrap().is_empty());
+    assert!(eval_bool(&engine, allowed)?);
+
+    for script in [
+        r#"process::cmd(["python3", "-c", "import os"]).build().run(); true"#,
+        r#"process::cmd(["python3"]).arg("-V").build().run(); true"#,
+        r#"process::cmd(["python3", "-c"]).build().with_args(["print(1)"]).run(); true"#,
+    ] {
+        let err = engine
+            .eval::<bool>(script)
+            .expect_err("argument should be blocked");
+        assert!(err
+            .to_string()
+            .contains("not permitted for command 'python3'"));
+    }
+
+    let engine = engine_with(Config::default().allow_command_with_args("python3", ["-V"]));
+    assert!(eval_bool(
+        &engine,
+        r#"process::cmd(["echo", "hi"]).build().run().success"#
+    )?);
+    let engine = engine_with(
+        Config::default()
+            .allow_commands(["ls"])
+            .allow_command_with_args("python3", ["-V"]),
+    );
+    let err = engine
+        .eval::<bool>(r#"process::cmd(["python3", "-V"]).build().run().success"#)
+        .expect_err("the allow list should still apply");
+    assert!(err
+        .to_string()
+        .contains("command 'python3' is not permitted"));
+    Ok(())
+}
+
+#[test]
+fn command_filter_can_veto_commands() -> Result<(), Box<EvalAltResult>> {
+    let engine = engine_with(Config::default().command_filter(|request| {
+        if request.cwd() == Some(std::path::Path::new("/")) {
+            return Err("commands may not run in /".into());
+        }
+        match request.env().get("CONTEXT").map(String::as_str) {
+            Some("staging") => Ok(()),
+            _ => Err(format!("{} requires CONTEXT=staging", request.program())),
+        }
+    }));
+    let allowed = r#"
+        process::cmd(["python3", "-c", "print('ok')"]).env_var("CONTEXT", "staging")
+            .build().run().stdout == "ok\n"
+    "#;
+    assert!(eval_bool(&engine, allowed)?);
+
+    let err = engine
+        .eval::<bool>(r#"process::cmd(["python3", "-V"]).build().run(); true"#)
+        .expect_err("missing context should be vetoed");
+    assert!(err.to_string().contains("python3 requires CONTEXT=staging"));
+
+    let err = engine
+        .eval::<bool>(
+            r#"
+            let ok = process::cmd(["true"]).env_var("CONTEXT", "staging");
+            (ok | process::cmd(["true"]).cwd("/").env_var("CONTEXT", "staging")).build().run();
+            true
+            "#,
+        )
+        .expect_err("cwd should be vetoed");
+    assert!(err.to_string().contains("commands may not run in /"));
+    Ok(())
+}
+
+#[test]
+fn allow_cwd_under_restricts_working_directories() -> Result<(), Box<EvalAltResult>> {
+    let dir = tempdir().expect("tempdir");
+    std::fs::create_dir(dir.path().join("sub")).expect("create subdir");
+    let engine = engine_with(Config::default().allow_cwd_under([dir.path()]));
+    let root = dir.path().to_str().unwrap();
+    let allowed = format!(
+        r#"
+        let result = process::cmd(["pwd"]).cwd("{root}/sub").build().run();
+        process::cmd(["pwd"]).build().cwd("{root}").run().success
+            && result.stdout.ends_with("sub\n")
+        "#
+    );
+    assert!(eval_bool(&engine, &allowed)?);
+
+    for script in [
+        r#"process::cmd(["pwd"]).build().cwd("/etc"); true"#.to_string(),
+        format!(r#"process::cmd(["pwd"]).cwd("{root}/sub/../.."); true"#),
+        format!(r#"process::cmd(["pwd"]).cwd("{root}/missing"); true"#),
+    ] {
+        let err = engine
+            .eval::<bool>(&script)
+            .expect_err("cwd should be rejected");
+        assert!(err.to_string().contains("is not permitted"));
+    }
+    Ok(())
+}
+
+#[test]
+fn match_command_by_basename_and_resolved_path() -> Result<(), Box<EvalAltResult>> {
+    let engine = engine_with(
+        Config::default()
+            .deny_commands(["ls"])
+            .match_command_by(CommandMatch::Basename),
+    );
+    let err = engine
+        .eval::<bool>(r#"process::cmd(["/bin/ls"]).build().run(); true"#)
+        .expect_err("/bin/ls should match the basename");
+    assert!(err.to_string().contains("not permitted"));
+
+    let resolved = std::fs::canonicalize("/usr/bin/true").expect("canonical true");
+    let engine = engine_with(
+        Config::default()
+            .allow_commands([resolved.to_str().unwrap()])
+            .match_command_by(CommandMatch::ResolvedPath),
+    );
+    let allowed = r#"
+        process::cmd(["true"]).build().run().success
+            && process::cmd(["/usr/bin/true"]).build().run().success
+            && process::cmd(["true"]).env_var("PATH", "/nonexistent").build().run().success
+    "#;
+    assert!(eval_bool(&engine, allowed)?);
+    for script in [
+        r#"process::cmd(["ls"]).build().run(); true"#,
+        r#"process::cmd(["no-such-program-rhai-process"]).build().run(); true"#,
+    ] {
+        let err = engine
+            .eval::<bool>(script)
+            .expect_err("command should be blocked");
+        assert!(err.to_string().contains("not permitted"));
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn search_path_restricts_program_resolution() -> Result<(), Box<EvalAltResult>> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = tempdir().expect("tempdir");
+    let tool = dir.path().join("tool");
+    std::fs::write(&tool, "#!/bin/sh\necho \"$PATH\"\n").expect("write tool");
+    std::fs::set_permissions(&tool, std::fs::Permissions::from_mode(0o755)).expect("chmod");
+    let engine = engine_with(Config::default().search_path([dir.path()]));
+    let script = format!(
+        r#"
+        let result = process::cmd(["tool"]).env_var("PATH", "/usr/bin").build().run();
+        result.stdout == "{dir}\n" && process::cmd(["{dir}/tool"]).build().run().success
+        "#,
+        dir = dir.path().to_str().unwrap()
+    );
+    assert!(eval_bool(&engine, &script)?);
+
+    for script in [
+        r#"process::cmd(["true"]).build().run(); true"#,
+        r#"process::cmd(["/usr/bin/true"]).build().run(); true"#,
+    ] {
+        let err = engine
+            .eval::<bool>(script)
+            .expect_err("program outside the search path should be rejected");
+        assert!(err.to_string().contains("not found in the search path"));
+    }
+    Ok(())
+}
+
+#[test]
+fn locked_down_preset_restricts_scripts() -> Result<(), Box<EvalAltResult>> {
+    let engine = engine_with(Config::locked_down().allow_commands(["python3"]));
+    let script = r#"
+        let result = process::cmd(["/usr/bin/python3", "-c", "print('x' * 2000000)"]).build().run();
+        result.success && result.stdout.len == 1024 * 1024
+    "#;
+    assert!(eval_bool(&engine, script)?);
+
+    for script in [
+        r#"process::cmd(["ls"]); true"#,
+        r#"process::cmd(["python3"]).env_var("A", "1"); true"#,
+        r#"process::cmd(["python3"]).cwd("/tmp"); true"#,
+    ] {
+        let err = engine
+            .eval::<bool>(script)
+            .expect_err("locked down config should reject this");
+        assert!(err.to_string().contains("not permitted"));
+    }
+    Ok(())
+}
+
+#[cfg(feature = "toml")]
+#[test]
+fn config_loads_from_toml() -> Result<(), Box<EvalAltResult>> {
+    let config = Config::from_toml_str(
+        r#"
+        preset = "locked_down"
+        allow_commands = ["python3"]
+        match_command_by = "full_path"
+        max_output_bytes = 4
+        "#,
+    )
+    .expect("valid config");
+    let engine = engine_with(config);
+    let script = r#"
+        process::cmd(["python3", "-c", "print('hello')"]).build().run().stdout == "hell"
+    "#;
+    assert!(eval_bool(&engine, script)?);
+    let err = engine
+        .eval::<bool>(r#"process::cmd(["/usr/bin/python3"]); true"#)
+        .expect_err("full path should not match");
+    assert!(err.to_string().contains("not permitted"));
+
+    let err = Config::from_toml_str("default_timeout_ms = 0").expect_err("zero timeout");
+    assert_eq!(
+        err.to_string(),
+        "default_timeout_ms must be greater than zero"
+    );
+    assert!(Config::from_toml_str("allow_comands = []").is_err());
+    Ok(())
+}
+
+#[cfg(feature = "definitions")]
+#[test]
+fn definitions_describe_builders_and_executors() {
+    let definitions = rhai_process::definitions();
+    assert!(definitions.starts_with("module static;"));
+    for signature in [
+        "fn cmd(program: String, args: array) -> CommandBuilder;",
+        "fn pipe(builder: CommandBuilder, next: CommandBuilder) -> PipeBuilder;",
+        "fn build(pipeline: PipeBuilder) -> PipelineExecutor;",
+        "fn timeout(executor: PipelineExecutor, timeout: int) -> PipelineExecutor;",
+        "fn run(executor: PipelineExecutor) -> ProcessResult;",
+    ] {
+        assert!(definitions.contains(signature), "missing {signature}");
+    }
+    assert!(definitions.contains("/// Turns the command into an executor.\nfn build("));
+    assert!(!definitions.contains("RhaiResult"));
+    assert!(definitions.contains("fn jobs() -> array;"));
+    assert!(!definitions.contains(" >;"));
+}
+
+#[cfg(all(unix, feature = "tracing"))]
+#[test]
+fn tracing_records_spawn_stage_and_exit_events() -> Result<(), Box<EvalAltResult>> {
+    use tracing_subscriber::util::SubscriberInitExt;
+
+    #[derive(Clone, Default)]
+    struct Capture(Arc<Mutex<Vec<u8>>>);
+
+    impl std::io::Write for Capture {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let capture = Capture::default();
+    let writer = capture.clone();
+    let guard = tracing_subscriber::fmt()
+        .with_writer(move || writer.clone())
+        .with_ansi(false)
+        .finish()
+        .set_default();
+    let engine = engine_with(Config::default());
+    engine.run(r#"process::cmd(["sh", "-c", "exit 3"]).build().run();"#)?;
+    drop(guard);
+
+    let logs = String::from_utf8(capture.0.lock().unwrap().clone()).expect("utf-8 logs");
+    assert!(logs.contains("stage spawned") && logs.contains("program=sh"));
+    assert!(logs.contains("args_hash=") && logs.contains("pid="));
+    assert!(logs.contains("stage exited") && logs.contains("status=3"));
+    assert!(logs.contains("pipeline exited") && logs.contains("success=false"));
+    assert!(
+        !logs.contains("exit 3"),
+        "arguments are only logged as a hash"
+    );
+    Ok(())
+}
+
+#[cfg(feature = "json")]
+#[test]
+fn config_loads_from_json() {
+    let config = Config::from_json_str(r#"{ "deny_commands": ["ls"], "allow_shell": true }"#)
+        .expect("valid config");
+    let engine = engine_with(config);
+    let err = engine
+        .eval::<bool>(r#"process::cmd(["ls"]); true"#)
+        .expect_err("ls should be denied");
+    assert!(err.to_string().contains("not permitted"));
+
+    let err = Config::from_json_str(r#"{ "allow_commands": ["re:("] }"#).expect_err("bad regex");
+    assert!(err.to_string().starts_with("invalid pattern 're:('"));
+}
+
+#[cfg(feature = "json")]
+#[test]
+fn config_results_and_pipelines_round_trip_through_serde() -> Result<(), Box<EvalAltResult>> {
+    let config = Config::locked_down()
+        .allow_commands(["echo", "cat", "re:py.*"])
+        .allow_command_with_args("git", ["status"])
+        .allow_env_vars(["HOME"])
+        .inherit_only(["HOME"]);
+    let json = serde_json::to_value(&config).expect("serialize config");
+    assert_eq!(
+        json["allow_commands"],
+        serde_json::json!(["echo", "cat", "re:py.*"])
+    );
+    assert_eq!(
+        json["allow_command_with_args"]["git"],
+        serde_json::json!(["status"])
+    );
+    assert_eq!(json["inherit_only"], serde_json::json!(["HOME"]));
+    assert_eq!(json["default_timeout_ms"], 30_000);
+    let restored: Config = serde_json::from_value(json.clone()).expect("deserialize config");
+    assert_eq!(
+        serde_json::to_value(&restored).expect("serialize again"),
+        json
+    );
+    assert!(serde_json::from_str::<Config>(r#"{ "max_concurrent": 0 }"#).is_err());
+
+    let engine = engine_with(restored.clone());
+    let err = engine
+        .eval::<bool>(r#"process::cmd(["ls"]); true"#)
+        .expect_err("ls is not allowed");
+    assert!(err.to_string().contains("not permitted"));
+
+    let result = engine.eval::<ProcessResult>(r#"process::cmd(["echo", "hi"]).build().run()"#)?;
+    let text = serde_json::to_string(&result).expect("serialize result");
+    let back: ProcessResult = serde_json::from_str(&text).expect("deserialize result");
+    assert_eq!(back.stdout(), "hi\n");
+    assert_eq!(back.stages()[0].program(), "echo");
+    assert_eq!(back.started_at(), result.started_at());
+
+    let pipeline = engine.eval::<PipeBuilder>(
+        r#"process::cmd(["echo", "hi"]).env_var("HOME", "/tmp").pipe(process::cmd(["cat"]))"#,
+    )?;
+    let json = serde_json::to_value(&pipeline).expect("serialize pipeline");
+    assert_eq!(json[0]["program"], "echo");
+    assert_eq!(json[0]["env"]["HOME"], "/tmp");
+    assert_eq!(json[0]["pipe_source"], "stdout");
+    let rebuilt = PipeBuilder::deserialize_with(restored.clone(), json).expect("valid pipeline");
+    let mut scope = rhai::Scope::new();
+    scope.push("pipeline", rebuilt);
+    let stdout = engine.eval_with_scope::<String>(&mut scope, "pipeline.build().run().stdout")?;
+    assert_eq!(stdout, "hi\n");
+
+    let err = PipeBuilder::deserialize_with(
+        restored,
+        serde_json::json!([{ "program": "cat" }, { "program": "rm", "args": ["-rf", "/"] }]),
+    )
+    .expect_err("rm is not allowed");
+    assert!(err.to_string().contains("not permitted"));
+    Ok(())
+}
+
+#[cfg(feature = "json")]
+#[test]
+fn result_json_parses_stdout() -> Result<(), Box<EvalAltResult>> {
+    let engine = engine_with(Config::default());
+    let script = r#"
+        let doc = process::cmd(["python3", "-c", "print('{\"items\": [1, 2.5, null], \"ok\": true}')"])
+            .build().run().json();
+        let list = process::cmd(["python3", "-c", "print('[\"a\"]')"]).build().run().json();
+        doc.items[0] == 1 && doc.items[1] == 2.5 && doc.items[2] == () && doc.ok && list == ["a"]
+    "#;
+    assert!(eval_bool(&engine, script)?);
+
+    let err = engine
+        .eval::<rhai::Dynamic>(
+            r#"process::cmd(["python3", "-c", "print('{\"a\": oops}')"]).build().run().json()"#,
+        )
+        .expect_err("invalid JSON");
+    let message = err.to_string();
+    assert!(message.contains("stdout is not valid JSON"), "{message}");
+    // Checked in two parts, since an error message may escape the quotes in it.
+    assert!(
+        message.contains("near '{") && message.contains("oops}'"),
+        "{message}"
+    );
+    Ok(())
+}
+
+#[test]
+fn env_clear_starts_with_empty_environment() -> Result<(), Box<EvalAltResult>> {
+    let engine = engine_with(Config::default());
+    let script = r#"
+        let only = process::cmd(["env"]).env_var("ONLY", "1").env_clear().build();
+        let piped = (process::cmd(["env"]) | process::cmd(["sort"])).build().env_clear();
+        only.run().stdout == "ONLY=1\n" && piped.run().stdout == ""
+            && only.dry_run().stages[0].env_clear
+            && !process::cmd(["env"]).build().dry_run().stages[0].env_clear
+    "#;
+    assert!(eval_bool(&engine, script)?);
+    Ok(())
+}
+
+#[test]
+fn config_controls_env_inheritance() -> Result<(), Box<EvalAltResult>> {
+    let script = r#"
+        process::cmd(["env"]).env_var("SET_BY_SCRIPT", "1").build().run().stdout
+    "#;
+    let engine = engine_with(Config::default().inherit_env(false));
+    let stdout = engine.eval::<String>(script)?;
+    assert_eq!(stdout, "SET_BY_SCRIPT=1\n");
+
+    let engine = engine_with(Config::default().inherit_only(["PATH", "RHAI_PROCESS_UNSET"]));
+    let stdout = engine.eval::<String>(script)?;
+    let mut keys: Vec<_> = stdout
+        .lines()
+        .filter_map(|line| line.split('=').next())
+        .collect();
+    keys.sort();
+    assert_eq!(keys, ["PATH", "SET_BY_SCRIPT"]);
+    Ok(())
+}
+
+#[test]
+fn env_remove_unsets_inherited_variables() -> Result<(), Box<EvalAltResult>> {
+    let engine = engine_with(Config::default());
+    let script = r#"
+        let removed = process::cmd(["env"]).env_var("TEMP_VAR", "1").env_remove("TEMP_VAR")
+            .env_remove("HOME");
+        let restored = removed.env_var("HOME", "/restored").build().run().stdout;
+        let stdout = removed.build().run().stdout;
+        let lines = stdout.split("\n");
+        lines.filter(|line| line.starts_with("HOME=") || line.starts_with("TEMP_VAR=")).is_empty()
+            && restored.contains("HOME=/restored")
+            && removed.build().dry_run().stages[0].env_remove == ["HOME", "TEMP_VAR"]
+    "#;
+    assert!(eval_bool(&engine, script)?);
+    Ok(())
+}
+
+#[test]
+fn default_env_applies_before_script_env() -> Result<(), Box<EvalAltResult>> {
+    let engine = engine_with(Config::default().default_env([("CI", "1"), ("NO_COLOR", "1")]));
+    let script = r#"
+        let show = ["python3", "-c", "import os; print(os.environ.get('CI'), os.environ.get('NO_COLOR'))"];
+        process::cmd(show).build().run().stdout == "1 1\n"
+            && process::cmd(show).env_var("CI", "0").env_remove("NO_COLOR").build().run().stdout
+                == "0 None\n"
+            && process::cmd(["env"]).env_clear().build().run().stdout == "CI=1\nNO_COLOR=1\n"
+    "#;
+    assert!(eval_bool(&engine, script)?);
+    Ok(())
+}
+
+#[test]
+fn env_value_filter_rejects_values() -> Result<(), Box<EvalAltResult>> {
+    let engine = engine_with(
+        Config::default().env_value_filter(|key, value| !value.contains('\n') && key != "LOCKED"),
+    );
+    let allowed = r#"
+        process::cmd(["env"]).env_var("OK", "fine").build().run().stdout.contains("OK=fine")
+    "#;
+    assert!(eval_bool(&engine, allowed)?);
+
+    for script in [
+        r#"process::cmd(["env"]).env_var("A", "two\nlines"); true"#,
+        r#"process::cmd(["env"]).env(#{ LOCKED: "x" }); true"#,
+        r#"process::cmd(["env"]).build().with_env(#{ B: "x\ny" }); true"#,
+    ] {
+        let err = engine
+            .eval::<bool>(script)
+            .expect_err("value should be rejected");
+        assert!(err.to_string().contains("value of environment variable"));
+    }
+    Ok(())
+}
+
+#[test]
+fn env_file_loads_dotenv_files() -> Result<(), Box<EvalAltResult>> {
+    let dir = tempdir().expect("tempdir");
+    let env_file = dir.path().join(".env");
+    std::fs::write(
+        &env_file,
+        "# settings\n\
+         export PLAIN = value # comment\n\
+         SINGLE='a \\n b'\n\
+         DOUBLE=\"line1\\nline2\"\n\
+         MULTI=\"first\nsecond\"\n\
+         EMPTY=\n",
+    )
+    .expect("write env file");
+    let engine = engine_with(Config::default().deny_env_vars(["BLOCKED"]));
+    let script = format!(
+        r#"
+        let show = "import os; print([os.environ.get(k) for k in ['PLAIN', 'SINGLE', 'DOUBLE', 'MULTI', 'EMPTY']])";
+        process::cmd(["python3", "-c", show]).env_file("{path}").build().run().stdout
+        "#,
+        path = env_file.to_str().unwrap()
+    );
+    let stdout = engine.eval::<String>(&script)?;
+    assert_eq!(
+        stdout,
+        "['value', 'a \\\\n b', 'line1\\nline2', 'first\\nsecond', '']\n"
+    );
+
+    std::fs::write(&env_file, "OK=1\nBLOCKED=1\n").expect("write env file");
+    let script = format!(
+        r#"process::cmd(["env"]).env_file("{path}"); true"#,
+        path = env_file.to_str().unwrap()
+    );
+    let err = engine.eval::<bool>(&script).expect_err("BLOCKED is denied");
+    assert!(err
+        .to_string()
+        .contains("environment variable 'BLOCKED' is not permitted"));
+
+    std::fs::write(&env_file, "OK=1\nnot a pair\n").expect("write env file");
+    let err = engine.eval::<bool>(&script).expect_err("malformed file");
+    assert!(err.to_string().contains("line 2: expected KEY=value"));
+    Ok(())
+}
+
+#[test]
+fn passthrough_env_copies_host_variables() -> Result<(), Box<EvalAltResult>> {
+    let engine = engine_with(Config::default().inherit_env(false).deny_env_vars(["PATH"]));
+    let script = r#"
+        process::cmd(["env"]).passthrough_env(["HOME", "RHAI_PROCESS_MISSING"]).build().run().stdout
+    "#;
+    let home = std::env::var("HOME").expect("HOME is set");
+    assert_eq!(engine.eval::<String>(script)?, format!("HOME={home}\n"));
+
+    let err = engine
+        .eval::<bool>(r#"process::cmd(["env"]).passthrough_env(["PATH"]); true"#)
+        .expect_err("PATH is denied");
+    assert!(err.to_string().contains("not permitted"));
+    Ok(())
+}
+
+#[test]
+fn redact_env_values_hides_secrets() -> Result<(), Box<EvalAltResult>> {
+    let config = Config::default()
+        .redact_env_values(["*TOKEN*"])
+        .redact_output(true)
+        .command_filter(|request| match request.env().get("API_TOKEN") {
+            Some(token) if token == "t0ken-abc" => Err(format!("token {token} was rejected")),
+            _ => Ok(()),
+        });
+    let engine = engine_with(config);
+    let script = r#"
+        let builder = process::cmd(["python3", "-c", "import os; print(os.environ['API_TOKEN'], 'ok')"])
+            .env_var("API_TOKEN", "s3cr3t");
+        let result = builder.build().run();
+        result.stdout == "*** ok\n" && builder.env_map() == #{ API_TOKEN: "***" }
+            && builder.build().dry_run().stages[0].env.API_TOKEN == "***"
+    "#;
+    assert!(eval_bool(&engine, script)?);
+
+    let err = engine
+        .eval::<bool>(
+            r#"process::cmd(["true"]).env_var("API_TOKEN", "t0ken-abc").build().run(); true"#,
+        )
+        .expect_err("filter rejects the token");
+    assert!(err.to_string().contains("token *** was rejected"));
+    Ok(())
+}
+
+#[test]
+fn env_reads_host_variables_through_policy() -> Result<(), Box<EvalAltResult>> {
+    let engine = engine_with(Config::default().allow_env_vars(["HOME", "RHAI_PROCESS_MISSING"]));
+    let home = std::env::var("HOME").expect("HOME is set");
+    assert_eq!(engine.eval::<String>(r#"process::env("HOME")"#)?, home);
+    assert!(eval_bool(
+        &engine,
+        r#"process::env("RHAI_PROCESS_MISSING") == ()"#
+    )?);
+
+    let err = engine
+        .eval::<bool>(r#"process::env("PATH"); true"#)
+        .expect_err("PATH is not allowed");
+    assert!(err
+        .to_string()
+        .contains("environment variable 'PATH' is not permitted"));
+    Ok(())
+}
+
+#[test]
+fn errors_are_structured_maps() -> Result<(), Box<EvalAltResult>> {
+    let engine = engine_with(Config::default().deny_commands(["ls"]));
+    let script = r#"
+        let denied = ();
+        let timeout = ();
+        let invalid = ();
+        try { process::cmd(["ls"]); } catch (err) { denied = err; }
+        try {
+            process::cmd(["sleep", "5"]).build().timeout(100).run();
+        } catch (err) { timeout = err; }
+        try { process::cmd([]); } catch (err) { invalid = err; }
+        denied.kind == "denied" && denied.command == "ls"
+            && denied.message == "command 'ls' is not permitted" && denied.status == ()
+            && timeout.kind == "timeout" && timeout.command == "sleep 5"
+            && timeout.message.contains("timed out")
+            && invalid.kind == "invalid" && invalid.command == ()
+    "#;
+    assert!(eval_bool(&engine, script)?);
+    Ok(())
+}
+
+#[test]
+fn start_errors_name_the_failed_stage() -> Result<(), Box<EvalAltResult>> {
+    let dir = tempdir().expect("tempdir");
+    let script_path = dir.path().join("not-executable.sh");
+    std::fs::write(&script_path, "#!/bin/sh\n").expect("write script");
+    let engine = engine_with(Config::default());
+    let script = format!(
+        r#"
+        let missing = ();
+        let no_dir = ();
+        let no_exec = ();
+        try {{
+            (process::cmd(["echo", "hi"]) | process::cmd(["no-such-program-xyz"])).build().run();
+        }} catch (err) {{ missing = err; }}
+        try {{ process::cmd(["pwd"]).cwd("{missing_dir}").build().run(); }} catch (err) {{ no_dir = err; }}
+        try {{ process::cmd(["{no_exec}"]).build().run(); }} catch (err) {{ no_exec = err; }}
+        missing.kind == "not_found" && missing.command == "no-such-program-xyz"
+            && missing.message == "command 'no-such-program-xyz' was not found (stage 2 of 2)"
+            && no_dir.kind == "not_found" && no_dir.message.contains("does not exist")
+            && no_exec.kind == "permission_denied" && no_exec.message.contains("permission denied")
+    "#,
+        missing_dir = dir.path().join("missing").display(),
+        no_exec = script_path.display(),
+    );
+    assert!(eval_bool(&engine, &script)?);
+    Ok(())
+}
+
+#[test]
+fn env_injection_and_whitelist() -> Result<(), Box<EvalAltResult>> {
+    let engine = engine_with(Config::default().allow_env_vars(["RHAI_PROCESS_TEST"]));
+    let script = r#"
+        let result = process::cmd(["env"]).env(#{ "RHAI_PROCESS_TEST": "ok" }).build().run();
+        result.stdout.contains("RHAI_PROCESS_TEST=ok")
+    "#;
+    assert!(eval_bool(&engine, script)?);
+
+    let forbidden = r#"
+        process::cmd(["env"]).env(#{ "OTHER": "nope" }).build().run();
+        true
+    "#;
+    let err = engine
+        .eval::<bool>(forbidden)
+        .expect_err("OTHER should be blocked");
+    assert!(err.to_string().contains("not permitted"));
+    Ok(())
+}
+
+#[test]
+fn deny_env_vars_blocks_key() {
+    let engine = engine_with(Config::default().deny_env_vars(["BLOCKED"]));
+    let script = r#"
+        process::cmd(["env"]).env(#{ "BLOCKED": "1" }).build().run();
+        true
+    "#;
+    let err = engine
+        .eval::<bool>(script)
+        .expect_err("BLOCKED should be denied");
+    assert!(err.to_string().contains("not permitted"));
+}
+
+#[test]
+fn env_var_sets_single_entry() -> Result<(), Box<EvalAltResult>> {
+    let engine = engine_with(Config::default().allow_env_vars(["SINGLE_VAR"]));
+    let script = r#"
+        let result = process::cmd(["env"]).env_var("SINGLE_VAR", "value").build().run();
+        result.stdout.contains("SINGLE_VAR=value")
+    "#;
+    assert!(eval_bool(&engine, script)?);
+    Ok(())
+}
+
+#[test]
+fn allow_exit_codes_mark_success() -> Result<(), Box<EvalAltResult>> {
+    let engine = engine_with(Config::default());
+    let script = r#"
+        let result = process::cmd(["false"]).build().allow_exit_codes([1]).run();
+        result.success
+    "#;
+    assert!(
+        eval_bool(&engine, script)?,
+        "exit code 1 should be tolerated"
+    );
+    Ok(())
+}
+
+#[test]
+fn default_timeout_triggers_error() {
+    let engine = engine_with(Config::default().default_timeout_ms(100));
+    let script = r#"
+        process::cmd(["python3", "-c", "import time; time.sleep(1)"]).build().run();
+        true
+    "#;
+    let err = engine.eval::<bool>(script).expect_err("should time out");
+    assert!(err.to_string().contains("timed out") || err.to_string().contains("I/O error"));
+}
+
+#[test]
+fn capture_reports_duration() -> Result<(), Box<EvalAltResult>> {
+    let engine = engine_with(Config::default());
+    let script = r#"
+        let result = process::cmd(["python3", "-c", "print('ok')"]).build().run();
+        result.duration_ms >= 0
+    "#;
+    assert!(eval_bool(&engine, script)?);
+    Ok(())
+}
+
+#[test]
+fn cwd_switches_directory() -> Result<(), Box<EvalAltResult>> {
+    let dir = tempdir().expect("tempdir");
+    let file_path = dir.path().join("hello.txt");
+    std::fs::write(&file_path, "hi").expect("write temp file");
+    let dir_str = dir.path().to_str().unwrap();
+    let script = format!(
+        r#"
+        let result = process::cmd(["ls"])
+            .build()
+            .cwd("{dir}")
+            .run();
+        result.stdout.contains("hello.txt")
+        "#,
+        dir = dir_str
+    );
+    let engine = engine_with(Config::default());
+    assert!(eval_bool(&engine, &script)?);
+    Ok(())
+}
+
+#[test]
+fn cwd_per_stage_overrides_pipeline() -> Result<(), Box<EvalAltResult>> {
+    let first = tempdir().expect("tempdir");
+    let second = tempdir().expect("tempdir");
+    std::fs::write(first.path().join("first.txt"), "").expect("write temp file");
+    std::fs::write(second.path().join("second.txt"), "").expect("write temp file");
+    let script = format!(
+        r#"
+        let result = process::cmd(["python3", "-c", "import os; print(os.listdir())"])
+            .cwd("{first}")
+            .pipe(process::cmd(["python3", "-c", "import os, sys; print(sys.stdin.read().strip(), os.listdir())"]))
+            .build()
+            .cwd("{second}")
+            .run();
+        result.stdout.contains("first.txt") && result.stdout.contains("second.txt")
+        "#,
+        first = first.path().to_str().unwrap(),
+        second = second.path().to_str().unwrap(),
+    );
+    let engine = engine_with(Config::default());
+    assert!(eval_bool(&engine, &script)?);
+    Ok(())
+}
+
+#[test]
+fn cwd_invalid_directory_errors() {
+    let engine = engine_with(Config::default());
+    let script = r#"
+        process::cmd(["ls"])
+            .build()
+            .cwd("/definitely/not/a/dir")
+            .run();
+        true
+    "#;
+    let err = engine
+        .eval::<bool>(script)
+        .expect_err("invalid cwd should fail");
+    assert!(err
+        .to_string()
+        .contains("working directory '/definitely/not/a/dir' does not exist"));
+}
+
+#[test]
+fn per_command_timeout_applies() {
+    let engine = engine_with(Config::default());
+    let script = r#"
+        process::cmd(["python3", "-c", "import time; time.sleep(1)"])
+            .build()
+            .timeout(100)
+            .run();
+        true
+    "#;
+    let err = engine
+        .eval::<bool>(script)
+        .expect_err("per-command timeout should trigger");
+    assert!(err.to_string().contains("timed out") || err.to_string().contains("I/O error"));
+}
+
+#[test]
+fn stage_timeout_kills_only_that_stage() -> Result<(), Box<EvalAltResult>> {
+    let engine = engine_with(Config::default());
+    let script = r#"
+        let result = process::cmd(["python3", "-c", "import sys, time; print('partial', flush=True); time.sleep(30)"])
+            .timeout(1500)
+            .pipe(process::cmd(["python3", "-c", "import sys, time; data = sys.stdin.read(); time.sleep(0.3); print('got ' + data.strip())"]))
+            .build()
+            .timeout(10000)
+            .last_stage_status()
+            .run();
+        result.success && result.stdout.contains("got partial")
+            && result.stage_statuses[0] != 0 && result.stage_statuses[1] == 0
+            && result.duration_ms < 8000
+    "#;
+    assert!(eval_bool(&engine, script)?);
+    Ok(())
+}
+
+#[test]
+fn max_concurrent_waits_for_a_free_slot() -> Result<(), Box<EvalAltResult>> {
+    let engine = engine_with(Config::default().max_concurrent(1));
+    let script = r#"
+        let first = process::cmd(["sleep", "0.3"]).build().start();
+        let started = timestamp();
+        process::cmd(["true"]).build().run();
+        let waited = started.elapsed;
+        first.wait();
+        waited >= 0.25
+    "#;
+    assert!(eval_bool(&engine, script)?);
+    Ok(())
+}
+
+#[test]
+fn total_runtime_budget_stops_further_runs() -> Result<(), Box<EvalAltResult>> {
+    let engine = engine_with(Config::default().total_runtime_budget_ms(200));
+    let script = r#"
+        process::cmd(["sleep", "0.3"]).build().run();
+        let kind = ();
+        try {
+            process::cmd(["true"]).build().run();
+        } catch (err) {
+            kind = err.kind;
+        }
+        kind == "budget"
+    "#;
+    assert!(eval_bool(&engine, script)?);
+    Ok(())
+}
+
+#[test]
+fn max_spawns_per_second_spaces_out_starts() -> Result<(), Box<EvalAltResult>> {
+    let engine = engine_with(Config::default().max_spawns_per_second(2));
+    let script = r#"
+        let started = timestamp();
+        for i in 0..3 {
+            process::cmd(["true"]).build().run();
+        }
+        started.elapsed >= 0.9
+    "#;
+    assert!(eval_bool(&engine, script)?);
+    Ok(())
+}
+
+#[test]
+fn max_pipeline_stages_limits_pipes() -> Result<(), Box<EvalAltResult>> {
+    let engine = engine_with(Config::default().max_pipeline_stages(2));
+    let ok = r#"
+        let result = process::cmd(["python3", "-c", "print('a')"])
+            .pipe(process::cmd(["python3", "-c", "import sys; print(sys.stdin.read().strip())"]))
+            .build()
+            .run();
+        result.stdout == "a\n"
+    "#;
+    assert!(eval_bool(&engine, ok)?);
+
+    for script in [
+        r#"process::cmd(["true"]) | process::cmd(["true"]) | process::cmd(["true"]); true"#,
+        r#"process::pipeline([["true"], ["true"], ["true"]]); true"#,
+    ] {
+        let err = engine
+            .eval::<bool>(script)
+            .expect_err("three stages should exceed the limit");
+        assert!(err.to_string().contains("maximum of 2 stages"));
+    }
+    Ok(())
+}
+
+#[test]
+#[should_panic(expected = "default_timeout_ms must be greater than zero")]
+fn default_timeout_zero_rejected() {
+    let _ = Config::default().default_timeout_ms(0);
+}
+
+#[test]
+fn run_stream_returns_empty_buffers() -> Result<(), Box<EvalAltResult>> {
+    let engine = engine_with(Config::default());
+    let script = r#"
+        let result = process::cmd(["python3", "-c", "print('hi')"])
+            .build()
+            .run_stream();
+        result.stdout == "" && result.stderr == "" && result.success
+    "#;
+    assert!(eval_bool(&engine, script)?);
+    Ok(())
+}
+
+#[test]
+fn run_stream_invokes_callbacks() -> Result<(), Box<EvalAltResult>> {
+    let stdout_log = Arc::new(Mutex::new(Vec::<String>::new()));
+    let stderr_log = Arc::new(Mutex::new(Vec::<String>::new()));
+    let mut engine = engine_with(Config::default());
+
+    {
+        let log = stdout_log.clone();
+        engine.register_fn("record_out", move |text: ImmutableString| {
+            log.lock().unwrap().push(text.into());
+        });
+    }
+
+    {
+        let log = stderr_log.clone();
+        engine.register_fn("record_err", move |text: ImmutableString| {
+            log.lock().unwrap().push(text.into());
+        });
+    }
+
+    let script = r#"
+        fn out_cb(text) { record_out(text); }
+        fn err_cb(text) { record_err(text); }
+        let result = process::cmd(["python3", "-c", "import sys; sys.stdout.write('foo'); sys.stderr.write('bar')"])
+            .build()
+            .run_stream(out_cb, err_cb);
+        result.success
+    "#;
+    assert!(eval_bool(&engine, script)?);
+    assert!(!stdout_log.lock().unwrap().is_empty());
+    assert!(!stderr_log.lock().unwrap().is_empty());
+    Ok(())
+}
+
+#[test]
+fn run_stream_lines_delivers_whole_lines() -> Result<(), Box<EvalAltResult>> {
+    let stdout_log = Arc::new(Mutex::new(Vec::<String>::new()));
+    let mut engine = engine_with(Config::default());
+
+    {
+        let log = stdout_log.clone();
+        engine.register_fn("record_out", move |text: ImmutableString| {
+            log.lock().unwrap().push(text.into());
+        });
+    }
+
+    let script = r#"
+        fn out_cb(text) { record_out(text); }
+        let result = process::cmd(["python3", "-c", "import sys, time; sys.stdout.write('alp'); sys.stdout.flush(); time.sleep(0.1); sys.stdout.write('ha\\nbeta\\ngam'); sys.stdout.flush(); time.sleep(0.1); sys.stdout.write('ma')"])
+            .build()
+            .run_stream_lines(out_cb);
+        result.success
+    "#;
+    assert!(eval_bool(&engine, script)?);
+    assert_eq!(
+        *stdout_log.lock().unwrap(),
+        vec!["alpha".to_string(), "beta".to_string(), "gamma".to_string()]
+    );
+    Ok(())
+}
+
+#[test]
+fn run_stream_can_capture_output() -> Result<(), Box<EvalAltResult>> {
+    let engine = engine_with(Config::default());
+    let script = r#"
+        fn out_cb(text) { }
+        let result = process::cmd(["python3", "-c", "import sys; sys.stdout.write('foo'); sys.stderr.write('bar')"])
+            .build()
+            .capture_while_streaming(true)
+            .run_stream(out_cb, out_cb);
+        result.stdout == "foo" && result.stderr == "bar"
+    "#;
+    assert!(eval_bool(&engine, script)?);
+    Ok(())
+}
+
+#[test]
+fn run_stream_callback_can_cancel() -> Result<(), Box<EvalAltResult>> {
+    let engine = engine_with(Config::default());
+    let script = r#"
+        fn out_cb(text) { !text.contains("ready") }
+        let result = process::cmd(["python3", "-c", "import time; print('ready', flush=True); time.sleep(5)"])
+            .build()
+            .timeout(3_000)
+            .run_stream_lines(out_cb);
+        result.cancelled && !result.success && result.duration_ms < 3_000
+    "#;
+    assert!(eval_bool(&engine, script)?);
+    Ok(())
+}
+
+#[test]
+fn stream_buffer_size_limits_chunk_length() -> Result<(), Box<EvalAltResult>> {
+    let chunks = Arc::new(Mutex::new(Vec::<String>::new()));
+    let mut engine = engine_with(
+        Config::default()
+            .stream_buffer_size(4)
+            .stream_poll_interval_ms(10),
+    );
+
+    {
+        let log = chunks.clone();
+        engine.register_fn("record_out", move |text: ImmutableString| {
+            log.lock().unwrap().push(text.into());
+        });
+    }
+
+    let script = r#"
+        fn out_cb(text) { record_out(text); }
+        let result = process::cmd(["python3", "-c", "import sys; sys.stdout.write('abcdefghij')"])
+            .build()
+            .run_stream(out_cb);
+        result.success
+    "#;
+    assert!(eval_bool(&engine, script)?);
+    let chunks = chunks.lock().unwrap();
+    assert!(chunks.iter().all(|chunk| chunk.len() <= 4));
+    assert_eq!(chunks.concat(), "abcdefghij");
+    Ok(())
+}
+
+#[test]
+#[should_panic(expected = "stream_buffer_size must be greater than zero")]
+fn stream_buffer_size_zero_rejected() {
+    let _ = Config::default().stream_buffer_size(0);
+}
+
+#[cfg(not(feature = "no_index"))]
+#[test]
+fn run_stream_bytes_preserves_raw_output() -> Result<(), Box<EvalAltResult>> {
+    let bytes = Arc::new(Mutex::new(Vec::<u8>::new()));
+    let mut engine = engine_with(Config::default());
+
+    {
+        let log = bytes.clone();
+        engine.register_fn("record_bytes", move |chunk: rhai::Blob| {
+            log.lock().unwrap().extend(chunk);
+        });
+    }
+
+    let script = r#"
+        fn out_cb(chunk) { record_bytes(chunk); }
+        let result = process::cmd(["python3", "-c", "import sys; sys.stdout.buffer.write(bytes([0, 159, 255, 10]))"])
+            .build()
+            .run_stream_bytes(out_cb);
+        result.success
+    "#;
+    assert!(eval_bool(&engine, script)?);
+    assert_eq!(*bytes.lock().unwrap(), vec![0u8, 159, 255, 10]);
+    Ok(())
+}
+
+#[test]
+fn on_spawn_receives_each_stage_pid() -> Result<(), Box<EvalAltResult>> {
+    let engine = engine_with(Config::default());
+    let script = r#"
+        let pids = [];
+        let result = process::cmd(["python3", "-c", "print('x')"])
+            .pipe(process::cmd(["python3", "-c", "import sys; sys.stdout.write(sys.stdin.read())"]))
+            .build()
+            .on_spawn(|pid| pids.push(pid))
+            .run();
+        result.success && pids.len() == 2 && pids[0] > 0 && pids[0] != pids[1]
+    "#;
+    assert!(eval_bool(&engine, script)?);
+    Ok(())
+}
+
+#[test]
+fn idle_timeout_kills_silent_process() {
+    let engine = engine_with(Config::default());
+    let script = r#"
+        process::cmd(["python3", "-c", "import time; print('start', flush=True); time.sleep(5)"])
+            .build()
+            .idle_timeout(200)
+            .run();
+        true
+    "#;
+    let err = engine
+        .eval::<bool>(script)
+        .expect_err("idle process should be killed");
+    assert!(err.to_string().contains("waiting for output"));
+}
+
+#[test]
+fn timeout_can_return_result() -> Result<(), Box<EvalAltResult>> {
+    let engine = engine_with(Config::default());
+    let script = r#"
+        let slow = process::cmd(["python3", "-c", "import time; print('partial', flush=True); time.sleep(5)"])
+            .build()
+            .timeout(300)
+            .on_timeout_return_result(true)
+            .run();
+        let closed = process::cmd(["python3", "-c", "import os, time; print('early', flush=True); os.close(1); os.close(2); time.sleep(5)"])
+            .build()
+            .timeout(300)
+            .on_timeout_return_result(true)
+            .run();
+        let idle = process::cmd(["sleep", "5"]).build().idle_timeout(200).on_timeout_return_result(true).run();
+        let quick = process::cmd(["echo", "hi"]).build().timeout(5000).on_timeout_return_result(true).run();
+        slow.timed_out && !slow.success && slow.stdout == "partial\n" && slow.to_map().timed_out
+            && closed.timed_out && closed.stdout == "early\n"
+            && idle.timed_out && !idle.success
+            && !quick.timed_out && quick.success
+    "#;
+    assert!(eval_bool(&engine, script)?);
+    Ok(())
+}
+
+#[test]
+fn result_reports_pid_and_timestamps() -> Result<(), Box<EvalAltResult>> {
+    let engine = engine_with(Config::default());
+    let before = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .expect("clock after epoch")
+        .as_millis();
+    let script = format!(
+        r#"
+        let result = process::cmd(["sleep", "0.2"]).build().run();
+        let handle = process::cmd(["echo", "hi"]).build().start();
+        let waited = handle.wait();
+        result.pid > 0 && waited.pid == handle.pid()
+            && result.started_at >= {before} && result.finished_at - result.started_at >= 200
+            && result.to_map().finished_at == result.finished_at
+    "#
+    );
+    assert!(eval_bool(&engine, &script)?);
+    Ok(())
+}
+
+#[test]
+fn result_lists_stage_details() -> Result<(), Box<EvalAltResult>> {
+    let engine = engine_with(Config::default());
+    let script = r#"
+        let result = (process::cmd(["sh", "-c", "exit 3"])
+            | process::cmd(["sleep", "0.5"])).build().run();
+        let first = result.stages[0];
+        let second = result.stages[1];
+        let single = process::cmd(["echo", "hi"]).build().run();
+        result.stages.len() == 2
+            && first.program == "sh" && first.args[0] == "-c" && first.status == 3
+            && second.program == "sleep" && second.args == ["0.5"] && second.status == 0
+            && first.duration_ms < second.duration_ms && second.duration_ms >= 500
+            && single.stages.len() == 1 && single.stages[0].duration_ms == single.duration_ms
+            && result.to_map().stages[0].status == 3
+    "#;
+    assert!(eval_bool(&engine, script)?);
+    Ok(())
+}
+
+#[test]
+fn check_and_expect_raise_on_failure() -> Result<(), Box<EvalAltResult>> {
+    let engine = engine_with(Config::default());
+    let script = r#"
+        let failing = process::cmd(["sh", "-c", "echo first >&2; echo 'no docs' >&2; exit 4"]).build();
+        let checked = ();
+        let expected = ();
+        try { failing.check(); } catch (err) { checked = err; }
+        try { failing.run().expect("building docs failed"); } catch (err) { expected = err; }
+        let ok = process::cmd(["echo", "fine"]).build().check();
+        checked.kind == "failed" && checked.status == 4
+            && checked.command == failing.to_string()
+            && checked.message.starts_with("command 'sh -c")
+            && checked.message.ends_with("exited with status 4:\nfirst\nno docs")
+            && expected.message.starts_with("building docs failed: command 'sh")
+            && ok.stdout == "fine\n" && ok.expect("unused").success
+            && process::cmd(["true"]).build().expect("true failed").success
+    "#;
+    assert!(eval_bool(&engine, script)?);
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn result_reports_resource_usage() -> Result<(), Box<EvalAltResult>> {
+    let engine = engine_with(Config::default());
+    let script = r#"
+        let program = "data = bytearray(64 * 1024 * 1024)\nsum(range(2000000))";
+        let result = process::cmd(["python3", "-c", program]).build().run();
+        let piped = process::cmd(["echo", "hi"]).pipe(process::cmd(["cat"])).build().run();
+        let handle = process::cmd(["sleep", "5"]).build().start();
+        handle.kill();
+        let killed = handle.wait();
+        result.max_rss_kb > 60000
+            && result.cpu_time_ms > 0
+            && type_of(piped.max_rss_kb) == "i64"
+            && type_of(killed.max_rss_kb) == "i64"
+            && result.to_map().cpu_time_ms == result.cpu_time_ms
+    "#;
+    assert!(eval_bool(&engine, script)?);
+    Ok(())
+}
+
+#[test]
+fn windows_creation_flags_are_accepted_everywhere() -> Result<(), Box<EvalAltResult>> {
+    let engine = engine_with(Config::default());
+    let script = r#"
+        process::cmd(["python3", "-c", "print('ok')"])
+            .build()
+            .hide_window(true)
+            .creation_flags(0x200)
+            .run()
+            .stdout == "ok\n"
+    "#;
+    assert!(eval_bool(&engine, script)?);
+
+    let err = engine
+        .eval::<()>(r#"process::cmd(["true"]).build().creation_flags(-1);"#)
+        .expect_err("negative flags");
+    assert!(err.to_string().contains("unsigned 32-bit"));
+    Ok(())
+}
+
+#[test]
+fn raw_arg_keeps_its_place_among_args() -> Result<(), Box<EvalAltResult>> {
+    let engine = engine_with(Config::default());
+    let script = r#"
+        process::cmd(["python3", "-c", "import sys; print(sys.argv[1:])"])
+            .raw_arg("a \"b\"")
+            .arg("c")
+            .build()
+            .run()
+            .stdout == "['a \"b\"', 'c']\n"
+    "#;
+    assert!(eval_bool(&engine, script)?);
+    Ok(())
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn run_async_runs_executors_built_by_scripts() -> Result<(), Box<EvalAltResult>> {
+    let engine = engine_with(Config::default());
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .expect("runtime");
+
+    let executor: rhai_process::PipelineExecutor =
+        engine.eval(r#"process::cmd(["python3", "-c", "print('hi')"]).build()"#)?;
+    let result = runtime.block_on(executor.run_async())?;
+    assert!(result.success());
+    assert_eq!(result.stdout(), "hi\n");
+
+    let executor: rhai_process::PipelineExecutor = engine.eval(
+        r#"
+        let app = process::cmd(["python3", "-c", "import sys; print('out'); sys.stdout.flush(); print('ERROR bad', file=sys.stderr)"]);
+        let upper = process::cmd(["python3", "-c", "import sys; sys.stdout.write(sys.stdin.read().upper())"]);
+        app.pipe_stderr(upper).build()
+    "#,
+    )?;
+    let result = runtime.block_on(executor.run_async())?;
+    assert_eq!(result.stdout(), "ERROR BAD\n");
+    assert_eq!(result.stderr(), "out\n");
+
+    let executor: rhai_process::PipelineExecutor = engine.eval(
+        r#"process::cmd(["sh", "-c", "echo partial; sleep 5"]).build().timeout(200).on_timeout_return_result(true)"#,
+    )?;
+    let result = runtime.block_on(executor.run_async())?;
+    assert!(result.timed_out() && !result.success());
+    assert_eq!(result.stdout(), "partial\n");
+
+    let executor: rhai_process::PipelineExecutor =
+        engine.eval(r#"process::cmd(["true"]).build().on_exit(|status| ())"#)?;
+    let err = runtime
+        .block_on(executor.run_async())
+        .expect_err("callbacks need a script");
+    assert!(err.to_string().contains("on_exit"));
+    Ok(())
+}
+
+#[cfg(all(feature = "async", target_os = "linux"))]
+#[test]
+fn dropping_run_async_kills_the_pipeline() -> Result<(), Box<EvalAltResult>> {
+    let engine = engine_with(Config::default());
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .expect("runtime");
+    let dir = tempdir().expect("tempdir");
+    let pid_file = dir.path().join("pid");
+
+    let executor: rhai_process::PipelineExecutor = engine.eval(&format!(
+        r#"process::cmd(["sh", "-c", "sleep 30 & echo $! > {}; wait"]).build()"#,
+        pid_file.display()
+    ))?;
+    let dropped = runtime.block_on(async {
+        tokio::time::timeout(std::time::Duration::from_millis(500), executor.run_async()).await
+    });
+    assert!(dropped.is_err());
+
+    let pid: rhai::INT = std::fs::read_to_string(&pid_file)
+        .expect("pid file")
+        .trim()
+        .parse()
+        .expect("pid");
+    let deadline = std::time::Instant::now() + std::time::Duration::from_secs(2);
+    while pid_alive(pid) && std::time::Instant::now() < deadline {
+        std::thread::sleep(std::time::Duration::from_millis(20));
+    }
+    assert!(!pid_alive(pid));
+    Ok(())
+}
+
+#[cfg(feature = "sync")]
+#[test]
+fn sync_engine_runs_scripts_from_many_threads() -> Result<(), Box<EvalAltResult>> {
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<Config>();
+    assert_send_sync::<rhai_process::CommandBuilder>();
+    assert_send_sync::<rhai_process::PipelineExecutor>();
+    assert_send_sync::<rhai_process::ProcessHandle>();
+    assert_send_sync::<rhai_process::ProcessResult>();
+    assert_send_sync::<rhai_process::ProcessPackage>();
+
+    let engine = Arc::new(engine_with(Config::default()));
+    let workers: Vec<_> = (0..4)
+        .map(|n| {
+            let engine = Arc::clone(&engine);
+            std::thread::spawn(move || {
+                engine.eval::<String>(&format!(
+                    r#"process::cmd(["echo", "{n}"]).build().run_stream_lines(|line| ()).stdout + "{n}""#
+                ))
+            })
+        })
+        .collect();
+    for (n, worker) in workers.into_iter().enumerate() {
+        assert_eq!(worker.join().expect("thread")?, n.to_string());
+    }
+    Ok(())
+}
+
+#[test]
+fn idle_timeout_allows_chatty_process() -> Result<(), Box<EvalAltResult>> {
+    let engine = engine_with(Config::default());
+    let script = r#"
+        let result = process::cmd(["python3", "-c", "import time\nfor i in range(4):\n    print(i, flush=True)\n    time.sleep(0.1)"])
+            .build()
+            .idle_timeout(300)
+            .run();
+        result.success && result.stdout.contains("3")
+    "#;
+    assert!(eval_bool(&engine, script)?);
+    Ok(())
+}
+
+#[test]
+fn on_exit_reports_status_for_streamed_runs() -> Result<(), Box<EvalAltResult>> {
+    let engine = engine_with(Config::default());
+    let script = r#"
+        let seen = [];
+        fn out_cb(text) { }
+        let result = process::cmd(["python3", "-c", "import sys; print('bye'); sys.exit(3)"])
+            .build()
+            .on_exit(|status| seen.push(status))
+            .run_stream(out_cb);
+        seen.len() == 1 && seen[0].status == 3 && !seen[0].success && result.status == 3
+    "#;
+    assert!(eval_bool(&engine, script)?);
+    Ok(())
+}
+
+#[test]
+fn capture_combined_preserves_order() -> Result<(), Box<EvalAltResult>> {
+    let engine = engine_with(Config::default());
+    let script = r#"
+        let result = process::cmd(["python3", "-c", "import sys, time\nfor s, t in [(sys.stdout, 'a'), (sys.stderr, 'b'), (sys.stdout, 'c')]:\n    s.write(t); s.flush(); time.sleep(0.05)"])
+            .build()
+            .capture_combined()
+            .run();
+        result.combined == "abc" && result.stdout == "ac" && result.stderr == "b"
+    "#;
+    assert!(eval_bool(&engine, script)?);
+    Ok(())
+}
+
+#[test]
+fn spawn_returns_waitable_handle() -> Result<(), Box<EvalAltResult>> {
+    let engine = engine_with(Config::default());
+    let script = r#"
+        let handle = process::cmd(["python3", "-c", "print('bg')"]).build().start();
+        let pid_ok = handle.pid() > 0;
+        let result = handle.wait();
+        pid_ok && result.success && result.stdout.contains("bg") && !handle.is_running()
+    "#;
+    assert!(eval_bool(&engine, script)?);
+    Ok(())
+}
+
+#[test]
+fn spawn_handle_can_be_killed() -> Result<(), Box<EvalAltResult>> {
+    let engine = engine_with(Config::default());
+    let script = r#"
+        let handle = process::cmd(["python3", "-c", "import time; time.sleep(5)"]).build().start();
+        let running = handle.is_running() && handle.try_wait() == ();
+        handle.kill();
+        let result = handle.wait();
+        running && !result.success && !handle.is_running()
+    "#;
+    assert!(eval_bool(&engine, script)?);
+    Ok(())
+}
+
+#[test]
+fn handle_wait_timeout_polls() -> Result<(), Box<EvalAltResult>> {
+    let engine = engine_with(Config::default());
+    let script = r#"
+        let handle = process::cmd(["python3", "-c", "import time; time.sleep(0.5); print('done')"]).build().start();
+        let early = handle.wait_timeout(50);
+        let result = handle.wait_timeout(5000);
+        early == () && result.success && result.stdout.contains("done")
+    "#;
+    assert!(eval_bool(&engine, script)?);
+    Ok(())
+}
+
+#[test]
+fn handle_drives_stdin() -> Result<(), Box<EvalAltResult>> {
+    let engine = engine_with(Config::default());
+    let script = r#"
+        let handle = process::cmd(["python3", "-c", "import sys; name = input(); print('hello ' + name); sys.stdout.write(sys.stdin.read().upper())"])
+            .build()
+            .start();
+        handle.write_stdin("rhai\n");
+        handle.write_stdin("rest");
+        handle.close_stdin();
+        let result = handle.wait();
+        result.stdout.contains("hello rhai") && result.stdout.contains("REST")
+    "#;
+    assert!(eval_bool(&engine, script)?);
+    Ok(())
+}
+
+#[test]
+fn handle_polls_output_without_blocking() -> Result<(), Box<EvalAltResult>> {
+    let engine = engine_with(Config::default());
+    let script = r#"
+        let handle = process::cmd(["python3", "-c", "import time; print('one', flush=True); time.sleep(0.3); print('two')"])
+            .build()
+            .start();
+        let first = handle.read_stdout_line();
+        while first == () {
+            first = handle.read_stdout_line();
+        }
+        let still_running = handle.is_running();
+        let result = handle.wait();
+        let second = handle.read_stdout_line();
+        first == "one" && still_running && second == "two"
+            && handle.read_stdout_line() == () && result.stdout.contains("one")
+    "#;
+    assert!(eval_bool(&engine, script)?);
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn run_detached_starts_new_session() -> Result<(), Box<EvalAltResult>> {
+    let dir = tempdir().expect("tempdir");
+    let marker = dir.path().join("sid.txt");
+    let script = format!(
+        r#"
+        process::cmd(["python3", "-c", "import os; open(r'{path}', 'w').write('%d %d' % (os.getpid(), os.getsid(0)))"])
+            .build()
+            .run_detached()
+        "#,
+        path = marker.display()
+    );
+    let engine = engine_with(Config::default());
+    let pid = engine.eval::<rhai::INT>(&script)?;
+    let deadline = std::time::Instant::now() + std::time::Duration::from_secs(5);
+    while !marker.exists() && std::time::Instant::now() < deadline {
+        std::thread::sleep(std::time::Duration::from_millis(20));
+    }
+    std::thread::sleep(std::time::Duration::from_millis(50));
+    let contents = std::fs::read_to_string(&marker).expect("detached child should write marker");
+    assert_eq!(contents, format!("{pid} {pid}"));
+    Ok(())
+}
+
+#[test]
+fn job_registry_lists_and_kills_jobs() -> Result<(), Box<EvalAltResult>> {
+    let engine = engine_with(Config::default());
+    let script = r#"
+        let handle = process::cmd(["python3", "-c", "import time; time.sleep(5)"]).build().start();
+        let listed = process::jobs();
+        let found = listed.len() == 1 && listed[0].id == handle.id() && listed[0].pid == handle.pid()
+            && listed[0].command.contains("python3");
+        let killed = process::kill_job(handle.id());
+        let result = handle.wait();
+        found && killed && !result.success && process::jobs().is_empty() && !process::kill_job(handle.id())
+    "#;
+    assert!(eval_bool(&engine, script)?);
+
+    // Imported as a global module, the calls are not namespaced, which the
+    // optimizer would otherwise evaluate while compiling.
+    let config = Config::default();
+    let mut engine = Engine::new();
+    engine.register_global_module(module(config.clone()).into());
+    register(&mut engine, config);
+    engine.set_optimization_level(rhai::OptimizationLevel::Full);
+    let script = r#"
+        let handle = cmd(["sleep", "5"]).build().start();
+        let running = jobs().len();
+        handle.kill();
+        running == 1 && split("a b") == ["a", "b"]
+    "#;
+    assert!(eval_bool(&engine, script)?);
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn timeout_kills_grandchildren() {
+    let dir = tempdir().expect("tempdir");
+    let marker = dir.path().join("grandchild.pid");
+    let script = format!(
+        r#"
+        process::cmd(["sh", "-c", "sleep 30 & echo $! > '{path}'; wait"])
+            .build()
+            .timeout(300)
+            .run();
+        true
+        "#,
+        path = marker.display()
+    );
+    let engine = engine_with(Config::default());
+    let err = engine.eval::<bool>(&script).expect_err("should time out");
+    assert!(err.to_string().contains("timed out"));
+
+    let pid = std::fs::read_to_string(&marker).expect("grandchild pid");
+    let stat_path = format!("/proc/{}/stat", pid.trim());
+    let deadline = std::time::Instant::now() + std::time::Duration::from_secs(2);
+    let alive = || {
+        std::fs::read_to_string(&stat_path)
+            .map(|stat| !stat.contains(") Z"))
+            .unwrap_or(false)
+    };
+    while alive() && std::time::Instant::now() < deadline {
+        std::thread::sleep(std::time::Duration::from_millis(20));
+    }
+    assert!(!alive(), "grandchild should be killed with the pipeline");
+}
+
+#[cfg(unix)]
+#[test]
+fn grace_period_lets_process_handle_sigterm() {
+    let dir = tempdir().expect("tempdir");
+    let marker = dir.path().join("flushed.txt");
+    let script = format!(
+        r#"
+        process::cmd(["python3", "-c", "import signal, sys, time\ndef done(*_):\n    open(r'{path}', 'w').write('flushed')\n    sys.exit(0)\nsignal.signal(signal.SIGTERM, done)\nprint('ready', flush=True)\ntime.sleep(30)"])
+            .build()
+            .timeout(500)
+            .grace_period(2_000)
+            .run();
+        true
+        "#,
+        path = marker.display()
+    );
+    let engine = engine_with(Config::default().kill_grace_ms(10));
+    let err = engine.eval::<bool>(&script).expect_err("should time out");
+    assert!(err.to_string().contains("timed out"));
+    assert_eq!(
+        std::fs::read_to_string(&marker).expect("SIGTERM handler should run"),
+        "flushed"
+    );
+}
+
+#[cfg(unix)]
+#[test]
+fn handle_sends_signals() -> Result<(), Box<EvalAltResult>> {
+    let engine = engine_with(Config::default());
+    let script = r#"
+        let handle = process::cmd(["python3", "-c", "import signal, time\nsignal.signal(signal.SIGHUP, lambda *_: print('reload', flush=True))\nprint('ready', flush=True)\ntime.sleep(5)"])
+            .build()
+            .start();
+        while handle.read_stdout_line() != "ready" {}
+        handle.signal("SIGHUP");
+        let line = handle.read_stdout_line();
+        while line == () {
+            line = handle.read_stdout_line();
+        }
+        let alive = handle.is_running();
+        handle.signal(15);
+        let result = handle.wait();
+        line == "reload" && alive && !result.success
+    "#;
+    assert!(eval_bool(&engine, script)?);
+
+    let err = engine
+        .eval::<()>(r#"process::cmd(["true"]).build().start().signal("SIGBOGUS")"#)
+        .expect_err("unknown signal should fail");
+    assert!(err.to_string().contains("unknown signal"));
+
+    let err = engine
+        .eval::<()>(r#"let h = process::cmd(["true"]).build().start(); h.wait(); h.signal(15)"#)
+        .expect_err("a reaped process must not be signalled");
+    assert!(err.to_string().contains("already exited"));
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn result_reports_terminating_signal() -> Result<(), Box<EvalAltResult>> {
+    let engine = engine_with(Config::default());
+    let script = r#"
+        let handle = process::cmd(["sleep", "5"]).build().start();
+        handle.kill();
+        let killed = handle.wait();
+        let piped = (process::cmd(["sh", "-c", "kill -SEGV $$"]) | process::cmd(["cat"])).build().pipefail(true).run();
+        let clean = process::cmd(["true"]).build().run();
+        killed.status == -1 && killed.signal.number == 9 && killed.signal.name == "SIGKILL"
+            && piped.signal.name == "SIGSEGV" && piped.stages[0].signal.name == "SIGSEGV"
+            && piped.stages[1].signal == () && clean.signal == ()
+            && killed.to_map().signal.name == "SIGKILL"
+    "#;
+    assert!(eval_bool(&engine, script)?);
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn memory_limit_caps_children() -> Result<(), Box<EvalAltResult>> {
+    let script = r#"
+        let alloc = "x = bytearray(400 * 1024 * 1024)";
+        let small = process::cmd(["python3", "-c", "x = bytearray(1024)"]).build().limit_memory_mb(200).run();
+        let capped = process::cmd(["python3", "-c", alloc]).build().limit_memory_mb(200).run();
+        small.success && !capped.success && capped.stderr.contains("MemoryError")
+    "#;
+    assert!(eval_bool(&engine_with(Config::default()), script)?);
+
+    let engine = engine_with(Config::default().default_memory_limit_mb(200));
+    let script = r#"
+        let result = process::cmd(["python3", "-c", "x = bytearray(400 * 1024 * 1024)"]).build().run();
+        !result.success && result.stderr.contains("MemoryError")
+    "#;
+    assert!(eval_bool(&engine, script)?);
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn cpu_limit_kills_busy_children() -> Result<(), Box<EvalAltResult>> {
+    let engine = engine_with(Config::default());
+    let script = r#"
+        let result = process::cmd(["python3", "-c", "while True: pass"])
+            .build()
+            .timeout(20000)
+            .limit_cpu_seconds(1)
+            .run();
+        !result.success && result.signal.name == "SIGXCPU" && result.duration_ms < 10000
+    "#;
+    assert!(eval_bool(&engine, script)?);
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn open_file_limit_caps_descriptors() -> Result<(), Box<EvalAltResult>> {
+    let engine = engine_with(Config::default());
+    let script = r#"
+        let open_many = "files = [open('/dev/null') for _ in range(64)]";
+        let capped = process::cmd(["python3", "-c", open_many]).build().limit_open_files(32).run();
+        let roomy = process::cmd(["python3", "-c", open_many]).build().limit_open_files(256).run();
+        !capped.success && capped.stderr.contains("Too many open files") && roomy.success
+            && process::cmd(["true"]).build().limit_processes(64).run().success
+    "#;
+    assert!(eval_bool(&engine, script)?);
+
+    let err = engine
+        .eval::<()>(r#"process::cmd(["true"]).build().limit_processes(0);"#)
+        .expect_err("zero is rejected");
+    assert!(err
+        .to_string()
+        .contains("limit_processes must be a positive integer"));
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn nice_lowers_child_priority() -> Result<(), Box<EvalAltResult>> {
+    let engine = engine_with(Config::default());
+    let script = r#"
+        let niceness = ["python3", "-c", "import os; print(os.nice(0))"];
+        process::cmd(niceness).build().nice(5).run().stdout == "5\n"
+            && process::cmd(niceness).build().priority("low").run().stdout == "10\n"
+    "#;
+    assert!(eval_bool(&engine, script)?);
+
+    let err = engine
+        .eval::<()>(r#"process::cmd(["true"]).build().priority("urgent");"#)
+        .expect_err("unknown level");
+    assert!(err.to_string().contains("unknown priority 'urgent'"));
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn cpu_affinity_pins_child_to_cpus() -> Result<(), Box<EvalAltResult>> {
+    let engine = engine_with(Config::default());
+    let script = r#"
+        process::cmd(["python3", "-c", "import os; print(sorted(os.sched_getaffinity(0)))"])
+            .build()
+            .cpu_affinity([0])
+            .run()
+            .stdout == "[0]\n"
+    "#;
+    assert!(eval_bool(&engine, script)?);
+
+    let err = engine
+        .eval::<()>(r#"process::cmd(["true"]).build().cpu_affinity([]);"#)
+        .expect_err("empty set");
+    assert!(err.to_string().contains("needs at least one CPU"));
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn io_priority_sets_io_class() -> Result<(), Box<EvalAltResult>> {
+    let engine = engine_with(Config::default());
+    let script = r#"
+        let ionice = ["sh", "-c", "ionice -p $$"];
+        process::cmd(ionice).build().io_priority("idle").run().stdout == "idle\n"
+            && process::cmd(ionice).build().io_priority("best_effort", 7).run().stdout
+                == "best-effort: prio 7\n"
+    "#;
+    assert!(eval_bool(&engine, script)?);
+
+    let err = engine
+        .eval::<()>(r#"process::cmd(["true"]).build().io_priority("best_effort", 9);"#)
+        .expect_err("level out of range");
+    assert!(err.to_string().contains("between 0 and 7"));
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn windows_builtins_are_left_alone_elsewhere() -> Result<(), Box<EvalAltResult>> {
+    let engine = engine_with(Config::default().allow_windows_builtins(true));
+    let script = r#"
+        process::cmd(["echo", "hi"]).build().run().stdout == "hi\n"
+    "#;
+    assert!(eval_bool(&engine, script)?);
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn run_as_user_requires_permission() -> Result<(), Box<EvalAltResult>> {
+    let engine = engine_with(Config::default());
+    let err = engine
+        .eval::<()>(r#"process::cmd(["id"]).build().run_as_user("nobody");"#)
+        .expect_err("privilege change disabled");
+    assert!(err.to_string().contains("allow_privilege_change"));
+
+    let running_as_root = std::process::Command::new("id")
+        .arg("-u")
+        .output()
+        .is_ok_and(|output| output.stdout == b"0\n");
+    if running_as_root {
+        let engine = engine_with(Config::default().allow_privilege_change(true));
+        let script = r#"
+            process::cmd(["id", "-u"]).build().run_as_user("nobody").run().stdout != "0\n"
+                && process::cmd(["id", "-g"]).build().uid(1234).gid(4321).run().stdout
+                    == "4321\n"
+                && process::cmd(["python3", "-c", "import os; print(os.getuid(), os.nice(0))"])
+                    .build()
+                    .uid(1234)
+                    .nice(-5)
+                    .run()
+                    .stdout == "1234 -5\n"
+        "#;
+        assert!(eval_bool(&engine, script)?);
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn umask_applies_to_children() -> Result<(), Box<EvalAltResult>> {
+    let engine = engine_with(Config::default());
+    let script = r#"
+        process::cmd(["sh", "-c", "umask"]).build().umask(0o027).run().stdout == "0027\n"
+    "#;
+    assert!(eval_bool(&engine, script)?);
+
+    let err = engine
+        .eval::<()>(r#"process::cmd(["true"]).build().umask(0o1000);"#)
+        .expect_err("mask out of range");
+    assert!(err.to_string().contains("umask must be between"));
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn chroot_confines_children() -> Result<(), Box<EvalAltResult>> {
+    let engine = engine_with(Config::default());
+    let err = engine
+        .eval::<()>(r#"process::cmd(["true"]).build().chroot("/tmp");"#)
+        .expect_err("chroot disabled");
+    assert!(err.to_string().contains("allow_chroot"));
+
+    let engine = engine_with(Config::default().allow_chroot(true));
+    let err = engine
+        .eval::<()>(r#"process::cmd(["true"]).build().cwd("/").chroot("/tmp").run();"#)
+        .expect_err("cwd and chroot");
+    assert!(err.to_string().contains("cannot be combined"));
+
+    let running_as_root = std::process::Command::new("id")
+        .arg("-u")
+        .output()
+        .is_ok_and(|output| output.stdout == b"0\n");
+    if running_as_root {
+        // The empty tree has no shell to run.
+        let root = tempfile::tempdir().expect("tempdir");
+        let script = format!(
+            r#"
+            let kind = ();
+            try {{
+                process::cmd(["/bin/sh", "-c", "true"]).build().chroot("{}").run();
+            }} catch (err) {{
+                kind = err.kind;
+            }}
+            kind == "not_found"
+            "#,
+            root.path().display()
+        );
+        assert!(eval_bool(&engine, &script)?);
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn pty_attaches_a_terminal() -> Result<(), Box<EvalAltResult>> {
+    let engine = engine_with(Config::default());
+    let script = r#"
+        let probe = "test -t 0 && test -t 1 && test -t 2 && echo tty || echo pipe";
+        let lines = [];
+        process::cmd(["sh", "-c", probe]).build().pty(true)
+            .run_stream_lines(|line| lines.push(line));
+        lines == ["tty"] && process::cmd(["sh", "-c", probe]).build().run().stdout == "pipe\n"
+    "#;
+    assert!(eval_bool(&engine, script)?);
+
+    let script = r#"
+        let handle = process::cmd(["sh", "-c", "read line; echo got:$line >&2"])
+            .build()
+            .spawn_pty();
+        handle.write_stdin("secret\n");
+        let result = handle.wait();
+        result.stdout.contains("got:secret\r\n") && result.stderr == ""
+    "#;
+    assert!(eval_bool(&engine, script)?);
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn expect_drives_interactive_prompts() -> Result<(), Box<EvalAltResult>> {
+    let engine = engine_with(Config::default());
+    let script = r#"
+        let login = "printf 'user: '; read u; printf 'password: '; read p; echo \"hello $u/$p\"";
+        let handle = process::cmd(["sh", "-c", login]).build().spawn_pty();
+        handle.expect("user:", 5000);
+        handle.send_line("bob");
+        handle.expect("password:", 5000);
+        handle.send_line("secret");
+        let greeting = handle.expect("re:hello \\w+/\\w+", 5000);
+
+        let kinds = [];
+        try {
+            handle.expect("never", 5000);
+        } catch (err) {
+            kinds.push(err.kind);
+        }
+        let quiet = process::cmd(["sleep", "5"]).build().spawn_pty();
+        try {
+            quiet.expect("never", 100);
+        } catch (err) {
+            kinds.push(err.kind);
+        }
+        quiet.kill();
+        greeting.ends_with("hello bob/secret") && kinds == ["failed", "timeout"]
+    "#;
+    assert!(eval_bool(&engine, script)?);
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn parallel_runs_pipelines_concurrently() -> Result<(), Box<EvalAltResult>> {
+    let engine = engine_with(Config::default());
+    let script = r#"
+        let slow = |n| process::cmd(["sh", "-c", `sleep 0.4; echo ${n}`]);
+        let started = timestamp();
+        let results = process::parallel([slow.call(1), slow.call(2).build(), slow.call(3)]);
+        let together = started.elapsed;
+        started = timestamp();
+        let limited = process::parallel_limit(1, [slow.call(4), slow.call(5)]);
+        let one_by_one = started.elapsed;
+        results.map(|r| r.stdout) == ["1\n", "2\n", "3\n"]
+            && limited.map(|r| r.stdout) == ["4\n", "5\n"]
+            && together < 1.0 && one_by_one >= 0.8
+    "#;
+    assert!(eval_bool(&engine, script)?);
+
+    let script = r#"
+        let started = timestamp();
+        let kind = ();
+        try {
+            process::parallel([
+                process::cmd(["sleep", "5"]),
+                process::cmd(["sleep", "5"]).build().timeout(200),
+            ]);
+        } catch (err) {
+            kind = err.kind;
+        }
+        kind == "timeout" && started.elapsed < 2.0
+    "#;
+    assert!(eval_bool(&engine, script)?);
+
+    let err = engine
+        .eval::<rhai::Dynamic>("process::parallel_limit(0, [])")
+        .expect_err("zero limit");
+    assert!(err.to_string().contains("greater than zero"));
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn race_returns_first_finisher() -> Result<(), Box<EvalAltResult>> {
+    let engine = engine_with(Config::default());
+    let script = r#"
+        let started = timestamp();
+        let winner = process::race([
+            process::cmd(["sh", "-c", "sleep 5; echo slow"]),
+            process::cmd(["sh", "-c", "sleep 0.2; echo fast"]),
+        ]);
+        winner.stdout == "fast\n" && started.elapsed < 2.0
+    "#;
+    assert!(eval_bool(&engine, script)?);
+
+    let err = engine
+        .eval::<rhai::Dynamic>("process::race([])")
+        .expect_err("nothing to race");
+    assert!(err.to_string().contains("at least one"));
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn wait_all_and_wait_any_collect_handles() -> Result<(), Box<EvalAltResult>> {
+    let engine = engine_with(Config::default());
+    let script = r#"
+        let slow = process::cmd(["sh", "-c", "sleep 0.6; echo slow"]).build().start();
+        let fast = process::cmd(["sh", "-c", "sleep 0.1; echo fast"]).build().start();
+        let first = process::wait_any([slow, fast]);
+        let fast_first = first.index == 1 && first.result.stdout == "fast\n" && slow.is_running();
+
+        let all = process::wait_all([slow, fast, process::cmd(["cat"]).build().start()]);
+        fast_first && all.map(|r| r.stdout) == ["slow\n", "fast\n", ""]
+    "#;
+    assert!(eval_bool(&engine, script)?);
+
+    let err = engine
+        .eval::<rhai::Dynamic>(r#"process::wait_all([process::cmd(["true"])])"#)
+        .expect_err("not a handle");
+    assert!(err.to_string().contains("expected a ProcessHandle"));
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn cancellation_handle_kills_in_flight_pipelines() -> Result<(), Box<EvalAltResult>> {
+    let config = Config::default();
+    let cancel = config.cancellation_handle();
+    let engine = engine_with(config);
+    let canceller = std::thread::spawn(move || {
+        std::thread::sleep(std::time::Duration::from_millis(300));
+        cancel.cancel();
+    });
+    let script = r#"
+        let started = timestamp();
+        let background = process::cmd(["sleep", "10"]).build().start();
+        let kinds = [];
+        try {
+            process::cmd(["sleep", "10"]).build().run();
+        } catch (err) {
+            kinds.push(err.kind);
+        }
+        try {
+            background.wait();
+        } catch (err) {
+            kinds.push(err.kind);
+        }
+        kinds == ["cancelled", "cancelled"]
+            && started.elapsed < 5.0
+            && process::cmd(["echo", "again"]).build().run().stdout == "again\n"
+    "#;
+    assert!(eval_bool(&engine, script)?);
+    canceller.join().expect("canceller");
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn engine_termination_kills_pipelines() {
+    // SIGKILL is delivered asynchronously, so give the processes a moment.
+    let gone = |pattern: &str| {
+        (0..40).any(|_| {
+            let found = std::process::Command::new("pgrep")
+                .args(["-fx", pattern])
+                .output()
+                .is_ok_and(|output| output.status.success());
+            if found {
+                std::thread::sleep(std::time::Duration::from_millis(50));
+            }
+            !found
+        })
+    };
+
+    // Terminated while blocked in run().
+    let stop = Arc::new(std::sync::atomic::AtomicBool::new(false));
+    let flag = Arc::clone(&stop);
+    let engine = engine_with(Config::default().on_progress(move |_| {
+        flag.load(std::sync::atomic::Ordering::Relaxed)
+            .then(|| "stop".into())
+    }));
+    let setter = std::thread::spawn(move || {
+        std::thread::sleep(std::time::Duration::from_millis(300));
+        stop.store(true, std::sync::atomic::Ordering::Relaxed);
+    });
+    let started = std::time::Instant::now();
+    let err = engine
+        .run(
+            r#"
+            let background = process::cmd(["sleep", "10.37"]).build().start();
+            process::cmd(["sleep", "10.38"]).build().run();
+            "#,
+        )
+        .expect_err("terminated");
+    setter.join().expect("setter");
+    assert!(
+        matches!(*err, EvalAltResult::ErrorTerminated(ref token, _) if token.to_string() == "stop")
+    );
+    assert!(started.elapsed() < std::time::Duration::from_secs(5));
+    assert!(gone("sleep 10.37"), "10.37");
+    assert!(gone("sleep 10.38"), "10.38");
+
+    // Terminated by the engine between operations.
+    let engine = engine_with(
+        Config::default().on_progress(|count| (count > 10_000).then(|| "too long".into())),
+    );
+    let err = engine
+        .run(
+            r#"
+            let background = process::cmd(["sleep", "10.39"]).build().start();
+            let i = 0;
+            loop { i += 1; }
+            "#,
+        )
+        .expect_err("terminated");
+    assert!(matches!(*err, EvalAltResult::ErrorTerminated(..)));
+    assert!(gone("sleep 10.39"), "10.39");
+}
+
+#[cfg(unix)]
+#[test]
+fn supervise_restarts_failed_pipeline() -> Result<(), Box<EvalAltResult>> {
+    let engine = engine_with(Config::default());
+    let script = r#"
+        let attempts = [];
+        let supervisor = process::cmd(["sh", "-c", "echo up; exit 3"])
+            .build()
+            .supervise(#{
+                restarts: 2,
+                backoff_ms: 20,
+                on_restart: |attempt, result| attempts.push([attempt, result.status]),
+            });
+        let last = supervisor.wait();
+        let failed = last.status == 3 && last.stdout == "up\n" && !supervisor.is_running()
+            && supervisor.restarts() == 2 && attempts == [[1, 3], [2, 3]];
+
+        let clean = process::cmd(["true"]).build().supervise(#{ restarts: 5 });
+        failed && clean.wait().success && clean.restarts() == 0
+    "#;
+    assert!(eval_bool(&engine, script)?);
+
+    let script = r#"
+        let started = timestamp();
+        let supervisor = process::cmd(["sleep", "5"]).build().supervise(#{});
+        let running = supervisor.is_running() && supervisor.pid() > 0;
+        let last = supervisor.stop();
+        running && !last.success && supervisor.restarts() == 0 && started.elapsed < 2.0
+    "#;
+    assert!(eval_bool(&engine, script)?);
+
+    let err = engine
+        .eval::<rhai::Dynamic>(r#"process::cmd(["true"]).build().supervise(#{ retries: 1 })"#)
+        .expect_err("unknown option");
+    assert!(err.to_string().contains("retries"));
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn every_reruns_on_schedule() -> Result<(), Box<EvalAltResult>> {
+    let engine = engine_with(Config::default());
+    let script = r#"
+        let seen = [];
+        let started = timestamp();
+        let poll = process::every(100, process::cmd(["echo", "tick"]), |result| {
+            seen.push(result.stdout);
+            seen.len() < 3
+        });
+        poll.wait();
+        seen == ["tick\n", "tick\n", "tick\n"] && started.elapsed >= 0.2
+            && !poll.is_running() && poll.runs() == 3
+    "#;
+    assert!(eval_bool(&engine, script)?);
+
+    let script = r#"
+        let started = timestamp();
+        let poll = process::every(10000, process::cmd(["sleep", "5"]), |result| true);
+        let running = poll.is_running();
+        poll.stop();
+        running && !poll.is_running() && poll.runs() == 0 && started.elapsed < 2.0
+    "#;
+    assert!(eval_bool(&engine, script)?);
+
+    let script = r#"
+        let poll = process::every(10, process::cmd(["true"]), |result| true);
+        process::cmd(["sleep", "0.6"]).build().run();
+        poll.stop();
+        poll.runs()
+    "#;
+    assert_eq!(
+        engine.eval::<rhai::INT>(script)?,
+        16,
+        "only the latest results are kept"
+    );
+
+    let err = engine
+        .eval::<rhai::Dynamic>(r#"process::every(0, process::cmd(["true"]), |r| true)"#)
+        .expect_err("zero interval");
+    assert!(err.to_string().contains("greater than zero"));
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn pid_alive(pid: rhai::INT) -> bool {
+    std::fs::read_to_string(format!("/proc/{pid}/stat"))
+        .map(|stat| !stat.contains(") Z"))
+        .unwrap_or(false)
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn process_group_isolates_and_kills_the_group() -> Result<(), Box<EvalAltResult>> {
+    let engine = engine_with(Config::default());
+    let script = r#"
+        let leads = "import os; print(os.getpgid(0) == os.getpid(), os.getsid(0) == os.getpid())";
+        let grouped = process::cmd(["python3", "-c", leads]).build().process_group(true).run();
+        let session = process::cmd(["python3", "-c", leads]).build().new_session(true).run();
+        let plain = process::cmd(["python3", "-c", leads]).build().run();
+        grouped.stdout == "True False\n" && session.stdout == "True True\n"
+            && plain.stdout == "True False\n"
+    "#;
+    assert!(eval_bool(&engine, script)?);
+
+    // The subshell exits at once, so its sleep is reparented and only
+    // reachable through the process group, which every stage leads.
+    let dir = tempdir().expect("tempdir");
+    let pid_file = dir.path().join("orphan.pid");
+    let script = format!(
+        r#"
+        let result = process::cmd(["sh", "-c", "(sleep 30 & echo $! > '{}'); sleep 30"])
+            .build()
+            .timeout(500)
+            .on_timeout_return_result(true)
+            .run();
+        result.timed_out
+        "#,
+        pid_file.display()
+    );
+    assert!(eval_bool(&engine, &script)?);
+    let orphan: rhai::INT = std::fs::read_to_string(&pid_file)
+        .expect("pid file")
+        .trim()
+        .parse()
+        .expect("pid");
+    std::thread::sleep(std::time::Duration::from_millis(100));
+    assert!(!pid_alive(orphan), "the orphaned sleep should be killed");
+    Ok(())
+}
+
+#[cfg(all(target_os = "linux", not(feature = "no_index")))]
+#[test]
+fn job_registry_releases_finished_jobs() -> Result<(), Box<EvalAltResult>> {
+    let engine = engine_with(Config::default());
+    let script = r#"
+        let pids = [];
+        for i in 0..20 {
+            let handle = process::cmd(["true"]).build().start();
+            pids.push(handle.pid());
+        }
+        process::cmd(["sleep", "0.2"]).build().run();
+        process::cmd(["true"]).build().start().wait();
+        pids
+    "#;
+    let pids = engine.eval::<rhai::Array>(script)?;
+    assert_eq!(pids.len(), 20);
+    for pid in pids {
+        let pid = pid.as_int().expect("pid");
+        assert!(
+            !std::path::Path::new(&format!("/proc/{pid}")).exists(),
+            "finished job {pid} should be reaped and released"
+        );
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn kill_on_drop_terminates_dropped_handles() -> Result<(), Box<EvalAltResult>> {
+    let script = r#"
+        let pid = 0;
+        {
+            let handle = process::cmd(["sleep", "30"]).build().start();
+            pid = handle.pid();
+        }
+        pid
+    "#;
+    let engine = engine_with(Config::default().kill_on_drop(true));
+    let pid = engine.eval::<rhai::INT>(script)?;
+    assert!(!pid_alive(pid), "dropped handle should kill the child");
+
+    let engine = engine_with(Config::default());
+    let pid = engine.eval::<rhai::INT>(script)?;
+    assert!(pid_alive(pid), "default policy leaves the child running");
+    let killed = engine.eval::<bool>(&format!(
+        "process::kill_job(process::jobs()[0].id) && {pid} > 0"
+    ))?;
+    assert!(killed);
+
+    let overridden = r#"
+        let handle = process::cmd(["sleep", "30"]).build().start();
+        handle.kill_on_drop(true);
+        handle.pid()
+    "#;
+    let pid = engine.eval::<rhai::INT>(overridden)?;
+    assert!(!pid_alive(pid), "per-handle override should kill the child");
     Ok(())
 }